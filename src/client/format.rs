@@ -0,0 +1,213 @@
+use std::fmt;
+use std::str::FromStr;
+
+use unicode_width::UnicodeWidthStr;
+
+use crate::executor::{Column, QueryResult};
+use crate::types::{DataType, Value};
+
+// how rows are written out. messages like "Inserted 2 rows" are written as they are in every format
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Format {
+    #[default]
+    Table, // lined up under the column names, for people
+    Csv,   // RFC 4180, NULL is an empty field and an empty string is ""
+    Tsv,   // tab separated with \t, \n, \r and \ escaped by a backslash and NULL as \N
+    Json,  // an array with an object per row
+    Jsonl, // an object per line
+    Markdown,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name.to_lowercase().as_str() {
+            "table" => Ok(Format::Table),
+            "csv" => Ok(Format::Csv),
+            "tsv" => Ok(Format::Tsv),
+            "json" => Ok(Format::Json),
+            "jsonl" => Ok(Format::Jsonl),
+            "markdown" | "md" => Ok(Format::Markdown),
+            _ => Err(format!("unknown format '{}', expected table, csv, tsv, json, jsonl or markdown", name)),
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Format::Table => "table",
+            Format::Csv => "csv",
+            Format::Tsv => "tsv",
+            Format::Json => "json",
+            Format::Jsonl => "jsonl",
+            Format::Markdown => "markdown",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl Format {
+    pub fn render(&self, result: &QueryResult) -> String {
+        let QueryResult::Rows { columns, rows } = result else {
+            return result.to_string();
+        };
+        match self {
+            Format::Table => table(columns, rows),
+            Format::Csv => separated(columns, rows, ",", csv_field),
+            Format::Tsv => separated(columns, rows, "\t", tsv_field),
+            Format::Json => {
+                let objects: Vec<String> = rows.iter().map(|row| format!("  {}", json_object(columns, row))).collect();
+                if objects.is_empty() { "[]".to_string() } else { format!("[\n{}\n]", objects.join(",\n")) }
+            }
+            Format::Jsonl => rows.iter().map(|row| json_object(columns, row)).collect::<Vec<_>>().join("\n"),
+            Format::Markdown => markdown(columns, rows),
+        }
+    }
+}
+
+// numbers line up on the right like in psql, everything else on the left. widths are measured in
+// terminal columns, so text with wide characters like CJK still lines up
+fn table(columns: &[Column], rows: &[Vec<Value>]) -> String {
+    let cells: Vec<Vec<String>> = rows.iter().map(|row| row.iter().map(|value| value.to_string()).collect()).collect();
+    let mut widths: Vec<usize> = columns.iter().map(|c| c.name.width()).collect();
+    for row in &cells {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.width());
+        }
+    }
+
+    let line = |values: &[String], headers: bool| {
+        let padded: Vec<String> = columns.iter().zip(&widths).zip(values)
+            .map(|((column, &width), value)| {
+                let padding = " ".repeat(width.saturating_sub(value.width()));
+                match column.data_type {
+                    DataType::Integer | DataType::Real if !headers => format!(" {}{} ", padding, value),
+                    _ => format!(" {}{} ", value, padding),
+                }
+            })
+            .collect();
+        padded.join("|").trim_end().to_string()
+    };
+    let names: Vec<String> = columns.iter().map(|c| c.name.clone()).collect();
+    let mut lines = vec![line(&names, true)];
+    lines.push(widths.iter().map(|width| "-".repeat(width + 2)).collect::<Vec<_>>().join("+"));
+    lines.extend(cells.iter().map(|row| line(row, false)));
+    lines.push(format!("({} row{})", rows.len(), if rows.len() == 1 { "" } else { "s" }));
+    lines.join("\n")
+}
+
+fn separated(columns: &[Column], rows: &[Vec<Value>], separator: &str, field: fn(Option<&str>) -> String) -> String {
+    let mut lines = vec![columns.iter().map(|c| field(Some(&c.name))).collect::<Vec<_>>().join(separator)];
+    for row in rows {
+        let fields: Vec<String> = row.iter()
+            .map(|value| match value {
+                Value::Null => field(None),
+                value => field(Some(&value.to_string())),
+            })
+            .collect();
+        lines.push(fields.join(separator));
+    }
+    lines.join("\n")
+}
+
+// quoted when it has a comma, quote or line break in it or would otherwise read as NULL
+fn csv_field(text: Option<&str>) -> String {
+    match text {
+        None => String::new(),
+        Some(text) if text.is_empty() || text.contains([',', '"', '\n', '\r']) => format!("\"{}\"", text.replace('"', "\"\"")),
+        Some(text) => text.to_string(),
+    }
+}
+
+fn tsv_field(text: Option<&str>) -> String {
+    let Some(text) = text else {
+        return "\\N".to_string();
+    };
+    let mut field = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\\' => field.push_str("\\\\"),
+            '\t' => field.push_str("\\t"),
+            '\n' => field.push_str("\\n"),
+            '\r' => field.push_str("\\r"),
+            c => field.push(c),
+        }
+    }
+    field
+}
+
+// values keep their type, reals that JSON has no number for (NaN and the infinities) are null
+fn json_object(columns: &[Column], row: &[Value]) -> String {
+    let fields: Vec<String> = columns.iter().zip(row)
+        .map(|(column, value)| {
+            let value = match value {
+                Value::Null => "null".to_string(),
+                Value::Integer(i) => i.to_string(),
+                Value::Real(r) if r.is_finite() => format!("{:?}", r),
+                Value::Real(_) => "null".to_string(),
+                Value::Boolean(b) => b.to_string(),
+                Value::Text(text) => json_string(text),
+            };
+            format!("{}: {}", json_string(&column.name), value)
+        })
+        .collect();
+    format!("{{{}}}", fields.join(", "))
+}
+
+fn json_string(text: &str) -> String {
+    let mut out = String::with_capacity(text.len() + 2);
+    out.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+// a pipe in a value would end the cell and a line break the row, so they are escaped
+fn markdown(columns: &[Column], rows: &[Vec<Value>]) -> String {
+    let cell = |text: &str| text.replace('\\', "\\\\").replace('|', "\\|").replace("\r\n", "<br>").replace('\n', "<br>");
+    let mut lines = vec![format!("| {} |", columns.iter().map(|c| cell(&c.name)).collect::<Vec<_>>().join(" | "))];
+    let aligns: Vec<&str> = columns.iter()
+        .map(|c| match c.data_type {
+            DataType::Integer | DataType::Real => "---:",
+            _ => "---",
+        })
+        .collect();
+    lines.push(format!("| {} |", aligns.join(" | ")));
+    for row in rows {
+        let cells: Vec<String> = row.iter().map(|value| cell(&value.to_string())).collect();
+        lines.push(format!("| {} |", cells.join(" | ")));
+    }
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wide_text_lines_up() {
+        let columns = vec![
+            Column { name: "name".to_string(), data_type: DataType::Text },
+            Column { name: "n".to_string(), data_type: DataType::Integer },
+        ];
+        let rows = vec![
+            vec![Value::Text("東京".to_string()), Value::Integer(1)],
+            vec![Value::Text("paris".to_string()), Value::Integer(22)],
+        ];
+        let rendered = Format::Table.render(&QueryResult::Rows { columns, rows });
+        let lines: Vec<&str> = rendered.lines().collect();
+        assert_eq!(lines[..4], [" name  | n", "-------+----", " 東京  |  1", " paris | 22"]);
+    }
+}
@@ -1,23 +1,160 @@
-use std::io::{Read, Write};
-use std::net::TcpStream;
+mod format;
+mod shell;
 
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream};
+
+use crate::error::Error;
+use crate::executor::{OnError, QueryResult};
+use crate::protocol;
+use crate::storage::codec::{self, Reader};
+use crate::types::Value;
+
+pub use format::Format;
+pub use shell::Shell;
+
+const ADDRESS: &str = "127.0.0.1:12345";
+
+// the server reads until we close our side, so requests can be any size
 pub fn send_command(command: &str) {
-    let mut stream = TcpStream::connect("127.0.0.1:12345")
+    let mut stream = TcpStream::connect(ADDRESS)
                         .expect("Could not connect to database server");
     stream.write_all(command.as_bytes()).unwrap();
+    stream.shutdown(Shutdown::Write).unwrap();
 
-    let mut buffer = [0; 512];
-    let size = stream.read(&mut buffer).unwrap();
-    println!("Response: {}", String::from_utf8_lossy(&buffer[..size]));
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).unwrap();
+    println!("Response: {}", String::from_utf8_lossy(&response));
 }
 
-pub fn send_query(query: &str) {
-    let mut stream = TcpStream::connect("127.0.0.1:12345")
-                        .expect("Could not connect to database server");
-    let json_query = format!(r#"{{"query": "{}"}}"#, query);
-    stream.write_all(json_query.as_bytes()).unwrap();
+// a connection speaking the framed protocol, which stays open for any number of statements and keeps
+// the statements prepared on it. values for parameters are sent apart from the statement text, so
+// code taking input from users should prepare a statement once and bind the input to it rather than
+// putting the input into the SQL
+pub struct Connection {
+    stream: TcpStream,
+}
+
+impl Connection {
+    pub fn connect() -> Result<Self, Error> {
+        let mut stream = TcpStream::connect(ADDRESS).map_err(connection_failed)?;
+        stream.write_all(protocol::MAGIC).map_err(connection_failed)?;
+        Ok(Connection { stream })
+    }
+
+    pub fn query(&mut self, sql: &str) -> Result<QueryResult, Error> {
+        self.request(protocol::QUERY, sql.as_bytes())
+    }
+
+    // prepares sql, with $1, $2, ... or ? where the values go, under name. an empty name is replaced
+    // by the next statement prepared without one
+    pub fn prepare(&mut self, name: &str, sql: &str) -> Result<QueryResult, Error> {
+        let mut payload = Vec::new();
+        codec::put_str(&mut payload, name);
+        codec::put_str(&mut payload, sql);
+        self.request(protocol::PREPARE, &payload)
+    }
+
+    pub fn bind(&mut self, name: &str, values: &[Value]) -> Result<QueryResult, Error> {
+        let mut payload = Vec::new();
+        codec::put_str(&mut payload, name);
+        protocol::put_values(&mut payload, values);
+        self.request(protocol::BIND, &payload)
+    }
 
-    let mut buffer = [0; 512];
-    let size = stream.read(&mut buffer).unwrap();
-    println!("Response: {}", String::from_utf8_lossy(&buffer[..size]));
-}
\ No newline at end of file
+    // runs the statement with the values it was last bound to
+    pub fn execute(&mut self, name: &str) -> Result<QueryResult, Error> {
+        let mut payload = Vec::new();
+        codec::put_str(&mut payload, name);
+        self.request(protocol::EXECUTE, &payload)
+    }
+
+    // runs the statements of a script one after another and returns what each of those that ran
+    // gave back. the error is for a script that doesnt parse, in which case none of it was run, or for
+    // the connection failing
+    pub fn script(&mut self, sql: &str, on_error: OnError) -> Result<Vec<Result<QueryResult, Error>>, Error> {
+        let mut payload = Vec::new();
+        codec::put_u8(&mut payload, (on_error == OnError::Continue) as u8);
+        codec::put_str(&mut payload, sql);
+        protocol::write_frame(&mut self.stream, protocol::SCRIPT, &payload).map_err(connection_failed)?;
+        let count = match self.read()? {
+            (protocol::RESULTS, count) => Reader::new(&count).u32().map_err(|e| Error::Protocol(format!("malformed result count from server: {}", e)))?,
+            // the error for a script that doesnt parse
+            frame => {
+                self.answer(frame)??;
+                return Err(Error::Protocol("expected a result count from server".to_string()));
+            }
+        };
+        (0..count)
+            .map(|_| {
+                let frame = self.read()?;
+                self.answer(frame)
+            })
+            .collect()
+    }
+
+    // writes the SQL that makes the database again to out as it arrives, see Executor::dump. the rest
+    // of the dump is still read if out fails, so the connection can carry on
+    pub fn dump(&mut self, out: &mut impl Write) -> Result<(), Error> {
+        protocol::write_frame(&mut self.stream, protocol::DUMP, &[]).map_err(connection_failed)?;
+        let mut written = Ok(());
+        loop {
+            match self.read()? {
+                (protocol::CHUNK, sql) => {
+                    if written.is_ok() {
+                        written = out.write_all(&sql);
+                    }
+                }
+                (protocol::DONE, _) => break,
+                (protocol::ERROR, error) => return Err(read_error(&error)?),
+                (kind, _) => return Err(Error::Protocol(format!("unexpected message type {} in the dump from server", kind))),
+            }
+        }
+        written.and_then(|()| out.flush()).map_err(|e| Error::Io(format!("Failed to write the dump: {}", e)))
+    }
+
+    // the response to a message, or the error the server sent instead. errors keep the code the
+    // server gave them, so a caller can match on the kind, like a Constraint with Violation::Unique
+    fn request(&mut self, kind: u8, payload: &[u8]) -> Result<QueryResult, Error> {
+        protocol::write_frame(&mut self.stream, kind, payload).map_err(connection_failed)?;
+        let frame = self.read()?;
+        self.answer(frame)?
+    }
+
+    // what the server answered a statement with, reading the rest of the frames of a result that
+    // comes in more than one. the outer error is for a frame that isnt an answer
+    fn answer(&mut self, (kind, payload): (u8, Vec<u8>)) -> Result<Result<QueryResult, Error>, Error> {
+        let malformed = |e: io::Error| Error::Protocol(format!("malformed rows from server: {}", e));
+        match kind {
+            protocol::RESPONSE => Ok(Ok(QueryResult::Message(String::from_utf8_lossy(&payload).into_owned()))),
+            protocol::ROWS => {
+                let columns = protocol::read_columns(&payload).map_err(malformed)?;
+                let mut rows = Vec::new();
+                loop {
+                    match self.read()? {
+                        (protocol::BATCH, batch) => protocol::read_batch(&batch, &mut rows).map_err(malformed)?,
+                        (protocol::DONE, _) => return Ok(Ok(QueryResult::Rows { columns, rows })),
+                        (protocol::ERROR, error) => return Ok(Err(read_error(&error)?)),
+                        (kind, _) => return Err(Error::Protocol(format!("unexpected message type {} in rows from server", kind))),
+                    }
+                }
+            }
+            protocol::ERROR => Ok(Err(read_error(&payload)?)),
+            kind => Err(Error::Protocol(format!("unexpected message type {} from server", kind))),
+        }
+    }
+
+    fn read(&mut self) -> Result<(u8, Vec<u8>), Error> {
+        protocol::read_frame(&mut self.stream)
+            .map_err(connection_failed)?
+            .ok_or_else(|| Error::Io("Server closed the connection".to_string()))
+    }
+}
+
+fn read_error(payload: &[u8]) -> Result<Error, Error> {
+    protocol::read_error(payload).map_err(|e| Error::Protocol(format!("malformed error from server: {}", e)))
+}
+
+fn connection_failed(e: io::Error) -> Error {
+    Error::Io(format!("Connection to the server failed: {}", e))
+}
@@ -0,0 +1,187 @@
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use rustyline::DefaultEditor;
+use rustyline::error::ReadlineError;
+
+use super::Connection;
+use super::format::Format;
+use crate::error::Error;
+use crate::executor::OnError;
+use crate::parser;
+
+// kept in $XDG_STATE_HOME, or the home directory without it, so it lasts between sessions and each
+// user has their own
+const HISTORY_FILE: &str = "tony_db_history";
+
+const HELP: &str = "\
+\\dt              list tables
+\\d [table]       describe a table, or list tables
+\\timing [on|off] show how long each statement takes
+\\o [file]        write results to file, or back to the terminal
+\\format [name]   show results as table, csv, tsv, json, jsonl or markdown
+\\dump [file]     write the SQL that makes the database again, to file or the output
+\\? or \\h        this help
+\\q               quit
+statements run once a line ends with ;";
+
+// an interactive prompt on one connection, so prepared statements last for the whole session.
+// lines are put together until one ends a statement with ;, and lines starting with \ are commands
+// for the shell itself
+pub struct Shell {
+    connection: Connection,
+    timing: bool,
+    output: Option<File>, // where results go instead of the terminal
+    format: Format,
+}
+
+impl Shell {
+    pub fn connect() -> Result<Self, Error> {
+        Ok(Shell { connection: Connection::connect()?, timing: false, output: None, format: Format::Table })
+    }
+
+    pub fn run(&mut self) -> Result<(), Error> {
+        let mut editor = DefaultEditor::new().map_err(|e| Error::Io(format!("Failed to start the line editor: {}", e)))?;
+        let history = history_path();
+        if let Some(path) = &history {
+            // there is no history the first time
+            let _ = editor.load_history(path);
+        }
+        println!("tony_db shell, \\? for help, \\q to quit");
+
+        let mut buffer = String::new();
+        loop {
+            let prompt = if buffer.is_empty() { "tony_db=> " } else { "tony_db-> " };
+            match editor.readline(prompt) {
+                Ok(line) if buffer.is_empty() && line.trim_start().starts_with('\\') => {
+                    remember(&mut editor, history.as_deref(), line.trim());
+                    if !self.command(line.trim()) {
+                        break;
+                    }
+                }
+                Ok(line) if buffer.is_empty() && line.trim().is_empty() => {}
+                Ok(line) => {
+                    buffer.push_str(&line);
+                    buffer.push('\n');
+                    if parser::ends_statement(&buffer) {
+                        remember(&mut editor, history.as_deref(), buffer.trim());
+                        self.statements(&buffer);
+                        buffer.clear();
+                    }
+                }
+                // ctrl-c throws away what has been typed, like in psql
+                Err(ReadlineError::Interrupted) => buffer.clear(),
+                Err(ReadlineError::Eof) => break,
+                Err(e) => {
+                    eprintln!("Failed to read input: {}", e);
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // runs what was typed, which can be more than one statement, and shows what each gave back
+    fn statements(&mut self, sql: &str) {
+        let start = Instant::now();
+        let results = self.connection.script(sql, OnError::Continue);
+        let elapsed = start.elapsed();
+        match results {
+            Ok(results) => {
+                for result in results {
+                    match result {
+                        Ok(result) => self.write(&self.format.render(&result)),
+                        Err(e) => eprintln!("{}", e),
+                    }
+                }
+            }
+            Err(e) => eprintln!("{}", e),
+        }
+        if self.timing {
+            println!("Time: {:.3} ms", elapsed.as_secs_f64() * 1000.0);
+        }
+    }
+
+    // false once the shell should stop
+    fn command(&mut self, line: &str) -> bool {
+        let mut words = line.split_whitespace();
+        let command = words.next().unwrap_or_default();
+        let argument = words.next();
+        match (command, argument) {
+            ("\\q", _) => return false,
+            ("\\?" | "\\h", _) => println!("{}", HELP),
+            ("\\dt", _) | ("\\d", None) => self.statements("SHOW TABLES;"),
+            ("\\d", Some(table)) => self.statements(&format!("DESCRIBE {};", parser::quote_identifier(table))),
+            ("\\timing", argument) => {
+                self.timing = match argument {
+                    Some("on") => true,
+                    Some("off") => false,
+                    _ => !self.timing,
+                };
+                println!("Timing is {}.", if self.timing { "on" } else { "off" });
+            }
+            ("\\o", Some(path)) => match File::create(path) {
+                Ok(file) => self.output = Some(file),
+                Err(e) => eprintln!("Failed to open {}: {}", path, e),
+            },
+            ("\\o", None) => self.output = None,
+            ("\\format", Some(name)) => match name.parse() {
+                Ok(format) => self.format = format,
+                Err(e) => eprintln!("{}", e),
+            },
+            ("\\format", None) => println!("Output format is {}.", self.format),
+            ("\\dump", Some(path)) => match File::create(path) {
+                Ok(mut file) => match self.connection.dump(&mut file) {
+                    Ok(()) => println!("Dumped to {}.", path),
+                    Err(e) => eprintln!("{}", e),
+                },
+                Err(e) => eprintln!("Failed to write {}: {}", path, e),
+            },
+            ("\\dump", None) => {
+                let dumped = match &mut self.output {
+                    Some(file) => self.connection.dump(file),
+                    None => self.connection.dump(&mut io::stdout()),
+                };
+                if let Err(e) = dumped {
+                    eprintln!("{}", e);
+                }
+            }
+            (command, _) => eprintln!("Invalid command {}. Try \\? for help.", command),
+        }
+        true
+    }
+
+    fn write(&mut self, text: &str) {
+        let written = match &mut self.output {
+            Some(file) => writeln!(file, "{}", text),
+            None => writeln!(io::stdout(), "{}", text),
+        };
+        if let Err(e) = written {
+            eprintln!("Failed to write the result: {}", e);
+        }
+    }
+}
+
+fn history_path() -> Option<PathBuf> {
+    let dir = match std::env::var_os("XDG_STATE_HOME").filter(|dir| !dir.is_empty()) {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(std::env::var_os("HOME").filter(|dir| !dir.is_empty())?),
+    };
+    Some(dir.join(HISTORY_FILE))
+}
+
+// adds an entry to the history and appends it to the file straight away, so a shell that is killed
+// or crashes still keeps what was typed
+fn remember(editor: &mut DefaultEditor, history: Option<&Path>, entry: &str) {
+    let _ = editor.add_history_entry(entry);
+    if let Some(path) = history {
+        if let Some(dir) = path.parent() {
+            let _ = std::fs::create_dir_all(dir);
+        }
+        if let Err(e) = editor.append_history(path) {
+            eprintln!("Failed to save history: {}", e);
+        }
+    }
+}
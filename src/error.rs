@@ -0,0 +1,229 @@
+use std::fmt;
+use std::io;
+
+// errors as clients see them. each kind has a five character code in the style of SQLSTATE that
+// doesnt change between versions, so programs can tell a duplicate key from a typo without reading
+// the message. the message is for people and can change
+
+// where in the statement text a parse error is. offset counts bytes from the start of the text, line
+// and column count from 1 and column counts characters
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Position {
+    pub offset: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Position {
+    pub fn in_text(text: &str, offset: usize) -> Self {
+        let mut offset = offset.min(text.len());
+        while !text.is_char_boundary(offset) {
+            offset -= 1;
+        }
+        let before = &text[..offset];
+        let line_start = before.rfind('\n').map_or(0, |newline| newline + 1);
+        Position {
+            offset,
+            line: before.matches('\n').count() + 1,
+            column: before[line_start..].chars().count() + 1,
+        }
+    }
+}
+
+// the constraint a row broke
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Violation {
+    NotNull,
+    Unique, // PRIMARY KEY or UNIQUE
+    ForeignKey,
+    Check,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+    // the statement isnt valid SQL, position is where the parser gave up
+    Parse { message: String, position: Option<Position> },
+    // a prepared statement that doesnt exist or was given the wrong number of values
+    Bind(String),
+    Constraint { violation: Violation, message: String },
+    // a value that cant be converted to the type it needs to be
+    Type(String),
+    UndefinedTable(String),
+    UndefinedColumn(String),
+    // a table, column, constraint or prepared statement with the name already exists
+    Duplicate(String),
+    // reading or writing the database file failed
+    Io(String),
+    // the database file has something in it that cant have been written by us
+    Corruption(String),
+    // a framed connection sent something that isnt a valid message
+    Protocol(String),
+    // anything else the statement asked for that cant be done
+    Execution(String),
+}
+
+impl Error {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::Parse { .. } => "42601",
+            Error::Bind(_) => "07001",
+            Error::Constraint { violation: Violation::NotNull, .. } => "23502",
+            Error::Constraint { violation: Violation::ForeignKey, .. } => "23503",
+            Error::Constraint { violation: Violation::Unique, .. } => "23505",
+            Error::Constraint { violation: Violation::Check, .. } => "23514",
+            Error::Type(_) => "22P02",
+            Error::UndefinedTable(_) => "42P01",
+            Error::UndefinedColumn(_) => "42703",
+            Error::Duplicate(_) => "42710",
+            Error::Io(_) => "58030",
+            Error::Corruption(_) => "XX001",
+            Error::Protocol(_) => "08P01",
+            Error::Execution(_) => "42000",
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        match self {
+            Error::Parse { message, .. } | Error::Constraint { message, .. } => message,
+            Error::Bind(message)
+            | Error::Type(message)
+            | Error::UndefinedTable(message)
+            | Error::UndefinedColumn(message)
+            | Error::Duplicate(message)
+            | Error::Io(message)
+            | Error::Corruption(message)
+            | Error::Protocol(message)
+            | Error::Execution(message) => message,
+        }
+    }
+
+    pub fn position(&self) -> Option<Position> {
+        match self {
+            Error::Parse { position, .. } => *position,
+            _ => None,
+        }
+    }
+
+    // the error a code and message stand for, how a client turns an error frame back into one.
+    // codes it doesnt know are kept as Execution
+    pub fn from_code(code: &str, message: String, position: Option<Position>) -> Self {
+        match code {
+            "42601" => Error::Parse { message, position },
+            "07001" => Error::Bind(message),
+            "23502" => Error::Constraint { violation: Violation::NotNull, message },
+            "23503" => Error::Constraint { violation: Violation::ForeignKey, message },
+            "23505" => Error::Constraint { violation: Violation::Unique, message },
+            "23514" => Error::Constraint { violation: Violation::Check, message },
+            "22P02" => Error::Type(message),
+            "42P01" => Error::UndefinedTable(message),
+            "42703" => Error::UndefinedColumn(message),
+            "42710" => Error::Duplicate(message),
+            "58030" => Error::Io(message),
+            "XX001" => Error::Corruption(message),
+            "08P01" => Error::Protocol(message),
+            _ => Error::Execution(message),
+        }
+    }
+
+    // the same kind of error with what was being done when it happened in front of the message
+    pub fn context(self, context: &str) -> Self {
+        let message = format!("{}: {}", context, self.message());
+        Error::from_code(self.code(), message, self.position())
+    }
+
+    // a storage error with what was being done when it happened. data that doesnt decode means the
+    // file is damaged rather than that it couldnt be read
+    pub fn storage(context: &str, e: io::Error) -> Self {
+        let message = format!("{}: {}", context, e);
+        match e.kind() {
+            io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof => Error::Corruption(message),
+            _ => Error::Io(message),
+        }
+    }
+}
+
+// the text the server has always answered with, the code only goes over the framed protocol
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Parse { message, position: Some(position) } => write!(f, "Parse error: {} at line {}, column {}", message, position.line, position.column),
+            Error::Parse { message, position: None } => write!(f, "Parse error: {}", message),
+            Error::Protocol(message) => write!(f, "Protocol error: {}", message),
+            other => write!(f, "Execution error: {}", other.message()),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+// errors only described by their message
+impl From<String> for Error {
+    fn from(message: String) -> Self {
+        Error::Execution(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn every_kind() -> Vec<Error> {
+        let message = || "m".to_string();
+        let mut errors = vec![
+            Error::Parse { message: message(), position: Some(Position { offset: 3, line: 1, column: 4 }) },
+            Error::Bind(message()),
+            Error::Type(message()),
+            Error::UndefinedTable(message()),
+            Error::UndefinedColumn(message()),
+            Error::Duplicate(message()),
+            Error::Io(message()),
+            Error::Corruption(message()),
+            Error::Protocol(message()),
+            Error::Execution(message()),
+        ];
+        for violation in [Violation::NotNull, Violation::Unique, Violation::ForeignKey, Violation::Check] {
+            errors.push(Error::Constraint { violation, message: message() });
+        }
+        errors
+    }
+
+    #[test]
+    fn each_kind_has_a_code_of_its_own_that_gives_it_back() {
+        let errors = every_kind();
+        let mut codes: Vec<&str> = errors.iter().map(Error::code).collect();
+        for e in &errors {
+            assert_eq!(&Error::from_code(e.code(), e.message().to_string(), e.position()), e);
+            assert_eq!(e.code().len(), 5);
+        }
+        codes.sort();
+        codes.dedup();
+        assert_eq!(codes.len(), errors.len());
+        assert_eq!(Error::from_code("99999", "m".to_string(), None), Error::Execution("m".to_string()));
+    }
+
+    #[test]
+    fn context_keeps_the_kind() {
+        for e in every_kind() {
+            let with_context = e.clone().context("Line 3");
+            assert_eq!((with_context.code(), with_context.message(), with_context.position()), (e.code(), "Line 3: m", e.position()));
+        }
+    }
+
+    #[test]
+    fn positions_count_lines_and_chars() {
+        let text = "ab\nçd\n";
+        assert_eq!(Position::in_text(text, 0), Position { offset: 0, line: 1, column: 1 });
+        assert_eq!(Position::in_text(text, 3), Position { offset: 3, line: 2, column: 1 });
+        assert_eq!(Position::in_text(text, 5), Position { offset: 5, line: 2, column: 2 });
+        // an offset inside a char goes back to its start, one past the end to the end
+        assert_eq!(Position::in_text(text, 4), Position { offset: 3, line: 2, column: 1 });
+        assert_eq!(Position::in_text(text, 99), Position { offset: 7, line: 3, column: 1 });
+    }
+
+    #[test]
+    fn display_is_the_text_the_server_always_sent() {
+        let parse = Error::Parse { message: "Unexpected x".to_string(), position: Some(Position { offset: 9, line: 2, column: 3 }) };
+        assert_eq!(parse.to_string(), "Parse error: Unexpected x at line 2, column 3");
+        assert_eq!(Error::Duplicate("Table t already exists".to_string()).to_string(), "Execution error: Table t already exists");
+    }
+}
@@ -0,0 +1,209 @@
+use super::integrity;
+use super::{system, Table};
+use crate::error::Error;
+use crate::parser::ast::CopyOptions;
+use crate::storage::catalog::{ColumnSchema, IndexSchema};
+use crate::storage::record;
+use crate::storage::storage::StorageEngine;
+use crate::storage::wal;
+use crate::storage::tree::{BTree, RecordId};
+use crate::types::Value;
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+// COPY moves rows between a table and a CSV file on the server. loading checks every row before any
+// of them is written, then writes the heap a page at a time and adds the index entries in key order
+// afterwards, which is a lot less page traffic than an INSERT per row
+
+// a field as it was written in the file. only an unquoted field can stand for NULL, so "" can still
+// be an empty string when NULL is written as nothing
+#[derive(Default)]
+struct Field {
+    text: String,
+    quoted: bool,
+}
+
+// a record of the file and the line it starts on, or why it couldnt be read
+type Record = (usize, Result<Vec<Field>, String>);
+
+// files are named relative to the directory the database file is in and have to stay inside it, so a
+// client cant read or overwrite anything else the server can. the database file and the write ahead
+// log live there too and are off limits
+pub fn resolve(db_path: &Path, path: &str) -> Result<PathBuf, Error> {
+    let relative = Path::new(path);
+    let inside = relative.components().all(|part| matches!(part, Component::Normal(_) | Component::CurDir));
+    if !inside || relative.file_name().is_none() {
+        return Err(Error::Execution(format!("'{}' has to be a file in the database directory, named without / at the start or ..", path)));
+    }
+    let dir = db_path.parent().unwrap_or(Path::new(""));
+    let resolved = dir.join(relative);
+    if resolved == db_path || resolved.starts_with(dir.join(wal::WAL_DIR)) {
+        return Err(Error::Execution(format!("'{}' is the database file or part of its write ahead log", path)));
+    }
+    Ok(resolved)
+}
+
+pub fn copy_from(engine: &mut StorageEngine, table: &Table, columns: &Option<Vec<String>>, db_path: &Path, path: &str, options: &CopyOptions) -> Result<String, Error> {
+    let positions = table.insert_positions(columns)?;
+    let text = fs::read_to_string(resolve(db_path, path)?).map_err(|e| Error::Io(format!("Failed to read '{}': {}", path, e)))?;
+    // the header line is skipped without looking at it, the columns come from the column list
+    let records = records(&text, options.delimiter)?.into_iter().skip(usize::from(options.header));
+
+    let unique: Vec<(usize, &IndexSchema)> = table.schema.indexes.iter().enumerate().filter(|(_, i)| i.unique).collect();
+    let mut seen = HashSet::new();
+    let mut rows = Vec::new();
+    let mut errors = Vec::new();
+    for (line, record) in records {
+        match record_row(table, &positions, record, options).and_then(|row| integrity::checked_row(engine, table, &unique, &mut seen, row)) {
+            Ok(row) => rows.push((line, row)),
+            Err(e) => errors.push((line, e)),
+        }
+    }
+    let rows = integrity::with_parents(engine, table, rows, &mut errors)?;
+
+    // nothing has been written yet, so stopping here leaves the table as it was
+    errors.sort_by_key(|(line, _)| *line);
+    if !options.skip_errors && !errors.is_empty() {
+        let (line, e) = errors.swap_remove(0);
+        return Err(e.context(&format!("Line {}", line)));
+    }
+
+    let encoded: Vec<Vec<u8>> = rows.iter().map(|(_, row)| record::encode_row(&table.schema, row)).collect();
+    let rids = engine.insert_records(table.schema.heap_page_id, &encoded)
+        .map_err(|e| Error::storage("Failed to write rows", e))?;
+    for index in &table.schema.indexes {
+        let mut entries: Vec<(String, RecordId)> = rows.iter().zip(&rids).map(|((_, row), rid)| (table.index_key(index, row), *rid)).collect();
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        let mut tree = BTree::open(engine, index.root_page_id);
+        for (key, rid) in entries {
+            tree.insert(key, rid).map_err(|e| Error::storage(&format!("Failed to update index {}", index.name), e))?;
+        }
+    }
+
+    let mut message = format!("Copied {} row{}", rows.len(), if rows.len() == 1 { "" } else { "s" });
+    if !errors.is_empty() {
+        message.push_str(&format!(", skipped {}", errors.len()));
+        for (line, e) in errors {
+            message.push_str(&format!("\nLine {}: {}", line, e.message()));
+        }
+    }
+    Ok(message)
+}
+
+// the row a record stands for, before any of it is checked
+fn record_row(table: &Table, positions: &[usize], record: Result<Vec<Field>, String>, options: &CopyOptions) -> Result<Vec<Value>, Error> {
+    let values = record.map_err(Error::Type)?
+        .into_iter()
+        .map(|field| Some(if !field.quoted && field.text == options.null { Value::Null } else { Value::Text(field.text) }))
+        .collect();
+    table.new_row(positions, values)
+}
+
+// the column names and rows COPY table TO writes out, system tables included
+pub fn table_rows(engine: &mut StorageEngine, name: &str, columns: &Option<Vec<String>>) -> Result<(Vec<String>, Vec<Vec<Value>>), Error> {
+    let (schema, rows): (Vec<ColumnSchema>, Vec<Vec<Value>>) = match system::load(engine, name)? {
+        Some(system_table) => (system_table.columns, system_table.rows),
+        None => {
+            let table = Table::load(engine, name)?;
+            let rows = table.scan(engine)?.into_iter().map(|(_, row)| row).collect();
+            (table.schema.columns, rows)
+        }
+    };
+    let Some(columns) = columns else {
+        return Ok((schema.into_iter().map(|c| c.name).collect(), rows));
+    };
+    let positions = columns.iter()
+        .map(|column| schema.iter().position(|c| c.name == *column)
+            .ok_or_else(|| Error::UndefinedColumn(format!("Column '{}' not found in table '{}'", column, name))))
+        .collect::<Result<Vec<_>, _>>()?;
+    let rows = rows.into_iter().map(|row| positions.iter().map(|&idx| row[idx].clone()).collect()).collect();
+    Ok((columns.clone(), rows))
+}
+
+pub fn copy_to(names: &[String], rows: &[Vec<Value>], db_path: &Path, path: &str, options: &CopyOptions) -> Result<String, Error> {
+    let file = resolve(db_path, path)?;
+    let mut text = String::new();
+    if options.header {
+        let fields: Vec<String> = names.iter().map(|name| field(name, options)).collect();
+        text.push_str(&fields.join(&options.delimiter.to_string()));
+        text.push('\n');
+    }
+    for row in rows {
+        let fields: Vec<String> = row.iter()
+            .map(|value| match value {
+                Value::Null => options.null.clone(),
+                value => field(&value.to_string(), options),
+            })
+            .collect();
+        text.push_str(&fields.join(&options.delimiter.to_string()));
+        text.push('\n');
+    }
+    fs::write(file, text).map_err(|e| Error::Io(format!("Failed to write '{}': {}", path, e)))?;
+    Ok(format!("Copied {} row{} to '{}'", rows.len(), if rows.len() == 1 { "" } else { "s" }, path))
+}
+
+// quoted when it has the delimiter, a quote or a line break in it, or would read back as NULL
+fn field(text: &str, options: &CopyOptions) -> String {
+    if text == options.null || text.contains([options.delimiter, '"', '\n', '\r']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text.to_string()
+    }
+}
+
+// splits CSV text into records. quoted fields can have the delimiter, line breaks and doubled quotes in
+// them. a record with something after the closing quote of a field is bad on its own, but a quote that
+// is never closed leaves no way to tell where the next record starts and fails the whole file
+fn records(text: &str, delimiter: char) -> Result<Vec<Record>, Error> {
+    let mut records = Vec::new();
+    let mut chars = text.chars().peekable();
+    let mut line = 1;
+    while chars.peek().is_some() {
+        let start = line;
+        let mut fields = Vec::new();
+        let mut field = Field::default();
+        let mut bad = None;
+        loop {
+            match chars.next() {
+                None => break,
+                Some('"') if field.text.is_empty() && !field.quoted => {
+                    field.quoted = true;
+                    loop {
+                        match chars.next() {
+                            None => return Err(Error::Type(format!("Line {}: quoted field is never closed", start))),
+                            Some('"') if chars.peek() == Some(&'"') => {
+                                chars.next();
+                                field.text.push('"');
+                            }
+                            Some('"') => break,
+                            Some(c) => {
+                                if c == '\n' {
+                                    line += 1;
+                                }
+                                field.text.push(c);
+                            }
+                        }
+                    }
+                    match chars.peek() {
+                        None | Some('\n' | '\r') => {}
+                        Some(&c) if c == delimiter => {}
+                        Some(&c) => {
+                            bad.get_or_insert_with(|| format!("Unexpected character '{}' after a quoted field", c));
+                        }
+                    }
+                }
+                Some('\r') if chars.peek() == Some(&'\n') => {}
+                Some('\n') => {
+                    line += 1;
+                    break;
+                }
+                Some(c) if c == delimiter => fields.push(std::mem::take(&mut field)),
+                Some(c) => field.text.push(c),
+            }
+        }
+        fields.push(field);
+        records.push((start, bad.map_or(Ok(fields), Err)));
+    }
+    Ok(records)
+}
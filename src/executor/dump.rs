@@ -0,0 +1,87 @@
+use super::Table;
+use crate::error::Error;
+use crate::parser::quote_identifier;
+use crate::storage::catalog::{Catalog, TableSchema};
+use crate::storage::storage::StorageEngine;
+use crate::types::Value;
+use std::io::{self, Write};
+
+// a logical dump is the SQL that makes the tables again with their rows in them, so it can be read,
+// diffed and replayed into a database whatever its page layout. each table is created with its
+// columns, keys and CHECKs and then filled a row per INSERT. foreign keys are added at the end so the
+// rows can go in in any order, even between tables that reference each other. its written to out a
+// statement at a time, so the dump is never held in memory all at once
+pub fn dump(engine: &mut StorageEngine, out: &mut dyn Write) -> Result<(), Error> {
+    let written = |e: io::Error| Error::Io(format!("Failed to write the dump: {}", e));
+    let tables = Catalog::list_tables(engine).map_err(|e| Error::storage("Failed to read catalog", e))?;
+    writeln!(out, "-- tony_db dump").map_err(written)?;
+    for schema in &tables {
+        write!(out, "\n{}", create_table(schema)).map_err(written)?;
+        let names = names(schema.columns.iter().map(|c| c.name.as_str()));
+        let table = Table::from_schema(schema.clone())?;
+        for (_, row) in table.scan(engine)? {
+            let values: Vec<String> = row.iter().map(literal).collect();
+            writeln!(out, "INSERT INTO {} ({}) VALUES ({});", quote_identifier(&schema.name), names, values.join(", ")).map_err(written)?;
+        }
+    }
+
+    let mut foreign_keys = tables.iter().flat_map(|schema| schema.foreign_keys.iter().map(move |fk| (schema, fk))).peekable();
+    if foreign_keys.peek().is_some() {
+        writeln!(out).map_err(written)?;
+    }
+    for (schema, fk) in foreign_keys {
+        writeln!(
+            out,
+            "ALTER TABLE {} ADD CONSTRAINT {} FOREIGN KEY ({}) REFERENCES {} ({}) ON DELETE {} ON UPDATE {}{};",
+            quote_identifier(&schema.name), quote_identifier(&fk.name), names(fk.columns.iter().map(String::as_str)),
+            quote_identifier(&fk.parent_table), names(fk.parent_columns.iter().map(String::as_str)),
+            fk.on_delete, fk.on_update, if fk.deferred { " DEFERRABLE INITIALLY DEFERRED" } else { "" }
+        ).map_err(written)?;
+    }
+    out.flush().map_err(written)
+}
+
+fn create_table(schema: &TableSchema) -> String {
+    let mut lines: Vec<String> = schema.columns.iter()
+        .map(|column| {
+            let mut line = format!("    {} {}", quote_identifier(&column.name), column.data_type);
+            if column.not_null {
+                line.push_str(" NOT NULL");
+            }
+            if let Some(default) = &column.default {
+                line.push_str(&format!(" DEFAULT {}", default));
+            }
+            line
+        })
+        .collect();
+    // the only index that isnt unique is the one on the first column of a table without a primary
+    // key, and CREATE TABLE makes that again by itself
+    for index in schema.indexes.iter().filter(|index| index.unique) {
+        let kind = if index.primary { "PRIMARY KEY" } else { "UNIQUE" };
+        lines.push(format!("    CONSTRAINT {} {} ({})", quote_identifier(&index.name), kind, names(index.columns.iter().map(String::as_str))));
+    }
+    for check in &schema.checks {
+        lines.push(format!("    CONSTRAINT {} CHECK ({})", quote_identifier(&check.name), check.expr));
+    }
+    format!("CREATE TABLE {} (\n{}\n);\n", quote_identifier(&schema.name), lines.join(",\n"))
+}
+
+fn names<'a>(names: impl Iterator<Item = &'a str>) -> String {
+    names.map(quote_identifier).collect::<Vec<_>>().join(", ")
+}
+
+// a value as SQL that reads back as exactly the same value
+fn literal(value: &Value) -> String {
+    match value {
+        Value::Null => "NULL".to_string(),
+        // the lexer reads the minus apart from the number, which on its own is too big
+        Value::Integer(i64::MIN) => format!("({} - 1)", i64::MIN + 1),
+        Value::Integer(i) => i.to_string(),
+        // debug formatting always has a point or exponent and is exact, NaN and the infinities have
+        // no literal so they go through text
+        Value::Real(r) if r.is_finite() => format!("{:?}", r),
+        Value::Real(r) => format!("CAST('{}' AS REAL)", r),
+        Value::Text(text) => format!("'{}'", text.replace('\'', "''")),
+        Value::Boolean(b) => if *b { "TRUE" } else { "FALSE" }.to_string(),
+    }
+}
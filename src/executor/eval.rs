@@ -0,0 +1,263 @@
+use super::functions;
+use super::pattern;
+use super::query::Relation;
+use crate::parser::ast::{Expression, SelectQuery};
+use crate::error::Error;
+use crate::storage::catalog::ColumnSchema;
+use crate::types::{DataType, Value};
+use std::cmp::Ordering;
+use std::rc::Rc;
+
+// runs the subqueries in an expression. outer is the scope of the row the expression is being worked
+// out for, correlated subqueries read its columns
+pub trait Subqueries {
+    fn rows(&self, query: &SelectQuery, outer: &Scope) -> Result<Rc<Relation>, Error>;
+
+    // whether value is among the values of the subquerys single column, None when NULLs make it unknown
+    fn contains(&self, query: &SelectQuery, value: &Value, outer: &Scope) -> Result<Option<bool>, Error>;
+}
+
+// the rows an expression can name columns of, each under the name of its table. a column named
+// without a table comes from the first row that has it, t.col only from the row called t. columns
+// not found are looked for in the scope of the query around a subquery
+#[derive(Default, Clone, Copy)]
+pub struct Scope<'a> {
+    rows: &'a [(Option<&'a str>, &'a [ColumnSchema], &'a [Value])],
+    outer: Option<&'a Scope<'a>>,
+    subqueries: Option<&'a dyn Subqueries>,
+    windows: &'a [(&'a Expression, Value)], // the values of the window function calls or aggregates for this row
+}
+
+impl<'a> Scope<'a> {
+    pub fn new(rows: &'a [(Option<&'a str>, &'a [ColumnSchema], &'a [Value])]) -> Self {
+        Scope { rows, outer: None, subqueries: None, windows: &[] }
+    }
+
+    pub fn with_subqueries(self, subqueries: &'a dyn Subqueries) -> Self {
+        Scope { subqueries: Some(subqueries), ..self }
+    }
+
+    pub fn with_windows(self, windows: &'a [(&'a Expression, Value)]) -> Self {
+        Scope { windows, ..self }
+    }
+
+    // the scope of a subquery, whose own rows hide those of the query around it
+    pub fn inside(self, outer: Option<&'a Scope<'a>>) -> Self {
+        Scope { outer, ..self }
+    }
+
+    fn column(&self, table: Option<&str>, name: &str) -> Option<Value> {
+        for (row_table, columns, row) in self.rows {
+            if table.is_some() && table != *row_table {
+                continue;
+            }
+            if let Some(idx) = columns.iter().position(|c| c.name == name) {
+                return Some(row.get(idx).cloned().unwrap_or(Value::Null));
+            }
+        }
+        self.outer.and_then(|outer| outer.column(table, name))
+    }
+
+    fn subqueries(&self) -> Result<&'a dyn Subqueries, Error> {
+        self.subqueries.ok_or_else(|| Error::Execution("Subqueries cannot be used here".to_string()))
+    }
+}
+
+// work out the value of an expression for one row, columns names the values in row.
+// NULL propagates through comparisons and arithmetic, AND and OR use three valued logic
+pub fn eval(expr: &Expression, columns: &[ColumnSchema], row: &[Value]) -> Result<Value, Error> {
+    eval_in(expr, &Scope::new(&[(None, columns, row)]))
+}
+
+pub fn eval_in(expr: &Expression, scope: &Scope) -> Result<Value, Error> {
+    match expr {
+        Expression::Literal(value) => Ok(value.clone()),
+        Expression::Parameter(n) => Err(Error::Execution(format!("Parameter ${} has no value", n))),
+        Expression::Column { table, name } => scope.column(table.as_deref(), name).ok_or_else(|| Error::UndefinedColumn(match table {
+            Some(table) => format!("Column '{}.{}' not found", table, name),
+            None => format!("Column '{}' not found", name),
+        })),
+        Expression::Subquery(query) => {
+            let relation = scope.subqueries()?.rows(query, scope)?;
+            if relation.columns.len() != 1 {
+                return Err(Error::Execution(format!("A subquery used as a value must return one column, not {}", relation.columns.len())));
+            }
+            match relation.rows.as_slice() {
+                [] => Ok(Value::Null),
+                [row] => Ok(row[0].clone()),
+                _ => Err(Error::Execution("A subquery used as a value returned more than one row".to_string())),
+            }
+        }
+        Expression::Window(_) => scope.windows.iter()
+            .find(|(call, _)| std::ptr::eq(*call, expr))
+            .map(|(_, value)| value.clone())
+            .ok_or_else(|| Error::Execution("Window functions can only be used in the select list and ORDER BY".to_string())),
+        Expression::Aggregate { .. } => scope.windows.iter()
+            .find(|(call, _)| std::ptr::eq(*call, expr))
+            .map(|(_, value)| value.clone())
+            .ok_or_else(|| Error::Execution("Aggregates can only be used in the select list, HAVING and ORDER BY".to_string())),
+        Expression::Function { name, args } => {
+            let values = args.iter().map(|arg| eval_in(arg, scope)).collect::<Result<Vec<_>, _>>()?;
+            functions::call(name, values)
+        }
+        Expression::Cast { operand, data_type } => functions::cast(eval_in(operand, scope)?, *data_type),
+        // only the branch that is picked gets worked out
+        Expression::Case { operand, branches, otherwise } => {
+            let operand = operand.as_ref().map(|operand| eval_in(operand, scope)).transpose()?;
+            for (when, then) in branches {
+                let when = eval_in(when, scope)?;
+                let picked = match &operand {
+                    Some(operand) => operand.compare(&when) == Some(Ordering::Equal),
+                    None => truth(&when)? == Some(true),
+                };
+                if picked {
+                    return eval_in(then, scope);
+                }
+            }
+            otherwise.as_ref().map_or(Ok(Value::Null), |otherwise| eval_in(otherwise, scope))
+        }
+        Expression::Exists(query) => Ok(Value::Boolean(!scope.subqueries()?.rows(query, scope)?.rows.is_empty())),
+        Expression::InSubquery { operand, query } => {
+            let value = eval_in(operand, scope)?;
+            Ok(scope.subqueries()?.contains(query, &value, scope)?.map_or(Value::Null, Value::Boolean))
+        }
+        // true when an item equals the operand, otherwise NULL if an item or the operand is NULL
+        Expression::InList { operand, list } => {
+            let value = eval_in(operand, scope)?;
+            let mut unknown = value.is_null();
+            for item in list {
+                match value.compare(&eval_in(item, scope)?) {
+                    Some(Ordering::Equal) => return Ok(Value::Boolean(true)),
+                    Some(_) => {}
+                    None => unknown = true,
+                }
+            }
+            Ok(if unknown { Value::Null } else { Value::Boolean(false) })
+        }
+        Expression::Between { operand, low, high } => {
+            let value = eval_in(operand, scope)?;
+            let above_low = value.compare(&eval_in(low, scope)?).map(|ordering| ordering != Ordering::Less);
+            let below_high = value.compare(&eval_in(high, scope)?).map(|ordering| ordering != Ordering::Greater);
+            Ok(match (above_low, below_high) {
+                (Some(false), _) | (_, Some(false)) => Value::Boolean(false),
+                (Some(true), Some(true)) => Value::Boolean(true),
+                _ => Value::Null,
+            })
+        }
+        Expression::IsNull { operand, negated } => {
+            let is_null = eval_in(operand, scope)?.is_null();
+            Ok(Value::Boolean(is_null != *negated))
+        }
+        Expression::UnaryOp { operator, operand } => {
+            let value = eval_in(operand, scope)?;
+            match operator.as_str() {
+                "NOT" => Ok(truth(&value)?.map_or(Value::Null, |b| Value::Boolean(!b))),
+                "-" => arithmetic("-", &Value::Integer(0), &value),
+                _ => Err(Error::Execution(format!("Unknown operator {}", operator))),
+            }
+        }
+        Expression::BinaryOp { left, operator, right } => {
+            let left = eval_in(left, scope)?;
+            // the right side of AND and OR isnt needed when the left already decides the result
+            match (operator.as_str(), truth_if_logical(operator, &left)?) {
+                ("AND", Some(false)) => return Ok(Value::Boolean(false)),
+                ("OR", Some(true)) => return Ok(Value::Boolean(true)),
+                _ => {}
+            }
+            let right = eval_in(right, scope)?;
+            match operator.as_str() {
+                "AND" | "OR" => {
+                    let (l, r) = (truth(&left)?, truth(&right)?);
+                    Ok(match (l, r) {
+                        (Some(l), Some(r)) => Value::Boolean(if operator == "AND" { l && r } else { l || r }),
+                        // the decisive cases were handled above so anything with a NULL left is unknown
+                        (_, Some(r)) if (operator == "AND") != r => Value::Boolean(r),
+                        _ => Value::Null,
+                    })
+                }
+                "=" | "!=" | "<" | "<=" | ">" | ">=" => Ok(match left.compare(&right) {
+                    Some(ordering) => Value::Boolean(match operator.as_str() {
+                        "=" => ordering == Ordering::Equal,
+                        "!=" => ordering != Ordering::Equal,
+                        "<" => ordering == Ordering::Less,
+                        "<=" => ordering != Ordering::Greater,
+                        ">" => ordering == Ordering::Greater,
+                        _ => ordering != Ordering::Less,
+                    }),
+                    None => Value::Null,
+                }),
+                "||" | "LIKE" | "ILIKE" | "GLOB" if left.is_null() || right.is_null() => Ok(Value::Null),
+                "LIKE" | "ILIKE" | "GLOB" => Ok(Value::Boolean(pattern::matches(operator, &left.to_string(), &right.to_string())?)),
+                "||" => Ok(Value::Text(format!("{}{}", left, right))),
+                _ => arithmetic(operator, &left, &right),
+            }
+        }
+    }
+}
+
+// whether a row passes a WHERE clause, NULL counts as not passing
+pub fn matches(expr: &Expression, scope: &Scope) -> Result<bool, Error> {
+    Ok(truth(&eval_in(expr, scope)?)? == Some(true))
+}
+
+// a value used as a condition, None for NULL
+pub fn truth(value: &Value) -> Result<Option<bool>, Error> {
+    match value {
+        Value::Null => Ok(None),
+        Value::Boolean(b) => Ok(Some(*b)),
+        Value::Integer(i) => Ok(Some(*i != 0)),
+        other => match other.clone().coerce(DataType::Boolean) {
+            Ok(Value::Boolean(b)) => Ok(Some(b)),
+            _ => Err(Error::Execution(format!("'{}' cannot be used as a condition", other))),
+        },
+    }
+}
+
+fn truth_if_logical(operator: &str, value: &Value) -> Result<Option<bool>, Error> {
+    if operator == "AND" || operator == "OR" { truth(value) } else { Ok(None) }
+}
+
+pub fn arithmetic(operator: &str, left: &Value, right: &Value) -> Result<Value, Error> {
+    if left.is_null() || right.is_null() {
+        return Ok(Value::Null);
+    }
+    match (number(left)?, number(right)?) {
+        (Value::Integer(l), Value::Integer(r)) => {
+            let result = match operator {
+                "+" => l.checked_add(r),
+                "-" => l.checked_sub(r),
+                "*" => l.checked_mul(r),
+                "/" | "%" if r == 0 => return Err(Error::Execution("Division by zero".to_string())),
+                "/" => l.checked_div(r),
+                "%" => l.checked_rem(r),
+                _ => return Err(Error::Execution(format!("Unknown operator {}", operator))),
+            };
+            result.map(Value::Integer).ok_or_else(|| Error::Execution(format!("Integer overflow in {} {} {}", l, operator, r)))
+        }
+        (l, r) => {
+            let (Value::Real(l), Value::Real(r)) = (l.coerce(DataType::Real)?, r.coerce(DataType::Real)?) else {
+                return Err(Error::Type(format!("Cannot apply {} to {} and {}", operator, left, right)));
+            };
+            Ok(Value::Real(match operator {
+                "+" => l + r,
+                "-" => l - r,
+                "*" => l * r,
+                "/" | "%" if r == 0.0 => return Err(Error::Execution("Division by zero".to_string())),
+                "/" => l / r,
+                "%" => l % r,
+                _ => return Err(Error::Execution(format!("Unknown operator {}", operator))),
+            }))
+        }
+    }
+}
+
+// integers stay integers, text has to read as a number
+pub fn number(value: &Value) -> Result<Value, Error> {
+    match value {
+        Value::Integer(_) | Value::Real(_) => Ok(value.clone()),
+        Value::Text(t) => DataType::Integer.parse(t)
+            .or_else(|| DataType::Real.parse(t))
+            .ok_or_else(|| Error::Type(format!("'{}' is not a number", t))),
+        other => Err(Error::Type(format!("'{}' is not a number", other))),
+    }
+}
@@ -0,0 +1,188 @@
+use super::eval;
+use crate::error::Error;
+use crate::types::{DataType, Value};
+use std::cmp::Ordering;
+
+type Implementation = fn(&str, Vec<Value>) -> Result<Value, Error>;
+
+// the built-in scalar functions: name, fewest and most arguments (None for any number), whether a
+// NULL argument makes the result NULL without calling it, and what it does
+const FUNCTIONS: [(&str, usize, Option<usize>, bool, Implementation); 21] = [
+    ("LOWER", 1, Some(1), true, text_function),
+    ("UPPER", 1, Some(1), true, text_function),
+    ("LENGTH", 1, Some(1), true, text_function),
+    ("SUBSTR", 2, Some(3), true, substr),
+    ("TRIM", 1, Some(2), true, trim),
+    ("LTRIM", 1, Some(2), true, trim),
+    ("RTRIM", 1, Some(2), true, trim),
+    ("REPLACE", 3, Some(3), true, text_function),
+    ("CONCAT", 0, None, false, concat),
+    ("ABS", 1, Some(1), true, numeric_function),
+    ("ROUND", 1, Some(2), true, round),
+    ("FLOOR", 1, Some(1), true, numeric_function),
+    ("CEIL", 1, Some(1), true, numeric_function),
+    ("CEILING", 1, Some(1), true, numeric_function),
+    ("MOD", 2, Some(2), true, numeric_function),
+    ("POWER", 2, Some(2), true, numeric_function),
+    ("POW", 2, Some(2), true, numeric_function),
+    ("SQRT", 1, Some(1), true, numeric_function),
+    ("COALESCE", 1, None, false, null_function),
+    ("NULLIF", 2, Some(2), false, null_function),
+    ("IFNULL", 2, Some(2), false, null_function),
+];
+
+pub fn call(name: &str, args: Vec<Value>) -> Result<Value, Error> {
+    let Some(&(_, min_args, max_args, strict, implementation)) = FUNCTIONS.iter().find(|f| f.0 == name) else {
+        return Err(Error::Execution(format!("Unknown function {}", name)));
+    };
+    if args.len() < min_args || max_args.is_some_and(|max| args.len() > max) {
+        let expected = match max_args {
+            Some(max) if max == min_args => min_args.to_string(),
+            Some(max) => format!("{} to {}", min_args, max),
+            None => format!("at least {}", min_args),
+        };
+        return Err(Error::Execution(format!("{} takes {} arguments, not {}", name, expected, args.len())));
+    }
+    if strict && args.iter().any(Value::is_null) {
+        return Ok(Value::Null);
+    }
+    implementation(name, args)
+}
+
+// CAST(value AS type). unlike storing into a column, REAL rounds to INTEGER and numbers and booleans
+// convert both ways
+pub fn cast(value: Value, data_type: DataType) -> Result<Value, Error> {
+    match (&value, data_type) {
+        (Value::Real(r), DataType::Integer) => {
+            let rounded = r.round();
+            if rounded.is_finite() && rounded.abs() < i64::MAX as f64 {
+                Ok(Value::Integer(rounded as i64))
+            } else {
+                Err(Error::Execution(format!("{} is out of range for INTEGER", r)))
+            }
+        }
+        (Value::Boolean(b), DataType::Integer) => Ok(Value::Integer(*b as i64)),
+        (Value::Boolean(b), DataType::Real) => Ok(Value::Real(if *b { 1.0 } else { 0.0 })),
+        (Value::Integer(i), DataType::Boolean) => Ok(Value::Boolean(*i != 0)),
+        (Value::Text(t), _) if data_type != DataType::Text => Value::Text(t.trim().to_string()).coerce(data_type),
+        _ => value.coerce(data_type),
+    }
+}
+
+// anything can be used as text
+fn text(value: &Value) -> String {
+    match value {
+        Value::Text(t) => t.clone(),
+        other => other.to_string(),
+    }
+}
+
+fn integer(name: &str, value: &Value) -> Result<i64, Error> {
+    match eval::number(value)? {
+        Value::Integer(i) => Ok(i),
+        other => Err(Error::Execution(format!("{} needs a whole number, not {}", name, other))),
+    }
+}
+
+fn real(value: &Value) -> Result<f64, Error> {
+    match eval::number(value)? {
+        Value::Integer(i) => Ok(i as f64),
+        Value::Real(r) => Ok(r),
+        other => Err(Error::Type(format!("'{}' is not a number", other))),
+    }
+}
+
+fn text_function(name: &str, args: Vec<Value>) -> Result<Value, Error> {
+    let s = text(&args[0]);
+    Ok(match name {
+        "LOWER" => Value::Text(s.to_lowercase()),
+        "UPPER" => Value::Text(s.to_uppercase()),
+        "LENGTH" => Value::Integer(s.chars().count() as i64),
+        _ => {
+            let from = text(&args[1]);
+            Value::Text(if from.is_empty() { s } else { s.replace(&from, &text(&args[2])) })
+        }
+    })
+}
+
+// SUBSTR(text, start [, length]) counts characters from 1. a start before 1 still uses up length
+fn substr(name: &str, args: Vec<Value>) -> Result<Value, Error> {
+    let chars: Vec<char> = text(&args[0]).chars().collect();
+    let start = integer(name, &args[1])?.saturating_sub(1);
+    let end = match args.get(2) {
+        Some(length) => {
+            let length = integer(name, length)?;
+            if length < 0 {
+                return Err(Error::Execution(format!("{} cannot take a negative length", name)));
+            }
+            start.saturating_add(length)
+        }
+        None => i64::MAX,
+    };
+    let clamp = |i: i64| i.clamp(0, chars.len() as i64) as usize;
+    Ok(Value::Text(chars[clamp(start)..clamp(end).max(clamp(start))].iter().collect()))
+}
+
+// TRIM(text [, characters]) takes the characters, spaces by default, off both ends. LTRIM and RTRIM
+// only off one
+fn trim(name: &str, args: Vec<Value>) -> Result<Value, Error> {
+    let s = text(&args[0]);
+    let characters: Vec<char> = args.get(1).map_or(" ".to_string(), text).chars().collect();
+    let trimmed = match name {
+        "LTRIM" => s.trim_start_matches(characters.as_slice()),
+        "RTRIM" => s.trim_end_matches(characters.as_slice()),
+        _ => s.trim_matches(characters.as_slice()),
+    };
+    Ok(Value::Text(trimmed.to_string()))
+}
+
+// joins its arguments as text, leaving out NULLs
+fn concat(_: &str, args: Vec<Value>) -> Result<Value, Error> {
+    Ok(Value::Text(args.iter().filter(|arg| !arg.is_null()).map(text).collect()))
+}
+
+fn numeric_function(name: &str, args: Vec<Value>) -> Result<Value, Error> {
+    let value = eval::number(&args[0])?;
+    match (name, &value) {
+        ("ABS", Value::Integer(i)) => i.checked_abs().map(Value::Integer).ok_or_else(|| Error::Execution(format!("ABS of {} is out of range for INTEGER", i))),
+        ("FLOOR" | "CEIL" | "CEILING", Value::Integer(_)) => Ok(value),
+        ("ABS", _) => Ok(Value::Real(real(&value)?.abs())),
+        ("FLOOR", _) => Ok(Value::Real(real(&value)?.floor())),
+        ("CEIL" | "CEILING", _) => Ok(Value::Real(real(&value)?.ceil())),
+        ("MOD", _) => eval::arithmetic("%", &value, &args[1]),
+        ("SQRT", _) => {
+            let r = real(&value)?;
+            if r < 0.0 {
+                return Err(Error::Execution(format!("SQRT of the negative number {}", r)));
+            }
+            Ok(Value::Real(r.sqrt()))
+        }
+        _ => Ok(Value::Real(real(&value)?.powf(real(&args[1])?))),
+    }
+}
+
+// ROUND(number [, digits]) rounds halves away from zero. integers stay integers
+fn round(name: &str, args: Vec<Value>) -> Result<Value, Error> {
+    let digits = match args.get(1) {
+        Some(digits) => integer(name, digits)?.clamp(-18, 18) as i32,
+        None => 0,
+    };
+    let factor = 10f64.powi(digits.abs());
+    let scale = |r: f64| if digits >= 0 { (r * factor).round() / factor } else { (r / factor).round() * factor };
+    match eval::number(&args[0])? {
+        Value::Integer(i) if digits >= 0 => Ok(Value::Integer(i)),
+        Value::Integer(i) => cast(Value::Real(scale(i as f64)), DataType::Integer),
+        other => Ok(Value::Real(scale(real(&other)?))),
+    }
+}
+
+fn null_function(name: &str, mut args: Vec<Value>) -> Result<Value, Error> {
+    match name {
+        "NULLIF" => {
+            let equal = args[0].compare(&args[1]) == Some(Ordering::Equal);
+            Ok(if equal { Value::Null } else { args.swap_remove(0) })
+        }
+        // COALESCE and IFNULL
+        _ => Ok(args.into_iter().find(|arg| !arg.is_null()).unwrap_or(Value::Null)),
+    }
+}
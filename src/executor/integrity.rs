@@ -0,0 +1,344 @@
+use super::{display_row, Table};
+use crate::error::{Error, Violation};
+use crate::storage::catalog::{Catalog, ForeignKeySchema, IndexSchema, TableSchema};
+use crate::storage::storage::StorageEngine;
+use crate::storage::tree::RecordId;
+use crate::types::{ReferentialAction, Value};
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+
+// cascades that keep setting each other off are stopped after this many levels
+const MAX_CASCADE_DEPTH: usize = 64;
+
+// the tables referencing a parent and which of their foreign keys does
+type Children = Rc<Vec<(Rc<Table>, usize)>>;
+
+// writes rows for one statement while keeping foreign keys intact: a child row needs its parent to exist
+// and deleting or rekeying a parent row does what the constraints ON DELETE or ON UPDATE action says to the
+// rows referencing it. checks of INITIALLY DEFERRED constraints wait for finish, which runs after the rows
+// are written, and if one fails the executor rolls back everything the statement wrote. there are no
+// transactions yet so every statement commits on its own, once there are they will wait for COMMIT instead
+#[derive(Default)]
+pub struct Integrity {
+    // the catalog doesnt change during a statement so tables are only loaded once
+    tables: HashMap<String, Rc<Table>>,
+    children: HashMap<String, Children>,
+    parents_needed: Vec<(ForeignKeySchema, Vec<Value>)>, // a child row is using this key
+    parents_removed: Vec<(String, ForeignKeySchema, Vec<Value>)>, // this parent key went away, no child of the table may still use it
+}
+
+impl Integrity {
+    // the row as it was stored, converted to the column types, and where
+    pub fn insert(&mut self, engine: &mut StorageEngine, table: &Table, row: Vec<Value>) -> Result<(RecordId, Vec<Value>), Error> {
+        let row = table.prepare(engine, row, None)?;
+        self.check_parents(engine, table, &row, None)?;
+        Ok((table.insert(engine, &row)?, row))
+    }
+
+    // update and delete do nothing when an action set off earlier in the statement has already deleted
+    // or changed the row, update returns None then and the row as stored otherwise
+    pub fn update(&mut self, engine: &mut StorageEngine, table: &Table, rid: RecordId, old_row: &[Value], new_row: Vec<Value>) -> Result<Option<Vec<Value>>, Error> {
+        if table.fetch(engine, rid)?.as_deref() != Some(old_row) {
+            return Ok(None);
+        }
+        self.update_at(engine, table, rid, old_row, new_row, 0).map(Some)
+    }
+
+    pub fn delete(&mut self, engine: &mut StorageEngine, table: &Table, rid: RecordId, row: &[Value]) -> Result<bool, Error> {
+        if table.fetch(engine, rid)?.as_deref() != Some(row) {
+            return Ok(false);
+        }
+        self.delete_at(engine, table, rid, row, 0)?;
+        Ok(true)
+    }
+
+    // run the deferred checks, the statement fails if any of them do
+    pub fn finish(mut self, engine: &mut StorageEngine) -> Result<(), Error> {
+        for (fk, key) in std::mem::take(&mut self.parents_needed) {
+            if !self.parent_exists(engine, &fk, &key)? {
+                return Err(missing_parent(&fk, &key));
+            }
+        }
+        for (child_name, fk, key) in std::mem::take(&mut self.parents_removed) {
+            // a parent with the same key may have been put back later in the statement
+            if self.parent_exists(engine, &fk, &key)? {
+                continue;
+            }
+            let child = self.table(engine, &child_name)?;
+            if !child.rows_where_equal(engine, &fk.columns, &key)?.is_empty() {
+                return Err(still_referenced(&fk, &key, &child_name));
+            }
+        }
+        Ok(())
+    }
+
+    // check a row that was just added to a table against a foreign key given to it by ALTER TABLE
+    pub fn check_existing(&mut self, engine: &mut StorageEngine, table: &Table, fk: &ForeignKeySchema, row: &[Value]) -> Result<(), Error> {
+        let key = values_of(&table.schema, &fk.columns, row);
+        if key.iter().any(Value::is_null) || self.parent_exists(engine, fk, &key)? {
+            return Ok(());
+        }
+        Err(missing_parent(fk, &key))
+    }
+
+    fn update_at(&mut self, engine: &mut StorageEngine, table: &Table, rid: RecordId, old_row: &[Value], new_row: Vec<Value>, depth: usize) -> Result<Vec<Value>, Error> {
+        if depth > MAX_CASCADE_DEPTH {
+            return Err(Error::Execution(format!("Foreign key actions on table '{}' nested more than {} deep", table.schema.name, MAX_CASCADE_DEPTH)));
+        }
+        let new_row = table.prepare(engine, new_row, Some(rid))?;
+        self.check_parents(engine, table, &new_row, Some(old_row))?;
+        let children = self.children(engine, &table.schema.name)?;
+        self.restrict(engine, table, &children, rid, old_row, Some(&new_row))?;
+        table.update(engine, rid, old_row, &new_row)?;
+        self.apply_actions(engine, table, &children, old_row, Some(&new_row), depth)?;
+        Ok(new_row)
+    }
+
+    fn delete_at(&mut self, engine: &mut StorageEngine, table: &Table, rid: RecordId, row: &[Value], depth: usize) -> Result<(), Error> {
+        if depth > MAX_CASCADE_DEPTH {
+            return Err(Error::Execution(format!("Foreign key actions on table '{}' nested more than {} deep", table.schema.name, MAX_CASCADE_DEPTH)));
+        }
+        let children = self.children(engine, &table.schema.name)?;
+        self.restrict(engine, table, &children, rid, row, None)?;
+        table.delete(engine, rid, row)?;
+        self.apply_actions(engine, table, &children, row, None, depth)
+    }
+
+    // every foreign key of the row whose key is new needs a parent row with that key. a key with a NULL
+    // in it references nothing and is always allowed
+    fn check_parents(&mut self, engine: &mut StorageEngine, table: &Table, row: &[Value], old_row: Option<&[Value]>) -> Result<(), Error> {
+        for fk in &table.schema.foreign_keys {
+            let key = values_of(&table.schema, &fk.columns, row);
+            if key.iter().any(Value::is_null) || old_row.is_some_and(|old| same_key(&values_of(&table.schema, &fk.columns, old), &key)) {
+                continue;
+            }
+            // a row can be its own parent
+            if fk.parent_table == table.schema.name && same_key(&values_of(&table.schema, &fk.parent_columns, row), &key) {
+                continue;
+            }
+            if fk.deferred {
+                self.parents_needed.push((fk.clone(), key));
+            } else if !self.parent_exists(engine, fk, &key)? {
+                return Err(missing_parent(fk, &key));
+            }
+        }
+        Ok(())
+    }
+
+    // refuse to remove or rekey a parent row that is still referenced by a RESTRICT or NO ACTION
+    // constraint. deferred NO ACTION constraints are checked again in finish instead
+    fn restrict(&mut self, engine: &mut StorageEngine, table: &Table, children: &[(Rc<Table>, usize)], rid: RecordId, old_row: &[Value], new_row: Option<&[Value]>) -> Result<(), Error> {
+        for (child, fk_idx) in children {
+            let fk = &child.schema.foreign_keys[*fk_idx];
+            let action = if new_row.is_some() { fk.on_update } else { fk.on_delete };
+            let Some(key) = changed_key(&table.schema, fk, old_row, new_row) else {
+                continue;
+            };
+            match action {
+                ReferentialAction::NoAction if fk.deferred => {
+                    self.parents_removed.push((child.schema.name.clone(), fk.clone(), key));
+                }
+                ReferentialAction::Restrict | ReferentialAction::NoAction => {
+                    let same_table = child.schema.name == table.schema.name;
+                    let referencing = child.rows_where_equal(engine, &fk.columns, &key)?;
+                    if referencing.iter().any(|(child_rid, _)| !(same_table && *child_rid == rid)) {
+                        return Err(still_referenced(fk, &key, &child.schema.name));
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    // CASCADE, SET NULL and SET DEFAULT, once the parent row has been deleted or changed
+    fn apply_actions(&mut self, engine: &mut StorageEngine, table: &Table, children: &[(Rc<Table>, usize)], old_row: &[Value], new_row: Option<&[Value]>, depth: usize) -> Result<(), Error> {
+        for (child, fk_idx) in children {
+            let fk = &child.schema.foreign_keys[*fk_idx];
+            let action = if new_row.is_some() { fk.on_update } else { fk.on_delete };
+            if matches!(action, ReferentialAction::Restrict | ReferentialAction::NoAction) {
+                continue;
+            }
+            let Some(key) = changed_key(&table.schema, fk, old_row, new_row) else {
+                continue;
+            };
+
+            let positions: Vec<usize> = fk.columns.iter().filter_map(|c| child.schema.column_index(c)).collect();
+            for (child_rid, child_row) in child.rows_where_equal(engine, &fk.columns, &key)? {
+                // an earlier cascade in this loop may already have deleted or changed the row
+                if child.fetch(engine, child_rid)?.as_deref() != Some(child_row.as_slice()) {
+                    continue;
+                }
+                if action == ReferentialAction::Cascade && new_row.is_none() {
+                    self.delete_at(engine, child, child_rid, &child_row, depth + 1)?;
+                    continue;
+                }
+                let mut changed = child_row.clone();
+                for (i, &idx) in positions.iter().enumerate() {
+                    changed[idx] = match (action, new_row) {
+                        (ReferentialAction::Cascade, Some(new_row)) => values_of(&table.schema, &fk.parent_columns, new_row).swap_remove(i),
+                        (ReferentialAction::SetDefault, _) => child.default_value(idx)?,
+                        _ => Value::Null,
+                    };
+                }
+                self.update_at(engine, child, child_rid, &child_row, changed, depth + 1)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn parent_exists(&mut self, engine: &mut StorageEngine, fk: &ForeignKeySchema, key: &[Value]) -> Result<bool, Error> {
+        let parent = self.table(engine, &fk.parent_table)?;
+        Ok(!parent.rows_where_equal(engine, &fk.parent_columns, key)?.is_empty())
+    }
+
+    fn table(&mut self, engine: &mut StorageEngine, name: &str) -> Result<Rc<Table>, Error> {
+        if let Some(table) = self.tables.get(name) {
+            return Ok(table.clone());
+        }
+        let table = Rc::new(Table::load(engine, name)?);
+        self.tables.insert(name.to_string(), table.clone());
+        Ok(table)
+    }
+
+    fn children(&mut self, engine: &mut StorageEngine, parent: &str) -> Result<Children, Error> {
+        if let Some(children) = self.children.get(parent) {
+            return Ok(children.clone());
+        }
+        let mut children = Vec::new();
+        for schema in Catalog::referencing_tables(engine, parent).map_err(|e| Error::storage("Failed to read catalog", e))? {
+            let child = self.table(engine, &schema.name)?;
+            for (fk_idx, fk) in child.schema.foreign_keys.iter().enumerate() {
+                if fk.parent_table == parent {
+                    children.push((child.clone(), fk_idx));
+                }
+            }
+        }
+        let children = Rc::new(children);
+        self.children.insert(parent.to_string(), children.clone());
+        Ok(children)
+    }
+}
+
+// COPY and INSERT without ON CONFLICT check every row they add before writing any of them, so a bad
+// row fails the statement before the table has been touched. checked_row and with_parents are the two
+// halves of that
+
+// the row once its values have their types and it passes NOT NULL, CHECK and the unique constraints
+// (unique, the unique indexes of the table and where they are in its list), against both the rows
+// already in the table and the ones before it in seen
+pub fn checked_row(engine: &mut StorageEngine, table: &Table, unique: &[(usize, &IndexSchema)], seen: &mut HashSet<(usize, String)>, row: Vec<Value>) -> Result<Vec<Value>, Error> {
+    let row = table.validate(row)?;
+
+    let mut keys = Vec::new();
+    for (idx, index) in unique {
+        table.check_unique(engine, index, &row, None)?;
+        if table.index_values(index, &row).iter().any(Value::is_null) {
+            continue;
+        }
+        let key = (*idx, table.index_key(index, &row));
+        if seen.contains(&key) {
+            return Err(table.unique_violation(index, &row));
+        }
+        keys.push(key);
+    }
+    seen.extend(keys);
+    Ok(row)
+}
+
+// the rows whose foreign keys all have a parent, either in the table already or, when the table
+// references itself, among the rows being added. a row can reference one further down, so the rows
+// left over are looked at again until a pass finds no more parents. the rest go to errors, each with
+// the number it came with
+pub fn with_parents(engine: &mut StorageEngine, table: &Table, rows: Vec<(usize, Vec<Value>)>, errors: &mut Vec<(usize, Error)>) -> Result<Vec<(usize, Vec<Value>)>, Error> {
+    if table.schema.foreign_keys.is_empty() {
+        return Ok(rows);
+    }
+    let mut integrity = Integrity::default();
+    let mut added_keys = HashSet::new(); // (foreign key, parent key) of rows taken so far
+    let mut taken = Vec::with_capacity(rows.len());
+    let mut waiting = rows;
+    loop {
+        let mut missing = Vec::new();
+        let count = waiting.len();
+        for (line, row) in waiting {
+            match first_missing_parent(engine, &mut integrity, table, &added_keys, &row)? {
+                Some(e) => missing.push((line, row, e)),
+                None => {
+                    for (fk_idx, fk) in table.schema.foreign_keys.iter().enumerate().filter(|(_, fk)| fk.parent_table == table.schema.name) {
+                        added_keys.insert((fk_idx, key_text(&values_of(&table.schema, &fk.parent_columns, &row))));
+                    }
+                    taken.push((line, row));
+                }
+            }
+        }
+        if missing.len() == count || missing.is_empty() {
+            errors.extend(missing.into_iter().map(|(line, _, e)| (line, e)));
+            break;
+        }
+        waiting = missing.into_iter().map(|(line, row, _)| (line, row)).collect();
+    }
+    taken.sort_by_key(|(line, _)| *line);
+    Ok(taken)
+}
+
+// the error for the first foreign key of the row without a parent. deferred constraints are checked
+// here too since every row of the statement is known by now
+fn first_missing_parent(engine: &mut StorageEngine, integrity: &mut Integrity, table: &Table, added_keys: &HashSet<(usize, String)>, row: &[Value]) -> Result<Option<Error>, Error> {
+    for (fk_idx, fk) in table.schema.foreign_keys.iter().enumerate() {
+        let key = values_of(&table.schema, &fk.columns, row);
+        if key.iter().any(Value::is_null) {
+            continue;
+        }
+        if fk.parent_table == table.schema.name {
+            let own = values_of(&table.schema, &fk.parent_columns, row);
+            if key_text(&own) == key_text(&key) || added_keys.contains(&(fk_idx, key_text(&key))) {
+                continue;
+            }
+        }
+        if !integrity.parent_exists(engine, fk, &key)? {
+            return Ok(Some(missing_parent(fk, &key)));
+        }
+    }
+    Ok(None)
+}
+
+fn key_text(key: &[Value]) -> String {
+    key.iter().map(|value| value.to_string()).collect::<Vec<_>>().join("\0")
+}
+
+// the values of the named columns, in the order theyre named
+pub(super) fn values_of(schema: &TableSchema, columns: &[String], row: &[Value]) -> Vec<Value> {
+    columns.iter()
+        .map(|c| schema.column_index(c).map_or(Value::Null, |idx| row[idx].clone()))
+        .collect()
+}
+
+fn same_key(left: &[Value], right: &[Value]) -> bool {
+    left.len() == right.len() && left.iter().zip(right).all(|(l, r)| l.compare(r) == Some(Ordering::Equal))
+}
+
+// the parent key a foreign key referenced before the row was deleted or updated, None if the row
+// couldnt have been referenced or the update leaves the key as it was
+fn changed_key(parent: &TableSchema, fk: &ForeignKeySchema, old_row: &[Value], new_row: Option<&[Value]>) -> Option<Vec<Value>> {
+    let key = values_of(parent, &fk.parent_columns, old_row);
+    if key.iter().any(Value::is_null) || new_row.is_some_and(|new_row| same_key(&values_of(parent, &fk.parent_columns, new_row), &key)) {
+        return None;
+    }
+    Some(key)
+}
+
+pub(super) fn missing_parent(fk: &ForeignKeySchema, key: &[Value]) -> Error {
+    Error::Constraint { violation: Violation::ForeignKey, message: format!(
+        "FOREIGN KEY constraint '{}' violated: key ({})=({}) is not present in table '{}'",
+        fk.name, fk.columns.join(", "), display_row(key), fk.parent_table
+    ) }
+}
+
+fn still_referenced(fk: &ForeignKeySchema, key: &[Value], child: &str) -> Error {
+    Error::Constraint { violation: Violation::ForeignKey, message: format!(
+        "FOREIGN KEY constraint '{}' violated: key ({})=({}) is still referenced from table '{}'",
+        fk.name, fk.parent_columns.join(", "), display_row(key), child
+    ) }
+}
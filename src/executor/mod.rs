@@ -1,204 +1,1610 @@
+mod copy;
+mod dump;
+mod eval;
+mod functions;
+mod integrity;
+mod pattern;
+mod query;
+mod session;
+mod system;
+mod window;
+
+use crate::error::{Error, Violation};
 use crate::parser;
-use crate::parser::ast::{Query, SelectQuery, InsertQuery, DeleteQuery, CreateQuery, Expression};
-use crate::storage::storage::StorageEngine;
-use crate::storage::catalog::Catalog;
-use lazy_static::lazy_static;
-use std::collections::HashMap;
+use crate::parser::ast::{SelectQuery, SelectItem, InsertQuery, InsertSource, OnConflict, ConflictAction, UpdateQuery, DeleteQuery, CreateQuery, VacuumQuery, DropQuery, TruncateQuery, AlterQuery, AlterAction, DescribeQuery, CopyQuery, CopySource, WithQuery, PrepareQuery, ExecuteQuery, ColumnDef, ConstraintKind, TableConstraint, Expression};
+use crate::storage::storage::{default_db_path, StorageEngine, VacuumStats};
+use crate::storage::backup::{self, Label};
+use crate::storage::wal;
+use crate::storage::catalog::{Catalog, CATALOG_ROOT_PAGE, CheckSchema, ColumnSchema, ForeignKeySchema, IndexSchema, TableSchema};
+use crate::storage::record;
+use crate::storage::tree::{BTree, RecordId};
+use integrity::Integrity;
+use query::QueryRunner;
+use crate::types::{DataType, Value};
+use std::cmp::Ordering;
+use std::collections::HashSet;
+use std::fmt;
+use std::ops::Bound;
 use std::sync::Mutex;
 
-#[derive(Debug, Clone)]
+pub use session::Session;
+
+// what a script does when one of its statements fails
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnError {
+    Stop,     // the statements after it arent run
+    Continue, // the rest are run anyway
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum QueryResult {
     Message(String),
-    Rows(Vec<Vec<String>>),
+    Rows { columns: Vec<Column>, rows: Vec<Vec<Value>> },
 }
 
+// a column of a result. values in it are of its type or NULL
+#[derive(Debug, Clone, PartialEq)]
+pub struct Column {
+    pub name: String,
+    pub data_type: DataType,
+}
+
+// the text a result has always been sent back as, a line of column names and one per row with the
+// values between " | ". its ambiguous when a value has " | " or a newline in it, so programs should
+// use the rows over the framed protocol instead
+impl fmt::Display for QueryResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QueryResult::Message(message) => write!(f, "{}", message),
+            QueryResult::Rows { rows, .. } if rows.is_empty() => write!(f, "No rows found"),
+            QueryResult::Rows { columns, rows } => {
+                let names: Vec<&str> = columns.iter().map(|c| c.name.as_str()).collect();
+                write!(f, "{}", names.join(" | "))?;
+                for row in rows {
+                    write!(f, "\n{}", join_values(row, " | "))?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+// a table as described by its catalog entry. rows live in the heap chain starting at heap_page_id
+// and each of its indexes maps the indexed column values to the rows record id.
+// the DEFAULT and CHECK expressions kept as text in the catalog are parsed once when its loaded
 pub struct Table {
-    name: String,
-    columns: Vec<String>,
-    rows: Vec<Vec<String>>,
+    schema: TableSchema,
+    defaults: Vec<Option<Expression>>,
+    checks: Vec<(String, Expression)>,
 }
 
 impl Table {
-    fn new(name: String, columns: Vec<String>) -> Self {
-        Table {
-            name,
-            columns,
-            rows: Vec::new(),
+    fn load(engine: &mut StorageEngine, name: &str) -> Result<Self, Error> {
+        if system::is_system_table(name) {
+            return Err(Error::Execution(format!("Table '{}' is a read-only system table", name)));
+        }
+        let schema = Catalog::get_table(engine, name)
+            .map_err(|e| Error::storage("Failed to read catalog", e))?
+            .ok_or_else(|| Error::UndefinedTable(format!("Table '{}' not found", name)))?;
+        Self::from_schema(schema)
+    }
+
+    fn from_schema(schema: TableSchema) -> Result<Self, Error> {
+        let defaults = schema.columns.iter()
+            .map(|c| c.default.as_ref()
+                .map(|text| parser::parse_expression(text).map_err(|e| Error::Corruption(format!("Failed to read default of column '{}': {}", c.name, e.message()))))
+                .transpose())
+            .collect::<Result<_, _>>()?;
+        let checks = schema.checks.iter()
+            .map(|check| parser::parse_expression(&check.expr)
+                .map(|expr| (check.name.clone(), expr))
+                .map_err(|e| Error::Corruption(format!("Failed to read constraint '{}': {}", check.name, e.message()))))
+            .collect::<Result<_, _>>()?;
+        Ok(Table { schema, defaults, checks })
+    }
+
+    fn default_value(&self, idx: usize) -> Result<Value, Error> {
+        match &self.defaults[idx] {
+            Some(expr) => eval::eval(expr, &[], &[]),
+            None => Ok(Value::Null),
+        }
+    }
+
+    // the SET list of an UPDATE with each column replaced by its position
+    fn assignments<'a>(&self, updates: &'a [(String, Expression)]) -> Result<Vec<(usize, &'a Expression)>, Error> {
+        updates.iter()
+            .map(|(column, expr)| {
+                let idx = self.schema.column_index(column)
+                    .ok_or_else(|| Error::UndefinedColumn(format!("Column '{}' not found in table '{}'", column, self.schema.name)))?;
+                Ok((idx, expr))
+            })
+            .collect()
+    }
+
+    // positions of the columns an INSERT gives values for, every column in order if it doesnt list them
+    fn insert_positions(&self, columns: &Option<Vec<String>>) -> Result<Vec<usize>, Error> {
+        let Some(columns) = columns else {
+            return Ok((0..self.schema.columns.len()).collect());
+        };
+        let mut positions = Vec::with_capacity(columns.len());
+        for column in columns {
+            let idx = self.schema.column_index(column)
+                .ok_or_else(|| Error::UndefinedColumn(format!("Column '{}' not found in table '{}'", column, self.schema.name)))?;
+            if positions.contains(&idx) {
+                return Err(Error::Execution(format!("Column '{}' given more than once", column)));
+            }
+            positions.push(idx);
         }
+        Ok(positions)
     }
 
-    fn insert(&mut self, row: Vec<String>) -> Result<(), String> {
-        if row.len() != self.columns.len() {
-            return Err(format!(
+    // a row from the values an INSERT gives for the columns at positions, None is where DEFAULT was
+    // written. columns without a value get their default
+    fn new_row(&self, positions: &[usize], values: Vec<Option<Value>>) -> Result<Vec<Value>, Error> {
+        if values.len() != positions.len() {
+            let names: Vec<&str> = positions.iter().map(|&idx| self.schema.columns[idx].name.as_str()).collect();
+            return Err(Error::Execution(format!(
+                "Column count mismatch: expected {} values for columns {:?}, got {}",
+                positions.len(), names, values.len()
+            )));
+        }
+        let mut given: Vec<Option<Option<Value>>> = vec![None; self.schema.columns.len()];
+        for (&idx, value) in positions.iter().zip(values) {
+            given[idx] = Some(value);
+        }
+        given.into_iter()
+            .enumerate()
+            .map(|(idx, value)| match value.flatten() {
+                Some(value) => Ok(value),
+                None => self.default_value(idx),
+            })
+            .collect()
+    }
+
+    // each value converted to its columns type
+    fn coerce_row(&self, row: Vec<Value>) -> Result<Vec<Value>, Error> {
+        if row.len() != self.schema.columns.len() {
+            return Err(Error::Execution(format!(
                 "Column count mismatch: expected {} values, got {}",
-                self.columns.len(),
+                self.schema.columns.len(),
                 row.len()
-            ));
+            )));
+        }
+        self.schema.columns.iter()
+            .zip(row)
+            .map(|(column, value)| value.coerce(column.data_type).map_err(|e| Error::Type(format!("Value {} for column '{}'", e.message(), column.name))))
+            .collect()
+    }
+
+    // convert each value to its columns type and check the row against the tables constraints.
+    // rid is where the row already lives when its being updated, so it doesnt clash with itself
+    fn prepare(&self, engine: &mut StorageEngine, row: Vec<Value>, rid: Option<RecordId>) -> Result<Vec<Value>, Error> {
+        let typed = self.validate(row)?;
+        for index in self.schema.indexes.iter().filter(|i| i.unique) {
+            self.check_unique(engine, index, &typed, rid)?;
+        }
+        Ok(typed)
+    }
+
+    // the checks of prepare that only need the row itself, NOT NULL and CHECK
+    fn validate(&self, row: Vec<Value>) -> Result<Vec<Value>, Error> {
+        let typed = self.coerce_row(row)?;
+        if let Some(column) = self.schema.columns.iter().zip(&typed).find(|(c, v)| c.not_null && v.is_null()).map(|(c, _)| c) {
+            return Err(Error::Constraint { violation: Violation::NotNull, message: format!(
+                "NOT NULL constraint '{}_{}_not_null' violated: column '{}' cannot be NULL",
+                self.schema.name, column.name, column.name
+            ) });
+        }
+
+        // a CHECK only fails when its false, NULL lets the row through
+        for (name, expr) in &self.checks {
+            if eval::truth(&eval::eval(expr, &self.schema.columns, &typed)?)? == Some(false) {
+                return Err(Error::Constraint { violation: Violation::Check, message: format!("CHECK constraint '{}' violated by row ({})", name, display_row(&typed)) });
+            }
+        }
+        Ok(typed)
+    }
+
+    fn check_unique(&self, engine: &mut StorageEngine, index: &IndexSchema, row: &[Value], rid: Option<RecordId>) -> Result<(), Error> {
+        if self.conflicting_row(engine, index, row, rid)?.is_some() {
+            return Err(self.unique_violation(index, row));
+        }
+        Ok(())
+    }
+
+    fn unique_violation(&self, index: &IndexSchema, row: &[Value]) -> Error {
+        let key = self.index_values(index, row);
+        let kind = if index.primary { "PRIMARY KEY" } else { "UNIQUE" };
+        Error::Constraint { violation: Violation::Unique, message: format!(
+            "{} constraint '{}' violated: key ({})=({}) already exists",
+            kind, index.name, index.columns.join(", "), display_row(&key)
+        ) }
+    }
+
+    // the row other than the one at rid with the same key as row in a unique index. rows whose key
+    // contains a NULL never clash, same as in other databases
+    fn conflicting_row(&self, engine: &mut StorageEngine, index: &IndexSchema, row: &[Value], rid: Option<RecordId>) -> Result<Option<(RecordId, Vec<Value>)>, Error> {
+        let key = self.index_values(index, row);
+        if key.iter().any(Value::is_null) {
+            return Ok(None);
+        }
+        Ok(self.rows_where_equal(engine, &index.columns, &key)?.into_iter().find(|(other_rid, _)| Some(*other_rid) != rid))
+    }
+
+    // the values of the indexed columns in a row
+    fn index_values(&self, index: &IndexSchema, row: &[Value]) -> Vec<Value> {
+        index.columns.iter()
+            .filter_map(|c| self.schema.column_index(c))
+            .map(|idx| row[idx].clone())
+            .collect()
+    }
+
+    // the key a row is stored under in an index, values of multi column indexes are separated by a nul
+    fn index_key(&self, index: &IndexSchema, row: &[Value]) -> String {
+        index.columns.iter()
+            .filter_map(|c| self.schema.column_index(c))
+            .map(|idx| row[idx].to_index_key().unwrap_or_default())
+            .collect::<Vec<_>>()
+            .join("\0")
+    }
+
+    // insert and update take rows that have already been through prepare
+    fn insert(&self, engine: &mut StorageEngine, row: &[Value]) -> Result<RecordId, Error> {
+        let rid = engine.insert_record(self.schema.heap_page_id, &record::encode_row(&self.schema, row))
+            .map_err(|e| Error::storage("Failed to write row", e))?;
+        for index in &self.schema.indexes {
+            BTree::open(engine, index.root_page_id).insert(self.index_key(index, row), rid)
+                .map_err(|e| Error::storage(&format!("Failed to update index {}", index.name), e))?;
+        }
+        Ok(rid)
+    }
+
+    fn scan(&self, engine: &mut StorageEngine) -> Result<Vec<(RecordId, Vec<Value>)>, Error> {
+        let records = engine.scan_records(self.schema.heap_page_id)
+            .map_err(|e| Error::storage("Failed to read rows", e))?;
+        records.into_iter()
+            .map(|(rid, bytes)| Ok((rid, record::decode_row(&self.schema, &bytes).map_err(|e| Error::storage("Failed to decode row", e))?)))
+            .collect()
+    }
+
+    fn update(&self, engine: &mut StorageEngine, rid: RecordId, old_row: &[Value], new_row: &[Value]) -> Result<RecordId, Error> {
+        let new_rid = engine.update_record(self.schema.heap_page_id, rid, &record::encode_row(&self.schema, new_row))
+            .map_err(|e| Error::storage("Failed to write row", e))?;
+        // an index only needs touching if its key or where the row lives changed
+        for index in &self.schema.indexes {
+            let old_key = self.index_key(index, old_row);
+            let new_key = self.index_key(index, new_row);
+            if new_rid != rid || old_key != new_key {
+                let mut tree = BTree::open(engine, index.root_page_id);
+                tree.delete(&old_key, rid).map_err(|e| Error::storage(&format!("Failed to update index {}", index.name), e))?;
+                tree.insert(new_key, new_rid).map_err(|e| Error::storage(&format!("Failed to update index {}", index.name), e))?;
+            }
+        }
+        Ok(new_rid)
+    }
+
+    fn delete(&self, engine: &mut StorageEngine, rid: RecordId, row: &[Value]) -> Result<(), Error> {
+        engine.delete_record(rid).map_err(|e| Error::storage("Failed to delete row", e))?;
+        for index in &self.schema.indexes {
+            BTree::open(engine, index.root_page_id).delete(&self.index_key(index, row), rid)
+                .map_err(|e| Error::storage(&format!("Failed to update index {}", index.name), e))?;
+        }
+        Ok(())
+    }
+
+    // empty the heap and every index, keeping their first pages so the catalog entry stays valid
+    fn truncate(&self, engine: &mut StorageEngine) -> Result<(), Error> {
+        engine.free_heap(self.schema.heap_page_id, true).map_err(|e| Error::storage("Failed to free rows", e))?;
+        for index in &self.schema.indexes {
+            BTree::open(engine, index.root_page_id).clear()
+                .map_err(|e| Error::storage(&format!("Failed to clear index {}", index.name), e))?;
         }
-        self.rows.push(row);
         Ok(())
     }
 
+    // an index covering exactly this one column
+    fn index_on(&self, column: &str) -> Option<&IndexSchema> {
+        self.schema.indexes.iter().find(|i| i.columns.len() == 1 && i.columns[0] == column)
+    }
+
+    // rows where each of columns equals the value at the same position in values, through an index
+    // over those columns if there is one
+    fn rows_where_equal(&self, engine: &mut StorageEngine, columns: &[String], values: &[Value]) -> Result<Vec<(RecordId, Vec<Value>)>, Error> {
+        let positions: Vec<usize> = columns.iter()
+            .map(|c| self.schema.column_index(c).ok_or_else(|| Error::UndefinedColumn(format!("Column '{}' not found in table '{}'", c, self.schema.name))))
+            .collect::<Result<_, _>>()?;
+        let index = self.schema.indexes.iter()
+            .find(|i| i.columns.len() == columns.len() && columns.iter().all(|c| i.columns.contains(c)));
+        let candidates = match index {
+            Some(index) => {
+                let mut key_row = vec![Value::Null; self.schema.columns.len()];
+                for (&idx, value) in positions.iter().zip(values) {
+                    key_row[idx] = value.clone().coerce(self.schema.columns[idx].data_type)?;
+                }
+                self.lookup(engine, index, &self.index_key(index, &key_row))?
+            }
+            None => self.scan(engine)?,
+        };
+        Ok(candidates.into_iter()
+            .filter(|(_, row)| positions.iter().zip(values).all(|(&idx, value)| row[idx].compare(value) == Some(Ordering::Equal)))
+            .collect())
+    }
+
+    // the row stored at rid, None if its been deleted since it was read
+    fn fetch(&self, engine: &mut StorageEngine, rid: RecordId) -> Result<Option<Vec<Value>>, Error> {
+        match engine.read_record(rid) {
+            Ok(bytes) => Ok(Some(record::decode_row(&self.schema, &bytes).map_err(|e| Error::storage("Failed to decode row", e))?)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(Error::storage("Failed to read row", e)),
+        }
+    }
+
+    // rows stored under key in the index, found without scanning the heap
+    fn lookup(&self, engine: &mut StorageEngine, index: &IndexSchema, key: &String) -> Result<Vec<(RecordId, Vec<Value>)>, Error> {
+        let rids = BTree::open(engine, index.root_page_id).get_all(key)
+            .map_err(|e| Error::storage(&format!("Failed to read index {}", index.name), e))?;
+        self.read_rows(engine, rids)
+    }
+
+    // rows whose key in the index is between low and high and that keep accepts
+    fn lookup_range(&self, engine: &mut StorageEngine, index: &IndexSchema, low: Bound<&String>, high: Bound<&String>, keep: impl Fn(&str) -> bool) -> Result<Vec<(RecordId, Vec<Value>)>, Error> {
+        let entries = BTree::open(engine, index.root_page_id).range(low, high)
+            .map_err(|e| Error::storage(&format!("Failed to read index {}", index.name), e))?;
+        let rids = entries.into_iter().filter(|(key, _)| keep(key)).map(|(_, rid)| rid).collect();
+        self.read_rows(engine, rids)
+    }
+
+    fn read_rows(&self, engine: &mut StorageEngine, rids: Vec<RecordId>) -> Result<Vec<(RecordId, Vec<Value>)>, Error> {
+        rids.into_iter()
+            .map(|rid| {
+                let bytes = engine.read_record(rid).map_err(|e| Error::storage("Failed to read row", e))?;
+                Ok((rid, record::decode_row(&self.schema, &bytes).map_err(|e| Error::storage("Failed to decode row", e))?))
+            })
+            .collect()
+    }
+}
+
+fn display_row(row: &[Value]) -> String {
+    join_values(row, ", ")
+}
+
+// the text a result is sent back as
+pub fn format_result(result: Result<QueryResult, Error>) -> String {
+    match result {
+        Ok(result) => result.to_string(),
+        Err(e) => e.to_string(),
+    }
+}
+
+// what a RETURNING list makes of a row that was inserted, updated or deleted
+fn returning_row(table: &Table, items: &[SelectItem], row: &[Value]) -> Result<Vec<Value>, Error> {
+    let source = [(Some(table.schema.name.as_str()), table.schema.columns.as_slice(), row)];
+    query::project(items, &eval::Scope::new(&source), row)
+}
+
+// the rows a RETURNING list gave, under the names of its columns. with no RETURNING list the
+// message is the result instead
+fn returned_rows(table: &Table, returning: &Option<Vec<SelectItem>>, rows: Vec<Vec<Value>>, message: String) -> QueryResult {
+    match returning {
+        Some(items) => QueryResult::Rows { columns: result_columns(query::output_columns(items, &table.schema.columns, &rows)), rows },
+        None => QueryResult::Message(message),
+    }
+}
+
+// a t.* in a RETURNING list has to name the table, checked before any row is written
+fn check_returning(table: &Table, returning: &Option<Vec<SelectItem>>) -> Result<(), Error> {
+    match returning {
+        Some(items) => query::check_table_wildcards(items, Some(&table.schema.name)),
+        None => Ok(()),
+    }
+}
+
+fn result_columns(columns: Vec<ColumnSchema>) -> Vec<Column> {
+    columns.into_iter().map(|c| Column { name: c.name, data_type: c.data_type }).collect()
+}
+
+fn text_columns(names: &[&str]) -> Vec<Column> {
+    names.iter().map(|name| Column { name: name.to_string(), data_type: DataType::Text }).collect()
+}
+
+fn join_values(row: &[Value], separator: &str) -> String {
+    row.iter().map(|v| v.to_string()).collect::<Vec<_>>().join(separator)
 }
 
 pub struct Executor {
-    // hashmap until storage is wired up
-    storage: Mutex<HashMap<String, Table>>,
+    // each query opens the storage file itself, this stops queries from different connections interleaving
+    lock: Mutex<()>,
+    // the database file tests run against instead of the servers
+    #[cfg(test)]
+    path: Option<std::path::PathBuf>,
+}
+
+impl Default for Executor {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Executor {
     pub fn new() -> Self {
         Executor {
-            storage: Mutex::new(HashMap::new()),
+            lock: Mutex::new(()),
+            #[cfg(test)]
+            path: None,
         }
     }
 
+    #[cfg(test)]
+    fn at(path: &std::path::Path) -> Self {
+        Executor { lock: Mutex::new(()), path: Some(path.to_path_buf()) }
+    }
+
+    // the database file. COPY and BACKUP can only use files in the directory its in
+    fn db_path(&self) -> Result<std::path::PathBuf, Error> {
+        #[cfg(test)]
+        if let Some(path) = &self.path {
+            return Ok(path.clone());
+        }
+        default_db_path().map_err(|e| Error::storage("Failed to find the database file", e))
+    }
+
+    fn open_storage(&self) -> Result<StorageEngine, Error> {
+        #[cfg(test)]
+        let opened = match &self.path {
+            Some(path) => StorageEngine::open_at(path),
+            None => StorageEngine::open(),
+        };
+        #[cfg(not(test))]
+        let opened = StorageEngine::open();
+        let mut engine = opened.map_err(|e| Error::storage("Failed to open storage", e))?;
+        Catalog::init_if_missing(&mut engine).map_err(|e| Error::storage("Failed to initialise catalog", e))?;
+        Ok(engine)
+    }
+
+    // runs a statement on the storage file under the lock. nothing it writes reaches the file until it
+    // has succeeded, so a statement that fails partway, on a constraint or a deferred foreign key
+    // checked at the end, leaves the database as it was
+    fn statement<T>(&self, run: impl FnOnce(&mut StorageEngine) -> Result<T, Error>) -> Result<T, Error> {
+        let _guard = self.lock.lock().map_err(|e| format!("Storage lock poisoned: {}", e))?;
+        let mut engine = self.open_storage()?;
+        match run(&mut engine) {
+            Ok(result) => {
+                engine.commit().map_err(|e| Error::storage("Failed to write the changes", e))?;
+                Ok(result)
+            }
+            Err(e) => {
+                engine.rollback().map_err(|e| Error::storage("Failed to roll back", e))?;
+                Err(e)
+            }
+        }
+    }
+
+    // runs a statement on its own, anything it prepares is gone once it returns
     pub fn execute_query(&self, query: &str) -> String {
-        let parsed_query = match parser::parse_query(&query) {
-            Ok(query) => query,
-            Err(e) => return format!("Parse error: {}", e),
-        };
-
-        match parsed_query {
-            parser::Query::Select(select_query) => {
-                match self.execute_select(select_query) {
-                    Ok(QueryResult::Rows(rows)) => {
-                        // Format rows as a string
-                        if rows.is_empty() {
-                            "No rows found".to_string()
-                        } else {
-                            rows.iter()
-                                .map(|row| row.join(" | "))
-                                .collect::<Vec<_>>()
-                                .join("\n")
+        format_result(self.execute(&mut Session::default(), query))
+    }
+
+    // runs a statement as part of a connection that keeps its prepared statements in session
+    pub fn execute(&self, session: &mut Session, query: &str) -> Result<QueryResult, Error> {
+        let parsed = parser::parse_query(query)?;
+        self.run(session, parsed)
+    }
+
+    // runs the statements of a script in order, each on its own like it was sent by itself, so the
+    // ones before a failed statement stay done. the whole script is parsed first and nothing is run
+    // if any of it doesnt parse. with OnError::Stop the failed statement is the last result
+    pub fn execute_script(&self, session: &mut Session, script: &str, on_error: OnError) -> Result<Vec<Result<QueryResult, Error>>, Error> {
+        let mut results = Vec::new();
+        for statement in parser::parse_script(script)? {
+            let result = self.run(session, statement);
+            let failed = result.is_err();
+            results.push(result);
+            if failed && on_error == OnError::Stop {
+                break;
+            }
+        }
+        Ok(results)
+    }
+
+    // writes the SQL that makes every table again with the rows in it to out. its all read under the
+    // storage lock, so no statement can change the database partway through
+    pub fn dump(&self, out: &mut dyn std::io::Write) -> Result<(), Error> {
+        self.statement(|engine| dump::dump(engine, out))
+    }
+
+    // prepares a statement given over the protocol, whose parameters come later from bind
+    pub fn prepare(&self, session: &mut Session, name: &str, query: &str) -> Result<QueryResult, Error> {
+        let (statement, parameters) = parser::parse_prepared(query)?;
+        let prepare = PrepareQuery { name: name.to_string(), types: Vec::new(), statement: Box::new(statement), parameters };
+        session.prepare(prepare).map(QueryResult::Message)
+    }
+
+    pub fn bind(&self, session: &mut Session, name: &str, values: Vec<Value>) -> Result<QueryResult, Error> {
+        session.keep_bound(name, values)?;
+        Ok(QueryResult::Message(format!("Statement '{}' bound", name)))
+    }
+
+    pub fn execute_bound(&self, session: &mut Session, name: &str) -> Result<QueryResult, Error> {
+        let statement = session.bound(name)?;
+        self.run(session, statement)
+    }
+
+    fn run(&self, session: &mut Session, query: parser::Query) -> Result<QueryResult, Error> {
+        match query {
+            parser::Query::Select(select_query) => self.statement(|engine| self.execute_select(engine, select_query)),
+            parser::Query::Insert(insert_query) => self.statement(|engine| self.execute_insert(engine, insert_query)),
+            parser::Query::Create(create_query) => self.statement(|engine| self.execute_create(engine, create_query)),
+            parser::Query::Update(update_query) => self.statement(|engine| self.execute_update(engine, update_query)),
+            parser::Query::Delete(delete_query) => self.statement(|engine| self.execute_delete(engine, delete_query)),
+            parser::Query::Vacuum(vacuum_query) => self.statement(|engine| self.execute_vacuum(engine, vacuum_query)),
+            parser::Query::Drop(drop_query) => self.statement(|engine| self.execute_drop(engine, drop_query)),
+            parser::Query::Truncate(truncate_query) => self.statement(|engine| self.execute_truncate(engine, truncate_query)),
+            parser::Query::Alter(alter_query) => self.statement(|engine| self.execute_alter(engine, alter_query)),
+            parser::Query::ShowTables => self.statement(|engine| self.execute_show_tables(engine)),
+            parser::Query::With(with_query) => self.statement(|engine| self.execute_with(engine, with_query)),
+            parser::Query::Describe(describe_query) => self.statement(|engine| self.execute_describe(engine, describe_query)),
+            parser::Query::Copy(copy_query) => self.statement(|engine| self.execute_copy(engine, copy_query)),
+            parser::Query::Backup(path) => self.execute_backup(&path),
+            parser::Query::Prepare(prepare_query) => session.prepare(prepare_query).map(QueryResult::Message),
+            parser::Query::Execute(execute_query) => {
+                let statement = Self::execute_arguments(session, execute_query)?;
+                self.run(session, statement)
+            }
+            parser::Query::Deallocate(name) => session.deallocate(name).map(QueryResult::Message),
+        }
+    }
+
+    // the statement EXECUTE runs, its values worked out before they go in. they cant read tables or
+    // columns, only be constants
+    fn execute_arguments(session: &Session, query: ExecuteQuery) -> Result<parser::Query, Error> {
+        let scope = eval::Scope::new(&[]);
+        let values = query.args.iter().map(|arg| eval::eval_in(arg, &scope)).collect::<Result<Vec<_>, _>>()?;
+        session.bind(&query.name, values)
+    }
+
+    fn execute_select(&self, engine: &mut StorageEngine, query: SelectQuery) -> Result<QueryResult, Error> {
+        let relation = QueryRunner::new(engine).select(&query, None)?;
+        Ok(QueryResult::Rows { columns: result_columns(relation.columns), rows: relation.rows })
+    }
+
+    fn execute_with(&self, engine: &mut StorageEngine, query: WithQuery) -> Result<QueryResult, Error> {
+        let runner = QueryRunner::new(engine);
+        for table in &query.tables {
+            runner.add_common_table(table)?;
+        }
+        let relation = runner.select(&query.query, None)?;
+        Ok(QueryResult::Rows { columns: result_columns(relation.columns), rows: relation.rows })
+    }
+
+    fn select_rows(&self, engine: &mut StorageEngine, query: &SelectQuery) -> Result<Vec<Vec<Value>>, Error> {
+        Ok(QueryRunner::new(engine).select(query, None)?.rows)
+    }
+
+    fn execute_insert(&self, engine: &mut StorageEngine, query: InsertQuery) -> Result<QueryResult, Error> {
+        let table = Table::load(engine, &query.table_name)?;
+        check_returning(&table, &query.returning)?;
+        let positions = table.insert_positions(&query.columns)?;
+
+        // the SELECT is read in full before anything is written, so inserting into the table its
+        // reading from doesnt see its own rows
+        let sources: Vec<Vec<Option<Value>>> = match &query.source {
+            InsertSource::Values(rows) => {
+                let runner = QueryRunner::new(engine);
+                let scope = eval::Scope::new(&[]).with_subqueries(&runner);
+                rows.iter()
+                    .map(|row| row.iter().map(|value| value.as_ref().map(|expr| eval::eval_in(expr, &scope)).transpose()).collect())
+                    .collect::<Result<_, _>>()?
+            }
+            InsertSource::Select(select) => self.select_rows(engine, select)?
+                .into_iter()
+                .map(|row| row.into_iter().map(Some).collect())
+                .collect(),
+        };
+
+        // without ON CONFLICT every row is checked the way COPY checks them before any is written, so
+        // a bad row fails the statement with the table untouched. with it each row depends on what the
+        // ones before it did, those go one at a time and the statement is rolled back if one fails
+        if query.on_conflict.is_none() {
+            let unique: Vec<(usize, &IndexSchema)> = table.schema.indexes.iter().enumerate().filter(|(_, i)| i.unique).collect();
+            let mut seen = HashSet::new();
+            let mut rows = Vec::new();
+            for (n, values) in sources.into_iter().enumerate() {
+                let row = table.new_row(&positions, values)?;
+                rows.push((n, integrity::checked_row(engine, &table, &unique, &mut seen, row)?));
+            }
+            let mut errors = Vec::new();
+            let rows = integrity::with_parents(engine, &table, rows, &mut errors)?;
+            if let Some((_, e)) = errors.into_iter().min_by_key(|(n, _)| *n) {
+                return Err(e);
+            }
+
+            let mut returned = Vec::new();
+            for (_, row) in &rows {
+                table.insert(engine, row)?;
+                if let Some(items) = &query.returning {
+                    returned.push(returning_row(&table, items, row)?);
+                }
+            }
+            let message = format!("Inserted {} row{}", rows.len(), if rows.len() == 1 { "" } else { "s" });
+            return Ok(returned_rows(&table, &query.returning, returned, message));
+        }
+
+        // the unique indexes ON CONFLICT looks for clashes in. the whole statement runs under the
+        // storage lock so no other connection can add a clashing row between the check and the write
+        let conflict_indexes: Vec<&IndexSchema> = match &query.on_conflict {
+            None => Vec::new(),
+            Some(on_conflict) if on_conflict.columns.is_empty() => table.schema.indexes.iter().filter(|i| i.unique).collect(),
+            Some(on_conflict) => vec![table.schema.unique_index_on(&on_conflict.columns).ok_or_else(|| format!(
+                "ON CONFLICT ({}) does not match a PRIMARY KEY or UNIQUE constraint of table '{}'",
+                on_conflict.columns.join(", "), table.schema.name
+            ))?],
+        };
+        let conflict_updates = match &query.on_conflict {
+            Some(OnConflict { action: ConflictAction::Update { updates, where_clause }, .. }) => Some((table.assignments(updates)?, where_clause)),
+            _ => None,
+        };
+
+        let mut integrity = Integrity::default();
+        let (mut inserted, mut updated, mut skipped) = (0, 0, 0);
+        // keys written by this statement, a later row clashing with one of them cant update it again
+        let mut written_keys: Vec<(&str, Vec<Value>)> = Vec::new();
+        let mut returned = Vec::new();
+        for values in sources {
+            let row = table.new_row(&positions, values)?;
+
+            let mut conflict = None;
+            if !conflict_indexes.is_empty() {
+                let typed = table.coerce_row(row.clone())?;
+                for index in &conflict_indexes {
+                    if let Some(existing) = table.conflicting_row(engine, index, &typed, None)? {
+                        conflict = Some((index, typed, existing));
+                        break;
+                    }
+                }
+            }
+
+            let stored = match (conflict, &conflict_updates) {
+                (None, _) => {
+                    inserted += 1;
+                    integrity.insert(engine, &table, row)?.1
+                }
+                (Some(_), None) => {
+                    skipped += 1;
+                    continue;
+                }
+                (Some((index, excluded, (rid, existing))), Some((assignments, where_clause))) => {
+                    let key = table.index_values(index, &existing);
+                    if written_keys.iter().any(|(name, written)| *name == index.name && *written == key) {
+                        return Err(Error::Execution(format!(
+                            "ON CONFLICT DO UPDATE would change the row with key ({})=({}) a second time, the inserted rows repeat the key",
+                            index.columns.join(", "), display_row(&key)
+                        )));
+                    }
+                    let rows = [
+                        (Some(table.schema.name.as_str()), table.schema.columns.as_slice(), existing.as_slice()),
+                        (Some("excluded"), table.schema.columns.as_slice(), excluded.as_slice()),
+                    ];
+                    let scope = eval::Scope::new(&rows);
+                    let allowed = match where_clause {
+                        Some(condition) => eval::truth(&eval::eval_in(condition, &scope)?)? == Some(true),
+                        None => true,
+                    };
+                    if !allowed {
+                        skipped += 1;
+                        continue;
+                    }
+                    let mut new_row = existing.clone();
+                    for (idx, expr) in assignments {
+                        new_row[*idx] = eval::eval_in(expr, &scope)?;
+                    }
+                    match integrity.update(engine, &table, rid, &existing, new_row)? {
+                        Some(stored) => {
+                            updated += 1;
+                            stored
                         }
+                        None => continue,
                     }
-                    Ok(QueryResult::Message(msg)) => msg,
-                    Err(e) => format!("Execution error: {}", e),
-                }
-            },
-            parser::Query::Insert(insert_query) => {
-                match self.execute_insert(insert_query) {
-                    Ok(QueryResult::Message(msg)) => msg,
-                    Err(e) => format!("Execution error: {}", e),
-                    _ => format!("Execution error: unexpected result from insert"),
-                }
-            },
-            parser::Query::Create(create_query) => {
-                match self.execute_create(create_query) {
-                    Ok(QueryResult::Message(msg)) => msg,
-                    Err(e) => format!("Execution error: {}", e),
-                    _ => format!("Execution error: unexpected result from create"),
-                }
-            },
-            // parser::Query::Update(update_query) => {
-            //     execute_update(update_query)
-            // },
-            // parser::Query::Delete(delete_query) => {
-            //     execute_delete(delete_query)
-            // }
-            _ => "Unsupported query type".to_string(),
-        }
-    }
-
-    fn execute_select(&self, query: SelectQuery) -> Result<QueryResult, String> {
-        let storage = self.storage.lock().map_err(|e| format!("Storage lock poisoned: {}", e))?;
-        let table_opt = storage.get(&query.table_name);
-        if table_opt.is_none() {
-            return Err(format!("Table '{}' not found", query.table_name));
-        }
-        let table = table_opt.unwrap();
-        let rows = &table.rows;
-
-        let filtered_rows: Vec<Vec<String>> = rows
-            .iter()
-            .filter(|row| {
-                if let Some(expr) = &query.where_clause {
-                    self.eval_where(expr, &table.columns, row)
-                } else {
-                    true
                 }
-            })
-            .cloned()// have an iterator over &T but I need an iterator over T. use cloned for this
-            .collect();
+            };
+            for index in &conflict_indexes {
+                written_keys.push((&index.name, table.index_values(index, &stored)));
+            }
+            if let Some(items) = &query.returning {
+                returned.push(returning_row(&table, items, &stored)?);
+            }
+        }
+        integrity.finish(engine)?;
 
-        Ok(QueryResult::Rows(filtered_rows))
+        let mut message = format!("Inserted {} row{}", inserted, if inserted == 1 { "" } else { "s" });
+        if updated > 0 {
+            message.push_str(&format!(", updated {}", updated));
+        }
+        if skipped > 0 {
+            message.push_str(&format!(", skipped {}", skipped));
+        }
+        Ok(returned_rows(&table, &query.returning, returned, message))
     }
 
-    fn execute_insert(&self, query: InsertQuery) -> Result<QueryResult, String> {
-        let mut storage = self.storage.lock().map_err(|e| format!("Storage lock poisoned: {}", e))?;
-        let table = storage.get_mut(&query.table_name);
-        if table.is_none() {
-            return Err(format!("Table '{}' not found", query.table_name));
+    fn execute_update(&self, engine: &mut StorageEngine, query: UpdateQuery) -> Result<QueryResult, Error> {
+        let table = Table::load(engine, &query.table_name)?;
+        check_returning(&table, &query.returning)?;
+
+        let assignments = table.assignments(&query.updates)?;
+
+        // every SET expression sees the row as it was before the update, and subqueries in them see
+        // the table as it was before the statement, so the new rows are all worked out first
+        let mut changes = Vec::new();
+        {
+            let runner = QueryRunner::new(engine);
+            for (rid, row) in runner.table_rows(&table, &query.where_clause)? {
+                let source = [(Some(table.schema.name.as_str()), table.schema.columns.as_slice(), row.as_slice())];
+                let scope = eval::Scope::new(&source).with_subqueries(&runner);
+                let mut new_row = row.clone();
+                for (idx, expr) in &assignments {
+                    new_row[*idx] = eval::eval_in(expr, &scope)?;
+                }
+                changes.push((rid, row, new_row));
+            }
         }
-        let table = table.unwrap();
 
-        // validate column count
-        if query.values.len() != table.columns.len() {
-            return Err(format!(
-                "Column count mismatch: expected {} values for columns {:?}, got {}",
-                table.columns.len(), table.columns, query.values.len()
-            ));
+        let mut integrity = Integrity::default();
+        let mut updated = 0;
+        let mut returned = Vec::new();
+        for (rid, row, new_row) in changes {
+            if let Some(new_row) = integrity.update(engine, &table, rid, &row, new_row)? {
+                updated += 1;
+                if let Some(items) = &query.returning {
+                    returned.push(returning_row(&table, items, &new_row)?);
+                }
+            }
         }
+        integrity.finish(engine)?;
+
+        Ok(returned_rows(&table, &query.returning, returned, format!("Updated {} row{}", updated, if updated == 1 { "" } else { "s" })))
+    }
 
-        // clone values into a row
-        let row: Vec<String> = query.values.into_iter().collect();
-        table.insert(row)?;
+    fn execute_delete(&self, engine: &mut StorageEngine, query: DeleteQuery) -> Result<QueryResult, Error> {
+        let table = Table::load(engine, &query.table_name)?;
+        check_returning(&table, &query.returning)?;
 
-        Ok(QueryResult::Message("Inserted 1 row".to_string()))
+        let rows = QueryRunner::new(engine).table_rows(&table, &query.where_clause)?;
+        let mut integrity = Integrity::default();
+        let mut deleted = 0;
+        let mut returned = Vec::new();
+        for (rid, row) in &rows {
+            if integrity.delete(engine, &table, *rid, row)? {
+                deleted += 1;
+                if let Some(items) = &query.returning {
+                    returned.push(returning_row(&table, items, row)?);
+                }
+            }
+        }
+        integrity.finish(engine)?;
+
+        Ok(returned_rows(&table, &query.returning, returned, format!("Deleted {} row{}", deleted, if deleted == 1 { "" } else { "s" })))
     }
 
-    // fn execute_delete(query: DeleteQuery) -> &'static str {
-    //     "delete"
-    // }
+    // compact the heap pages of one table or all of them, then give the end of the file back
+    fn execute_vacuum(&self, engine: &mut StorageEngine, query: VacuumQuery) -> Result<QueryResult, Error> {
+
+        // vacuuming everything includes the catalog's own heap
+        let heaps = match &query.table_name {
+            Some(name) => vec![Table::load(engine, name)?.schema.heap_page_id],
+            None => std::iter::once(CATALOG_ROOT_PAGE)
+                .chain(Catalog::list_tables(engine)
+                    .map_err(|e| Error::storage("Failed to read catalog", e))?
+                    .into_iter()
+                    .map(|schema| schema.heap_page_id))
+                .collect(),
+        };
+
+        let mut total = VacuumStats::default();
+        for heap_page_id in &heaps {
+            let stats = engine.vacuum_heap(*heap_page_id).map_err(|e| Error::storage("Failed to vacuum", e))?;
+            total.pages_compacted += stats.pages_compacted;
+            total.pages_freed += stats.pages_freed;
+            total.bytes_reclaimed += stats.bytes_reclaimed;
+        }
+        let truncated = engine.truncate_free_tail().map_err(|e| Error::storage("Failed to truncate file", e))?;
 
-    // fn execute_update(query: UpdateQuery) -> &'static str {
-    //     "update"
-    // }
+        Ok(QueryResult::Message(format!(
+            "Vacuumed {} heaps: compacted {} pages, freed {} pages, reclaimed {} bytes, truncated {} bytes from the file",
+            heaps.len(), total.pages_compacted, total.pages_freed, total.bytes_reclaimed, truncated
+        )))
+    }
 
-    fn execute_create(&self, query: CreateQuery) -> Result<QueryResult, String> {
+    fn execute_create(&self, engine: &mut StorageEngine, query: CreateQuery) -> Result<QueryResult, Error> {
         let table_name = query.table_name;
-        let mut engine = StorageEngine::open().unwrap();
-        Catalog::init_if_missing(&mut engine).map_err(|e| format!("Failed to create table: {}", e))?;
-        
-        if Catalog::table_exists(&mut engine, &table_name).unwrap() {
-            return Err(format!("Table '{}' already exists", table_name));
-        }
-        
-        Catalog::add_table(&mut engine, &table_name, &query.columns).map_err(|e| format!("Failed to create table: {}", e))?;
+
+        if system::is_system_table(&table_name) || Catalog::table_exists(engine, &table_name).map_err(|e| Error::storage("Failed to read catalog", e))? {
+            return Err(Error::Duplicate(format!("Table '{}' already exists", table_name)));
+        }
+
+        let mut columns: Vec<ColumnSchema> = Vec::with_capacity(query.columns.len());
+        let mut constraints = Vec::new();
+        for def in query.columns {
+            if columns.iter().any(|existing| existing.name == def.name) {
+                return Err(Error::Execution(format!("Column '{}' given more than once", def.name)));
+            }
+            columns.push(column_schema(&def)?);
+            constraints.extend(def.constraints);
+        }
+        constraints.extend(query.constraints);
+        let Constraints { indexes, checks, mut foreign_keys } = build_constraints(&table_name, &mut columns, constraints, &[])?;
+        // what the table will look like, for foreign keys referencing the table itself
+        let new_table = TableSchema {
+            name: table_name.clone(),
+            heap_page_id: 0,
+            columns: columns.clone(),
+            indexes: indexes.clone(),
+            checks: Vec::new(),
+            foreign_keys: Vec::new(),
+            next_column_id: 0,
+        };
+        for fk in &mut foreign_keys {
+            resolve_foreign_key(engine, &new_table, fk)?;
+        }
+        Catalog::add_table(engine, &table_name, columns, indexes, checks, foreign_keys).map_err(|e| Error::storage("Failed to create table", e))?;
         Ok(QueryResult::Message(format!("Table '{}' created", table_name)))
     }
 
-    fn eval_where(&self, expr: &Expression, columns: &[String], row: &[String]) -> bool {
-        use crate::parser::ast::Expression::*;
-        
-        match expr {
-            BinaryOp { left, operator, right} => {
-                let left_val = match &**left {
-                    Column(name) => {
-                        if let Some(idx) = columns.iter().position(|c| c==name) {
-                            row.get(idx).cloned().unwrap_or_default()
-                        } else {
-                            "".to_string()
-                        }
-                    }
-                    Value(val) => val.clone(),
-                    _ => "".to_string(),
-                };
+    fn execute_drop(&self, engine: &mut StorageEngine, query: DropQuery) -> Result<QueryResult, Error> {
+        if system::is_system_table(&query.table_name) {
+            return Err(Error::Execution(format!("Table '{}' is a read-only system table", query.table_name)));
+        }
+        refuse_if_referenced(engine, &query.table_name, "drop")?;
+
+        let dropped = Catalog::drop_table(engine, &query.table_name).map_err(|e| Error::storage("Failed to drop table", e))?;
+        if dropped {
+            Ok(QueryResult::Message(format!("Table '{}' dropped", query.table_name)))
+        } else if query.if_exists {
+            Ok(QueryResult::Message(format!("Table '{}' does not exist, skipping", query.table_name)))
+        } else {
+            Err(Error::UndefinedTable(format!("Table '{}' not found", query.table_name)))
+        }
+    }
+
+    fn execute_truncate(&self, engine: &mut StorageEngine, query: TruncateQuery) -> Result<QueryResult, Error> {
+        let table = Table::load(engine, &query.table_name)?;
+        refuse_if_referenced(engine, &query.table_name, "truncate")?;
+
+        table.truncate(engine)?;
+        Ok(QueryResult::Message(format!("Table '{}' truncated", query.table_name)))
+    }
+
+    // only the catalog entry changes. rows keep the values they were written with and are read through
+    // the new schema by column id, so existing rows pick up added columns as their default and lose
+    // dropped ones the next time theyre written
+    fn execute_alter(&self, engine: &mut StorageEngine, query: AlterQuery) -> Result<QueryResult, Error> {
+        let table = Table::load(engine, &query.table_name)?;
+        let mut schema = table.schema.clone();
 
-                let right_val = match &**right {
-                    Value(val) => val.clone(),
-                    _ => "".to_string(),
+        let message = match query.action {
+            AlterAction::AddColumn(def) => {
+                if schema.column_index(&def.name).is_some() {
+                    return Err(Error::Duplicate(format!("Column '{}' already exists in table '{}'", def.name, schema.name)));
+                }
+                if def.constraints.iter().any(|c| matches!(c.kind, ConstraintKind::PrimaryKey(_) | ConstraintKind::Unique(_))) {
+                    return Err(Error::Execution(format!("Column '{}' cannot be added with a PRIMARY KEY or UNIQUE constraint", def.name)));
+                }
+                let mut column = column_schema(&def)?;
+                // rows already in the table read the new column as its default, worked out once here
+                let missing = match &table_default(&def)? {
+                    Some(value) => value.clone(),
+                    None => Value::Null,
                 };
+                if column.not_null && missing.is_null() && !table.scan(engine)?.is_empty() {
+                    return Err(Error::Constraint { violation: Violation::NotNull, message: format!("Column '{}' is NOT NULL but has no default for the rows already in table '{}'", def.name, schema.name) });
+                }
+                column.missing = missing.to_stored();
+                column.id = schema.next_column_id;
+                schema.next_column_id = column.id.checked_add(1)
+                    .ok_or_else(|| format!("Table '{}' has run out of column ids", schema.name))?;
+                schema.columns.push(column);
+
+                let taken = constraint_names(&schema);
+                let Constraints { checks, foreign_keys, .. } = build_constraints(&schema.name, &mut schema.columns, def.constraints, &taken)?;
+                // every existing row has the same value in the new column, so the first is enough for
+                // its foreign keys
+                add_row_constraints(engine, &mut schema, checks, foreign_keys, true)?;
+                format!("Column '{}' added to table '{}'", def.name, schema.name)
+            }
+            AlterAction::AddConstraint(constraint) => {
+                if matches!(constraint.kind, ConstraintKind::PrimaryKey(_) | ConstraintKind::Unique(_)) {
+                    return Err(Error::Execution(format!("PRIMARY KEY and UNIQUE constraints can only be given when table '{}' is created", schema.name)));
+                }
+                let taken = constraint_names(&schema);
+                let Constraints { checks, foreign_keys, .. } = build_constraints(&schema.name, &mut schema.columns, vec![constraint], &taken)?;
+                let name = checks.iter().map(|c| &c.name).chain(foreign_keys.iter().map(|fk| &fk.name)).next().cloned().unwrap_or_default();
+                add_row_constraints(engine, &mut schema, checks, foreign_keys, false)?;
+                format!("Constraint '{}' added to table '{}'", name, schema.name)
+            }
+            AlterAction::DropColumn(name) => {
+                let idx = schema.column_index(&name)
+                    .ok_or_else(|| Error::UndefinedColumn(format!("Column '{}' not found in table '{}'", name, schema.name)))?;
+                if schema.columns.len() == 1 {
+                    return Err(Error::Execution(format!("Cannot drop '{}', the only column of table '{}'", name, schema.name)));
+                }
+                for other in Catalog::referencing_tables(engine, &schema.name).map_err(|e| Error::storage("Failed to read catalog", e))? {
+                    let fk = other.foreign_keys.iter()
+                        .find(|fk| fk.parent_table == schema.name && fk.parent_columns.contains(&name) && !(other.name == schema.name && fk.columns.contains(&name)));
+                    if let Some(fk) = fk {
+                        return Err(Error::Execution(format!("Cannot drop column '{}' of table '{}', constraint '{}' on table '{}' references it", name, schema.name, fk.name, other.name)));
+                    }
+                }
+                schema.columns.remove(idx);
+                // indexes, CHECK and FOREIGN KEY constraints over the column go with it
+                let (dropped, kept): (Vec<IndexSchema>, Vec<IndexSchema>) = schema.indexes.into_iter()
+                    .partition(|index| index.columns.contains(&name));
+                schema.indexes = kept;
+                for index in &dropped {
+                    BTree::open(engine, index.root_page_id).destroy()
+                        .map_err(|e| Error::storage(&format!("Failed to drop index {}", index.name), e))?;
+                }
+                schema.checks.retain(|check| !table.checks.iter().any(|(n, expr)| *n == check.name && expr.references_column(&name)));
+                schema.foreign_keys.retain(|fk| !fk.columns.contains(&name));
+                format!("Column '{}' dropped from table '{}'", name, schema.name)
+            }
+            AlterAction::RenameColumn { from, to } => {
+                let idx = schema.column_index(&from)
+                    .ok_or_else(|| Error::UndefinedColumn(format!("Column '{}' not found in table '{}'", from, schema.name)))?;
+                if schema.column_index(&to).is_some() {
+                    return Err(Error::Duplicate(format!("Column '{}' already exists in table '{}'", to, schema.name)));
+                }
+                schema.columns[idx].name = to.clone();
+                for index in &mut schema.indexes {
+                    rename_in(&mut index.columns, &from, &to);
+                }
+                for fk in &mut schema.foreign_keys {
+                    rename_in(&mut fk.columns, &from, &to);
+                    if fk.parent_table == schema.name {
+                        rename_in(&mut fk.parent_columns, &from, &to);
+                    }
+                }
+                // checks were loaded in catalog order so they line up with schema.checks
+                for (check, (_, expr)) in schema.checks.iter_mut().zip(&table.checks) {
+                    let mut expr = expr.clone();
+                    expr.rename_column(&from, &to);
+                    check.expr = expr.to_string();
+                }
+                format!("Column '{}' renamed to '{}' in table '{}'", from, to, schema.name)
+            }
+            AlterAction::RenameTable(new_name) => {
+                if system::is_system_table(&new_name) || Catalog::table_exists(engine, &new_name).map_err(|e| Error::storage("Failed to read catalog", e))? {
+                    return Err(Error::Duplicate(format!("Table '{}' already exists", new_name)));
+                }
+                schema.name = new_name.clone();
+                for fk in schema.foreign_keys.iter_mut().filter(|fk| fk.parent_table == query.table_name) {
+                    fk.parent_table = new_name.clone();
+                }
+                format!("Table '{}' renamed to '{}'", query.table_name, new_name)
+            }
+        };
+
+        Catalog::update_table(engine, &query.table_name, &schema).map_err(|e| Error::storage("Failed to update catalog", e))?;
+
+        // foreign keys of other tables name this table and its columns too
+        let referencing = Catalog::referencing_tables(engine, &query.table_name).map_err(|e| Error::storage("Failed to read catalog", e))?;
+        for mut other in referencing.into_iter().filter(|other| other.name != query.table_name) {
+            for fk in other.foreign_keys.iter_mut().filter(|fk| fk.parent_table == query.table_name) {
+                fk.parent_table = schema.name.clone();
+                for (old, new) in table.schema.columns.iter().zip(&schema.columns) {
+                    if old.id == new.id && old.name != new.name {
+                        rename_in(&mut fk.parent_columns, &old.name, &new.name);
+                    }
+                }
+            }
+            Catalog::update_table(engine, &other.name, &other).map_err(|e| Error::storage("Failed to update catalog", e))?;
+        }
+        Ok(QueryResult::Message(message))
+    }
+
+    // SHOW TABLES and DESCRIBE are shorthands for selecting from the system tables
+    fn execute_show_tables(&self, engine: &mut StorageEngine) -> Result<QueryResult, Error> {
+        let tables = system::load(engine, "tony_tables")?.map(|t| t.rows).unwrap_or_default();
+        if tables.is_empty() {
+            return Ok(QueryResult::Message("No tables found".to_string()));
+        }
+        Ok(QueryResult::Rows { columns: text_columns(&["table_name"]), rows: tables.into_iter().map(|row| vec![row[0].clone()]).collect() })
+    }
 
-                match operator.as_str() {
-                    "=" => left_val == right_val,
-                    ">" => left_val > right_val,
-                    "<" => left_val < right_val,
-                    _ => false,
+    fn execute_copy(&self, engine: &mut StorageEngine, query: CopyQuery) -> Result<QueryResult, Error> {
+        let db_path = self.db_path()?;
+        let message = match query.source {
+            CopySource::Table { name, columns } if !query.to => {
+                let table = Table::load(engine, &name)?;
+                copy::copy_from(engine, &table, &columns, &db_path, &query.path, &query.options)?
+            }
+            CopySource::Table { name, columns } => {
+                let (names, rows) = copy::table_rows(engine, &name, &columns)?;
+                copy::copy_to(&names, &rows, &db_path, &query.path, &query.options)?
+            }
+            CopySource::Query(select) => {
+                let relation = QueryRunner::new(engine).select(&select, None)?;
+                let names: Vec<String> = relation.columns.into_iter().map(|c| c.name).collect();
+                copy::copy_to(&names, &relation.rows, &db_path, &query.path, &query.options)?
+            }
+        };
+        Ok(QueryResult::Message(message))
+    }
+
+    // copies the database file while other statements carry on. the lock is only held to read where
+    // the log is before and after the copy, replaying the log between the two over the copy is what
+    // makes it consistent, which recover does
+    fn execute_backup(&self, path: &str) -> Result<QueryResult, Error> {
+        let db_path = self.db_path()?;
+        let target = copy::resolve(&db_path, path)?;
+
+        let start_lsn = {
+            let _guard = self.lock.lock().map_err(|e| format!("Storage lock poisoned: {}", e))?;
+            // a database that doesnt exist yet is created so there is something to copy
+            drop(self.open_storage()?);
+            let start_lsn = wal::current_lsn().map_err(|e| Error::storage("Failed to read the write ahead log", e))?;
+            wal::hold(start_lsn).map_err(|e| Error::storage("Failed to keep the write ahead log", e))?;
+            start_lsn
+        };
+        let pages = backup::copy_pages(&db_path, &target).map_err(|e| Error::Io(format!("Failed to write '{}': {}", path, e)))?;
+        let label = {
+            let _guard = self.lock.lock().map_err(|e| format!("Storage lock poisoned: {}", e))?;
+            let end_lsn = wal::current_lsn().map_err(|e| Error::storage("Failed to read the write ahead log", e))?;
+            Label { start_lsn, end_lsn, time: wal::now_millis() }
+        };
+        label.write(&target).map_err(|e| Error::Io(format!("Failed to write the label of '{}': {}", path, e)))?;
+        // segments from before this backup arent needed to recover it, the next checkpoint deletes them
+        wal::keep_from(start_lsn).map_err(|e| Error::storage("Failed to keep the write ahead log", e))?;
+        Ok(QueryResult::Message(format!(
+            "Backed up {} page{} to '{}', consistent once the log from LSN {} to {} is replayed",
+            pages, if pages == 1 { "" } else { "s" }, path, start_lsn, label.end_lsn
+        )))
+    }
+
+    fn execute_describe(&self, engine: &mut StorageEngine, query: DescribeQuery) -> Result<QueryResult, Error> {
+
+        // system tables describe themselves too
+        let (columns, indexes) = match system::load(engine, &query.table_name)? {
+            Some(system_table) => (system_table.columns, Vec::new()),
+            None => {
+                let schema = Table::load(engine, &query.table_name)?.schema;
+                (schema.columns, schema.indexes)
+            }
+        };
+        let key_of = |column: &str| -> Value {
+            let kind = indexes.iter()
+                .filter(|i| i.unique && i.columns.len() == 1 && i.columns[0] == column)
+                .map(|i| if i.primary { "PRIMARY KEY" } else { "UNIQUE" })
+                .min() // PRIMARY KEY sorts before UNIQUE
+                .or_else(|| indexes.iter().find(|i| i.primary && i.columns.iter().any(|c| c == column)).map(|_| "PRIMARY KEY"));
+            kind.map_or(Value::Null, |k| Value::Text(k.to_string()))
+        };
+        let rows = columns.iter()
+            .map(|c| vec![
+                Value::Text(c.name.clone()),
+                Value::Text(c.data_type.to_string()),
+                Value::Text(if c.not_null { "NOT NULL" } else { "NULL" }.to_string()),
+                c.default.clone().map_or(Value::Null, Value::Text),
+                key_of(&c.name),
+            ])
+            .collect();
+        Ok(QueryResult::Rows { columns: text_columns(&["column", "type", "null", "default", "key"]), rows })
+    }
+}
+
+// the default of a column definition worked out and converted to the columns type
+fn table_default(def: &ColumnDef) -> Result<Option<Value>, Error> {
+    if def.default.as_ref().is_some_and(Expression::contains_subquery) {
+        return Err(Error::Execution(format!("Invalid default for column '{}': a default cannot use a subquery", def.name)));
+    }
+    def.default.as_ref()
+        .map(|expr| eval::eval(expr, &[], &[]).and_then(|value| value.coerce(def.data_type)))
+        .transpose()
+        .map_err(|e| e.context(&format!("Invalid default for column '{}'", def.name)))
+}
+
+// the catalog entry for a column from CREATE or ADD COLUMN. its id is filled in later
+fn column_schema(def: &ColumnDef) -> Result<ColumnSchema, Error> {
+    table_default(def)?;
+    Ok(ColumnSchema {
+        id: 0,
+        name: def.name.clone(),
+        data_type: def.data_type,
+        not_null: def.not_null,
+        default: def.default.as_ref().map(|expr| expr.to_string()),
+        missing: None,
+    })
+}
+
+struct Constraints {
+    indexes: Vec<IndexSchema>,
+    checks: Vec<CheckSchema>,
+    foreign_keys: Vec<ForeignKeySchema>,
+}
+
+// turn PRIMARY KEY and UNIQUE constraints into unique indexes and CHECK and FOREIGN KEY constraints into
+// their catalog form, naming the ones that werent given a name. primary key columns become NOT NULL.
+// foreign keys still have to be checked against their parent with resolve_foreign_key.
+// taken holds constraint names the table already uses
+fn build_constraints(table_name: &str, columns: &mut [ColumnSchema], constraints: Vec<TableConstraint>, taken: &[String]) -> Result<Constraints, Error> {
+    let mut names = taken.to_vec();
+    let mut indexes: Vec<IndexSchema> = Vec::new();
+    let mut checks = Vec::new();
+    let mut foreign_keys = Vec::new();
+
+    for constraint in constraints {
+        let name = match (&constraint.name, &constraint.kind) {
+            (Some(name), _) => {
+                if names.contains(name) {
+                    return Err(Error::Duplicate(format!("Constraint '{}' given more than once in table '{}'", name, table_name)));
+                }
+                name.clone()
+            }
+            (None, ConstraintKind::PrimaryKey(_)) => unused_name(format!("{}_pkey", table_name), &names),
+            (None, ConstraintKind::Unique(cols)) => unused_name(format!("{}_{}_key", table_name, cols.join("_")), &names),
+            (None, ConstraintKind::Check(expr)) => {
+                let referenced: Vec<&ColumnSchema> = columns.iter().filter(|c| expr.references_column(&c.name)).collect();
+                match referenced.as_slice() {
+                    [column] => unused_name(format!("{}_{}_check", table_name, column.name), &names),
+                    _ => unused_name(format!("{}_check", table_name), &names),
+                }
+            }
+            (None, ConstraintKind::ForeignKey(fk)) => unused_name(format!("{}_{}_fkey", table_name, fk.columns.join("_")), &names),
+        };
+        names.push(name.clone());
+
+        match constraint.kind {
+            ConstraintKind::PrimaryKey(cols) | ConstraintKind::Unique(cols) if cols.is_empty() => {
+                return Err(Error::Execution(format!("Constraint '{}' needs at least one column", name)));
+            }
+            ConstraintKind::PrimaryKey(cols) => {
+                if indexes.iter().any(|i| i.primary) {
+                    return Err(Error::Execution(format!("Table '{}' can only have one PRIMARY KEY", table_name)));
+                }
+                for idx in constraint_columns(&name, columns, &cols)? {
+                    columns[idx].not_null = true;
+                }
+                indexes.push(IndexSchema { name, columns: cols, root_page_id: 0, unique: true, primary: true });
+            }
+            ConstraintKind::Unique(cols) => {
+                constraint_columns(&name, columns, &cols)?;
+                indexes.push(IndexSchema { name, columns: cols, root_page_id: 0, unique: true, primary: false });
+            }
+            ConstraintKind::Check(expr) => {
+                if expr.contains_subquery() {
+                    return Err(Error::Execution(format!("Invalid CHECK constraint '{}': a CHECK cannot use a subquery", name)));
+                }
+                // evaluating against a row of NULLs catches unknown columns without rejecting anything valid
+                eval::eval(&expr, columns, &vec![Value::Null; columns.len()])
+                    .map_err(|e| e.context(&format!("Invalid CHECK constraint '{}'", name)))?;
+                checks.push(CheckSchema { name, expr: expr.to_string() });
+            }
+            ConstraintKind::ForeignKey(fk) => {
+                if fk.columns.is_empty() {
+                    return Err(Error::Execution(format!("Constraint '{}' needs at least one column", name)));
                 }
+                constraint_columns(&name, columns, &fk.columns)?;
+                foreign_keys.push(ForeignKeySchema {
+                    name,
+                    columns: fk.columns,
+                    parent_table: fk.parent_table,
+                    parent_columns: fk.parent_columns,
+                    on_delete: fk.on_delete,
+                    on_update: fk.on_update,
+                    deferred: fk.deferred,
+                });
             }
-            _ => true,
         }
     }
-}
\ No newline at end of file
+    Ok(Constraints { indexes, checks, foreign_keys })
+}
+
+// check a foreign key of child against the table it references, which may be child itself. a key that
+// doesnt list parent columns references the parents primary key. the parent columns have to be covered
+// by a PRIMARY KEY or UNIQUE constraint and match the types of the child columns
+fn resolve_foreign_key(engine: &mut StorageEngine, child: &TableSchema, fk: &mut ForeignKeySchema) -> Result<(), Error> {
+    if system::is_system_table(&fk.parent_table) {
+        return Err(Error::Execution(format!("Constraint '{}' cannot reference the system table '{}'", fk.name, fk.parent_table)));
+    }
+    let parent = if fk.parent_table == child.name {
+        child.clone()
+    } else {
+        Catalog::get_table(engine, &fk.parent_table)
+            .map_err(|e| Error::storage("Failed to read catalog", e))?
+            .ok_or_else(|| Error::UndefinedTable(format!("Table '{}' referenced by constraint '{}' not found", fk.parent_table, fk.name)))?
+    };
+
+    if fk.parent_columns.is_empty() {
+        fk.parent_columns = parent.primary_key()
+            .ok_or_else(|| format!("Table '{}' referenced by constraint '{}' has no PRIMARY KEY", parent.name, fk.name))?
+            .columns.clone();
+    }
+    if fk.parent_columns.len() != fk.columns.len() {
+        return Err(Error::Execution(format!("Constraint '{}' has {} columns but references {}", fk.name, fk.columns.len(), fk.parent_columns.len())));
+    }
+    let parent_positions = constraint_columns(&fk.name, &parent.columns, &fk.parent_columns)?;
+    if parent.unique_index_on(&fk.parent_columns).is_none() {
+        return Err(Error::Execution(format!("Constraint '{}' references ({}) in table '{}', which is not a PRIMARY KEY or UNIQUE", fk.name, fk.parent_columns.join(", "), parent.name)));
+    }
+    for (column, parent_idx) in fk.columns.iter().zip(parent_positions) {
+        let child_column = &child.columns[child.column_index(column).ok_or_else(|| Error::UndefinedColumn(format!("Column '{}' in constraint '{}' not found", column, fk.name)))?];
+        let parent_column = &parent.columns[parent_idx];
+        if child_column.data_type != parent_column.data_type {
+            return Err(Error::Execution(format!(
+                "Constraint '{}' cannot reference {} column '{}' from {} column '{}'",
+                fk.name, parent_column.data_type, parent_column.name, child_column.data_type, child_column.name
+            )));
+        }
+    }
+    Ok(())
+}
+
+// add CHECK and FOREIGN KEY constraints to a table, refused if a row already in it breaks one. with
+// same_keys every row has the same values in the foreign key columns, so only the first is looked up
+fn add_row_constraints(engine: &mut StorageEngine, schema: &mut TableSchema, checks: Vec<CheckSchema>, mut foreign_keys: Vec<ForeignKeySchema>, same_keys: bool) -> Result<(), Error> {
+    if checks.is_empty() && foreign_keys.is_empty() {
+        return Ok(());
+    }
+    for fk in &mut foreign_keys {
+        resolve_foreign_key(engine, schema, fk)?;
+    }
+    let (check_count, fk_count) = (schema.checks.len(), schema.foreign_keys.len());
+    schema.checks.extend(checks);
+    schema.foreign_keys.extend(foreign_keys);
+
+    let altered = Table::from_schema(schema.clone())?;
+    let mut integrity = Integrity::default();
+    for (i, (_, row)) in altered.scan(engine)?.into_iter().enumerate() {
+        for (name, expr) in &altered.checks[check_count..] {
+            if eval::truth(&eval::eval(expr, &schema.columns, &row)?)? == Some(false) {
+                return Err(Error::Constraint { violation: Violation::Check, message: format!("CHECK constraint '{}' violated by existing row ({})", name, display_row(&row)) });
+            }
+        }
+        if i == 0 || !same_keys {
+            for fk in &altered.schema.foreign_keys[fk_count..] {
+                integrity.check_existing(engine, &altered, fk, &row)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// DROP and TRUNCATE would leave other tables referencing rows that are gone
+fn refuse_if_referenced(engine: &mut StorageEngine, table_name: &str, action: &str) -> Result<(), Error> {
+    let referencing = Catalog::referencing_tables(engine, table_name).map_err(|e| Error::storage("Failed to read catalog", e))?;
+    match referencing.iter().find(|other| other.name != table_name) {
+        Some(other) => Err(Error::Execution(format!("Cannot {} table '{}', table '{}' has a foreign key referencing it", action, table_name, other.name))),
+        None => Ok(()),
+    }
+}
+
+// names of every constraint on the table, new ones must not clash with them
+fn constraint_names(schema: &TableSchema) -> Vec<String> {
+    schema.indexes.iter().map(|i| i.name.clone())
+        .chain(schema.checks.iter().map(|c| c.name.clone()))
+        .chain(schema.foreign_keys.iter().map(|fk| fk.name.clone()))
+        .collect()
+}
+
+fn rename_in(columns: &mut [String], from: &str, to: &str) {
+    for column in columns.iter_mut().filter(|c| *c == from) {
+        *column = to.to_string();
+    }
+}
+
+// positions of the columns a PRIMARY KEY, UNIQUE or FOREIGN KEY constraint lists
+fn constraint_columns(name: &str, columns: &[ColumnSchema], listed: &[String]) -> Result<Vec<usize>, Error> {
+    let mut positions = Vec::with_capacity(listed.len());
+    for column in listed {
+        let idx = columns.iter().position(|c| c.name == *column)
+            .ok_or_else(|| Error::UndefinedColumn(format!("Column '{}' in constraint '{}' not found", column, name)))?;
+        if positions.contains(&idx) {
+            return Err(Error::Execution(format!("Column '{}' listed more than once in constraint '{}'", column, name)));
+        }
+        positions.push(idx);
+    }
+    Ok(positions)
+}
+
+// base, or base followed by the first number that makes it unused
+fn unused_name(base: String, taken: &[String]) -> String {
+    if !taken.contains(&base) {
+        return base;
+    }
+    (1..).map(|n| format!("{}{}", base, n)).find(|name| !taken.contains(name)).unwrap_or(base)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    // an executor over a database file of its own in a directory of its own, deleted when the test is done
+    struct Scratch {
+        dir: PathBuf,
+        executor: Executor,
+    }
+
+    impl Scratch {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("tony_db_executor_{}_{}", std::process::id(), name));
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(&dir).unwrap();
+            let executor = Executor::at(&dir.join("tony.db"));
+            Scratch { dir, executor }
+        }
+
+        fn run(&self, sql: &str) -> Result<QueryResult, Error> {
+            self.executor.execute(&mut Session::default(), sql)
+        }
+
+        fn rows(&self, sql: &str) -> Vec<Vec<Value>> {
+            match self.run(sql) {
+                Ok(QueryResult::Rows { rows, .. }) => rows,
+                other => panic!("{} gave {:?} instead of rows", sql, other),
+            }
+        }
+    }
+
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    fn ints(values: &[Option<i64>]) -> Vec<Vec<Value>> {
+        values.iter().map(|v| vec![v.map_or(Value::Null, Value::Integer)]).collect()
+    }
+
+    #[test]
+    fn nulls_sort_as_if_larger_than_any_value() {
+        let scratch = Scratch::new("nulls_order");
+        scratch.run("CREATE TABLE e (sal INTEGER)").unwrap();
+        scratch.run("INSERT INTO e VALUES (2), (NULL), (1)").unwrap();
+        assert_eq!(scratch.rows("SELECT sal FROM e ORDER BY sal"), ints(&[Some(1), Some(2), None]));
+        assert_eq!(scratch.rows("SELECT sal FROM e ORDER BY sal DESC"), ints(&[None, Some(2), Some(1)]));
+        assert_eq!(scratch.rows("SELECT sal FROM e ORDER BY sal NULLS FIRST"), ints(&[None, Some(1), Some(2)]));
+        assert_eq!(scratch.rows("SELECT sal FROM e ORDER BY sal DESC NULLS LAST"), ints(&[Some(2), Some(1), None]));
+    }
+
+    #[test]
+    fn ddl_bumps_the_schema_version() {
+        let scratch = Scratch::new("schema_version");
+        let version = || match scratch.rows("SELECT schema_version FROM tony_database")[..] {
+            [ref row] => row[0].clone(),
+            ref rows => panic!("tony_database has {} rows", rows.len()),
+        };
+        assert_eq!(version(), Value::Integer(0));
+        scratch.run("CREATE TABLE t (id INTEGER PRIMARY KEY)").unwrap();
+        assert_eq!(version(), Value::Integer(1));
+        scratch.run("INSERT INTO t VALUES (1)").unwrap();
+        scratch.run("ALTER TABLE t ADD COLUMN name TEXT").unwrap();
+        assert_eq!(version(), Value::Integer(2));
+        scratch.run("DROP TABLE t").unwrap();
+        assert_eq!(version(), Value::Integer(3));
+    }
+
+    #[test]
+    fn copy_stays_in_the_database_directory() {
+        let scratch = Scratch::new("copy_paths");
+        scratch.run("CREATE TABLE t (id INTEGER PRIMARY KEY)").unwrap();
+        scratch.run("INSERT INTO t VALUES (1), (2)").unwrap();
+        scratch.run("COPY t TO 't.csv'").unwrap();
+        assert_eq!(std::fs::read_to_string(scratch.dir.join("t.csv")).unwrap(), "1\n2\n");
+        scratch.run("DELETE FROM t").unwrap();
+        scratch.run("COPY t FROM './t.csv'").unwrap();
+        assert_eq!(scratch.rows("SELECT id FROM t ORDER BY id"), ints(&[Some(1), Some(2)]));
+
+        for path in ["/etc/passwd", "../t.csv", "sub/../../t.csv", "tony.db", "tony_wal/0000000000000000.wal", ""] {
+            assert!(scratch.run(&format!("COPY t FROM '{}'", path)).is_err(), "COPY t FROM '{}' was allowed", path);
+            assert!(scratch.run(&format!("COPY t TO '{}'", path)).is_err(), "COPY t TO '{}' was allowed", path);
+            assert!(scratch.run(&format!("BACKUP TO '{}'", path)).is_err(), "BACKUP TO '{}' was allowed", path);
+        }
+        assert_eq!(scratch.rows("SELECT id FROM t ORDER BY id"), ints(&[Some(1), Some(2)]));
+    }
+
+    // the code of the error sql fails with
+    fn fails(scratch: &Scratch, sql: &str) -> &'static str {
+        match scratch.run(sql) {
+            Err(e) => e.code(),
+            Ok(result) => panic!("{} gave {:?} instead of failing", sql, result),
+        }
+    }
+
+    fn family(name: &str, deferred: &str) -> Scratch {
+        let scratch = Scratch::new(name);
+        scratch.run("CREATE TABLE p (id INTEGER PRIMARY KEY)").unwrap();
+        scratch.run(&format!("CREATE TABLE c (id INTEGER PRIMARY KEY, p_id INTEGER REFERENCES p {}, n INTEGER)", deferred)).unwrap();
+        scratch.run("INSERT INTO p VALUES (1), (2)").unwrap();
+        scratch.run("INSERT INTO c VALUES (1, 1, 1), (2, 2, 2)").unwrap();
+        scratch
+    }
+
+    #[test]
+    fn a_deferred_foreign_key_that_fails_writes_nothing() {
+        let scratch = family("deferred_insert", "DEFERRABLE INITIALLY DEFERRED");
+        assert_eq!(fails(&scratch, "INSERT INTO c VALUES (4, 9, 9)"), "23503");
+        assert_eq!(scratch.rows("SELECT id FROM c ORDER BY id"), ints(&[Some(1), Some(2)]));
+        assert_eq!(fails(&scratch, "UPDATE c SET p_id = 9 WHERE id = 1"), "23503");
+        assert_eq!(scratch.rows("SELECT p_id FROM c ORDER BY id"), ints(&[Some(1), Some(2)]));
+    }
+
+    #[test]
+    fn a_deferred_no_action_delete_leaves_no_orphans() {
+        let scratch = family("deferred_delete", "DEFERRABLE INITIALLY DEFERRED");
+        assert_eq!(fails(&scratch, "DELETE FROM p WHERE id = 1"), "23503");
+        assert_eq!(scratch.rows("SELECT id FROM p ORDER BY id"), ints(&[Some(1), Some(2)]));
+        assert_eq!(fails(&scratch, "UPDATE p SET id = id + 10"), "23503");
+        assert_eq!(scratch.rows("SELECT id FROM p ORDER BY id"), ints(&[Some(1), Some(2)]));
+        scratch.run("DELETE FROM c WHERE id = 2").unwrap();
+        scratch.run("DELETE FROM p WHERE id = 2").unwrap();
+        assert_eq!(scratch.rows("SELECT id FROM p"), ints(&[Some(1)]));
+    }
+
+    #[test]
+    fn a_cascade_that_fails_further_down_writes_nothing() {
+        let scratch = Scratch::new("cascade_rollback");
+        scratch.run("CREATE TABLE p (id INTEGER PRIMARY KEY)").unwrap();
+        scratch.run("CREATE TABLE c (id INTEGER PRIMARY KEY, p_id INTEGER REFERENCES p ON DELETE CASCADE)").unwrap();
+        scratch.run("CREATE TABLE g (id INTEGER PRIMARY KEY, c_id INTEGER REFERENCES c ON DELETE RESTRICT)").unwrap();
+        scratch.run("INSERT INTO p VALUES (1), (2)").unwrap();
+        scratch.run("INSERT INTO c VALUES (1, 1), (2, 1), (3, 2)").unwrap();
+        scratch.run("INSERT INTO g VALUES (1, 2)").unwrap();
+        // the cascade from p 1 reaches c 2, which g still needs, after other rows already went
+        assert_eq!(fails(&scratch, "DELETE FROM p"), "23503");
+        assert_eq!(scratch.rows("SELECT id FROM p ORDER BY id"), ints(&[Some(1), Some(2)]));
+        assert_eq!(scratch.rows("SELECT id FROM c ORDER BY id"), ints(&[Some(1), Some(2), Some(3)]));
+    }
+
+    #[test]
+    fn an_insert_with_a_bad_row_writes_none_of_them() {
+        let scratch = Scratch::new("insert_atomic");
+        scratch.run("CREATE TABLE m (id INTEGER PRIMARY KEY, n INTEGER NOT NULL CHECK (n > 0))").unwrap();
+        scratch.run("CREATE TABLE r (id INTEGER PRIMARY KEY, m_id INTEGER REFERENCES m)").unwrap();
+        scratch.run("INSERT INTO m VALUES (1, 1)").unwrap();
+        assert_eq!(fails(&scratch, "INSERT INTO m VALUES (2, 2), (3, 3), (4, -1)"), "23514");
+        assert_eq!(fails(&scratch, "INSERT INTO m VALUES (2, 2), (3, NULL)"), "23502");
+        assert_eq!(fails(&scratch, "INSERT INTO m VALUES (2, 2), (1, 5)"), "23505");
+        assert_eq!(fails(&scratch, "INSERT INTO m VALUES (10, 1), (10, 2)"), "23505");
+        assert_eq!(fails(&scratch, "INSERT INTO m VALUES (2, 2), (3, 'x')"), "22P02");
+        assert_eq!(scratch.rows("SELECT id FROM m"), ints(&[Some(1)]));
+        assert_eq!(fails(&scratch, "INSERT INTO r VALUES (1, 1), (2, 9)"), "23503");
+        assert_eq!(scratch.rows("SELECT id FROM r"), ints(&[]));
+
+        // the rows of a SELECT go the same way
+        scratch.run("CREATE TABLE src (id INTEGER, n INTEGER)").unwrap();
+        scratch.run("INSERT INTO src VALUES (5, 5), (6, 0)").unwrap();
+        assert_eq!(fails(&scratch, "INSERT INTO m SELECT id, n FROM src"), "23514");
+        assert_eq!(scratch.rows("SELECT id FROM m"), ints(&[Some(1)]));
+        scratch.run("INSERT INTO m VALUES (2, 2), (3, 3)").unwrap();
+        assert_eq!(scratch.rows("SELECT id FROM m ORDER BY id"), ints(&[Some(1), Some(2), Some(3)]));
+    }
+
+    #[test]
+    fn an_insert_can_reference_a_row_later_in_the_same_statement() {
+        let scratch = Scratch::new("insert_self_reference");
+        scratch.run("CREATE TABLE node (id INTEGER PRIMARY KEY, parent INTEGER REFERENCES node)").unwrap();
+        scratch.run("INSERT INTO node VALUES (2, 1), (1, NULL)").unwrap();
+        assert_eq!(scratch.rows("SELECT id FROM node ORDER BY id"), ints(&[Some(1), Some(2)]));
+        assert_eq!(fails(&scratch, "INSERT INTO node VALUES (3, 4), (4, 5)"), "23503");
+        assert_eq!(scratch.rows("SELECT id FROM node ORDER BY id"), ints(&[Some(1), Some(2)]));
+    }
+
+    #[test]
+    fn an_upsert_that_fails_partway_writes_nothing() {
+        let scratch = Scratch::new("upsert_atomic");
+        scratch.run("CREATE TABLE m (id INTEGER PRIMARY KEY, n INTEGER CHECK (n > 0))").unwrap();
+        scratch.run("INSERT INTO m VALUES (1, 1), (2, 2)").unwrap();
+        let sql = "INSERT INTO m VALUES (1, 10), (3, 3), (2, -1) ON CONFLICT (id) DO UPDATE SET n = excluded.n";
+        assert_eq!(fails(&scratch, sql), "23514");
+        assert_eq!(scratch.rows("SELECT n FROM m ORDER BY id"), ints(&[Some(1), Some(2)]));
+        assert_eq!(fails(&scratch, "INSERT INTO m VALUES (4, 4), (5, -5) ON CONFLICT DO NOTHING"), "23514");
+        assert_eq!(scratch.rows("SELECT id FROM m ORDER BY id"), ints(&[Some(1), Some(2)]));
+    }
+
+    fn texts(values: &[&str]) -> Vec<Vec<Value>> {
+        values.iter().map(|v| vec![Value::Text(v.to_string())]).collect()
+    }
+
+    // the message of the error sql fails with
+    fn message(scratch: &Scratch, sql: &str) -> String {
+        match scratch.run(sql) {
+            Err(e) => e.message().to_string(),
+            Ok(result) => panic!("{} gave {:?} instead of failing", sql, result),
+        }
+    }
+
+    #[test]
+    fn not_null_and_default() {
+        let scratch = Scratch::new("not_null");
+        scratch.run("CREATE TABLE t (id INTEGER PRIMARY KEY, name TEXT NOT NULL, kind TEXT DEFAULT 'plain', n INTEGER DEFAULT 1 + 2)").unwrap();
+        scratch.run("INSERT INTO t (id, name) VALUES (1, 'a')").unwrap();
+        assert_eq!(scratch.rows("SELECT kind, n FROM t"), vec![vec![Value::Text("plain".to_string()), Value::Integer(3)]]);
+        assert_eq!(fails(&scratch, "INSERT INTO t (id) VALUES (2)"), "23502");
+        assert!(message(&scratch, "INSERT INTO t VALUES (2, NULL, 'x', 1)").contains("t_name_not_null"));
+        assert_eq!(fails(&scratch, "UPDATE t SET name = NULL"), "23502");
+        assert_eq!(scratch.rows("SELECT name FROM t"), texts(&["a"]));
+    }
+
+    #[test]
+    fn primary_keys_and_unique_constraints() {
+        let scratch = Scratch::new("unique");
+        scratch.run("CREATE TABLE t (a INTEGER, b INTEGER, email TEXT UNIQUE, PRIMARY KEY (a, b))").unwrap();
+        scratch.run("INSERT INTO t VALUES (1, 1, 'x'), (1, 2, 'y'), (2, 1, NULL), (2, 2, NULL)").unwrap();
+        assert_eq!(fails(&scratch, "INSERT INTO t VALUES (1, 2, 'z')"), "23505");
+        assert!(message(&scratch, "INSERT INTO t VALUES (3, 3, 'x')").contains("t_email_key"));
+        assert_eq!(fails(&scratch, "UPDATE t SET b = 1 WHERE a = 1 AND b = 2"), "23505");
+        assert_eq!(fails(&scratch, "UPDATE t SET email = 'x' WHERE a = 2"), "23505");
+        // an update that moves every key along doesnt clash with itself
+        scratch.run("UPDATE t SET b = b + 10").unwrap();
+        assert_eq!(scratch.rows("SELECT b FROM t ORDER BY a, b"), ints(&[Some(11), Some(12), Some(11), Some(12)]));
+    }
+
+    #[test]
+    fn check_constraints() {
+        let scratch = Scratch::new("check");
+        scratch.run("CREATE TABLE t (id INTEGER PRIMARY KEY, lo INTEGER CHECK (lo >= 0), hi INTEGER, CONSTRAINT ordered CHECK (lo <= hi))").unwrap();
+        scratch.run("INSERT INTO t VALUES (1, 0, 5), (2, NULL, 1)").unwrap();
+        assert_eq!(fails(&scratch, "INSERT INTO t VALUES (3, -1, 5)"), "23514");
+        assert!(message(&scratch, "INSERT INTO t VALUES (3, 6, 5)").contains("'ordered'"));
+        assert_eq!(fails(&scratch, "UPDATE t SET hi = -1 WHERE id = 1"), "23514");
+        assert_eq!(scratch.rows("SELECT hi FROM t ORDER BY id"), ints(&[Some(5), Some(1)]));
+    }
+
+    #[test]
+    fn a_child_needs_a_parent() {
+        let scratch = family("fk_child", "");
+        assert_eq!(fails(&scratch, "INSERT INTO c VALUES (3, 9, 0)"), "23503");
+        assert_eq!(fails(&scratch, "UPDATE c SET p_id = 9 WHERE id = 1"), "23503");
+        scratch.run("INSERT INTO c VALUES (3, NULL, 0)").unwrap();
+        scratch.run("UPDATE c SET p_id = 2 WHERE id = 1").unwrap();
+        assert_eq!(scratch.rows("SELECT p_id FROM c ORDER BY id"), ints(&[Some(2), Some(2), None]));
+    }
+
+    // family with the actions given for the foreign key, after sql ran on it: the p_id of each child
+    fn after(name: &str, actions: &str, sql: &str) -> Result<Vec<Vec<Value>>, &'static str> {
+        let scratch = family(name, actions);
+        match scratch.run(sql) {
+            Ok(_) => Ok(scratch.rows("SELECT p_id FROM c ORDER BY id")),
+            Err(e) => {
+                assert_eq!(scratch.rows("SELECT id FROM p ORDER BY id"), ints(&[Some(1), Some(2)]));
+                assert_eq!(scratch.rows("SELECT p_id FROM c ORDER BY id"), ints(&[Some(1), Some(2)]));
+                Err(e.code())
+            }
+        }
+    }
+
+    #[test]
+    fn referential_actions_on_delete() {
+        let delete = "DELETE FROM p WHERE id = 1";
+        assert_eq!(after("delete_no_action", "", delete), Err("23503"));
+        assert_eq!(after("delete_restrict", "ON DELETE RESTRICT", delete), Err("23503"));
+        assert_eq!(after("delete_cascade", "ON DELETE CASCADE", delete), Ok(ints(&[Some(2)])));
+        assert_eq!(after("delete_set_null", "ON DELETE SET NULL", delete), Ok(ints(&[None, Some(2)])));
+        assert_eq!(after("delete_set_default", "ON DELETE SET DEFAULT DEFAULT 2", delete), Ok(ints(&[Some(2), Some(2)])));
+        // the default has to have a parent too
+        assert_eq!(after("delete_set_missing_default", "ON DELETE SET DEFAULT DEFAULT 9", delete), Err("23503"));
+    }
+
+    #[test]
+    fn referential_actions_on_update() {
+        let update = "UPDATE p SET id = 5 WHERE id = 1";
+        assert_eq!(after("update_no_action", "", update), Err("23503"));
+        assert_eq!(after("update_restrict", "ON UPDATE RESTRICT", update), Err("23503"));
+        assert_eq!(after("update_cascade", "ON UPDATE CASCADE", update), Ok(ints(&[Some(5), Some(2)])));
+        assert_eq!(after("update_set_null", "ON UPDATE SET NULL", update), Ok(ints(&[None, Some(2)])));
+        assert_eq!(after("update_set_default", "ON UPDATE SET DEFAULT DEFAULT 2", update), Ok(ints(&[Some(2), Some(2)])));
+        // a parent that keeps its key isnt changed as far as its children go
+        assert_eq!(after("update_same_key", "ON UPDATE RESTRICT", "UPDATE p SET id = id"), Ok(ints(&[Some(1), Some(2)])));
+    }
+
+    #[test]
+    fn errors_carry_the_code_for_what_went_wrong() {
+        let scratch = Scratch::new("error_codes");
+        scratch.run("CREATE TABLE t (id INTEGER PRIMARY KEY)").unwrap();
+        scratch.run("INSERT INTO t VALUES (1)").unwrap();
+        assert_eq!(fails(&scratch, "SELECT * FROM missing"), "42P01");
+        assert_eq!(fails(&scratch, "SELECT nope FROM t"), "42703");
+        assert_eq!(fails(&scratch, "CREATE TABLE t (id INTEGER)"), "42710");
+        assert_eq!(fails(&scratch, "INSERT INTO t VALUES ('one')"), "22P02");
+        assert_eq!(fails(&scratch, "SELEC 1"), "42601");
+        assert_eq!(fails(&scratch, "EXECUTE nothing (1)"), "07001");
+    }
+}
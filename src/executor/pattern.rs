@@ -0,0 +1,130 @@
+// LIKE, ILIKE and GLOB patterns. LIKE and ILIKE use % for any run of characters and _ for one, with \
+// in front of a character matching it literally. GLOB uses * and ?, [abc] or [a-z] for one of a set of
+// characters and [^...] for one not in it. only ILIKE ignores case
+
+use crate::error::Error;
+
+enum Piece {
+    Any, // any number of characters, none included
+    One, // exactly one character
+    Char(char),
+    Class { negated: bool, ranges: Vec<(char, char)> },
+}
+
+impl Piece {
+    fn matches(&self, c: char) -> bool {
+        match self {
+            Piece::Any | Piece::One => true,
+            Piece::Char(p) => *p == c,
+            Piece::Class { negated, ranges } => ranges.iter().any(|(low, high)| (*low..=*high).contains(&c)) != *negated,
+        }
+    }
+}
+
+// whether all of text matches the pattern of operator, which is LIKE, ILIKE or GLOB
+pub fn matches(operator: &str, text: &str, pattern: &str) -> Result<bool, Error> {
+    if operator == "ILIKE" {
+        let text: String = text.chars().flat_map(char::to_lowercase).collect();
+        let pattern: String = pattern.chars().flat_map(char::to_lowercase).collect();
+        return Ok(wildcard(&text.chars().collect::<Vec<_>>(), &parse(operator, &pattern)?));
+    }
+    Ok(wildcard(&text.chars().collect::<Vec<_>>(), &parse(operator, pattern)?))
+}
+
+// the text every match has to start with, which is everything before the first wildcard. ILIKE has
+// none since any case matches
+pub fn prefix(operator: &str, pattern: &str) -> Result<String, Error> {
+    if operator == "ILIKE" {
+        return Ok(String::new());
+    }
+    Ok(parse(operator, pattern)?.iter()
+        .map_while(|piece| match piece {
+            Piece::Char(c) => Some(*c),
+            _ => None,
+        })
+        .collect())
+}
+
+fn parse(operator: &str, pattern: &str) -> Result<Vec<Piece>, Error> {
+    let chars: Vec<char> = pattern.chars().collect();
+    let mut pieces = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let piece = match (operator, chars[i]) {
+            ("GLOB", '*') | ("LIKE" | "ILIKE", '%') => Piece::Any,
+            ("GLOB", '?') | ("LIKE" | "ILIKE", '_') => Piece::One,
+            ("GLOB", '[') => {
+                let (class, end) = parse_class(&chars, i + 1).ok_or_else(|| format!("Unclosed [ in GLOB pattern '{}'", pattern))?;
+                i = end;
+                class
+            }
+            ("LIKE" | "ILIKE", '\\') => {
+                i += 1;
+                match chars.get(i) {
+                    Some(&escaped) => Piece::Char(escaped),
+                    None => return Err(Error::Execution(format!("{} pattern '{}' ends with an escape character", operator, pattern))),
+                }
+            }
+            (_, c) => Piece::Char(c),
+        };
+        // runs of % match the same as one
+        if !(matches!(piece, Piece::Any) && matches!(pieces.last(), Some(Piece::Any))) {
+            pieces.push(piece);
+        }
+        i += 1;
+    }
+    Ok(pieces)
+}
+
+// the class starting at start, just after its [, and the position of its closing ]. a ] straight
+// after [ or [^ is one of the characters, not the end
+fn parse_class(chars: &[char], start: usize) -> Option<(Piece, usize)> {
+    let negated = chars.get(start) == Some(&'^');
+    let first = if negated { start + 1 } else { start };
+    let mut ranges = Vec::new();
+    let mut i = first;
+    loop {
+        let c = *chars.get(i)?;
+        if c == ']' && i > first {
+            return Some((Piece::Class { negated, ranges }, i));
+        }
+        match (chars.get(i + 1), chars.get(i + 2)) {
+            (Some('-'), Some(&high)) if high != ']' => {
+                ranges.push((c, high));
+                i += 3;
+            }
+            _ => {
+                ranges.push((c, c));
+                i += 1;
+            }
+        }
+    }
+}
+
+// matches the pieces in order, going back to the last Any and letting it take one more character
+// whenever something after it doesnt fit. later Anys make earlier ones never need to be retried
+fn wildcard(text: &[char], pattern: &[Piece]) -> bool {
+    let (mut t, mut p) = (0, 0);
+    let mut retry: Option<(usize, usize)> = None; // the piece after the last Any and where in text it was tried
+    while t < text.len() {
+        match pattern.get(p) {
+            Some(Piece::Any) => {
+                retry = Some((p + 1, t));
+                p += 1;
+            }
+            Some(piece) if piece.matches(text[t]) => {
+                p += 1;
+                t += 1;
+            }
+            _ => match retry {
+                Some((after_any, tried)) => {
+                    p = after_any;
+                    t = tried + 1;
+                    retry = Some((after_any, t));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|piece| matches!(piece, Piece::Any))
+}
@@ -0,0 +1,1150 @@
+use super::eval::{self, Scope, Subqueries};
+use super::pattern;
+use super::window::{self, Aggregate, Window, WindowRow};
+use super::{system, Table};
+use crate::error::Error;
+use crate::parser::ast::{CommonTable, Expression, FromItem, OrderItem, SelectItem, SelectQuery, SetOperation, SetOperator};
+use crate::storage::catalog::{Catalog, ColumnSchema, IndexSchema};
+use crate::storage::storage::StorageEngine;
+use crate::storage::tree::RecordId;
+use crate::types::{DataType, Value};
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::ops::Bound;
+use std::rc::Rc;
+
+// a WITH RECURSIVE table that keeps adding rows is stopped after this many steps
+const MAX_RECURSION_STEPS: usize = 1000;
+
+// rows with named columns, what a SELECT or a table in FROM produces
+pub struct Relation {
+    pub columns: Vec<ColumnSchema>,
+    pub rows: Vec<Vec<Value>>,
+}
+
+// runs the SELECTs of one statement, subqueries included. a subquery that doesnt read the row around
+// it is only run once. one whose WHERE only ties it to the row around it with inner_column = outer
+// expression is also run once, without those conditions, and its rows grouped by the inner columns so
+// each outer row finds its rows with a hash lookup. that turns EXISTS, NOT EXISTS and IN into semi and
+// anti joins. anything else runs again for every outer row
+pub struct QueryRunner<'e> {
+    engine: RefCell<&'e mut StorageEngine>,
+    tables: RefCell<HashMap<String, Rc<Table>>>,
+    common_tables: RefCell<HashMap<String, Rc<Relation>>>, // from WITH, they hide tables of the same name
+    plans: RefCell<HashMap<*const SelectQuery, Rc<Plan>>>,
+}
+
+// the rows of a FROM item, the name a query knows it by and its columns
+type Source = (Option<String>, Vec<ColumnSchema>, Vec<Vec<Value>>);
+
+// an index, the type of the column its on and the lowest and highest values wanted from it
+type KeyRange<'a> = (&'a IndexSchema, DataType, Bound<Value>, Bound<Value>);
+
+enum Plan {
+    PerRow,
+    Grouped {
+        outer_keys: Vec<(Expression, DataType)>, // what the key columns are compared with, and their types
+        groups: HashMap<Vec<String>, Rc<Group>>,
+        empty: Rc<Group>,
+    },
+}
+
+// the rows of a subquery for one outer row
+struct Group {
+    relation: Rc<Relation>,
+    values: HashSet<String>, // the first column in stored form, for IN
+    has_null: bool,
+}
+
+impl Group {
+    fn new(relation: Relation) -> Self {
+        let mut values = HashSet::new();
+        let mut has_null = false;
+        for row in &relation.rows {
+            match row.first().and_then(Value::to_stored) {
+                Some(value) => {
+                    values.insert(value);
+                }
+                None => has_null = true,
+            }
+        }
+        Group { relation: Rc::new(relation), values, has_null }
+    }
+}
+
+impl<'e> QueryRunner<'e> {
+    pub fn new(engine: &'e mut StorageEngine) -> Self {
+        QueryRunner {
+            engine: RefCell::new(engine),
+            tables: RefCell::new(HashMap::new()),
+            common_tables: RefCell::new(HashMap::new()),
+            plans: RefCell::new(HashMap::new()),
+        }
+    }
+
+    // outer is the scope of the row a subquery is run for
+    pub fn select(&self, query: &SelectQuery, outer: Option<&Scope>) -> Result<Relation, Error> {
+        let (mut relation, keys) = if query.set_operations.is_empty() {
+            self.select_single(query, &query.order_by, outer)?
+        } else {
+            // ORDER BY after a set operation only sees the combined rows
+            let relation = self.combine(query, outer)?;
+            let mut keys = Vec::with_capacity(relation.rows.len());
+            for row in &relation.rows {
+                let result = [(None, relation.columns.as_slice(), row.as_slice())];
+                keys.push(self.order_keys(&query.order_by, &Scope::new(&result).with_subqueries(self).inside(outer), row)?);
+            }
+            (relation, keys)
+        };
+
+        if !query.order_by.is_empty() {
+            let mut order: Vec<usize> = (0..relation.rows.len()).collect();
+            order.sort_by(|&a, &b| compare_keys(&keys[a], &keys[b], &query.order_by));
+            let mut rows: Vec<Option<Vec<Value>>> = relation.rows.into_iter().map(Some).collect();
+            relation.rows = order.into_iter().filter_map(|i| rows[i].take()).collect();
+        }
+        let offset = self.count(&query.offset, "OFFSET", outer)?.unwrap_or(0);
+        let limit = self.count(&query.limit, "LIMIT", outer)?.unwrap_or(usize::MAX);
+        if offset > 0 || limit < relation.rows.len() {
+            relation.rows = relation.rows.into_iter().skip(offset).take(limit).collect();
+        }
+        Ok(relation)
+    }
+
+    // one SELECT, leaving out what comes after it. the ORDER BY keys of every row are worked out along
+    // the way as they can use columns of the FROM item that arent in the result
+    fn select_single(&self, query: &SelectQuery, order_by: &[OrderItem], outer: Option<&Scope>) -> Result<(Relation, Vec<Vec<Value>>), Error> {
+        let (name, columns, rows) = self.source(query, outer)?;
+        let mut kept = Vec::new();
+        for row in rows {
+            let source = [(name.as_deref(), columns.as_slice(), row.as_slice())];
+            let keep = match &query.where_clause {
+                Some(expr) => eval::matches(expr, &Scope::new(&source).with_subqueries(self).inside(outer))?,
+                None => true,
+            };
+            if keep {
+                kept.push(row);
+            }
+        }
+
+        // window functions need every row that passed WHERE before any of them can be projected
+        let mut calls = Vec::new();
+        for item in &query.items {
+            if let SelectItem::Expression { expr, .. } = item {
+                expr.window_calls(&mut calls);
+            }
+        }
+        for item in order_by {
+            item.expr.window_calls(&mut calls);
+        }
+        // with GROUP BY or aggregates each group turns into one row, and the aggregates are passed in
+        // for it the way window values are
+        let mut aggregates = Vec::new();
+        for item in &query.items {
+            if let SelectItem::Expression { expr, .. } = item {
+                expr.aggregate_calls(&mut aggregates);
+            }
+        }
+        for expr in query.having.iter().chain(order_by.iter().map(|item| &item.expr)) {
+            expr.aggregate_calls(&mut aggregates);
+        }
+        let (calls, window_values) = if !query.group_by.is_empty() || query.having.is_some() || !aggregates.is_empty() {
+            if !calls.is_empty() {
+                return Err(Error::Execution("Window functions cannot be used with GROUP BY or aggregates".to_string()));
+            }
+            let values = self.group_rows(query, &aggregates, name.as_deref(), &columns, &mut kept, outer)?;
+            (aggregates, values)
+        } else {
+            let values = self.window_values(&calls, name.as_deref(), &columns, &kept, outer)?;
+            (calls, values)
+        };
+
+        check_table_wildcards(&query.items, name.as_deref())?;
+        let names = output_columns(&query.items, &columns, &[]);
+        let mut projected = Vec::with_capacity(kept.len());
+        let mut keys = Vec::new();
+        let mut seen = HashSet::new();
+        for (i, row) in kept.iter().enumerate() {
+            let windows: Vec<(&Expression, Value)> = calls.iter().zip(&window_values).map(|(call, values)| (*call, values[i].clone())).collect();
+            let source = [(name.as_deref(), columns.as_slice(), row.as_slice())];
+            let scope = Scope::new(&source).with_subqueries(self).inside(outer).with_windows(&windows);
+            if let Some(having) = &query.having
+                && !eval::matches(having, &scope)?
+            {
+                continue;
+            }
+            let values = project(&query.items, &scope, row)?;
+            // DISTINCT keeps the first of each set of equal rows, and sorts it by that ones ORDER BY values
+            if query.distinct && !seen.insert(row_key(&values)) {
+                continue;
+            }
+            if !order_by.is_empty() {
+                // a name in ORDER BY means the result column before a column of the FROM item
+                let both = [(None, names.as_slice(), values.as_slice()), source[0]];
+                keys.push(self.order_keys(order_by, &Scope::new(&both).with_subqueries(self).inside(outer).with_windows(&windows), &values)?);
+            }
+            projected.push(values);
+        }
+        Ok((Relation { columns: output_columns(&query.items, &columns, &projected), rows: projected }, keys))
+    }
+
+    // the values of each window function call for each row, calls first. the rows are split into the
+    // partitions of a call first, and its ORDER BY and arguments are only worked out for one partition
+    // at a time
+    fn window_values(&self, calls: &[&Expression], name: Option<&str>, columns: &[ColumnSchema], rows: &[Vec<Value>], outer: Option<&Scope>) -> Result<Vec<Vec<Value>>, Error> {
+        let mut values = Vec::with_capacity(calls.len());
+        for call in calls {
+            let Expression::Window(call) = call else {
+                continue;
+            };
+            let window = Window::new(call)?;
+            let eval_all = |row: &[Value], exprs: &mut dyn Iterator<Item = &Expression>| {
+                let source = [(name, columns, row)];
+                let scope = Scope::new(&source).with_subqueries(self).inside(outer);
+                exprs.map(|expr| eval::eval_in(expr, &scope)).collect::<Result<Vec<_>, _>>()
+            };
+            let keys = rows.iter().map(|row| eval_all(row, &mut call.partition_by.iter())).collect::<Result<Vec<_>, _>>()?;
+            let mut results = vec![Value::Null; rows.len()];
+            for partition in window::partitions(keys) {
+                let mut inputs = Vec::with_capacity(partition.len());
+                for &row in &partition {
+                    inputs.push(WindowRow {
+                        order: eval_all(&rows[row], &mut call.order_by.iter().map(|item| &item.expr))?,
+                        args: eval_all(&rows[row], &mut call.args.iter())?,
+                    });
+                }
+                for (row, value) in partition.into_iter().zip(window.compute(&inputs)?) {
+                    results[row] = value;
+                }
+            }
+            values.push(results);
+        }
+        Ok(values)
+    }
+
+    // replaces the rows of a query with GROUP BY or aggregates by one for each group, and returns the
+    // value of each aggregate for each group, aggregates first. a group keeps its first row, so a column
+    // that isnt grouped by takes its value from that. without GROUP BY every row is in one group, even
+    // with none
+    fn group_rows(&self, query: &SelectQuery, aggregates: &[&Expression], name: Option<&str>, columns: &[ColumnSchema], rows: &mut Vec<Vec<Value>>, outer: Option<&Scope>) -> Result<Vec<Vec<Value>>, Error> {
+        let group_by = query.group_by.iter().map(|expr| group_expression(expr, &query.items, columns)).collect::<Result<Vec<_>, _>>()?;
+        let eval_all = |row: &[Value], exprs: &[&Expression]| {
+            let source = [(name, columns, row)];
+            let scope = Scope::new(&source).with_subqueries(self).inside(outer);
+            exprs.iter().map(|expr| eval::eval_in(expr, &scope)).collect::<Result<Vec<_>, _>>()
+        };
+        let mut groups: Vec<Vec<usize>> = Vec::new();
+        let mut found: HashMap<Vec<Option<String>>, usize> = HashMap::new();
+        for (i, row) in rows.iter().enumerate() {
+            let key = row_key(&eval_all(row, &group_by)?);
+            match found.get(&key) {
+                Some(&group) => groups[group].push(i),
+                None => {
+                    found.insert(key, groups.len());
+                    groups.push(vec![i]);
+                }
+            }
+        }
+
+        let mut values = Vec::with_capacity(aggregates.len());
+        for call in aggregates {
+            let Expression::Aggregate { function, args } = call else {
+                continue;
+            };
+            let args: Vec<&Expression> = args.iter().collect();
+            let mut results = Vec::with_capacity(groups.len().max(1));
+            for group in &groups {
+                let mut aggregate = Aggregate::new(function, args.is_empty());
+                for &row in group {
+                    aggregate.add(eval_all(&rows[row], &args)?.first().unwrap_or(&Value::Null))?;
+                }
+                results.push(aggregate.result());
+            }
+            if groups.is_empty() && group_by.is_empty() {
+                results.push(Aggregate::new(function, args.is_empty()).result());
+            }
+            values.push(results);
+        }
+
+        if groups.is_empty() && group_by.is_empty() {
+            *rows = vec![vec![Value::Null; columns.len()]];
+            return Ok(values);
+        }
+        let mut all: Vec<Option<Vec<Value>>> = std::mem::take(rows).into_iter().map(Some).collect();
+        *rows = groups.iter().filter_map(|group| all[group[0]].take()).collect();
+        Ok(values)
+    }
+
+    // the rows of a query with set operations, before ORDER BY and LIMIT. INTERSECT goes before UNION
+    // and EXCEPT, which go from left to right
+    fn combine(&self, query: &SelectQuery, outer: Option<&Scope>) -> Result<Relation, Error> {
+        let mut relations = vec![self.select_single(query, &[], outer)?.0];
+        for operation in &query.set_operations {
+            relations.push(self.select_single(&operation.query, &[], outer)?.0);
+        }
+        let columns = common_columns(&query.set_operations, &relations)?;
+        let mut operands = Vec::with_capacity(relations.len());
+        for relation in relations {
+            let rows = relation.rows.into_iter()
+                .map(|row| row.into_iter().zip(&columns).map(|(value, column)| value.coerce(column.data_type)).collect())
+                .collect::<Result<Vec<Vec<Value>>, Error>>()?;
+            operands.push(rows);
+        }
+
+        let mut operands = operands.into_iter();
+        let mut terms: Vec<(Option<&SetOperation>, Vec<Vec<Value>>)> = vec![(None, operands.next().unwrap_or_default())];
+        for (operation, rows) in query.set_operations.iter().zip(operands) {
+            match terms.last_mut() {
+                Some((_, left)) if operation.operator == SetOperator::Intersect => {
+                    *left = apply_set_operation(operation, std::mem::take(left), rows);
+                }
+                _ => terms.push((Some(operation), rows)),
+            }
+        }
+        let mut terms = terms.into_iter();
+        let mut rows = terms.next().map(|(_, rows)| rows).unwrap_or_default();
+        for (operation, right) in terms {
+            if let Some(operation) = operation {
+                rows = apply_set_operation(operation, rows, right);
+            }
+        }
+        Ok(Relation { columns, rows })
+    }
+
+    fn order_keys(&self, order_by: &[OrderItem], scope: &Scope, result: &[Value]) -> Result<Vec<Value>, Error> {
+        let mut keys = Vec::with_capacity(order_by.len());
+        for item in order_by {
+            keys.push(match &item.expr {
+                Expression::Literal(Value::Integer(position)) => (*position)
+                    .try_into()
+                    .ok()
+                    .and_then(|position: usize| result.get(position.checked_sub(1)?))
+                    .cloned()
+                    .ok_or_else(|| format!("ORDER BY position {} is not in the select list", position))?,
+                expr => eval::eval_in(expr, scope)?,
+            });
+        }
+        Ok(keys)
+    }
+
+    // the row count of a LIMIT or OFFSET, None when there isnt one or its NULL
+    fn count(&self, expr: &Option<Expression>, clause: &str, outer: Option<&Scope>) -> Result<Option<usize>, Error> {
+        let Some(expr) = expr else {
+            return Ok(None);
+        };
+        match eval::eval_in(expr, &Scope::new(&[]).with_subqueries(self).inside(outer))? {
+            Value::Null => Ok(None),
+            Value::Integer(n) if n >= 0 => Ok(Some(n as usize)),
+            other => Err(Error::Execution(format!("{} must be a whole number that isnt negative, not {}", clause, other))),
+        }
+    }
+
+    // work out a table named by WITH, which later tables and the query can then read. a recursive one
+    // starts with the rows of its anchor query and runs its step on the rows the last step added until
+    // it adds none. UNION leaves out rows it already has, which is what stops walks over cycles
+    pub fn add_common_table(&self, table: &CommonTable) -> Result<(), Error> {
+        let mut relation = self.select(&table.query, None)?;
+        if let Some(names) = &table.columns {
+            if names.len() != relation.columns.len() {
+                return Err(Error::Execution(format!("WITH table '{}' has {} columns but {} names were given", table.name, relation.columns.len(), names.len())));
+            }
+            for (column, name) in relation.columns.iter_mut().zip(names) {
+                column.name = name.clone();
+            }
+        }
+
+        let Some((all, step)) = &table.step else {
+            self.bind(&table.name, relation);
+            return Ok(());
+        };
+        let mut seen = HashSet::new();
+        if !all {
+            relation.rows.retain(|row| seen.insert(row_key(row)));
+        }
+        let mut added = relation.rows.clone();
+        let mut steps = 0;
+        while !added.is_empty() {
+            steps += 1;
+            if steps > MAX_RECURSION_STEPS {
+                return Err(Error::Execution(format!("WITH RECURSIVE table '{}' was still adding rows after {} steps", table.name, MAX_RECURSION_STEPS)));
+            }
+            self.bind(&table.name, Relation { columns: relation.columns.clone(), rows: added });
+            let produced = self.select(step, None)?;
+            if produced.columns.len() != relation.columns.len() {
+                return Err(Error::Execution(format!(
+                    "The recursive part of '{}' returns {} columns but the part before UNION returns {}",
+                    table.name, produced.columns.len(), relation.columns.len()
+                )));
+            }
+            added = Vec::new();
+            for row in produced.rows {
+                let row = row.into_iter().zip(&relation.columns).map(|(value, column)| value.coerce(column.data_type)).collect::<Result<Vec<_>, _>>()?;
+                if *all || seen.insert(row_key(&row)) {
+                    added.push(row);
+                }
+            }
+            relation.rows.extend(added.iter().cloned());
+        }
+        self.bind(&table.name, relation);
+        Ok(())
+    }
+
+    fn bind(&self, name: &str, relation: Relation) {
+        self.common_tables.borrow_mut().insert(name.to_string(), Rc::new(relation));
+        // subqueries run before may have read the table when it had other rows
+        self.plans.borrow_mut().clear();
+    }
+
+    fn common_table(&self, name: &str) -> Option<Rc<Relation>> {
+        self.common_tables.borrow().get(name).cloned()
+    }
+
+    // the rows of a table matching a WHERE clause along with where they are stored, for UPDATE and DELETE
+    pub fn table_rows(&self, table: &Table, where_clause: &Option<Expression>) -> Result<Vec<(RecordId, Vec<Value>)>, Error> {
+        let mut matching = Vec::new();
+        for (rid, row) in self.stored_rows(table, &table.schema.name, where_clause.as_ref())? {
+            let source = [(Some(table.schema.name.as_str()), table.schema.columns.as_slice(), row.as_slice())];
+            let keep = match where_clause {
+                Some(expr) => eval::matches(expr, &Scope::new(&source).with_subqueries(self))?,
+                None => true,
+            };
+            if keep {
+                matching.push((rid, row));
+            }
+        }
+        Ok(matching)
+    }
+
+    // the rows of the FROM item under the name the query knows it by. with no FROM theres a single empty row
+    fn source(&self, query: &SelectQuery, outer: Option<&Scope>) -> Result<Source, Error> {
+        match &query.from {
+            None => Ok((None, Vec::new(), vec![Vec::new()])),
+            Some(FromItem::Table { name, alias }) => {
+                let known_as = alias.clone().unwrap_or_else(|| name.clone());
+                if let Some(relation) = self.common_table(name) {
+                    return Ok((Some(known_as), relation.columns.clone(), relation.rows.clone()));
+                }
+                if let Some(system_table) = system::load(&mut self.engine.borrow_mut(), name)? {
+                    return Ok((Some(known_as), system_table.columns, system_table.rows));
+                }
+                let table = self.table(name)?;
+                let rows = self.stored_rows(&table, &known_as, query.where_clause.as_ref())?.into_iter().map(|(_, row)| row).collect();
+                Ok((Some(known_as), table.schema.columns.clone(), rows))
+            }
+            Some(FromItem::Subquery { query, alias }) => {
+                let relation = self.select(query, outer)?;
+                Ok((Some(alias.clone()), relation.columns, relation.rows))
+            }
+        }
+    }
+
+    // all rows of the table, or only the ones an index has under the right keys when the WHERE clause
+    // requires an indexed column to equal a literal or, failing that, to be in a range. columns are
+    // qualified by known_as, the alias of the table if it has one. the rows still have to pass the
+    // WHERE clause afterwards
+    fn stored_rows(&self, table: &Table, known_as: &str, where_clause: Option<&Expression>) -> Result<Vec<(RecordId, Vec<Value>)>, Error> {
+        let mut engine = self.engine.borrow_mut();
+        let terms = where_clause.map(conjuncts).unwrap_or_default();
+        for term in &terms {
+            let Expression::BinaryOp { left, operator, right } = term else {
+                continue;
+            };
+            if operator != "=" {
+                continue;
+            }
+            let ((Expression::Column { table: qualifier, name }, value) | (value, Expression::Column { table: qualifier, name })) = (&**left, &**right) else {
+                continue;
+            };
+            let Some(value) = literal(value) else {
+                continue;
+            };
+            if qualifier.as_ref().is_some_and(|q| q != known_as) {
+                continue;
+            }
+            // the key has to be in the form the column stores it, '7' and 7 are the same integer
+            let data_type = table.schema.columns.iter().find(|c| c.name == *name).map(|c| c.data_type);
+            let key = data_type.and_then(|t| value.coerce(t).ok()).and_then(|v| v.to_index_key());
+            if let Some((index, key)) = table.index_on(name).zip(key) {
+                return table.lookup(&mut engine, index, &key);
+            }
+        }
+        for term in &terms {
+            let Some((index, data_type, low, high)) = key_range(table, known_as, term)? else {
+                continue;
+            };
+            let (low_key, high_key) = (key_bound(&low, data_type, true), key_bound(&high, data_type, false));
+            let in_range = |key: &str| data_type.from_index_key(key).is_some_and(|value| {
+                let above = match &low {
+                    Bound::Included(low) => value.compare(low).is_some_and(|o| o != Ordering::Less),
+                    Bound::Excluded(low) => value.compare(low) == Some(Ordering::Greater),
+                    Bound::Unbounded => true,
+                };
+                let below = match &high {
+                    Bound::Included(high) => value.compare(high).is_some_and(|o| o != Ordering::Greater),
+                    Bound::Excluded(high) => value.compare(high) == Some(Ordering::Less),
+                    Bound::Unbounded => true,
+                };
+                above && below
+            });
+            return table.lookup_range(&mut engine, index, low_key.as_ref(), high_key.as_ref(), in_range);
+        }
+        table.scan(&mut engine)
+    }
+
+    fn table(&self, name: &str) -> Result<Rc<Table>, Error> {
+        if let Some(table) = self.tables.borrow().get(name) {
+            return Ok(table.clone());
+        }
+        let table = Rc::new(Table::load(&mut self.engine.borrow_mut(), name)?);
+        self.tables.borrow_mut().insert(name.to_string(), table.clone());
+        Ok(table)
+    }
+
+    // the rows of a subquery for the outer row in scope
+    fn group(&self, query: &SelectQuery, outer: &Scope) -> Result<Rc<Group>, Error> {
+        let Plan::Grouped { outer_keys, groups, empty } = &*self.plan(query)? else {
+            return Ok(Rc::new(Group::new(self.select(query, Some(outer))?)));
+        };
+        let mut key = Vec::with_capacity(outer_keys.len());
+        for (expr, data_type) in outer_keys {
+            let value = eval::eval_in(expr, outer)?;
+            if value.is_null() {
+                return Ok(empty.clone());
+            }
+            // comparing different types can find values equal that arent the same text, like '1.0' and 1
+            if !hashes_as(&value, *data_type) {
+                return Ok(Rc::new(Group::new(self.select(query, Some(outer))?)));
+            }
+            key.push(value.coerce(*data_type)?.to_stored().unwrap_or_default());
+        }
+        Ok(groups.get(&key).cloned().unwrap_or_else(|| empty.clone()))
+    }
+
+    fn plan(&self, query: &SelectQuery) -> Result<Rc<Plan>, Error> {
+        let id = query as *const SelectQuery;
+        if let Some(plan) = self.plans.borrow().get(&id) {
+            return Ok(plan.clone());
+        }
+        let plan = Rc::new(self.build_plan(query)?);
+        self.plans.borrow_mut().insert(id, plan.clone());
+        Ok(plan)
+    }
+
+    fn build_plan(&self, query: &SelectQuery) -> Result<Plan, Error> {
+        // LIMIT, ORDER BY, window functions and aggregates work on all the rows a subquery has for an
+        // outer row, so a correlated one cant be run once and split up afterwards
+        if !query.is_simple() || query.has_windows() || query.is_grouped() {
+            if !self.independent(query)? {
+                return Ok(Plan::PerRow);
+            }
+            let relation = self.select(query, None)?;
+            let empty = Rc::new(Group::new(Relation { columns: relation.columns.clone(), rows: Vec::new() }));
+            let groups = HashMap::from([(Vec::new(), Rc::new(Group::new(relation)))]);
+            return Ok(Plan::Grouped { outer_keys: Vec::new(), groups, empty });
+        }
+        let Some(names) = self.source_names(&query.from)? else {
+            return Ok(Plan::PerRow);
+        };
+        let inner = Inner { name: from_name(&query.from), columns: &names };
+
+        // the WHERE is split into conditions on the subquerys own rows and inner = outer equalities
+        let mut filters = Vec::new();
+        let mut keys: Vec<(&str, &Expression)> = Vec::new();
+        for term in query.where_clause.as_ref().map(conjuncts).unwrap_or_default() {
+            if self.only_inner(term, &inner)? {
+                filters.push(term);
+                continue;
+            }
+            let key = match term {
+                Expression::BinaryOp { left, operator, right } if operator == "=" => match (&**left, &**right) {
+                    (Expression::Column { table, name }, other) | (other, Expression::Column { table, name })
+                        if inner.has(table, name) && only_outer(other, &inner) => Some((name.as_str(), other)),
+                    _ => None,
+                },
+                _ => None,
+            };
+            match key {
+                Some(key) => keys.push(key),
+                None => return Ok(Plan::PerRow),
+            }
+        }
+        for item in &query.items {
+            if let SelectItem::Expression { expr, .. } = item
+                && !self.only_inner(expr, &inner)?
+            {
+                return Ok(Plan::PerRow);
+            }
+        }
+
+        let (name, columns, rows) = self.source(query, None)?;
+        let key_columns: Vec<usize> = keys.iter()
+            .map(|(column, _)| columns.iter().position(|c| c.name == *column).ok_or_else(|| Error::UndefinedColumn(format!("Column '{}' not found", column))))
+            .collect::<Result<_, _>>()?;
+
+        let mut grouped: HashMap<Vec<String>, Vec<Vec<Value>>> = HashMap::new();
+        let mut projected_rows = Vec::new();
+        'rows: for row in &rows {
+            let source = [(name.as_deref(), columns.as_slice(), row.as_slice())];
+            let scope = Scope::new(&source).with_subqueries(self);
+            for filter in &filters {
+                if !eval::matches(filter, &scope)? {
+                    continue 'rows;
+                }
+            }
+            // a NULL key column equals nothing
+            let mut key = Vec::with_capacity(key_columns.len());
+            for &idx in &key_columns {
+                match row[idx].to_stored() {
+                    Some(value) => key.push(value),
+                    None => continue 'rows,
+                }
+            }
+            let projected = project(&query.items, &scope, row)?;
+            projected_rows.push(projected.clone());
+            grouped.entry(key).or_default().push(projected);
+        }
+
+        let output = output_columns(&query.items, &columns, &projected_rows);
+        let groups = grouped.into_iter()
+            .map(|(key, rows)| (key, Rc::new(Group::new(Relation { columns: output.clone(), rows }))))
+            .collect();
+        let outer_keys = keys.iter().zip(&key_columns).map(|((_, expr), &idx)| ((*expr).clone(), columns[idx].data_type)).collect();
+        let empty = Rc::new(Group::new(Relation { columns: output, rows: Vec::new() }));
+        Ok(Plan::Grouped { outer_keys, groups, empty })
+    }
+
+    // the column names of a FROM item, None for a derived table that depends on the row around it
+    fn source_names(&self, from: &Option<FromItem>) -> Result<Option<Vec<String>>, Error> {
+        let columns = match from {
+            None => Vec::new(),
+            Some(FromItem::Table { name, .. }) => {
+                if let Some(relation) = self.common_table(name) {
+                    relation.columns.clone()
+                } else if let Some(system_table) = system::load(&mut self.engine.borrow_mut(), name)? {
+                    system_table.columns
+                } else {
+                    Catalog::get_table(&mut self.engine.borrow_mut(), name)
+                        .map_err(|e| Error::storage("Failed to read catalog", e))?
+                        .ok_or_else(|| Error::UndefinedTable(format!("Table '{}' not found", name)))?
+                        .columns
+                }
+            }
+            Some(FromItem::Subquery { query, .. }) => {
+                if !self.independent(query)? {
+                    return Ok(None);
+                }
+                return self.output_names(query);
+            }
+        };
+        Ok(Some(columns.into_iter().map(|c| c.name).collect()))
+    }
+
+    // the names of the columns a query returns, which a combined query takes from its first SELECT
+    fn output_names(&self, query: &SelectQuery) -> Result<Option<Vec<String>>, Error> {
+        let Some(source) = self.source_names(&query.from)? else {
+            return Ok(None);
+        };
+        let mut names = Vec::new();
+        for item in &query.items {
+            match item {
+                SelectItem::Wildcard | SelectItem::TableWildcard(_) => names.extend(source.iter().cloned()),
+                SelectItem::Expression { expr, alias } => names.push(alias.clone().unwrap_or_else(|| column_name(expr))),
+            }
+        }
+        Ok(Some(names))
+    }
+
+    // whether a query reads nothing from the rows of queries around it
+    fn independent(&self, query: &SelectQuery) -> Result<bool, Error> {
+        if !self.single_independent(query)? {
+            return Ok(false);
+        }
+        for operation in &query.set_operations {
+            if !self.single_independent(&operation.query)? {
+                return Ok(false);
+            }
+        }
+        // ORDER BY can use the columns of the result and those of the first SELECT
+        let (Some(mut names), Some(output)) = (self.source_names(&query.from)?, self.output_names(query)?) else {
+            return Ok(false);
+        };
+        names.extend(output);
+        let inner = Inner { name: from_name(&query.from), columns: &names };
+        for item in &query.order_by {
+            if !self.only_inner(&item.expr, &inner)? {
+                return Ok(false);
+            }
+        }
+        let constant = Inner { name: None, columns: &[] };
+        for expr in query.limit.iter().chain(&query.offset) {
+            if !self.only_inner(expr, &constant)? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    // the same for one SELECT, leaving out what comes after it
+    fn single_independent(&self, query: &SelectQuery) -> Result<bool, Error> {
+        let Some(names) = self.source_names(&query.from)? else {
+            return Ok(false);
+        };
+        let inner = Inner { name: from_name(&query.from), columns: &names };
+        for item in &query.items {
+            if let SelectItem::Expression { expr, .. } = item
+                && !self.only_inner(expr, &inner)?
+            {
+                return Ok(false);
+            }
+        }
+        for expr in query.where_clause.iter().chain(&query.group_by).chain(&query.having) {
+            if !self.only_inner(expr, &inner)? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    // whether every column expr reads belongs to the subquerys own rows
+    fn only_inner(&self, expr: &Expression, inner: &Inner) -> Result<bool, Error> {
+        Ok(match expr {
+            Expression::Column { table, name } => inner.has(table, name),
+            Expression::Literal(_) | Expression::Parameter(_) => true,
+            Expression::BinaryOp { left, right, .. } => self.only_inner(left, inner)? && self.only_inner(right, inner)?,
+            Expression::UnaryOp { operand, .. } | Expression::IsNull { operand, .. } => self.only_inner(operand, inner)?,
+            Expression::Subquery(query) | Expression::Exists(query) => self.independent(query)?,
+            Expression::InSubquery { operand, query } => self.only_inner(operand, inner)? && self.independent(query)?,
+            Expression::Window(call) => {
+                for expr in call.expressions() {
+                    if !self.only_inner(expr, inner)? {
+                        return Ok(false);
+                    }
+                }
+                true
+            }
+            Expression::Function { .. } | Expression::Aggregate { .. } | Expression::Cast { .. } | Expression::Case { .. } | Expression::InList { .. } | Expression::Between { .. } => {
+                for arg in expr.arguments() {
+                    if !self.only_inner(arg, inner)? {
+                        return Ok(false);
+                    }
+                }
+                true
+            }
+        })
+    }
+}
+
+impl Subqueries for QueryRunner<'_> {
+    fn rows(&self, query: &SelectQuery, outer: &Scope) -> Result<Rc<Relation>, Error> {
+        Ok(self.group(query, outer)?.relation.clone())
+    }
+
+    fn contains(&self, query: &SelectQuery, value: &Value, outer: &Scope) -> Result<Option<bool>, Error> {
+        let group = self.group(query, outer)?;
+        let relation = &group.relation;
+        if relation.columns.len() != 1 {
+            return Err(Error::Execution(format!("A subquery after IN must return one column, not {}", relation.columns.len())));
+        }
+        if relation.rows.is_empty() {
+            return Ok(Some(false));
+        }
+        if value.is_null() {
+            return Ok(None);
+        }
+        let data_type = relation.columns[0].data_type;
+        let found = if hashes_as(value, data_type) {
+            value.clone().coerce(data_type)?.to_stored().is_some_and(|key| group.values.contains(&key))
+        } else {
+            relation.rows.iter().any(|row| value.compare(&row[0]) == Some(Ordering::Equal))
+        };
+        Ok(if found { Some(true) } else if group.has_null { None } else { Some(false) })
+    }
+}
+
+// the name and columns of a subquerys FROM item
+struct Inner<'a> {
+    name: Option<&'a str>,
+    columns: &'a [String],
+}
+
+impl Inner<'_> {
+    fn has(&self, table: &Option<String>, column: &str) -> bool {
+        match table {
+            // t.col belongs to the nearest query that calls its FROM item t
+            Some(table) => self.name == Some(table.as_str()),
+            None => self.columns.iter().any(|c| c == column),
+        }
+    }
+}
+
+// whether expr reads only the row around the subquery, which makes it usable as a hash key
+fn only_outer(expr: &Expression, inner: &Inner) -> bool {
+    match expr {
+        Expression::Column { table, name } => !inner.has(table, name),
+        Expression::Literal(_) | Expression::Parameter(_) => true,
+        Expression::BinaryOp { left, right, .. } => only_outer(left, inner) && only_outer(right, inner),
+        Expression::UnaryOp { operand, .. } | Expression::IsNull { operand, .. } => only_outer(operand, inner),
+        Expression::Function { .. } | Expression::Cast { .. } | Expression::Case { .. } | Expression::InList { .. } | Expression::Between { .. } => expr.arguments().iter().all(|arg| only_outer(arg, inner)),
+        Expression::Subquery(_) | Expression::Exists(_) | Expression::InSubquery { .. } | Expression::Window(_) | Expression::Aggregate { .. } => false,
+    }
+}
+
+fn from_name(from: &Option<FromItem>) -> Option<&str> {
+    match from {
+        Some(FromItem::Table { alias: Some(alias), .. } | FromItem::Subquery { alias, .. }) => Some(alias),
+        Some(FromItem::Table { name, alias: None }) => Some(name),
+        None => None,
+    }
+}
+
+// the index on a column of the table and the range a WHERE conjunct keeps it in, for "col BETWEEN a
+// AND b", "col < a" and the like with literals, and "col LIKE 'abc%'" or "col GLOB 'abc*'" on a text
+// column
+fn key_range<'a>(table: &'a Table, known_as: &str, term: &Expression) -> Result<Option<KeyRange<'a>>, Error> {
+    let (column, low, high) = match term {
+        Expression::Between { operand, low, high } => match (literal(low), literal(high)) {
+            (Some(low), Some(high)) => (&**operand, Bound::Included(low), Bound::Included(high)),
+            _ => return Ok(None),
+        },
+        Expression::BinaryOp { left, operator, right } => {
+            // with the literal first the comparison is read the other way round
+            let (column, operator, value) = match ((&**left, literal(right)), (literal(left), &**right)) {
+                ((column @ Expression::Column { .. }, Some(value)), _) => (column, operator.as_str(), value),
+                (_, (Some(value), column @ Expression::Column { .. })) => {
+                    let flipped = match operator.as_str() {
+                        "<" => ">",
+                        "<=" => ">=",
+                        ">" => "<",
+                        ">=" => "<=",
+                        _ => return Ok(None),
+                    };
+                    (column, flipped, value)
+                }
+                _ => return Ok(None),
+            };
+            match operator {
+                "<" => (column, Bound::Unbounded, Bound::Excluded(value)),
+                "<=" => (column, Bound::Unbounded, Bound::Included(value)),
+                ">" => (column, Bound::Excluded(value), Bound::Unbounded),
+                ">=" => (column, Bound::Included(value), Bound::Unbounded),
+                "LIKE" | "GLOB" => {
+                    let Value::Text(text) = value else {
+                        return Ok(None);
+                    };
+                    return prefix_range(table, known_as, column, &pattern::prefix(operator, &text)?);
+                }
+                _ => return Ok(None),
+            }
+        }
+        _ => return Ok(None),
+    };
+    if [&low, &high].iter().any(|bound| matches!(bound, Bound::Included(Value::Null) | Bound::Excluded(Value::Null))) {
+        return Ok(None);
+    }
+    let Some((index, data_type)) = indexed_column(table, known_as, column) else {
+        return Ok(None);
+    };
+    Ok(Some((index, data_type, low, high)))
+}
+
+// the value of a literal, or of a minus in front of a number as thats how negative numbers are parsed
+fn literal(expr: &Expression) -> Option<Value> {
+    match expr {
+        Expression::Literal(value) => Some(value.clone()),
+        Expression::UnaryOp { operator, operand } if operator == "-" => match &**operand {
+            Expression::Literal(Value::Integer(i)) => i.checked_neg().map(Value::Integer),
+            Expression::Literal(Value::Real(r)) => Some(Value::Real(-r)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+// text starting with prefix sorts from prefix up to, not including, prefix with its last character
+// moved on by one
+fn prefix_range<'a>(table: &'a Table, known_as: &str, column: &Expression, prefix: &str) -> Result<Option<KeyRange<'a>>, Error> {
+    let Some((index, DataType::Text)) = indexed_column(table, known_as, column) else {
+        return Ok(None);
+    };
+    if prefix.is_empty() {
+        return Ok(None);
+    }
+    let mut end: Vec<char> = prefix.chars().collect();
+    let high = loop {
+        let Some(last) = end.pop() else {
+            break Bound::Unbounded;
+        };
+        if let Some(next) = (last as u32 + 1..=char::MAX as u32).find_map(char::from_u32) {
+            end.push(next);
+            break Bound::Excluded(Value::Text(end.into_iter().collect()));
+        }
+    };
+    Ok(Some((index, DataType::Text, Bound::Included(Value::Text(prefix.to_string())), high)))
+}
+
+// a bound on the values of a column as a bound on its index keys. one that isnt exactly a value of
+// the columns type is widened to the nearest one that is, or left open: text sorts by its characters
+// but compares with a number as one, and an integer column between two whole numbers starts at the
+// one above a low bound and ends at the one below a high one
+fn key_bound(bound: &Bound<Value>, data_type: DataType, low: bool) -> Bound<String> {
+    let (value, included) = match bound {
+        Bound::Included(value) => (value, true),
+        Bound::Excluded(value) => (value, false),
+        Bound::Unbounded => return Bound::Unbounded,
+    };
+    if data_type == DataType::Text && !matches!(value, Value::Text(_)) {
+        return Bound::Unbounded;
+    }
+    let exact = value.clone().coerce(data_type).ok().filter(|converted| converted.compare(value) == Some(Ordering::Equal));
+    match (exact.and_then(|exact| exact.to_index_key()), value) {
+        (Some(key), _) if included => Bound::Included(key),
+        (Some(key), _) => Bound::Excluded(key),
+        (None, Value::Real(r)) if data_type == DataType::Integer => {
+            let whole = if low { r.ceil() } else { r.floor() };
+            match Value::Real(whole).coerce(DataType::Integer).ok().and_then(|whole| whole.to_index_key()) {
+                Some(key) => Bound::Included(key),
+                None => Bound::Unbounded,
+            }
+        }
+        _ => Bound::Unbounded,
+    }
+}
+
+// the index on a column of the table and the columns type, when the column is unqualified or qualified
+// by the name the query knows the table by
+fn indexed_column<'a>(table: &'a Table, known_as: &str, column: &Expression) -> Option<(&'a IndexSchema, DataType)> {
+    let Expression::Column { table: qualifier, name } = column else {
+        return None;
+    };
+    if qualifier.as_ref().is_some_and(|q| q != known_as) {
+        return None;
+    }
+    let data_type = table.schema.columns.iter().find(|c| c.name == *name)?.data_type;
+    Some((table.index_on(name)?, data_type))
+}
+
+// the parts of a condition joined by AND
+fn conjuncts(expr: &Expression) -> Vec<&Expression> {
+    match expr {
+        Expression::BinaryOp { left, operator, right } if operator == "AND" => {
+            let mut terms = conjuncts(left);
+            terms.extend(conjuncts(right));
+            terms
+        }
+        other => vec![other],
+    }
+}
+
+// the columns of a combined query, named after the first SELECT. numbers of both kinds combine as REAL.
+// a column thats only NULL in a SELECT takes the type of the others
+fn common_columns(operations: &[SetOperation], relations: &[Relation]) -> Result<Vec<ColumnSchema>, Error> {
+    let mut columns = relations[0].columns.clone();
+    for (operation, relation) in operations.iter().zip(&relations[1..]) {
+        if relation.columns.len() != columns.len() {
+            return Err(Error::Execution(format!(
+                "Each SELECT of a {} must return the same number of columns, one returns {} and another {}",
+                operation.operator, columns.len(), relation.columns.len()
+            )));
+        }
+    }
+    for (i, column) in columns.iter_mut().enumerate() {
+        let mut common: Option<DataType> = None;
+        for (position, relation) in relations.iter().enumerate() {
+            if relation.rows.iter().all(|row| row[i].is_null()) {
+                continue;
+            }
+            let data_type = relation.columns[i].data_type;
+            common = match common {
+                None => Some(data_type),
+                Some(seen) if seen == data_type => Some(seen),
+                Some(DataType::Integer | DataType::Real) if matches!(data_type, DataType::Integer | DataType::Real) => Some(DataType::Real),
+                Some(seen) => {
+                    return Err(Error::Execution(format!(
+                        "{} cannot combine {} and {} in column {}",
+                        operations[position - 1].operator, seen, data_type, column.name
+                    )));
+                }
+            };
+        }
+        if let Some(data_type) = common {
+            column.data_type = data_type;
+        }
+    }
+    Ok(columns)
+}
+
+// UNION, INTERSECT and EXCEPT on rows that already have the same column types. without ALL the result
+// has no duplicates, with it INTERSECT and EXCEPT match rows up one for one
+fn apply_set_operation(operation: &SetOperation, left: Vec<Vec<Value>>, right: Vec<Vec<Value>>) -> Vec<Vec<Value>> {
+    if operation.operator == SetOperator::Union {
+        let rows = left.into_iter().chain(right);
+        if operation.all {
+            return rows.collect();
+        }
+        let mut seen = HashSet::new();
+        return rows.filter(|row| seen.insert(row_key(row))).collect();
+    }
+
+    let mut counts: HashMap<Vec<Option<String>>, usize> = HashMap::new();
+    for row in &right {
+        *counts.entry(row_key(row)).or_default() += 1;
+    }
+    let mut seen = HashSet::new();
+    let mut rows = Vec::new();
+    for row in left {
+        let key = row_key(&row);
+        let in_right = match counts.get_mut(&key) {
+            Some(count) if *count > 0 => {
+                if operation.all {
+                    *count -= 1;
+                }
+                true
+            }
+            _ => false,
+        };
+        let keep = in_right == (operation.operator == SetOperator::Intersect);
+        if keep && (operation.all || seen.insert(key)) {
+            rows.push(row);
+        }
+    }
+    rows
+}
+
+// ORDER BY, NULLs go last unless NULLS FIRST was asked for
+pub fn compare_keys(left: &[Value], right: &[Value], order_by: &[OrderItem]) -> Ordering {
+    for ((l, r), item) in left.iter().zip(right).zip(order_by) {
+        let ordering = match (l.is_null(), r.is_null()) {
+            (true, true) => Ordering::Equal,
+            (true, false) => if item.nulls_first { Ordering::Less } else { Ordering::Greater },
+            (false, true) => if item.nulls_first { Ordering::Greater } else { Ordering::Less },
+            (false, false) => {
+                let ordering = l.compare(r).unwrap_or(Ordering::Equal);
+                if item.descending { ordering.reverse() } else { ordering }
+            }
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+    Ordering::Equal
+}
+
+// what a GROUP BY item groups by. a number picks an item of the select list like in ORDER BY, and so
+// does a name that isnt a column of the FROM item but is the alias of an item
+fn group_expression<'a>(expr: &'a Expression, items: &'a [SelectItem], columns: &[ColumnSchema]) -> Result<&'a Expression, Error> {
+    let item = match expr {
+        Expression::Literal(Value::Integer(position)) => {
+            let item = usize::try_from(*position).ok().and_then(|position| items.get(position.checked_sub(1)?));
+            match item {
+                Some(SelectItem::Expression { expr, .. }) => expr,
+                _ => return Err(Error::Execution(format!("GROUP BY position {} is not an expression in the select list", position))),
+            }
+        }
+        Expression::Column { table: None, name } if !columns.iter().any(|column| column.name == *name) => {
+            let aliased = items.iter().find_map(|item| match item {
+                SelectItem::Expression { expr, alias: Some(alias) } if alias == name => Some(expr),
+                _ => None,
+            });
+            aliased.unwrap_or(expr)
+        }
+        _ => expr,
+    };
+    if item.contains_aggregate() || item.contains_window() {
+        return Err(Error::Execution("GROUP BY cannot use aggregates or window functions".to_string()));
+    }
+    Ok(item)
+}
+
+// a row in a form that can be hashed, for finding rows a UNION already has
+fn row_key(row: &[Value]) -> Vec<Option<String>> {
+    row.iter().map(Value::to_stored).collect()
+}
+
+// values of a type that compare equal exactly when their stored text is equal once converted to it
+fn hashes_as(value: &Value, data_type: DataType) -> bool {
+    matches!(
+        (value, data_type),
+        (Value::Integer(_), DataType::Integer | DataType::Real)
+            | (Value::Real(_), DataType::Real)
+            | (Value::Text(_), DataType::Text)
+            | (Value::Boolean(_), DataType::Boolean)
+    )
+}
+
+// t.* has to name the table the rows come from, there is only ever one
+pub fn check_table_wildcards(items: &[SelectItem], from: Option<&str>) -> Result<(), Error> {
+    for item in items {
+        if let SelectItem::TableWildcard(table) = item
+            && from != Some(table.as_str())
+        {
+            return Err(Error::UndefinedTable(format!("There is no table '{}' to take {}.* from", table, table)));
+        }
+    }
+    Ok(())
+}
+
+pub fn project(items: &[SelectItem], scope: &Scope, row: &[Value]) -> Result<Vec<Value>, Error> {
+    let mut values = Vec::with_capacity(items.len());
+    for item in items {
+        match item {
+            SelectItem::Wildcard | SelectItem::TableWildcard(_) => values.extend_from_slice(row),
+            SelectItem::Expression { expr, .. } => values.push(eval::eval_in(expr, scope)?),
+        }
+    }
+    Ok(values)
+}
+
+// names and types of the columns a select list produces. a column keeps its type, anything else
+// takes the type of the first value it has that isnt NULL
+pub fn output_columns(items: &[SelectItem], source: &[ColumnSchema], rows: &[Vec<Value>]) -> Vec<ColumnSchema> {
+    let mut columns = Vec::new();
+    for item in items {
+        match item {
+            SelectItem::Wildcard | SelectItem::TableWildcard(_) => columns.extend(source.iter().cloned()),
+            SelectItem::Expression { expr, alias } => {
+                let position = columns.len();
+                let data_type = match expr {
+                    Expression::Column { name, .. } => source.iter().find(|c| c.name == *name).map(|c| c.data_type),
+                    _ => None,
+                };
+                let data_type = data_type
+                    .or_else(|| rows.iter().find_map(|row| value_type(&row[position])))
+                    .unwrap_or(DataType::Text);
+                columns.push(ColumnSchema {
+                    id: position as u16,
+                    name: alias.clone().unwrap_or_else(|| column_name(expr)),
+                    data_type,
+                    not_null: false,
+                    default: None,
+                    missing: None,
+                });
+            }
+        }
+    }
+    columns
+}
+
+// what a select list entry is called in the result
+fn column_name(expr: &Expression) -> String {
+    match expr {
+        Expression::Column { name, .. } => name.clone(),
+        Expression::Exists(_) => "exists".to_string(),
+        Expression::Window(call) => call.function.to_lowercase(),
+        Expression::Aggregate { function, .. } => function.to_lowercase(),
+        Expression::Function { name, .. } => name.to_lowercase(),
+        Expression::Cast { operand, .. } => column_name(operand),
+        Expression::Case { .. } => "case".to_string(),
+        _ => "?column?".to_string(),
+    }
+}
+
+fn value_type(value: &Value) -> Option<DataType> {
+    match value {
+        Value::Null => None,
+        Value::Integer(_) => Some(DataType::Integer),
+        Value::Real(_) => Some(DataType::Real),
+        Value::Text(_) => Some(DataType::Text),
+        Value::Boolean(_) => Some(DataType::Boolean),
+    }
+}
@@ -0,0 +1,81 @@
+use crate::error::Error;
+use crate::parser::ast::{PrepareQuery, Query};
+use crate::types::{DataType, Value};
+use std::collections::HashMap;
+
+// what one client connection keeps between its statements. prepared statements are parsed once and
+// get their values put in on every EXECUTE, so values never go through the parser. bound holds the
+// statements the protocol has given values to and not yet run
+#[derive(Default)]
+pub struct Session {
+    prepared: HashMap<String, Prepared>,
+    bound: HashMap<String, Query>,
+}
+
+struct Prepared {
+    statement: Query,
+    types: Vec<DataType>, // the declared types of the first parameters, the rest take any value
+    parameters: usize,
+}
+
+impl Session {
+    // the statement prepared over the protocol without a name is replaced by the next one, any other
+    // name has to be deallocated before it can be used again
+    pub fn prepare(&mut self, prepare: PrepareQuery) -> Result<String, Error> {
+        if !prepare.statement.can_prepare() {
+            return Err(Error::Execution("Only SELECT, INSERT, UPDATE, DELETE and WITH statements can be prepared".to_string()));
+        }
+        if !prepare.name.is_empty() && self.prepared.contains_key(&prepare.name) {
+            return Err(Error::Duplicate(format!("Prepared statement '{}' already exists", prepare.name)));
+        }
+        self.bound.remove(&prepare.name);
+        self.prepared.insert(prepare.name.clone(), Prepared { statement: *prepare.statement, types: prepare.types, parameters: prepare.parameters });
+        Ok(format!("Statement '{}' prepared with {} parameter{}", prepare.name, prepare.parameters, if prepare.parameters == 1 { "" } else { "s" }))
+    }
+
+    // the prepared statement with values in place of its parameters, ready to run
+    pub fn bind(&self, name: &str, values: Vec<Value>) -> Result<Query, Error> {
+        let prepared = self.prepared.get(name).ok_or_else(|| Error::Bind(format!("Prepared statement '{}' does not exist", name)))?;
+        if values.len() != prepared.parameters {
+            return Err(Error::Bind(format!("Prepared statement '{}' takes {} parameter{}, not {}", name, prepared.parameters, if prepared.parameters == 1 { "" } else { "s" }, values.len())));
+        }
+        let values = values.into_iter().enumerate()
+            .map(|(i, value)| match prepared.types.get(i) {
+                Some(data_type) => value.coerce(*data_type).map_err(|e| e.context(&format!("Parameter ${}", i + 1))),
+                None => Ok(value),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut statement = prepared.statement.clone();
+        statement.bind(&values)?;
+        Ok(statement)
+    }
+
+    // binds the statement and keeps it until the protocol asks for it to be run
+    pub fn keep_bound(&mut self, name: &str, values: Vec<Value>) -> Result<(), Error> {
+        let statement = self.bind(name, values)?;
+        self.bound.insert(name.to_string(), statement);
+        Ok(())
+    }
+
+    // a bound statement can be run any number of times with the same values
+    pub fn bound(&self, name: &str) -> Result<Query, Error> {
+        self.bound.get(name).cloned().ok_or_else(|| Error::Bind(format!("Prepared statement '{}' has not been given values", name)))
+    }
+
+    // None deallocates every statement
+    pub fn deallocate(&mut self, name: Option<String>) -> Result<String, Error> {
+        match name {
+            Some(name) => {
+                self.prepared.remove(&name).ok_or_else(|| Error::Bind(format!("Prepared statement '{}' does not exist", name)))?;
+                self.bound.remove(&name);
+                Ok(format!("Statement '{}' deallocated", name))
+            }
+            None => {
+                let count = self.prepared.len();
+                self.prepared.clear();
+                self.bound.clear();
+                Ok(format!("{} statement{} deallocated", count, if count == 1 { "" } else { "s" }))
+            }
+        }
+    }
+}
@@ -0,0 +1,196 @@
+use crate::error::Error;
+use crate::storage::catalog::{Catalog, ColumnSchema};
+use crate::storage::page::PAGE_SIZE;
+use crate::storage::storage::StorageEngine;
+use crate::storage::tree::BTree;
+use crate::types::{DataType, Value};
+
+// read only tables describing the database itself. they arent stored anywhere, their rows are built
+// from the catalog and the storage file each time theyre selected from
+pub const SYSTEM_TABLES: [&str; 6] = ["tony_tables", "tony_columns", "tony_indexes", "tony_constraints", "tony_stats", "tony_database"];
+
+pub fn is_system_table(name: &str) -> bool {
+    SYSTEM_TABLES.contains(&name)
+}
+
+pub struct SystemTable {
+    pub columns: Vec<ColumnSchema>,
+    pub rows: Vec<Vec<Value>>,
+}
+
+// the columns and current rows of a system table, None if name isnt one
+pub fn load(engine: &mut StorageEngine, name: &str) -> Result<Option<SystemTable>, Error> {
+    let columns = match name {
+        "tony_tables" => columns(&[
+            ("table_name", DataType::Text),
+            ("heap_page", DataType::Integer),
+            ("column_count", DataType::Integer),
+            ("index_count", DataType::Integer),
+        ]),
+        "tony_columns" => columns(&[
+            ("table_name", DataType::Text),
+            ("column_name", DataType::Text),
+            ("position", DataType::Integer),
+            ("column_id", DataType::Integer),
+            ("data_type", DataType::Text),
+            ("not_null", DataType::Boolean),
+            ("default_value", DataType::Text),
+        ]),
+        "tony_indexes" => columns(&[
+            ("index_name", DataType::Text),
+            ("table_name", DataType::Text),
+            ("columns", DataType::Text),
+            ("root_page", DataType::Integer),
+            ("is_unique", DataType::Boolean),
+            ("is_primary", DataType::Boolean),
+        ]),
+        "tony_constraints" => columns(&[
+            ("constraint_name", DataType::Text),
+            ("table_name", DataType::Text),
+            ("constraint_type", DataType::Text),
+            ("columns", DataType::Text),
+            ("definition", DataType::Text),
+        ]),
+        "tony_stats" => columns(&[
+            ("table_name", DataType::Text),
+            ("row_count", DataType::Integer),
+            ("heap_pages", DataType::Integer),
+            ("dead_slots", DataType::Integer),
+            ("free_bytes", DataType::Integer),
+            ("index_pages", DataType::Integer),
+        ]),
+        "tony_database" => columns(&[
+            ("schema_version", DataType::Integer),
+            ("page_count", DataType::Integer),
+        ]),
+        _ => return Ok(None),
+    };
+
+    // one row about the whole file rather than one per table
+    if name == "tony_database" {
+        let schema_version = engine.schema_version().map_err(|e| Error::storage("Failed to read the file header", e))?;
+        let pages = engine.file_len().map_err(|e| Error::storage("Failed to read the file size", e))? / PAGE_SIZE as u64;
+        return Ok(Some(SystemTable { columns, rows: vec![vec![int(schema_version), int(pages)]] }));
+    }
+
+    let tables = Catalog::list_tables(engine).map_err(|e| Error::storage("Failed to read catalog", e))?;
+    let mut rows = Vec::new();
+    for table in &tables {
+        match name {
+            "tony_tables" => rows.push(vec![
+                text(&table.name),
+                int(table.heap_page_id),
+                int(table.columns.len()),
+                int(table.indexes.len()),
+            ]),
+            "tony_columns" => {
+                for (position, column) in table.columns.iter().enumerate() {
+                    rows.push(vec![
+                        text(&table.name),
+                        text(&column.name),
+                        int(position + 1),
+                        int(column.id),
+                        text(&column.data_type.to_string()),
+                        Value::Boolean(column.not_null),
+                        column.default.as_deref().map_or(Value::Null, text),
+                    ]);
+                }
+            }
+            "tony_indexes" => {
+                for index in &table.indexes {
+                    rows.push(vec![
+                        text(&index.name),
+                        text(&table.name),
+                        text(&index.columns.join(",")),
+                        int(index.root_page_id),
+                        Value::Boolean(index.unique),
+                        Value::Boolean(index.primary),
+                    ]);
+                }
+            }
+            "tony_constraints" => {
+                for index in table.indexes.iter().filter(|i| i.unique) {
+                    let kind = if index.primary { "PRIMARY KEY" } else { "UNIQUE" };
+                    rows.push(vec![
+                        text(&index.name),
+                        text(&table.name),
+                        text(kind),
+                        text(&index.columns.join(",")),
+                        text(&format!("{} ({})", kind, index.columns.join(", "))),
+                    ]);
+                }
+                for column in table.columns.iter().filter(|c| c.not_null) {
+                    rows.push(vec![
+                        text(&format!("{}_{}_not_null", table.name, column.name)),
+                        text(&table.name),
+                        text("NOT NULL"),
+                        text(&column.name),
+                        text(&format!("{} NOT NULL", column.name)),
+                    ]);
+                }
+                for check in &table.checks {
+                    rows.push(vec![
+                        text(&check.name),
+                        text(&table.name),
+                        text("CHECK"),
+                        Value::Null,
+                        text(&format!("CHECK {}", check.expr)),
+                    ]);
+                }
+                for fk in &table.foreign_keys {
+                    let deferred = if fk.deferred { " DEFERRABLE INITIALLY DEFERRED" } else { "" };
+                    rows.push(vec![
+                        text(&fk.name),
+                        text(&table.name),
+                        text("FOREIGN KEY"),
+                        text(&fk.columns.join(",")),
+                        text(&format!(
+                            "FOREIGN KEY ({}) REFERENCES {} ({}) ON DELETE {} ON UPDATE {}{}",
+                            fk.columns.join(", "), fk.parent_table, fk.parent_columns.join(", "), fk.on_delete, fk.on_update, deferred
+                        )),
+                    ]);
+                }
+            }
+            _ => {
+                let heap = engine.heap_stats(table.heap_page_id).map_err(|e| Error::storage("Failed to read rows", e))?;
+                let mut index_pages = 0;
+                for index in &table.indexes {
+                    index_pages += BTree::open(engine, index.root_page_id).page_count()
+                        .map_err(|e| Error::storage(&format!("Failed to read index {}", index.name), e))?;
+                }
+                rows.push(vec![
+                    text(&table.name),
+                    int(heap.live_records),
+                    int(heap.pages),
+                    int(heap.dead_slots),
+                    int(heap.free_bytes),
+                    int(index_pages),
+                ]);
+            }
+        }
+    }
+
+    Ok(Some(SystemTable { columns, rows }))
+}
+
+fn columns(defs: &[(&str, DataType)]) -> Vec<ColumnSchema> {
+    defs.iter()
+        .enumerate()
+        .map(|(id, (name, data_type))| ColumnSchema {
+            id: id as u16,
+            name: name.to_string(),
+            data_type: *data_type,
+            not_null: false,
+            default: None,
+            missing: None,
+        })
+        .collect()
+}
+
+fn text(s: &str) -> Value {
+    Value::Text(s.to_string())
+}
+
+fn int(n: impl TryInto<i64>) -> Value {
+    n.try_into().map_or(Value::Null, Value::Integer)
+}
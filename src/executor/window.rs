@@ -0,0 +1,302 @@
+use super::eval;
+use super::query::compare_keys;
+use crate::error::Error;
+use crate::parser::ast::{FrameBound, WindowCall};
+use crate::types::Value;
+use std::cmp::Ordering;
+
+// what a window function call needs from one row of its partition, worked out by the query beforehand
+pub struct WindowRow {
+    pub order: Vec<Value>,
+    pub args: Vec<Value>,
+}
+
+// the rows of each partition as their positions, given the PARTITION BY values of every row. the query
+// only works out the rest of what a call needs for one partition at a time, and is done with it
+// before the next
+pub fn partitions(keys: Vec<Vec<Value>>) -> Vec<Vec<usize>> {
+    let mut sorted: Vec<usize> = (0..keys.len()).collect();
+    sorted.sort_by(|&a, &b| compare_partitions(&keys[a], &keys[b]));
+    let mut partitions: Vec<Vec<usize>> = Vec::new();
+    for row in sorted {
+        match partitions.last_mut() {
+            Some(partition) if compare_partitions(&keys[partition[0]], &keys[row]) == Ordering::Equal => partition.push(row),
+            _ => partitions.push(vec![row]),
+        }
+    }
+    partitions
+}
+
+// a window function call with its frame checked
+pub struct Window<'a> {
+    call: &'a WindowCall,
+    frame: Frame,
+}
+
+impl<'a> Window<'a> {
+    pub fn new(call: &'a WindowCall) -> Result<Self, Error> {
+        Ok(Window { call, frame: Frame::new(call)? })
+    }
+
+    // the value of the call for every row of one partition, in the order the rows were given
+    pub fn compute(&self, rows: &[WindowRow]) -> Result<Vec<Value>, Error> {
+        let mut sorted: Vec<usize> = (0..rows.len()).collect();
+        sorted.sort_by(|&a, &b| compare_keys(&rows[a].order, &rows[b].order, &self.call.order_by));
+        let mut results = vec![Value::Null; rows.len()];
+        for (&row, value) in sorted.iter().zip(compute_partition(self.call, &self.frame, rows, &sorted)?) {
+            results[row] = value;
+        }
+        Ok(results)
+    }
+}
+
+// NULLs all go in one partition
+fn compare_partitions(left: &[Value], right: &[Value]) -> Ordering {
+    for (l, r) in left.iter().zip(right) {
+        let ordering = match (l.is_null(), r.is_null()) {
+            (true, true) => Ordering::Equal,
+            (true, false) => Ordering::Greater,
+            (false, true) => Ordering::Less,
+            (false, false) => l.compare(r).unwrap_or(Ordering::Equal),
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+    Ordering::Equal
+}
+
+// partition holds the rows of one partition in window order
+fn compute_partition(call: &WindowCall, frame: &Frame, rows: &[WindowRow], partition: &[usize]) -> Result<Vec<Value>, Error> {
+    let n = partition.len();
+    let arg = |position: usize, i: usize| rows[partition[position]].args.get(i).cloned().unwrap_or(Value::Null);
+
+    // rows that tie on the window ORDER BY are peers, they share a rank and RANGE frames include them all
+    let mut peers = Vec::with_capacity(n);
+    let mut group_start = 0;
+    for position in 0..n {
+        if position > 0 && compare_keys(&rows[partition[position - 1]].order, &rows[partition[position]].order, &call.order_by) != Ordering::Equal {
+            group_start = position;
+        }
+        peers.push(group_start);
+    }
+    let peer_end = |position: usize| (position + 1..n).find(|&later| peers[later] != peers[position]).unwrap_or(n);
+
+    match call.function.as_str() {
+        "ROW_NUMBER" => Ok((1..=n as i64).map(Value::Integer).collect()),
+        "RANK" => Ok(peers.iter().map(|&start| Value::Integer(start as i64 + 1)).collect()),
+        "DENSE_RANK" => {
+            let mut rank = 0;
+            Ok((0..n).map(|position| {
+                if peers[position] == position {
+                    rank += 1;
+                }
+                Value::Integer(rank)
+            }).collect())
+        }
+        "LAG" | "LEAD" => {
+            let mut values = Vec::with_capacity(n);
+            for position in 0..n {
+                let offset = match arg(position, 1) {
+                    Value::Null if call.args.len() > 1 => {
+                        values.push(Value::Null);
+                        continue;
+                    }
+                    Value::Null => 1,
+                    Value::Integer(offset) if offset >= 0 => offset as usize,
+                    other => return Err(Error::Execution(format!("The offset of {} must be a whole number that isnt negative, not {}", call.function, other))),
+                };
+                let target = if call.function == "LAG" { position.checked_sub(offset) } else { position.checked_add(offset).filter(|&t| t < n) };
+                values.push(match target {
+                    Some(target) => arg(target, 0),
+                    None => arg(position, 2),
+                });
+            }
+            Ok(values)
+        }
+        function => {
+            let keys = frame.range_keys(rows, partition)?;
+            let mut values = Vec::with_capacity(n);
+            // frames mostly grow at the end as the current row moves on, so an aggregate is only
+            // started again when the start of the frame moves
+            let mut aggregate = Aggregate::new(function, call.args.is_empty());
+            let mut covered = (0, 0);
+            for position in 0..n {
+                let (start, end) = frame.bounds(position, n, &peers, peer_end(position), &keys);
+                values.push(match function {
+                    "FIRST_VALUE" => if start < end { arg(start, 0) } else { Value::Null },
+                    "LAST_VALUE" => if start < end { arg(end - 1, 0) } else { Value::Null },
+                    _ => {
+                        if start != covered.0 || end < covered.1 {
+                            aggregate = Aggregate::new(function, call.args.is_empty());
+                            covered = (start, start);
+                        }
+                        for i in covered.1.max(start)..end {
+                            aggregate.add(&arg(i, 0))?;
+                        }
+                        covered = (start, end.max(covered.1));
+                        aggregate.result()
+                    }
+                });
+            }
+            Ok(values)
+        }
+    }
+}
+
+// the frame of a call with its offsets worked out. without a frame its RANGE BETWEEN UNBOUNDED
+// PRECEDING AND CURRENT ROW, which is the whole partition when theres no ORDER BY
+struct Frame {
+    range: bool,
+    start: Bound,
+    end: Bound,
+    descending: bool,
+}
+
+enum Bound {
+    Unbounded,
+    Current,
+    Offset(f64), // negative for PRECEDING
+}
+
+impl Frame {
+    fn new(call: &WindowCall) -> Result<Self, Error> {
+        let Some(frame) = &call.frame else {
+            return Ok(Frame { range: true, start: Bound::Unbounded, end: Bound::Current, descending: false });
+        };
+        let bound = |bound: &FrameBound| -> Result<Bound, Error> {
+            let (offset, sign) = match bound {
+                FrameBound::UnboundedPreceding | FrameBound::UnboundedFollowing => return Ok(Bound::Unbounded),
+                FrameBound::CurrentRow => return Ok(Bound::Current),
+                FrameBound::Preceding(offset) => (offset, -1.0),
+                FrameBound::Following(offset) => (offset, 1.0),
+            };
+            let offset = match (eval::eval(offset, &[], &[])?, frame.range) {
+                (Value::Integer(n), _) if n >= 0 => n as f64,
+                (Value::Real(r), true) if r >= 0.0 => r,
+                (other, _) => return Err(Error::Execution(format!("A frame offset must be a number that isnt negative, not {}", other))),
+            };
+            Ok(Bound::Offset(sign * offset))
+        };
+        let (start, end) = (bound(&frame.start)?, bound(&frame.end)?);
+        let has_offset = matches!(start, Bound::Offset(_)) || matches!(end, Bound::Offset(_));
+        if frame.range && has_offset && call.order_by.len() != 1 {
+            return Err(Error::Execution("RANGE with an offset needs exactly one ORDER BY expression".to_string()));
+        }
+        let descending = call.order_by.first().is_some_and(|item| item.descending);
+        Ok(Frame { range: frame.range, start, end, descending })
+    }
+
+    // the values RANGE offsets are measured on, counted in the direction of the ORDER BY
+    fn range_keys(&self, rows: &[WindowRow], partition: &[usize]) -> Result<Vec<Option<f64>>, Error> {
+        let has_offset = matches!(self.start, Bound::Offset(_)) || matches!(self.end, Bound::Offset(_));
+        if !self.range || !has_offset {
+            return Ok(Vec::new());
+        }
+        partition.iter()
+            .map(|&row| match &rows[row].order[0] {
+                Value::Null => Ok(None),
+                Value::Integer(i) => Ok(Some(*i as f64)),
+                Value::Real(r) => Ok(Some(*r)),
+                other => Err(Error::Execution(format!("RANGE with an offset needs a numeric ORDER BY, not {}", other))),
+            })
+            .map(|key| key.map(|key| key.map(|k| if self.descending { -k } else { k })))
+            .collect()
+    }
+
+    // the frame of the row at position as a range of positions, end not included
+    fn bounds(&self, position: usize, n: usize, peers: &[usize], peer_end: usize, keys: &[Option<f64>]) -> (usize, usize) {
+        let start = match self.start {
+            Bound::Unbounded => 0,
+            Bound::Current if self.range => peers[position],
+            Bound::Current => position,
+            Bound::Offset(offset) if !self.range => clamp(position as f64 + offset, n),
+            Bound::Offset(offset) => self.range_edge(position, offset, keys, peers[position], false),
+        };
+        let end = match self.end {
+            Bound::Unbounded => n,
+            Bound::Current if self.range => peer_end,
+            Bound::Current => position + 1,
+            Bound::Offset(offset) if !self.range => clamp(position as f64 + offset + 1.0, n),
+            Bound::Offset(offset) => self.range_edge(position, offset, keys, peer_end, true),
+        };
+        (start, end.max(start))
+    }
+
+    // the first row whose key is at least the current key plus offset, or past the last one at most
+    // that far when end is set. a NULL current key only has its peers
+    fn range_edge(&self, position: usize, offset: f64, keys: &[Option<f64>], peer_edge: usize, end: bool) -> usize {
+        let Some(current) = keys[position] else {
+            return peer_edge;
+        };
+        let first = keys.iter().position(Option::is_some).unwrap_or(0);
+        let last = keys.iter().rposition(Option::is_some).map_or(first, |last| last + 1);
+        let target = current + offset;
+        first + keys[first..last].partition_point(|key| {
+            let key = key.unwrap_or(f64::NAN);
+            if end { key <= target } else { key < target }
+        })
+    }
+}
+
+fn clamp(position: f64, n: usize) -> usize {
+    position.max(0.0).min(n as f64) as usize
+}
+
+// COUNT, SUM, AVG, MIN and MAX over the rows of a frame or of a GROUP BY group, NULLs are left out
+pub(super) struct Aggregate<'a> {
+    function: &'a str,
+    count_rows: bool, // COUNT(*)
+    count: i64,
+    sum: Value,
+    best: Value,
+}
+
+impl<'a> Aggregate<'a> {
+    pub(super) fn new(function: &'a str, count_rows: bool) -> Self {
+        Aggregate { function, count_rows, count: 0, sum: Value::Null, best: Value::Null }
+    }
+
+    pub(super) fn add(&mut self, value: &Value) -> Result<(), Error> {
+        if value.is_null() && !self.count_rows {
+            return Ok(());
+        }
+        self.count += 1;
+        match self.function {
+            "SUM" | "AVG" => {
+                self.sum = match (&self.sum, value) {
+                    (Value::Null, Value::Integer(_) | Value::Real(_)) => value.clone(),
+                    (Value::Integer(a), Value::Integer(b)) => Value::Integer(a.checked_add(*b).ok_or_else(|| format!("{} is out of range for INTEGER", self.function))?),
+                    (Value::Integer(a), Value::Real(b)) => Value::Real(*a as f64 + b),
+                    (Value::Real(a), Value::Integer(b)) => Value::Real(a + *b as f64),
+                    (Value::Real(a), Value::Real(b)) => Value::Real(a + b),
+                    (_, other) => return Err(Error::Execution(format!("{} needs numbers, not '{}'", self.function, other))),
+                };
+            }
+            "MIN" | "MAX" => {
+                let better = match value.compare(&self.best) {
+                    None => self.best.is_null(),
+                    Some(ordering) => ordering == if self.function == "MIN" { Ordering::Less } else { Ordering::Greater },
+                };
+                if better {
+                    self.best = value.clone();
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    pub(super) fn result(&self) -> Value {
+        match self.function {
+            "COUNT" => Value::Integer(self.count),
+            "SUM" => self.sum.clone(),
+            "AVG" => match self.sum {
+                Value::Integer(sum) => Value::Real(sum as f64 / self.count as f64),
+                Value::Real(sum) => Value::Real(sum / self.count as f64),
+                _ => Value::Null,
+            },
+            _ => self.best.clone(),
+        }
+    }
+}
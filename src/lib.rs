@@ -1,4 +1,12 @@
+pub mod client;
+pub mod error;
 pub mod executor;
 pub mod listener;
 pub mod parser;
-mod storage;
\ No newline at end of file
+pub mod protocol;
+pub mod types;
+mod storage;
+
+pub use storage::backup;
+
+pub use error::Error;
\ No newline at end of file
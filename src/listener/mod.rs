@@ -2,7 +2,10 @@ use std::io::{Read, Write};
 use std::net::{TcpListener, TcpStream};
 use std::thread;
 
-use crate::executor;
+use crate::error::Error;
+use crate::executor::{self, OnError, Session};
+use crate::protocol;
+use crate::storage::codec::{self, Reader};
 use crate::storage::storage::{self, StorageEngine};
 
 static EXECUTOR: once_cell::sync::Lazy<executor::Executor> = once_cell::sync::Lazy::new(|| {
@@ -10,15 +13,30 @@ static EXECUTOR: once_cell::sync::Lazy<executor::Executor> = once_cell::sync::La
 });
 
 fn handle_client(mut stream: TcpStream) {
-    let mut buffer = [0; 512];
-    if let Ok(size) = stream.read(&mut buffer) {
-        let message = String::from_utf8_lossy(&buffer[..size]);
+    // a framed client starts with the magic bytes. anything else is a statement, which can be
+    // shorter than them, so only as much as was sent is read
+    let mut buffer = Vec::new();
+    if (&mut stream).take(protocol::MAGIC.len() as u64).read_to_end(&mut buffer).is_err() {
+        return;
+    }
+    if buffer == protocol::MAGIC {
+        if let Err(e) = handle_frames(&mut stream) {
+            eprintln!("Connection closed: {}", e);
+        }
+        return;
+    }
+
+    // clients close their write side once the request is sent
+    if stream.read_to_end(&mut buffer).is_ok() {
+        let message = String::from_utf8_lossy(&buffer);
         println!("Received message {}", message);
 
         if message.trim() == "stop" {
             let _ = stream.write_all(b"Stopping the server as requested.");
             println!("Stopping the server as requested.");
-            let _ = StorageEngine::wipe().map_err(|e| format!("Failed to delete file at path {} with error {}", storage::default_db_path().unwrap().to_str().unwrap(), e));
+            if let Err(e) = StorageEngine::wipe() {
+                eprintln!("Failed to delete the database file: {}", e);
+            }
             std::process::exit(0);
         }
 
@@ -27,7 +45,87 @@ fn handle_client(mut stream: TcpStream) {
     }
 }
 
-pub fn start_server() {
+// answers each frame with a response frame, or an error frame when the statement fails, until the
+// client closes the connection
+fn handle_frames(stream: &mut TcpStream) -> std::io::Result<()> {
+    let mut session = Session::default();
+    while let Some((kind, payload)) = protocol::read_frame(stream)? {
+        let mut reader = Reader::new(&payload);
+        let malformed = |e: std::io::Error| Error::Protocol(format!("malformed message: {}", e));
+        let result = match kind {
+            protocol::QUERY => std::str::from_utf8(&payload)
+                .map_err(|e| Error::Protocol(format!("statement isnt UTF-8: {}", e)))
+                .and_then(|sql| EXECUTOR.execute(&mut session, sql)),
+            protocol::PREPARE => reader.str()
+                .and_then(|name| Ok((name, reader.str()?)))
+                .map_err(malformed)
+                .and_then(|(name, sql)| EXECUTOR.prepare(&mut session, &name, &sql)),
+            protocol::BIND => reader.str()
+                .and_then(|name| Ok((name, protocol::read_values(&mut reader)?)))
+                .map_err(malformed)
+                .and_then(|(name, values)| EXECUTOR.bind(&mut session, &name, values)),
+            protocol::EXECUTE => reader.str()
+                .map_err(malformed)
+                .and_then(|name| EXECUTOR.execute_bound(&mut session, &name)),
+            protocol::DUMP => {
+                let mut chunks = protocol::FrameWriter::new(stream, protocol::CHUNK);
+                match EXECUTOR.dump(&mut chunks) {
+                    Ok(()) => chunks.finish()?,
+                    Err(e) => write_error(stream, &e)?,
+                }
+                continue;
+            }
+            protocol::SCRIPT => {
+                let script = reader.u8()
+                    .and_then(|on_error| Ok((on_error, reader.str()?)))
+                    .map_err(malformed)
+                    .and_then(|(on_error, sql)| {
+                        let on_error = if on_error == 1 { OnError::Continue } else { OnError::Stop };
+                        EXECUTOR.execute_script(&mut session, &sql, on_error)
+                    });
+                match script {
+                    Ok(results) => {
+                        let mut count = Vec::new();
+                        codec::put_u32(&mut count, results.len() as u32);
+                        protocol::write_frame(stream, protocol::RESULTS, &count)?;
+                        for result in results {
+                            write_result(stream, result)?;
+                        }
+                    }
+                    Err(e) => write_result(stream, Err(e))?,
+                }
+                continue;
+            }
+            other => Err(Error::Protocol(format!("unknown message type {}", other))),
+        };
+        write_result(stream, result)?;
+    }
+    Ok(())
+}
+
+fn write_result(stream: &mut TcpStream, result: Result<executor::QueryResult, Error>) -> std::io::Result<()> {
+    let written = match result {
+        Err(e) => write_error(stream, &e),
+        Ok(executor::QueryResult::Rows { columns, rows }) => protocol::write_rows(stream, &columns, &rows),
+        Ok(message) => protocol::write_frame(stream, protocol::RESPONSE, message.to_string().as_bytes()),
+    };
+    match written {
+        // the client would have refused the frame, so it hears why instead and the connection carries on
+        Err(e) if e.kind() == std::io::ErrorKind::InvalidInput => write_error(stream, &Error::Protocol(format!("result too big to send: {}", e))),
+        written => written,
+    }
+}
+
+fn write_error(stream: &mut TcpStream, error: &Error) -> std::io::Result<()> {
+    let mut frame = Vec::new();
+    protocol::put_error(&mut frame, error);
+    protocol::write_frame(stream, protocol::ERROR, &frame)
+}
+
+// compress_overflow is whether rows and keys too big for their page are compressed when they are
+// written out to overflow pages
+pub fn start_server(compress_overflow: bool) {
+    storage::set_compress_overflow(compress_overflow);
     let listener = TcpListener::bind("127.0.0.1:12345")
                     .expect("Failed to bind port");
 
@@ -1,10 +1,10 @@
 use clap::{Parser, Subcommand};
-use std::net::{TcpStream};
-use std::process::Command;
-use std::thread;
-use tony_db;
-
-mod client;
+use std::path::{Path, PathBuf};
+use tony_db::client::{self, Connection, Format, Shell};
+use tony_db::backup;
+use tony_db::executor::OnError;
+use tony_db::{parser, protocol};
+use tony_db::types::Value;
 
 #[derive(Parser)]
 #[command(name = "TonyDB")]
@@ -16,45 +16,134 @@ struct Cli {
 
 #[derive(Subcommand)]
 enum Commands {
-    Init,
+    Init {
+        // store rows and keys too big for their page as they are instead of compressing them
+        #[arg(long)]
+        no_compression: bool,
+    },
     Query {
         query: String,
+        // values for $1, $2, ... in the query, sent apart from it
+        #[arg(short, long = "param")]
+        params: Vec<String>,
+        // table, csv, tsv, json, jsonl or markdown. without it the result is printed as plain text
+        #[arg(long)]
+        format: Option<Format>,
+    },
+    // runs the statements of a SQL file in order, stopping at the first that fails
+    Exec {
+        #[arg(short, long)]
+        file: PathBuf,
+        // run the rest of the statements after one fails
+        #[arg(short = 'c', long)]
+        continue_on_error: bool,
+        #[arg(long)]
+        format: Option<Format>,
+    },
+    // an interactive prompt that keeps one connection open
+    Shell,
+    // writes the SQL that makes every table again with its rows, all read at one point in time
+    Dump {
+        // the file to write instead of printing it
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    // runs a dump, stopping at the first statement that fails
+    Restore {
+        file: PathBuf,
+    },
+    // makes a database file from a BACKUP and the write ahead log, up to an LSN or time or else to the
+    // end of the log. doesnt need the server
+    Recover {
+        backup: PathBuf,
+        // the database file to write, which has to be new
+        #[arg(short, long)]
+        output: PathBuf,
+        // where the log segments are, the servers own log by default
+        #[arg(long)]
+        wal_dir: Option<PathBuf>,
+        #[arg(long, conflicts_with = "time")]
+        lsn: Option<u64>,
+        // YYYY-MM-DD HH:MM:SS[.fff] in UTC, or seconds since the unix epoch
+        #[arg(long)]
+        time: Option<String>,
     },
     Stop,
     #[command(hide = true)]
-    RunService,
+    RunService {
+        #[arg(long)]
+        no_compression: bool,
+    },
 }
 
 fn main() {
     let cli = Cli::parse();
 
     match cli.command {
-        Commands::RunService => {
+        Commands::RunService { no_compression } => {
             // hidden arg to start the service
-            tony_db::listener::start_server();
+            tony_db::listener::start_server(!no_compression);
         }
-        Commands::Init => {
-            start_background_service();
+        Commands::Init { no_compression } => {
+            start_background_service(no_compression);
         }
-        Commands::Query { query } => {
+        Commands::Query { query, params, format: None } if params.is_empty() => {
             send_command(&query);
         }
+        Commands::Query { query, params, format } => {
+            if !send_framed(&query, &params, format) {
+                std::process::exit(1);
+            }
+        }
+        Commands::Exec { file, continue_on_error, format } => {
+            let on_error = if continue_on_error { OnError::Continue } else { OnError::Stop };
+            if !run_script(&file, on_error, format) {
+                std::process::exit(1);
+            }
+        }
+        Commands::Shell => {
+            if let Err(e) = Shell::connect().and_then(|mut shell| shell.run()) {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        Commands::Dump { output } => {
+            if !dump(output.as_deref()) {
+                std::process::exit(1);
+            }
+        }
+        Commands::Restore { file } => {
+            if !restore(&file) {
+                std::process::exit(1);
+            }
+        }
+        Commands::Recover { backup, output, wal_dir, lsn, time } => {
+            if !recover(&backup, &output, wal_dir, lsn, time.as_deref()) {
+                std::process::exit(1);
+            }
+        }
         Commands::Stop => {
-            send_command(&"stop".to_string());
+            send_command("stop");
         }
     }
 }
 
-fn start_background_service() {
+fn start_background_service(no_compression: bool) {
     
     #[cfg(windows)]
     {
         use std::os::windows::process::CommandExt;
+        use std::process::Command;
+        use std::thread;
         const DETACHED_PROCESS: u32 = 0x00000008; // detached process code
         let current_exe = std::env::current_exe().expect("Failed to get current executable path");
 
-        Command::new(current_exe)
-            .arg("run-service")
+        let mut command = Command::new(current_exe);
+        command.arg("run-service");
+        if no_compression {
+            command.arg("--no-compression");
+        }
+        command
             .creation_flags(DETACHED_PROCESS)
             .spawn()
             .expect("Failed to start background service");
@@ -64,10 +153,193 @@ fn start_background_service() {
 
     #[cfg(not(target_os = "windows"))]
     {
+        let _ = no_compression;
         unimplemented!("This service is implemented only for Windows.");
     }
 }
 
-fn send_command(command: &String) {
+fn send_command(command: &str) {
     client::send_command(command)
-}
\ No newline at end of file
+}
+
+// runs the query over the framed protocol, prepared with the params as its values if there are any.
+// numbers are sent as numbers, anything else as text. with a format only the result is printed, so
+// it can be piped into something else, and errors go to stderr. false if the query failed
+fn send_framed(query: &str, params: &[String], format: Option<Format>) -> bool {
+    let values: Vec<Value> = params.iter()
+        .map(|param| param.parse().ok().map(Value::Integer)
+            .or_else(|| param.parse().ok().filter(|r: &f64| r.is_finite()).map(Value::Real))
+            .unwrap_or_else(|| Value::Text(param.clone())))
+        .collect();
+    let response = Connection::connect().and_then(|mut connection| {
+        if params.is_empty() {
+            return connection.query(query);
+        }
+        connection.prepare("", query)?;
+        connection.bind("", &values)?;
+        connection.execute("")
+    });
+    match (response, format) {
+        (Ok(result), Some(format)) => println!("{}", format.render(&result)),
+        (Ok(result), None) => println!("Response: {}", result),
+        (Err(e), Some(_)) => {
+            eprintln!("{}", e);
+            return false;
+        }
+        (Err(e), None) => println!("Response: {}", e),
+    }
+    true
+}
+
+// prints what each statement of the file gave back, false if the file couldnt be run or any of its
+// statements failed. with a format only the results are printed, errors still go to stderr
+fn run_script(file: &Path, on_error: OnError, format: Option<Format>) -> bool {
+    let script = match std::fs::read_to_string(file) {
+        Ok(script) => script,
+        Err(e) => {
+            eprintln!("Failed to read {}: {}", file.display(), e);
+            return false;
+        }
+    };
+    let results = match Connection::connect().and_then(|mut connection| connection.script(&script, on_error)) {
+        Ok(results) => results,
+        Err(e) => {
+            eprintln!("{}", e);
+            return false;
+        }
+    };
+    let failed = results.iter().filter(|result| result.is_err()).count();
+    for (i, result) in results.iter().enumerate() {
+        match (result, format) {
+            (Ok(result), Some(format)) => println!("{}", format.render(result)),
+            (Ok(result), None) => println!("{}: {}", i + 1, result),
+            (Err(e), _) => eprintln!("{}: {}", i + 1, e),
+        }
+    }
+    if format.is_none() {
+        println!("{} statement{} run, {} failed", results.len(), if results.len() == 1 { "" } else { "s" }, failed);
+    }
+    failed == 0
+}
+
+fn dump(output: Option<&Path>) -> bool {
+    let mut connection = match Connection::connect() {
+        Ok(connection) => connection,
+        Err(e) => {
+            eprintln!("{}", e);
+            return false;
+        }
+    };
+    let dumped = match output {
+        Some(path) => match std::fs::File::create(path) {
+            Ok(mut file) => connection.dump(&mut file),
+            Err(e) => {
+                eprintln!("Failed to write {}: {}", path.display(), e);
+                return false;
+            }
+        },
+        None => connection.dump(&mut std::io::stdout().lock()),
+    };
+    if let Err(e) = dumped {
+        eprintln!("{}", e);
+        return false;
+    }
+    true
+}
+
+// a dump is a script like any other, only its results arent worth printing one by one. its sent in
+// pieces of whole statements so a dump of any size stays under the frame limit
+fn restore(file: &Path) -> bool {
+    let script = match std::fs::read_to_string(file) {
+        Ok(script) => script,
+        Err(e) => {
+            eprintln!("Failed to read {}: {}", file.display(), e);
+            return false;
+        }
+    };
+    let mut connection = match Connection::connect() {
+        Ok(connection) => connection,
+        Err(e) => {
+            eprintln!("{}", e);
+            return false;
+        }
+    };
+    let mut restored = 0;
+    for batch in batches(&script) {
+        match connection.script(batch, OnError::Stop) {
+            Ok(results) => {
+                if let Some((i, Err(e))) = results.iter().enumerate().find(|(_, result)| result.is_err()) {
+                    eprintln!("Restore stopped at statement {}: {}", restored + i + 1, e);
+                    return false;
+                }
+                restored += results.len();
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                return false;
+            }
+        }
+    }
+    println!("Restored {} statement{} from {}", restored, if restored == 1 { "" } else { "s" }, file.display());
+    true
+}
+
+// script cut after whole statements into pieces of about protocol::BATCH_BYTES. a dump has a
+// statement per line, so only lines ending in ; are looked at as places to cut
+fn batches(script: &str) -> Vec<&str> {
+    let mut batches = Vec::new();
+    let (mut start, mut end) = (0, 0);
+    for line in script.split_inclusive('\n') {
+        end += line.len();
+        if end - start >= protocol::BATCH_BYTES && line.trim_end().ends_with(';') && parser::ends_statement(&script[start..end]) {
+            batches.push(&script[start..end]);
+            start = end;
+        }
+    }
+    if !script[start..].trim().is_empty() {
+        batches.push(&script[start..]);
+    }
+    batches
+}
+
+fn recover(backup: &Path, output: &Path, wal_dir: Option<PathBuf>, lsn: Option<u64>, time: Option<&str>) -> bool {
+    let target = match (lsn, time) {
+        (Some(lsn), _) => Some(backup::Target::Lsn(lsn)),
+        (None, Some(time)) => match backup::parse_time(time) {
+            Some(millis) => Some(backup::Target::Time(millis)),
+            None => {
+                eprintln!("Invalid time '{}', expected YYYY-MM-DD HH:MM:SS[.fff] in UTC or seconds since the unix epoch", time);
+                return false;
+            }
+        },
+        (None, None) => None,
+    };
+    if output.exists() {
+        eprintln!("{} already exists, recover only writes a new file", output.display());
+        return false;
+    }
+    let wal_dir = match wal_dir.map_or_else(backup::wal_dir, Ok) {
+        Ok(dir) => dir,
+        Err(e) => {
+            eprintln!("Failed to find the write ahead log: {}", e);
+            return false;
+        }
+    };
+    match backup::recover(backup, &wal_dir, output, target) {
+        Ok(recovered) => {
+            println!(
+                "Recovered {} to LSN {} ({}), replayed {} statement{} after the backup",
+                output.display(), recovered.lsn, backup::format_time(recovered.time),
+                recovered.statements, if recovered.statements == 1 { "" } else { "s" }
+            );
+            println!("Stop the server and put it in place of the database file to use it, then take a new backup");
+            true
+        }
+        Err(e) => {
+            // a half recovered file is no use to anyone
+            let _ = std::fs::remove_file(output);
+            eprintln!("Failed to recover {}: {}", backup.display(), e);
+            false
+        }
+    }
+}
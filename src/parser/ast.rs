@@ -1,264 +1,2230 @@
-use crate::parser::lexer::Token;
+use crate::parser::lexer::{quote_identifier, Token};
+use crate::types::{DataType, ReferentialAction, Value};
+use std::fmt;
 use std::iter::Peekable;
 
 
 // usage of all these commands on existing tables is like
 //  INSERT <table_name> VALUES (...)
-// Not regular SQL syntax where would do INSERT INTO.
+// Not regular SQL syntax where would do INSERT INTO, though INTO is accepted too.
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Query {
     Select(SelectQuery),
     Insert(InsertQuery),
-    // Update(UpdateQuery),
+    Update(UpdateQuery),
     Delete(DeleteQuery),
     Create(CreateQuery),
+    Vacuum(VacuumQuery),
+    Drop(DropQuery),
+    Truncate(TruncateQuery),
+    Alter(AlterQuery),
+    ShowTables,
+    Describe(DescribeQuery),
+    With(WithQuery),
+    Prepare(PrepareQuery),
+    Execute(ExecuteQuery),
+    Deallocate(Option<String>), // None for DEALLOCATE ALL
+    Copy(CopyQuery),
+    Backup(String), // "BACKUP TO 'file'", the file the database is copied to on the server
 }
 
-#[derive(Debug)]
+// "PREPARE name [(types)] AS statement". the statement is parsed once and run by EXECUTE with values
+// in place of its parameters, which are converted to the types when given
+#[derive(Debug, Clone)]
+pub struct PrepareQuery {
+    pub name: String,
+    pub types: Vec<DataType>,
+    pub statement: Box<Query>,
+    pub parameters: usize, // how many values EXECUTE has to give
+}
+
+// "EXECUTE name [(values)]"
+#[derive(Debug, Clone)]
+pub struct ExecuteQuery {
+    pub name: String,
+    pub args: Vec<Expression>,
+}
+
+impl Query {
+    // only statements that read or change rows can be prepared
+    pub fn can_prepare(&self) -> bool {
+        matches!(self, Query::Select(_) | Query::Insert(_) | Query::Update(_) | Query::Delete(_) | Query::With(_))
+    }
+
+    // puts values[n - 1] in place of each $n in a statement that can be prepared
+    pub fn bind(&mut self, values: &[Value]) -> Result<(), String> {
+        match self {
+            Query::Select(query) => query.bind(values),
+            Query::With(with) => {
+                for table in &mut with.tables {
+                    table.query.bind(values)?;
+                    if let Some((_, step)) = &mut table.step {
+                        step.bind(values)?;
+                    }
+                }
+                with.query.bind(values)
+            }
+            Query::Insert(insert) => {
+                match &mut insert.source {
+                    InsertSource::Values(rows) => {
+                        for expr in rows.iter_mut().flatten().flatten() {
+                            expr.bind(values)?;
+                        }
+                    }
+                    InsertSource::Select(query) => query.bind(values)?,
+                }
+                if let Some(OnConflict { action: ConflictAction::Update { updates, where_clause }, .. }) = &mut insert.on_conflict {
+                    for expr in updates.iter_mut().map(|(_, expr)| expr).chain(where_clause) {
+                        expr.bind(values)?;
+                    }
+                }
+                bind_items(insert.returning.as_deref_mut().unwrap_or_default(), values)
+            }
+            Query::Update(update) => {
+                for expr in update.updates.iter_mut().map(|(_, expr)| expr).chain(&mut update.where_clause) {
+                    expr.bind(values)?;
+                }
+                bind_items(update.returning.as_deref_mut().unwrap_or_default(), values)
+            }
+            Query::Delete(delete) => {
+                if let Some(expr) = &mut delete.where_clause {
+                    expr.bind(values)?;
+                }
+                bind_items(delete.returning.as_deref_mut().unwrap_or_default(), values)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+// "WITH [RECURSIVE] name [(columns)] AS (SELECT ...), ... SELECT ...". the named results can be used
+// like tables by the queries after them, in FROM and in subqueries
+#[derive(Debug, Clone)]
+pub struct WithQuery {
+    pub recursive: bool,
+    pub tables: Vec<CommonTable>,
+    pub query: SelectQuery,
+}
+
+#[derive(Debug, Clone)]
+pub struct CommonTable {
+    pub name: String,
+    pub columns: Option<Vec<String>>,
+    pub query: SelectQuery,
+    // under WITH RECURSIVE "anchor UNION [ALL] step", where query is the anchor and step reads the rows
+    // the previous step added under the tables own name. the bool is true for UNION ALL
+    pub step: Option<(bool, SelectQuery)>,
+}
+
+// "SELECT items [FROM source] [WHERE ...] [GROUP BY ... [HAVING ...]]". the older "SELECT table_name [columns] [WHERE ...]"
+// form is still accepted and selects whole rows of the table. either can be combined with more
+// SELECTs by UNION, INTERSECT and EXCEPT, and ORDER BY, LIMIT and OFFSET apply to the combined rows
+#[derive(Debug, Clone)]
 pub struct SelectQuery {
-    pub table_name: String,
-    pub columns: Vec<String>,
+    pub distinct: bool,
+    pub items: Vec<SelectItem>,
+    pub from: Option<FromItem>,
     pub where_clause: Option<Expression>,
+    // a number or the alias of a select item picks that item, like in ORDER BY
+    pub group_by: Vec<Expression>,
+    pub having: Option<Expression>,
+    pub set_operations: Vec<SetOperation>,
+    pub order_by: Vec<OrderItem>,
+    pub limit: Option<Expression>,
+    pub offset: Option<Expression>,
+}
+
+impl SelectQuery {
+    // a single SELECT whose rows come out in no particular order and all of them
+    pub fn is_simple(&self) -> bool {
+        self.set_operations.is_empty() && self.order_by.is_empty() && self.limit.is_none() && self.offset.is_none()
+    }
+
+    // whether the query reads the table anywhere, in FROM or in a subquery
+    pub fn reads_table(&self, table: &str) -> bool {
+        let from = match &self.from {
+            Some(FromItem::Table { name, .. }) => name == table,
+            Some(FromItem::Subquery { query, .. }) => query.reads_table(table),
+            None => false,
+        };
+        from || self.expressions().any(|expr| expr.reads_table(table)) || self.set_operations.iter().any(|op| op.query.reads_table(table))
+    }
+
+    pub fn has_windows(&self) -> bool {
+        self.expressions().any(Expression::contains_window)
+    }
+
+    // whether the rows are folded into groups, which an aggregate without GROUP BY does too
+    pub fn is_grouped(&self) -> bool {
+        !self.group_by.is_empty() || self.having.is_some() || self.expressions().any(Expression::contains_aggregate)
+    }
+
+    pub fn bind(&mut self, values: &[Value]) -> Result<(), String> {
+        bind_items(&mut self.items, values)?;
+        if let Some(FromItem::Subquery { query, .. }) = &mut self.from {
+            query.bind(values)?;
+        }
+        for expr in self.where_clause.iter_mut().chain(&mut self.group_by).chain(&mut self.having).chain(&mut self.limit).chain(&mut self.offset) {
+            expr.bind(values)?;
+        }
+        for item in &mut self.order_by {
+            item.expr.bind(values)?;
+        }
+        for operation in &mut self.set_operations {
+            operation.query.bind(values)?;
+        }
+        Ok(())
+    }
+
+    fn expressions(&self) -> impl Iterator<Item = &Expression> {
+        let items = self.items.iter().filter_map(|item| match item {
+            SelectItem::Expression { expr, .. } => Some(expr),
+            SelectItem::Wildcard | SelectItem::TableWildcard(_) => None,
+        });
+        items
+            .chain(&self.where_clause)
+            .chain(&self.group_by)
+            .chain(&self.having)
+            .chain(self.order_by.iter().map(|item| &item.expr))
+            .chain(&self.limit)
+            .chain(&self.offset)
+    }
+}
+
+fn bind_items(items: &mut [SelectItem], values: &[Value]) -> Result<(), String> {
+    for item in items {
+        if let SelectItem::Expression { expr, .. } = item {
+            expr.bind(values)?;
+        }
+    }
+    Ok(())
+}
+
+// "UNION [ALL] SELECT ...", INTERSECT and EXCEPT the same way. query has no set operations, ORDER BY
+// or LIMIT of its own
+#[derive(Debug, Clone)]
+pub struct SetOperation {
+    pub operator: SetOperator,
+    pub all: bool,
+    pub query: SelectQuery,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SetOperator {
+    Union,
+    Intersect,
+    Except,
+}
+
+impl fmt::Display for SetOperator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SetOperator::Union => write!(f, "UNION"),
+            SetOperator::Intersect => write!(f, "INTERSECT"),
+            SetOperator::Except => write!(f, "EXCEPT"),
+        }
+    }
+}
+
+// "expr [ASC|DESC] [NULLS FIRST|LAST]", a number picks a column of the result by position
+#[derive(Debug, Clone)]
+pub struct OrderItem {
+    pub expr: Expression,
+    pub descending: bool,
+    pub nulls_first: bool,
 }
 
-#[derive(Debug)]
+impl fmt::Display for OrderItem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} NULLS {}", self.expr, if self.descending { "DESC" } else { "ASC" }, if self.nulls_first { "FIRST" } else { "LAST" })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum FromItem {
+    Table {
+        name: String,
+        alias: Option<String>,
+    },
+    // a derived table, its rows are whatever the subquery returns
+    Subquery {
+        query: Box<SelectQuery>,
+        alias: String,
+    },
+}
+
+#[derive(Debug, Clone)]
 pub struct InsertQuery {
     pub table_name: String,
-    pub values: Vec<String>,
+    pub columns: Option<Vec<String>>, // the columns the values are for, all of them in order when not given
+    pub source: InsertSource,
+    pub on_conflict: Option<OnConflict>,
+    pub returning: Option<Vec<SelectItem>>,
+}
+
+// what to do with a row that would break a PRIMARY KEY or UNIQUE constraint instead of failing
+#[derive(Debug, Clone)]
+pub struct OnConflict {
+    pub columns: Vec<String>, // the constraint, any of them when empty
+    pub action: ConflictAction,
+}
+
+#[derive(Debug, Clone)]
+pub enum ConflictAction {
+    Nothing,
+    // the existing row is updated instead. the row that wasnt inserted is called excluded
+    Update {
+        updates: Vec<(String, Expression)>,
+        where_clause: Option<Expression>,
+    },
 }
 
-// #[derive(Debug)]
-// pub struct UpdateQuery {
-//     pub updates: Vec<String>,
-//     pub where_clause: Option<Expression>,
-// }
+#[derive(Debug, Clone)]
+pub enum InsertSource {
+    Values(Vec<Vec<Option<Expression>>>), // one list per row, None where DEFAULT was given
+    Select(Box<SelectQuery>),
+}
+
+#[derive(Debug, Clone)]
+pub struct UpdateQuery {
+    pub table_name: String,
+    pub updates: Vec<(String, Expression)>, // column, new value
+    pub where_clause: Option<Expression>,
+    pub returning: Option<Vec<SelectItem>>,
+}
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct DeleteQuery {
     pub table_name: String,
     pub where_clause: Option<Expression>,
+    pub returning: Option<Vec<SelectItem>>,
+}
+
+// an entry of a select list or RETURNING list
+#[derive(Debug, Clone)]
+pub enum SelectItem {
+    Wildcard, // * for every column
+    TableWildcard(String), // t.* for every column of the FROM item called t
+    Expression { expr: Expression, alias: Option<String> },
+}
+
+#[derive(Debug, Clone)]
+pub struct CreateQuery {
+    pub table_name: String,
+    pub columns: Vec<ColumnDef>,
+    pub constraints: Vec<TableConstraint>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ColumnDef {
+    pub name: String,
+    pub data_type: DataType,
+    pub not_null: bool,
+    pub default: Option<Expression>,
+    pub constraints: Vec<TableConstraint>, // PRIMARY KEY, UNIQUE and CHECK written on the column itself
+}
+
+// name is None when no CONSTRAINT name was given and one gets made up
+#[derive(Debug, Clone)]
+pub struct TableConstraint {
+    pub name: Option<String>,
+    pub kind: ConstraintKind,
+}
+
+#[derive(Debug, Clone)]
+pub enum ConstraintKind {
+    PrimaryKey(Vec<String>),
+    Unique(Vec<String>),
+    Check(Expression),
+    ForeignKey(ForeignKeyDef),
+}
+
+#[derive(Debug, Clone)]
+pub struct ForeignKeyDef {
+    pub columns: Vec<String>,
+    pub parent_table: String,
+    pub parent_columns: Vec<String>, // empty when the parents primary key is meant
+    pub on_delete: ReferentialAction,
+    pub on_update: ReferentialAction,
+    pub deferred: bool,
+}
+
+// vacuums every table when no table is given
+#[derive(Debug, Clone)]
+pub struct VacuumQuery {
+    pub table_name: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct DropQuery {
+    pub table_name: String,
+    pub if_exists: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct TruncateQuery {
+    pub table_name: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct AlterQuery {
+    pub table_name: String,
+    pub action: AlterAction,
+}
+
+#[derive(Debug, Clone)]
+pub enum AlterAction {
+    AddColumn(ColumnDef),
+    AddConstraint(TableConstraint), // CHECK or FOREIGN KEY
+    DropColumn(String),
+    RenameColumn { from: String, to: String },
+    RenameTable(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct DescribeQuery {
+    pub table_name: String,
+}
+
+// "COPY table [(columns)] FROM 'file' [WITH (options)]", "COPY table [(columns)] TO 'file' ..." or
+// "COPY (SELECT ...) TO 'file' ...". files are CSV and are read and written by the server
+#[derive(Debug, Clone)]
+pub struct CopyQuery {
+    pub source: CopySource,
+    pub to: bool, // writing the rows out rather than reading them in
+    pub path: String,
+    pub options: CopyOptions,
+}
+
+#[derive(Debug, Clone)]
+pub enum CopySource {
+    Table { name: String, columns: Option<Vec<String>> },
+    Query(Box<SelectQuery>),
+}
+
+#[derive(Debug, Clone)]
+pub struct CopyOptions {
+    pub header: bool,
+    pub delimiter: char,
+    pub null: String, // an unquoted field with this text is NULL
+    pub skip_errors: bool, // ON_ERROR SKIP leaves out rows that cant be inserted instead of failing
+}
+
+impl Default for CopyOptions {
+    fn default() -> Self {
+        CopyOptions { header: false, delimiter: ',', null: String::new(), skip_errors: false }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Expression {
+    BinaryOp {
+        left: Box<Expression>,
+        operator: String,
+        right: Box<Expression>,
+    },
+    UnaryOp {
+        operator: String, // NOT or -
+        operand: Box<Expression>,
+    },
+    IsNull {
+        operand: Box<Expression>,
+        negated: bool,
+    },
+    Column {
+        table: Option<String>, // for t.col
+        name: String,
+    },
+    Literal(Value),
+    // $n in a prepared statement, replaced by a literal when it is executed
+    Parameter(usize),
+    // a subquery returning one column, used as the value of its only row or NULL without one
+    Subquery(Box<SelectQuery>),
+    Exists(Box<SelectQuery>),
+    InSubquery {
+        operand: Box<Expression>,
+        query: Box<SelectQuery>,
+    },
+    // "operand IN (a, b, ...)", NOT IN is kept as NOT around it like with a subquery
+    InList {
+        operand: Box<Expression>,
+        list: Vec<Expression>,
+    },
+    // "operand BETWEEN low AND high", both ends included
+    Between {
+        operand: Box<Expression>,
+        low: Box<Expression>,
+        high: Box<Expression>,
+    },
+    Window(Box<WindowCall>),
+    // COUNT, SUM, AVG, MIN or MAX without OVER, folding the rows of a group into one value. the
+    // function is kept in capitals and COUNT(*) has no args
+    Aggregate {
+        function: String,
+        args: Vec<Expression>,
+    },
+    // a built-in scalar function, the name kept in capitals
+    Function {
+        name: String,
+        args: Vec<Expression>,
+    },
+    Cast {
+        operand: Box<Expression>,
+        data_type: DataType,
+    },
+    // "CASE [operand] WHEN ... THEN ... [ELSE ...] END". with an operand each WHEN is a value compared
+    // with it, without one a condition
+    Case {
+        operand: Option<Box<Expression>>,
+        branches: Vec<(Expression, Expression)>,
+        otherwise: Option<Box<Expression>>,
+    },
+}
+
+// "function(args) OVER ([PARTITION BY ...] [ORDER BY ...] [frame])". the function is kept in capitals
+// and COUNT(*) has no args
+#[derive(Debug, Clone)]
+pub struct WindowCall {
+    pub function: String,
+    pub args: Vec<Expression>,
+    pub partition_by: Vec<Expression>,
+    pub order_by: Vec<OrderItem>,
+    pub frame: Option<WindowFrame>,
+}
+
+impl WindowCall {
+    pub fn expressions(&self) -> impl Iterator<Item = &Expression> {
+        self.args.iter()
+            .chain(&self.partition_by)
+            .chain(self.order_by.iter().map(|item| &item.expr))
+            .chain(self.frame.iter().flat_map(|frame| [&frame.start, &frame.end]).filter_map(FrameBound::offset))
+    }
+
+    fn expressions_mut(&mut self) -> impl Iterator<Item = &mut Expression> {
+        self.args.iter_mut()
+            .chain(&mut self.partition_by)
+            .chain(self.order_by.iter_mut().map(|item| &mut item.expr))
+            .chain(self.frame.iter_mut().flat_map(|frame| [&mut frame.start, &mut frame.end]).filter_map(|bound| match bound {
+                FrameBound::Preceding(offset) | FrameBound::Following(offset) => Some(offset),
+                _ => None,
+            }))
+    }
+}
+
+// the rows of its partition a window function looks at for each row. ROWS counts rows, RANGE goes by
+// the value of the one ORDER BY expression and includes rows that tie with the current one
+#[derive(Debug, Clone)]
+pub struct WindowFrame {
+    pub range: bool,
+    pub start: FrameBound,
+    pub end: FrameBound,
+}
+
+#[derive(Debug, Clone)]
+pub enum FrameBound {
+    UnboundedPreceding,
+    Preceding(Expression),
+    CurrentRow,
+    Following(Expression),
+    UnboundedFollowing,
+}
+
+impl FrameBound {
+    fn offset(&self) -> Option<&Expression> {
+        match self {
+            FrameBound::Preceding(offset) | FrameBound::Following(offset) => Some(offset),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for FrameBound {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameBound::UnboundedPreceding => write!(f, "UNBOUNDED PRECEDING"),
+            FrameBound::Preceding(offset) => write!(f, "{} PRECEDING", offset),
+            FrameBound::CurrentRow => write!(f, "CURRENT ROW"),
+            FrameBound::Following(offset) => write!(f, "{} FOLLOWING", offset),
+            FrameBound::UnboundedFollowing => write!(f, "UNBOUNDED FOLLOWING"),
+        }
+    }
+}
+
+impl Expression {
+    // the expressions inside a function call, aggregate, CAST, CASE, IN list or BETWEEN
+    pub fn arguments(&self) -> Vec<&Expression> {
+        match self {
+            Expression::Function { args, .. } | Expression::Aggregate { args, .. } => args.iter().collect(),
+            Expression::InList { operand, list } => std::iter::once(&**operand).chain(list).collect(),
+            Expression::Between { operand, low, high } => vec![operand, low, high],
+            Expression::Cast { operand, .. } => vec![operand],
+            Expression::Case { operand, branches, otherwise } => operand.iter()
+                .map(|operand| &**operand)
+                .chain(branches.iter().flat_map(|(when, then)| [when, then]))
+                .chain(otherwise.iter().map(|otherwise| &**otherwise))
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    fn arguments_mut(&mut self) -> Vec<&mut Expression> {
+        match self {
+            Expression::Function { args, .. } | Expression::Aggregate { args, .. } => args.iter_mut().collect(),
+            Expression::InList { operand, list } => std::iter::once(&mut **operand).chain(list).collect(),
+            Expression::Between { operand, low, high } => vec![operand, low, high],
+            Expression::Cast { operand, .. } => vec![operand],
+            Expression::Case { operand, branches, otherwise } => operand.iter_mut()
+                .map(|operand| &mut **operand)
+                .chain(branches.iter_mut().flat_map(|(when, then)| [when, then]))
+                .chain(otherwise.iter_mut().map(|otherwise| &mut **otherwise))
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    pub fn references_column(&self, name: &str) -> bool {
+        match self {
+            Expression::Function { .. } | Expression::Aggregate { .. } | Expression::Cast { .. } | Expression::Case { .. } | Expression::InList { .. } | Expression::Between { .. } => self.arguments().iter().any(|arg| arg.references_column(name)),
+            Expression::BinaryOp { left, right, .. } => left.references_column(name) || right.references_column(name),
+            Expression::UnaryOp { operand, .. } | Expression::IsNull { operand, .. } => operand.references_column(name),
+            Expression::Column { name: column, .. } => column == name,
+            Expression::InSubquery { operand, .. } => operand.references_column(name),
+            Expression::Window(call) => call.expressions().any(|expr| expr.references_column(name)),
+            Expression::Literal(_) | Expression::Parameter(_) | Expression::Subquery(_) | Expression::Exists(_) => false,
+        }
+    }
+
+    // CHECK and DEFAULT expressions cant read other rows
+    pub fn contains_subquery(&self) -> bool {
+        match self {
+            Expression::Function { .. } | Expression::Aggregate { .. } | Expression::Cast { .. } | Expression::Case { .. } | Expression::InList { .. } | Expression::Between { .. } => self.arguments().iter().any(|arg| arg.contains_subquery()),
+            Expression::BinaryOp { left, right, .. } => left.contains_subquery() || right.contains_subquery(),
+            Expression::UnaryOp { operand, .. } | Expression::IsNull { operand, .. } => operand.contains_subquery(),
+            Expression::Column { .. } | Expression::Literal(_) | Expression::Parameter(_) => false,
+            Expression::Subquery(_) | Expression::Exists(_) | Expression::InSubquery { .. } => true,
+            Expression::Window(call) => call.expressions().any(Expression::contains_subquery),
+        }
+    }
+
+    // window functions inside the expression, not counting those of subqueries
+    pub fn contains_window(&self) -> bool {
+        match self {
+            Expression::Function { .. } | Expression::Aggregate { .. } | Expression::Cast { .. } | Expression::Case { .. } | Expression::InList { .. } | Expression::Between { .. } => self.arguments().iter().any(|arg| arg.contains_window()),
+            Expression::BinaryOp { left, right, .. } => left.contains_window() || right.contains_window(),
+            Expression::UnaryOp { operand, .. } | Expression::IsNull { operand, .. } | Expression::InSubquery { operand, .. } => operand.contains_window(),
+            Expression::Column { .. } | Expression::Literal(_) | Expression::Parameter(_) | Expression::Subquery(_) | Expression::Exists(_) => false,
+            Expression::Window(_) => true,
+        }
+    }
+
+    // add the window function calls in the expression to calls, outermost first
+    pub fn window_calls<'a>(&'a self, calls: &mut Vec<&'a Expression>) {
+        match self {
+            Expression::Function { .. } | Expression::Aggregate { .. } | Expression::Cast { .. } | Expression::Case { .. } | Expression::InList { .. } | Expression::Between { .. } => {
+                for arg in self.arguments() {
+                    arg.window_calls(calls);
+                }
+            }
+            Expression::BinaryOp { left, right, .. } => {
+                left.window_calls(calls);
+                right.window_calls(calls);
+            }
+            Expression::UnaryOp { operand, .. } | Expression::IsNull { operand, .. } | Expression::InSubquery { operand, .. } => operand.window_calls(calls),
+            Expression::Column { .. } | Expression::Literal(_) | Expression::Parameter(_) | Expression::Subquery(_) | Expression::Exists(_) => {}
+            Expression::Window(_) => calls.push(self),
+        }
+    }
+
+    // aggregates inside the expression, not counting those of subqueries and window functions
+    pub fn contains_aggregate(&self) -> bool {
+        match self {
+            Expression::Function { .. } | Expression::Cast { .. } | Expression::Case { .. } | Expression::InList { .. } | Expression::Between { .. } => self.arguments().iter().any(|arg| arg.contains_aggregate()),
+            Expression::BinaryOp { left, right, .. } => left.contains_aggregate() || right.contains_aggregate(),
+            Expression::UnaryOp { operand, .. } | Expression::IsNull { operand, .. } | Expression::InSubquery { operand, .. } => operand.contains_aggregate(),
+            Expression::Column { .. } | Expression::Literal(_) | Expression::Parameter(_) | Expression::Subquery(_) | Expression::Exists(_) | Expression::Window(_) => false,
+            Expression::Aggregate { .. } => true,
+        }
+    }
+
+    // add the aggregates in the expression to calls, outermost first
+    pub fn aggregate_calls<'a>(&'a self, calls: &mut Vec<&'a Expression>) {
+        match self {
+            Expression::Function { .. } | Expression::Cast { .. } | Expression::Case { .. } | Expression::InList { .. } | Expression::Between { .. } => {
+                for arg in self.arguments() {
+                    arg.aggregate_calls(calls);
+                }
+            }
+            Expression::BinaryOp { left, right, .. } => {
+                left.aggregate_calls(calls);
+                right.aggregate_calls(calls);
+            }
+            Expression::UnaryOp { operand, .. } | Expression::IsNull { operand, .. } | Expression::InSubquery { operand, .. } => operand.aggregate_calls(calls),
+            Expression::Column { .. } | Expression::Literal(_) | Expression::Parameter(_) | Expression::Subquery(_) | Expression::Exists(_) | Expression::Window(_) => {}
+            Expression::Aggregate { .. } => calls.push(self),
+        }
+    }
+
+    pub fn reads_table(&self, table: &str) -> bool {
+        match self {
+            Expression::Function { .. } | Expression::Aggregate { .. } | Expression::Cast { .. } | Expression::Case { .. } | Expression::InList { .. } | Expression::Between { .. } => self.arguments().iter().any(|arg| arg.reads_table(table)),
+            Expression::BinaryOp { left, right, .. } => left.reads_table(table) || right.reads_table(table),
+            Expression::UnaryOp { operand, .. } | Expression::IsNull { operand, .. } => operand.reads_table(table),
+            Expression::Column { .. } | Expression::Literal(_) | Expression::Parameter(_) => false,
+            Expression::Subquery(query) | Expression::Exists(query) => query.reads_table(table),
+            Expression::InSubquery { operand, query } => operand.reads_table(table) || query.reads_table(table),
+            Expression::Window(call) => call.expressions().any(|expr| expr.reads_table(table)),
+        }
+    }
+
+    pub fn rename_column(&mut self, from: &str, to: &str) {
+        match self {
+            Expression::Function { .. } | Expression::Aggregate { .. } | Expression::Cast { .. } | Expression::Case { .. } | Expression::InList { .. } | Expression::Between { .. } => {
+                for arg in self.arguments_mut() {
+                    arg.rename_column(from, to);
+                }
+            }
+            Expression::BinaryOp { left, right, .. } => {
+                left.rename_column(from, to);
+                right.rename_column(from, to);
+            }
+            Expression::UnaryOp { operand, .. } | Expression::IsNull { operand, .. } => operand.rename_column(from, to),
+            Expression::Column { name: column, .. } => {
+                if column == from {
+                    *column = to.to_string();
+                }
+            }
+            Expression::InSubquery { operand, .. } => operand.rename_column(from, to),
+            Expression::Window(call) => call.expressions_mut().for_each(|expr| expr.rename_column(from, to)),
+            Expression::Literal(_) | Expression::Parameter(_) | Expression::Subquery(_) | Expression::Exists(_) => {}
+        }
+    }
+
+    // puts values[n - 1] in place of each $n, subqueries included
+    pub fn bind(&mut self, values: &[Value]) -> Result<(), String> {
+        match self {
+            Expression::Function { .. } | Expression::Aggregate { .. } | Expression::Cast { .. } | Expression::Case { .. } | Expression::InList { .. } | Expression::Between { .. } => {
+                for arg in self.arguments_mut() {
+                    arg.bind(values)?;
+                }
+            }
+            Expression::BinaryOp { left, right, .. } => {
+                left.bind(values)?;
+                right.bind(values)?;
+            }
+            Expression::UnaryOp { operand, .. } | Expression::IsNull { operand, .. } => operand.bind(values)?,
+            Expression::Parameter(n) => {
+                let value = values.get(*n - 1).ok_or_else(|| format!("Parameter ${} has no value", n))?;
+                *self = Expression::Literal(value.clone());
+            }
+            Expression::Subquery(query) | Expression::Exists(query) => query.bind(values)?,
+            Expression::InSubquery { operand, query } => {
+                operand.bind(values)?;
+                query.bind(values)?;
+            }
+            Expression::Window(call) => {
+                for expr in call.expressions_mut() {
+                    expr.bind(values)?;
+                }
+            }
+            Expression::Column { .. } | Expression::Literal(_) => {}
+        }
+        Ok(())
+    }
+}
+
+// written back out as SQL that parses to the same expression, which is how CHECK and DEFAULT
+// expressions are kept in the catalog. every operation is bracketed so precedence cant change
+impl fmt::Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Expression::BinaryOp { left, operator, right } => write!(f, "({} {} {})", left, operator, right),
+            Expression::UnaryOp { operator, operand } if operator == "-" => write!(f, "(-{})", operand),
+            Expression::UnaryOp { operator, operand } => write!(f, "({} {})", operator, operand),
+            Expression::IsNull { operand, negated } => write!(f, "({} IS {}NULL)", operand, if *negated { "NOT " } else { "" }),
+            Expression::Column { table: Some(table), name } => write!(f, "{}.{}", quote_identifier(table), quote_identifier(name)),
+            Expression::Column { table: None, name } => write!(f, "{}", quote_identifier(name)),
+            Expression::Literal(Value::Text(text)) => write!(f, "'{}'", text.replace('\'', "''")),
+            Expression::Literal(Value::Boolean(b)) => write!(f, "{}", if *b { "TRUE" } else { "FALSE" }),
+            Expression::Literal(value) => write!(f, "{}", value),
+            Expression::Parameter(n) => write!(f, "${}", n),
+            Expression::Subquery(query) => write!(f, "({})", query),
+            Expression::Exists(query) => write!(f, "(EXISTS ({}))", query),
+            Expression::InSubquery { operand, query } => write!(f, "({} IN ({}))", operand, query),
+            Expression::InList { operand, list } => {
+                let list: Vec<String> = list.iter().map(|item| item.to_string()).collect();
+                write!(f, "({} IN ({}))", operand, list.join(", "))
+            }
+            Expression::Between { operand, low, high } => write!(f, "({} BETWEEN {} AND {})", operand, low, high),
+            Expression::Function { name, args } => {
+                let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+                write!(f, "{}({})", name, args.join(", "))
+            }
+            Expression::Aggregate { function, args } if args.is_empty() => write!(f, "{}(*)", function),
+            Expression::Aggregate { function, args } => {
+                let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+                write!(f, "{}({})", function, args.join(", "))
+            }
+            Expression::Cast { operand, data_type } => write!(f, "CAST({} AS {})", operand, data_type),
+            Expression::Case { operand, branches, otherwise } => {
+                write!(f, "CASE")?;
+                if let Some(operand) = operand {
+                    write!(f, " {}", operand)?;
+                }
+                for (when, then) in branches {
+                    write!(f, " WHEN {} THEN {}", when, then)?;
+                }
+                if let Some(otherwise) = otherwise {
+                    write!(f, " ELSE {}", otherwise)?;
+                }
+                write!(f, " END")
+            }
+            Expression::Window(call) => {
+                let args: Vec<String> = call.args.iter().map(|arg| arg.to_string()).collect();
+                write!(f, "{}({}) OVER (", call.function, if args.is_empty() && call.function == "COUNT" { "*".to_string() } else { args.join(", ") })?;
+                let mut clauses = Vec::new();
+                if !call.partition_by.is_empty() {
+                    let partition: Vec<String> = call.partition_by.iter().map(|expr| expr.to_string()).collect();
+                    clauses.push(format!("PARTITION BY {}", partition.join(", ")));
+                }
+                if !call.order_by.is_empty() {
+                    let order: Vec<String> = call.order_by.iter().map(|item| item.to_string()).collect();
+                    clauses.push(format!("ORDER BY {}", order.join(", ")));
+                }
+                if let Some(frame) = &call.frame {
+                    clauses.push(format!("{} BETWEEN {} AND {}", if frame.range { "RANGE" } else { "ROWS" }, frame.start, frame.end));
+                }
+                write!(f, "{})", clauses.join(" "))
+            }
+        }
+    }
+}
+
+impl fmt::Display for SelectQuery {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SELECT {}", if self.distinct { "DISTINCT " } else { "" })?;
+        for (i, item) in self.items.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            match item {
+                SelectItem::Wildcard => write!(f, "*")?,
+                SelectItem::TableWildcard(table) => write!(f, "{}.*", quote_identifier(table))?,
+                SelectItem::Expression { expr, alias: Some(alias) } => write!(f, "{} AS {}", expr, quote_identifier(alias))?,
+                SelectItem::Expression { expr, alias: None } => write!(f, "{}", expr)?,
+            }
+        }
+        match &self.from {
+            Some(FromItem::Table { name, alias: Some(alias) }) => write!(f, " FROM {} AS {}", quote_identifier(name), quote_identifier(alias))?,
+            Some(FromItem::Table { name, alias: None }) => write!(f, " FROM {}", quote_identifier(name))?,
+            Some(FromItem::Subquery { query, alias }) => write!(f, " FROM ({}) AS {}", query, quote_identifier(alias))?,
+            None => {}
+        }
+        if let Some(where_clause) = &self.where_clause {
+            write!(f, " WHERE {}", where_clause)?;
+        }
+        for (i, expr) in self.group_by.iter().enumerate() {
+            write!(f, "{}{}", if i == 0 { " GROUP BY " } else { ", " }, expr)?;
+        }
+        if let Some(having) = &self.having {
+            write!(f, " HAVING {}", having)?;
+        }
+        for operation in &self.set_operations {
+            write!(f, " {}{} {}", operation.operator, if operation.all { " ALL" } else { "" }, operation.query)?;
+        }
+        for (i, item) in self.order_by.iter().enumerate() {
+            write!(f, "{}{}", if i == 0 { " ORDER BY " } else { ", " }, item)?;
+        }
+        if let Some(limit) = &self.limit {
+            write!(f, " LIMIT {}", limit)?;
+        }
+        if let Some(offset) = &self.offset {
+            write!(f, " OFFSET {}", offset)?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses a list of tokens into a `Query` structure.
+///
+/// # Arguments
+/// - `tokens`: A vector of tokens generated by the lexer.
+///
+/// # Returns
+/// - `Result<Query, (String, usize)>`: A `Query` structure on success, or an error message and how
+///   many tokens had been read when parsing failed.
+pub fn parse_tokens_counted(tokens: Vec<Token>) -> Result<Query, (String, usize)> {
+    let total = tokens.len();
+    let mut tokens_iter = tokens.into_iter().peekable();
+    let query = parse_statement(&mut tokens_iter).and_then(|query| match tokens_iter.next() {
+        None => Ok(query),
+        Some(token) => Err(format!("Unexpected {:?} at end of statement", token)),
+    });
+    query.map_err(|e| (e, total - tokens_iter.len()))
+}
+
+fn parse_statement(tokens: &mut Peekable<std::vec::IntoIter<Token>>) -> Result<Query, String> {
+    match tokens.next() {
+        Some(Token::Select) => parse_select_query(tokens),
+        Some(Token::Insert) => parse_insert_query(tokens),
+        Some(Token::Update) => parse_update_query(tokens),
+        Some(Token::Delete) => parse_delete_query(tokens),
+        Some(Token::Create) => parse_create_query(tokens),
+        Some(Token::Vacuum) => parse_vacuum_query(tokens),
+        Some(Token::Drop) => parse_drop_query(tokens),
+        Some(Token::Truncate) => parse_truncate_query(tokens),
+        Some(Token::Alter) => parse_alter_query(tokens),
+        Some(Token::Show) => parse_show_query(tokens),
+        Some(Token::Describe) => parse_describe_query(tokens),
+        Some(Token::Identifier(word)) if word.eq_ignore_ascii_case("WITH") => parse_with_query(tokens),
+        Some(Token::Identifier(word)) if word.eq_ignore_ascii_case("PREPARE") => parse_prepare_query(tokens),
+        Some(Token::Identifier(word)) if word.eq_ignore_ascii_case("EXECUTE") => parse_execute_query(tokens),
+        Some(Token::Identifier(word)) if word.eq_ignore_ascii_case("DEALLOCATE") => parse_deallocate_query(tokens),
+        Some(Token::Identifier(word)) if word.eq_ignore_ascii_case("COPY") => parse_copy_query(tokens),
+        Some(Token::Identifier(word)) if word.eq_ignore_ascii_case("BACKUP") => parse_backup_query(tokens),
+        _ => Err("Unsupported query type".to_string()),
+    }
+}
+
+fn parse_copy_query(tokens: &mut Peekable<std::vec::IntoIter<Token>>) -> Result<Query, String> {
+    let source = if tokens.peek() == Some(&Token::ParenOpen) {
+        tokens.next();
+        if tokens.next() != Some(Token::Select) {
+            return Err("Expected SELECT after ( in COPY".to_string());
+        }
+        let query = parse_select(tokens)?;
+        if tokens.next() != Some(Token::ParenClose) {
+            return Err("Expected ) to close the query in COPY".to_string());
+        }
+        CopySource::Query(Box::new(query))
+    } else {
+        let name = expect_identifier(tokens, "table name or (SELECT ...) after COPY")?;
+        let columns = match tokens.peek() {
+            Some(Token::ParenOpen) => Some(parse_column_list(tokens, "COPY")?),
+            _ => None,
+        };
+        CopySource::Table { name, columns }
+    };
+
+    let to = if keyword(tokens, "TO") {
+        true
+    } else {
+        expect_keyword(tokens, "FROM", "or TO after the table in COPY")?;
+        false
+    };
+    if !to && matches!(source, CopySource::Query(_)) {
+        return Err("COPY (SELECT ...) can only be used with TO".to_string());
+    }
+    let path = match tokens.next() {
+        Some(Token::StringLiteral(path)) => path,
+        _ => return Err(format!("Expected a file name in quotes after {}", if to { "TO" } else { "FROM" })),
+    };
+
+    let mut options = CopyOptions::default();
+    if keyword(tokens, "WITH") {
+        if tokens.next() != Some(Token::ParenOpen) {
+            return Err("Expected ( after WITH in COPY".to_string());
+        }
+        loop {
+            let option = expect_identifier(tokens, "COPY option")?;
+            match option.to_uppercase().as_str() {
+                // HEADER on its own is the same as HEADER TRUE
+                "HEADER" => {
+                    options.header = !keyword(tokens, "FALSE");
+                    keyword(tokens, "TRUE");
+                }
+                "DELIMITER" => match tokens.next() {
+                    Some(Token::StringLiteral(text)) if text.chars().count() == 1 && text != "\"" && text != "\n" && text != "\r" => {
+                        options.delimiter = text.chars().next().unwrap_or(',');
+                    }
+                    _ => return Err("DELIMITER has to be a single character in quotes, other than a quote or line break".to_string()),
+                },
+                "NULL" => match tokens.next() {
+                    Some(Token::StringLiteral(text)) => options.null = text,
+                    _ => return Err("Expected the text that stands for NULL in quotes after NULL".to_string()),
+                },
+                "FORMAT" => {
+                    if !keyword(tokens, "CSV") {
+                        return Err("CSV is the only FORMAT COPY supports".to_string());
+                    }
+                }
+                "ON_ERROR" => {
+                    options.skip_errors = if keyword(tokens, "SKIP") {
+                        true
+                    } else {
+                        expect_keyword(tokens, "STOP", "or SKIP after ON_ERROR")?;
+                        false
+                    };
+                }
+                _ => return Err(format!("Unknown COPY option {}, expected HEADER, DELIMITER, NULL, FORMAT or ON_ERROR", option)),
+            }
+            match tokens.next() {
+                Some(Token::Comma) => continue,
+                Some(Token::ParenClose) => break,
+                _ => return Err("Expected , or ) in COPY options".to_string()),
+            }
+        }
+    }
+    if to && options.skip_errors {
+        return Err("ON_ERROR can only be used with COPY FROM".to_string());
+    }
+    expect_end(tokens, "COPY")?;
+    Ok(Query::Copy(CopyQuery { source, to, path, options }))
+}
+
+fn parse_backup_query(tokens: &mut Peekable<std::vec::IntoIter<Token>>) -> Result<Query, String> {
+    expect_keyword(tokens, "TO", "after BACKUP")?;
+    let path = match tokens.next() {
+        Some(Token::StringLiteral(path)) => path,
+        _ => return Err("Expected a file name in quotes after TO".to_string()),
+    };
+    expect_end(tokens, "BACKUP")?;
+    Ok(Query::Backup(path))
+}
+
+fn parse_prepare_query(tokens: &mut Peekable<std::vec::IntoIter<Token>>) -> Result<Query, String> {
+    // prepare should be like "PREPARE name [(type, ...)] AS statement"
+    let name = expect_identifier(tokens, "statement name after PREPARE")?;
+    let mut types = Vec::new();
+    if tokens.peek() == Some(&Token::ParenOpen) {
+        tokens.next();
+        loop {
+            let type_name = expect_identifier(tokens, &format!("parameter type in PREPARE {}", name))?;
+            types.push(DataType::from_name(&type_name).ok_or_else(|| format!("Unknown type {} in PREPARE {}", type_name, name))?);
+            skip_type_arguments(tokens)?;
+            match tokens.next() {
+                Some(Token::Comma) => {}
+                Some(Token::ParenClose) => break,
+                _ => return Err(format!("Expected , or ) in the parameter types of {}", name)),
+            }
+        }
+    }
+    expect_keyword(tokens, "AS", &format!("after PREPARE {}", name))?;
+
+    let highest = tokens.clone()
+        .filter_map(|token| match token {
+            Token::Parameter(n) => n,
+            _ => None,
+        })
+        .max()
+        .unwrap_or(0);
+    let statement = parse_statement(tokens)?;
+    Ok(Query::Prepare(PrepareQuery { name, parameters: highest.max(types.len()), types, statement: Box::new(statement) }))
+}
+
+fn parse_execute_query(tokens: &mut Peekable<std::vec::IntoIter<Token>>) -> Result<Query, String> {
+    // execute should be like "EXECUTE name [(value, ...)]"
+    let name = expect_identifier(tokens, "statement name after EXECUTE")?;
+    let mut args = Vec::new();
+    if tokens.peek() == Some(&Token::ParenOpen) {
+        tokens.next();
+        loop {
+            args.push(parse_expression(tokens)?);
+            match tokens.next() {
+                Some(Token::Comma) => {}
+                Some(Token::ParenClose) => break,
+                _ => return Err(format!("Expected , or ) in the values for {}", name)),
+            }
+        }
+    }
+    expect_end(tokens, "EXECUTE")?;
+    Ok(Query::Execute(ExecuteQuery { name, args }))
+}
+
+fn parse_deallocate_query(tokens: &mut Peekable<std::vec::IntoIter<Token>>) -> Result<Query, String> {
+    // deallocate should be like "DEALLOCATE [PREPARE] name" or "DEALLOCATE [PREPARE] ALL"
+    keyword(tokens, "PREPARE");
+    let name = expect_identifier(tokens, "statement name or ALL after DEALLOCATE")?;
+    expect_end(tokens, "DEALLOCATE")?;
+    Ok(Query::Deallocate(if name.eq_ignore_ascii_case("ALL") { None } else { Some(name) }))
+}
+
+fn parse_select_query(tokens: &mut Peekable<std::vec::IntoIter<Token>>) -> Result<Query, String> {
+    let query = parse_select(tokens)?;
+    expect_end(tokens, "SELECT")?;
+    Ok(Query::Select(query))
+}
+
+fn parse_with_query(tokens: &mut Peekable<std::vec::IntoIter<Token>>) -> Result<Query, String> {
+    let recursive = keyword(tokens, "RECURSIVE");
+    let mut tables = Vec::new();
+    loop {
+        let name = expect_identifier(tokens, "name after WITH")?;
+        let columns = match tokens.peek() {
+            Some(Token::ParenOpen) => Some(parse_column_list(tokens, "WITH")?),
+            _ => None,
+        };
+        expect_keyword(tokens, "AS", &format!("after {} in WITH", name))?;
+        if tokens.next() != Some(Token::ParenOpen) || tokens.next() != Some(Token::Select) {
+            return Err(format!("Expected (SELECT ...) after {} AS", name));
+        }
+        let mut query = parse_select(tokens)?;
+        // a table is only recursive if the SELECT after its last UNION reads it
+        let step = match query.set_operations.last() {
+            Some(last) if recursive && query.order_by.is_empty() && query.limit.is_none() && query.offset.is_none() && last.operator == SetOperator::Union && last.query.reads_table(&name) => {
+                query.set_operations.pop().map(|last| (last.all, last.query))
+            }
+            _ => None,
+        };
+        if tokens.next() != Some(Token::ParenClose) {
+            return Err(format!("Expected ) to close the query of {}", name));
+        }
+        tables.push(CommonTable { name, columns, query, step });
+
+        if tokens.peek() != Some(&Token::Comma) {
+            break;
+        }
+        tokens.next();
+    }
+
+    if tokens.next() != Some(Token::Select) {
+        return Err("Expected SELECT after the WITH tables".to_string());
+    }
+    let query = parse_select(tokens)?;
+    expect_end(tokens, "WITH")?;
+    Ok(Query::With(WithQuery { recursive, tables, query }))
+}
+
+// the part of a SELECT after the SELECT keyword, also used by INSERT ... SELECT and subqueries
+fn parse_select(tokens: &mut Peekable<std::vec::IntoIter<Token>>) -> Result<SelectQuery, String> {
+    let mut query = parse_single_select(tokens)?;
+    loop {
+        let operator = if keyword(tokens, "UNION") {
+            SetOperator::Union
+        } else if keyword(tokens, "INTERSECT") {
+            SetOperator::Intersect
+        } else if keyword(tokens, "EXCEPT") {
+            SetOperator::Except
+        } else {
+            break;
+        };
+        let all = keyword(tokens, "ALL");
+        if tokens.next() != Some(Token::Select) {
+            return Err(format!("Expected SELECT after {}", operator));
+        }
+        query.set_operations.push(SetOperation { operator, all, query: parse_single_select(tokens)? });
+    }
+
+    if keywords_ahead(tokens, &["ORDER", "BY"]) {
+        query.order_by = parse_order_by(tokens)?;
+    }
+    if keyword(tokens, "LIMIT") {
+        query.limit = Some(parse_expression(tokens)?);
+    }
+    if keyword(tokens, "OFFSET") {
+        query.offset = Some(parse_expression(tokens)?);
+    }
+    Ok(query)
+}
+
+// the list after ORDER BY
+fn parse_order_by(tokens: &mut Peekable<std::vec::IntoIter<Token>>) -> Result<Vec<OrderItem>, String> {
+    let mut order_by = Vec::new();
+    loop {
+        let expr = parse_expression(tokens)?;
+        let descending = if keyword(tokens, "DESC") {
+            true
+        } else {
+            keyword(tokens, "ASC");
+            false
+        };
+        // NULLs sort as if larger than any value unless told otherwise
+        let nulls_first = if keyword(tokens, "NULLS") {
+            if keyword(tokens, "FIRST") {
+                true
+            } else {
+                expect_keyword(tokens, "LAST", "or FIRST after NULLS")?;
+                false
+            }
+        } else {
+            descending
+        };
+        order_by.push(OrderItem { expr, descending, nulls_first });
+        if tokens.peek() != Some(&Token::Comma) {
+            return Ok(order_by);
+        }
+        tokens.next();
+    }
+}
+
+// one SELECT without the set operations, ORDER BY or LIMIT that can follow it
+fn parse_single_select(tokens: &mut Peekable<std::vec::IntoIter<Token>>) -> Result<SelectQuery, String> {
+    if is_table_select(tokens) {
+        return parse_table_select(tokens);
+    }
+
+    let distinct = keyword(tokens, "DISTINCT");
+    if !distinct {
+        keyword(tokens, "ALL");
+    }
+    let items = parse_select_items(tokens)?;
+    let from = if keyword(tokens, "FROM") {
+        Some(parse_from_item(tokens)?)
+    } else {
+        None
+    };
+    let where_clause = parse_where(tokens)?;
+    if where_clause.as_ref().is_some_and(Expression::contains_aggregate) {
+        return Err("Aggregates cannot be used in WHERE".to_string());
+    }
+    let mut group_by = Vec::new();
+    if keywords_ahead(tokens, &["GROUP", "BY"]) {
+        loop {
+            let expr = parse_expression(tokens)?;
+            if expr.contains_aggregate() {
+                return Err("Aggregates cannot be used in GROUP BY".to_string());
+            }
+            group_by.push(expr);
+            if tokens.peek() != Some(&Token::Comma) {
+                break;
+            }
+            tokens.next();
+        }
+    }
+    let having = if keyword(tokens, "HAVING") { Some(parse_expression(tokens)?) } else { None };
+    Ok(SelectQuery {
+        distinct,
+        items,
+        from,
+        where_clause,
+        group_by,
+        having,
+        set_operations: Vec::new(),
+        order_by: Vec::new(),
+        limit: None,
+        offset: None,
+    })
+}
+
+// "table [[AS] alias]" or "(SELECT ...) [AS] alias" after FROM
+fn parse_from_item(tokens: &mut Peekable<std::vec::IntoIter<Token>>) -> Result<FromItem, String> {
+    match tokens.next() {
+        Some(Token::Identifier(name) | Token::QuotedIdentifier(name)) => Ok(FromItem::Table { name, alias: parse_alias(tokens)? }),
+        Some(Token::ParenOpen) => {
+            if tokens.next() != Some(Token::Select) {
+                return Err("Expected SELECT after ( in FROM".to_string());
+            }
+            let query = parse_select(tokens)?;
+            if tokens.next() != Some(Token::ParenClose) {
+                return Err("Expected ) to close the subquery in FROM".to_string());
+            }
+            let alias = parse_alias(tokens)?.ok_or("A subquery in FROM needs a name, like (SELECT ...) AS name")?;
+            Ok(FromItem::Subquery { query: Box::new(query), alias })
+        }
+        _ => Err("Expected a table name or subquery after FROM".to_string()),
+    }
+}
+
+// words that can follow a FROM item or the table of the older SELECT form, so cant be taken as an
+// alias or column name there
+const CLAUSE_KEYWORDS: [&str; 10] = ["ON", "RETURNING", "GROUP", "HAVING", "UNION", "INTERSECT", "EXCEPT", "ORDER", "LIMIT", "OFFSET"];
+
+fn parse_alias(tokens: &mut Peekable<std::vec::IntoIter<Token>>) -> Result<Option<String>, String> {
+    if keyword(tokens, "AS") {
+        return expect_identifier(tokens, "name after AS").map(Some);
+    }
+    match tokens.peek() {
+        Some(Token::Identifier(word)) if !CLAUSE_KEYWORDS.iter().any(|k| word.eq_ignore_ascii_case(k)) => {
+            expect_identifier(tokens, "alias").map(Some)
+        }
+        Some(Token::QuotedIdentifier(_)) => expect_identifier(tokens, "alias").map(Some),
+        _ => Ok(None),
+    }
+}
+
+// the older form names the table straight after SELECT, possibly followed by column names. its
+// told apart by being only names and commas up to the WHERE or the end, none of them FROM
+fn is_table_select(tokens: &Peekable<std::vec::IntoIter<Token>>) -> bool {
+    let mut ahead = tokens.clone();
+    let mut first = true;
+    loop {
+        match ahead.next() {
+            Some(Token::Identifier(word)) if !first && CLAUSE_KEYWORDS.iter().any(|k| word.eq_ignore_ascii_case(k)) => return true,
+            Some(Token::Identifier(word)) => {
+                if ["FROM", "NULL", "TRUE", "FALSE", "NOT", "EXISTS", "CASE", "DISTINCT", "ALL"].iter().any(|k| word.eq_ignore_ascii_case(k)) {
+                    return false;
+                }
+                // a name followed by ( or . is a function or a qualified column
+                if matches!(ahead.peek(), Some(Token::Dot | Token::ParenOpen)) {
+                    return false;
+                }
+            }
+            Some(Token::QuotedIdentifier(_)) if matches!(ahead.peek(), Some(Token::Dot)) => return false,
+            Some(Token::QuotedIdentifier(_)) => {}
+            Some(Token::Comma) if !first => {}
+            None | Some(Token::Where | Token::Semicolon | Token::ParenClose) => return !first,
+            _ => return false,
+        }
+        first = false;
+    }
+}
+
+fn parse_table_select(tokens: &mut Peekable<std::vec::IntoIter<Token>>) -> Result<SelectQuery, String> {
+    let table_name = expect_identifier(tokens, "table name after SELECT")?;
+    // the columns listed after the table name, every column without any
+    let mut items = Vec::new();
+    while let Some(Token::Identifier(_) | Token::QuotedIdentifier(_) | Token::Comma) = tokens.peek() {
+        if matches!(tokens.peek(), Some(Token::Identifier(word)) if CLAUSE_KEYWORDS.iter().any(|k| word.eq_ignore_ascii_case(k))) {
+            break;
+        }
+        if let Some(Token::Identifier(name) | Token::QuotedIdentifier(name)) = tokens.next() {
+            items.push(SelectItem::Expression { expr: Expression::Column { table: None, name }, alias: None });
+        }
+    }
+    if items.is_empty() {
+        items.push(SelectItem::Wildcard);
+    }
+    let where_clause = parse_where(tokens)?;
+
+    Ok(SelectQuery {
+        distinct: false,
+        items,
+        from: Some(FromItem::Table { name: table_name, alias: None }),
+        where_clause,
+        group_by: Vec::new(),
+        having: None,
+        set_operations: Vec::new(),
+        order_by: Vec::new(),
+        limit: None,
+        offset: None,
+    })
+}
+
+fn parse_insert_query(tokens: &mut Peekable<std::vec::IntoIter<Token>>) -> Result<Query, String> {
+    // insert should be like "INSERT [INTO] table_name [(col1, col2)] VALUES (...), (...) [RETURNING ...]"
+    // or "INSERT [INTO] table_name [(col1, col2)] SELECT ... [RETURNING ...]"
+    keyword(tokens, "INTO");
+    let table_name = if let Some(Token::Identifier(name) | Token::QuotedIdentifier(name)) = tokens.next() {
+        name
+    } else {
+        return Err("Expected table name after INSERT".to_string());
+    };
+    let columns = if tokens.peek() == Some(&Token::ParenOpen) {
+        Some(parse_column_list(tokens, "INSERT")?)
+    } else {
+        None
+    };
+
+    let source = match tokens.next() {
+        Some(Token::Values) => {
+            let mut rows = Vec::new();
+            loop {
+                rows.push(parse_insert_row(tokens)?);
+                if tokens.peek() != Some(&Token::Comma) {
+                    break;
+                }
+                tokens.next();
+            }
+            InsertSource::Values(rows)
+        }
+        Some(Token::Select) => InsertSource::Select(Box::new(parse_select(tokens)?)),
+        _ => return Err("Expected VALUES or SELECT after INSERT".to_string()),
+    };
+    let on_conflict = parse_on_conflict(tokens)?;
+    let returning = parse_returning(tokens)?;
+    expect_end(tokens, "INSERT")?;
+
+    Ok(Query::Insert(InsertQuery { table_name, columns, source, on_conflict, returning }))
+}
+
+// "ON CONFLICT [(cols)] DO NOTHING" or "ON CONFLICT (cols) DO UPDATE SET col = expr, ... [WHERE ...]"
+fn parse_on_conflict(tokens: &mut Peekable<std::vec::IntoIter<Token>>) -> Result<Option<OnConflict>, String> {
+    if !keywords_ahead(tokens, &["ON", "CONFLICT"]) {
+        return Ok(None);
+    }
+    let columns = if tokens.peek() == Some(&Token::ParenOpen) {
+        parse_column_list(tokens, "ON CONFLICT")?
+    } else {
+        Vec::new()
+    };
+    expect_keyword(tokens, "DO", "after ON CONFLICT")?;
+    let action = if keyword(tokens, "NOTHING") {
+        ConflictAction::Nothing
+    } else if tokens.peek() == Some(&Token::Update) {
+        tokens.next();
+        if tokens.next() != Some(Token::Set) {
+            return Err("Expected SET after DO UPDATE".to_string());
+        }
+        if columns.is_empty() {
+            return Err("ON CONFLICT DO UPDATE needs the columns of the constraint, like ON CONFLICT (id)".to_string());
+        }
+        let updates = parse_assignments(tokens, "DO UPDATE")?;
+        let where_clause = parse_where(tokens)?;
+        ConflictAction::Update { updates, where_clause }
+    } else {
+        return Err("Expected NOTHING or UPDATE after ON CONFLICT DO".to_string());
+    };
+    Ok(Some(OnConflict { columns, action }))
 }
 
-#[derive(Debug)]
-pub struct CreateQuery {
-    pub table_name: String,
-    pub columns: Vec<String>,
+// "col = expr, col = expr" after SET
+fn parse_assignments(tokens: &mut Peekable<std::vec::IntoIter<Token>>, context: &str) -> Result<Vec<(String, Expression)>, String> {
+    let mut updates = Vec::new();
+    loop {
+        let column = expect_identifier(tokens, &format!("column name to SET in {}", context))?;
+        if tokens.next() != Some(Token::Operator("=".to_string())) {
+            return Err("Expected = after column name".to_string());
+        }
+        updates.push((column, parse_expression(tokens)?));
+        if tokens.peek() != Some(&Token::Comma) {
+            return Ok(updates);
+        }
+        tokens.next();
+    }
 }
 
-#[derive(Debug)]
-pub enum Expression {
-    BinaryOp {
-        left: Box<Expression>,
-        operator: String,
-        right: Box<Expression>,
-    },
-    Column(String),
-    Value(String),
+// one bracketed row of VALUES
+fn parse_insert_row(tokens: &mut Peekable<std::vec::IntoIter<Token>>) -> Result<Vec<Option<Expression>>, String> {
+    if tokens.next() != Some(Token::ParenOpen) {
+        return Err("Expected ( after VALUES".to_string());
+    }
+    let mut values = Vec::new();
+    loop {
+        if keyword(tokens, "DEFAULT") {
+            values.push(None);
+        } else {
+            values.push(Some(parse_expression(tokens)?));
+        }
+        match tokens.next() {
+            Some(Token::Comma) => continue,
+            Some(Token::ParenClose) => return Ok(values),
+            _ => return Err("Expected , or ) in INSERT values".to_string()),
+        }
+    }
 }
 
-/// Parses a list of tokens into a `Query` structure.
-///
-/// # Arguments
-/// - `tokens`: A vector of tokens generated by the lexer.
-///
-/// # Returns
-/// - `Result<Query, String>`: A `Query` structure on success, or an error message on failure.
-pub fn parse_tokens(tokens: Vec<Token>) -> Result<Query, String> {
-    let mut tokens_iter = tokens.into_iter().peekable();
-
-    match tokens_iter.next() {
-        Some(Token::Select) => parse_select_query(&mut tokens_iter),
-        Some(Token::Insert) => parse_insert_query(&mut tokens_iter),
-        // Some(Token::Update) => parse_update_query(&mut tokens_iter),
-        Some(Token::Delete) => parse_delete_query(&mut tokens_iter),
-        Some(Token::Create) => parse_create_query(&mut tokens_iter),
-        _ => Err("Unsupported query type".to_string()),
+// "RETURNING *" or "RETURNING expr, expr" at the end of INSERT, UPDATE and DELETE
+fn parse_returning(tokens: &mut Peekable<std::vec::IntoIter<Token>>) -> Result<Option<Vec<SelectItem>>, String> {
+    if !keyword(tokens, "RETURNING") {
+        return Ok(None);
     }
+    parse_select_items(tokens).map(Some)
 }
 
-fn parse_select_query(tokens: &mut Peekable<std::vec::IntoIter<Token>>) -> Result<Query, String> {
-    let mut columns = Vec::new();
-    let mut table_name = String::new();
-    // peek to not consume 'where'
-    while let Some(token_ref) = tokens.peek() {
-        match token_ref {
-            Token::Identifier(_) => {
-                // just sets table name to first identifier found
-                if table_name.is_empty() {
-                    if let Some(Token::Identifier(name)) = tokens.next() {
-                        table_name = name;
-                    }
-                } else {
-                if let Some(Token::Identifier(column)) = tokens.next() {
-                        columns.push(column);
+// "*, t.*, expr [[AS] alias], ..." after SELECT or RETURNING
+fn parse_select_items(tokens: &mut Peekable<std::vec::IntoIter<Token>>) -> Result<Vec<SelectItem>, String> {
+    let mut items = Vec::new();
+    loop {
+        let mut ahead = tokens.clone();
+        if tokens.peek() == Some(&Token::Operator("*".to_string())) {
+            tokens.next();
+            items.push(SelectItem::Wildcard);
+        } else if let (Some(Token::Identifier(table) | Token::QuotedIdentifier(table)), Some(Token::Dot), Some(Token::Operator(star))) = (ahead.next(), ahead.next(), ahead.next())
+            && star == "*"
+        {
+            tokens.nth(2);
+            items.push(SelectItem::TableWildcard(table));
+        } else {
+            let expr = parse_expression(tokens)?;
+            let alias = if keyword(tokens, "AS") {
+                Some(expect_identifier(tokens, "name after AS")?)
+            } else {
+                match tokens.peek() {
+                    Some(Token::Identifier(word)) if !word.eq_ignore_ascii_case("FROM") && !CLAUSE_KEYWORDS.iter().any(|k| word.eq_ignore_ascii_case(k)) => {
+                        Some(expect_identifier(tokens, "alias")?)
                     }
+                    Some(Token::QuotedIdentifier(_)) => Some(expect_identifier(tokens, "alias")?),
+                    _ => None,
                 }
-            }
-            Token::Comma => {
-                tokens.next(); // consume comma
-                continue;
-            }
-            Token::Where => break,
-            _ => return Err("Unexpected token in SELECT query".to_string()),
+            };
+            items.push(SelectItem::Expression { expr, alias });
         }
-    }
-
-    // If a WHERE token is present, consume it and attempt to parse the
-    // expression. If parsing fails, return an explicit error instead of
-    // silently treating the WHERE as absent.
-    let where_clause = if let Some(Token::Where) = tokens.peek() {
-        tokens.next(); // Consume WHERE
-        match parse_expression(tokens) {
-            Some(expr) => Some(expr),
-            None => return Err("Failed to parse WHERE expression".to_string()),
+        if tokens.peek() != Some(&Token::Comma) {
+            return Ok(items);
         }
-    } else {
-        None
-    };
-
-    Ok(Query::Select(SelectQuery {
-        table_name,
-        columns,
-        where_clause,
-    }))
+        tokens.next();
+    }
 }
 
-fn parse_insert_query(tokens: &mut Peekable<std::vec::IntoIter<Token>>) -> Result<Query, String> {
-    let table_name = if let Some(Token::Identifier(name)) = tokens.next() {
+fn parse_update_query(tokens: &mut Peekable<std::vec::IntoIter<Token>>) -> Result<Query, String> {
+    // update should be like "UPDATE table_name SET col1 = 'a', col2 = 'b' WHERE ..."
+    let table_name = if let Some(Token::Identifier(name) | Token::QuotedIdentifier(name)) = tokens.next() {
         name
     } else {
-        return Err("Expected table name after INSERT".to_string());
+        return Err("Expected table name after UPDATE".to_string());
     };
 
-    if tokens.next() != Some(Token::Values) {
-        return Err("Expected VALUES after INSERT".to_string());
+    if tokens.next() != Some(Token::Set) {
+        return Err("Expected SET after table name in UPDATE".to_string());
     }
 
-    let mut values = Vec::new();
+    let updates = parse_assignments(tokens, "UPDATE")?;
+    let where_clause = parse_where(tokens)?;
+    let returning = parse_returning(tokens)?;
+    expect_end(tokens, "UPDATE")?;
 
-    while let Some(token) = tokens.next() {
-        match token {
-            Token::ParenOpen => continue,
-            Token::ParenClose => break,
-            Token::Comma => continue,
-            Token::StringLiteral(some_string) => {
-                values.push(some_string.clone());
-            }
-            _ => return Err("Unexpected token in INSERT query".to_string()),
-        }
-    }
-
-    Ok(Query::Insert(InsertQuery { table_name,values }))
-}
-
-// fn parse_update_query(tokens: &mut Peekable<std::vec::IntoIter<Token>>) -> Result<Query, String> {
-//     if tokens.next() != Some(Token::Set) {
-//         return Err("Expected SET after UPDATE".to_string());
-//     }
-
-//     let mut updates = Vec::new();
-
-//     while let Some(token) = tokens.next() {
-//         match token {
-//             Token::Identifier(column) => {
-//                 if let Some(Token::Operator(op)) = tokens.next() {
-//                     if op == "=" {
-//                         if let Some(Token::StringLiteral(value)) = tokens.next() {
-//                             updates.push(value);
-//                         } else {
-//                             return Err("Expected value after =".to_string());
-//                         }
-//                     } else {
-//                         return Err("Expected = after column name".to_string());
-//                     }
-//                 } else {
-//                     return Err("Expected = after column name".to_string());
-//                 }
-//             }
-//             Token::Comma => continue,
-//             Token::Where => break,
-//             _ => return Err("Unexpected token in UPDATE query".to_string()),
-//         }
-//     }
-
-//     let where_clause = if let Some(Token::Where) = tokens.peek() {
-//         tokens.next(); // Consume WHERE
-//         parse_expression(tokens)
-//     } else {
-//         None
-//     };
-
-//     Ok(Query::Update(UpdateQuery {
-//         updates,
-//         where_clause,
-//     }))
-// }
+    Ok(Query::Update(UpdateQuery {
+        table_name,
+        updates,
+        where_clause,
+        returning,
+    }))
+}
 
 fn parse_delete_query(tokens: &mut Peekable<std::vec::IntoIter<Token>>) -> Result<Query, String> {
-    let table_name = if let Some(Token::Identifier(name)) = tokens.next() {
+    // delete should be like "DELETE [FROM] table_name [WHERE ...] [RETURNING ...]"
+    keyword(tokens, "FROM");
+    let table_name = if let Some(Token::Identifier(name) | Token::QuotedIdentifier(name)) = tokens.next() {
         name
     } else {
         return Err("Expected table name after DELETE".to_string());
     };
-    let where_clause = if let Some(Token::Where) = tokens.peek() {
-        tokens.next(); // Consume WHERE
-        parse_expression(tokens)
-    } else {
-        None
-    };
+    let where_clause = parse_where(tokens)?;
+    let returning = parse_returning(tokens)?;
+    expect_end(tokens, "DELETE")?;
 
-    Ok(Query::Delete(DeleteQuery { table_name, where_clause }))
+    Ok(Query::Delete(DeleteQuery { table_name, where_clause, returning }))
 }
 
 fn parse_create_query(tokens: &mut Peekable<std::vec::IntoIter<Token>>) -> Result<Query, String> {
-    // create should be like "CREATE table_name (col1, col2, ...)"
-    let table_name = if let Some(Token::Identifier(name)) = tokens.next() {
+    // create should be like "CREATE [TABLE] table_name (col1 INTEGER PRIMARY KEY, col2 TEXT NOT NULL DEFAULT 'x', ...,
+    // UNIQUE (col2, col3), CHECK (col1 > 0))", a column with no type is TEXT
+    keyword(tokens, "TABLE");
+    let table_name = if let Some(Token::Identifier(name) | Token::QuotedIdentifier(name)) = tokens.next() {
         name
     } else {
         return Err("Expected table name after CREATE".to_string());
     };
-    
+
     if tokens.next() != Some(Token::ParenOpen) {
         return Err(format!("Expected ( after table name {}", table_name));
     }
     let mut columns = Vec::new();
-    while let Some(token) = tokens.next() {
-        match token {
-            Token::Identifier(column) => {
-                columns.push(column);
-            }
-            Token::Comma => continue,
-            Token::ParenClose => break,
-            _ => return Err("Unexpected token in CREATE query".to_string()),
+    let mut constraints = Vec::new();
+    loop {
+        if let Some(constraint) = parse_table_constraint(tokens)? {
+            constraints.push(constraint);
+        } else {
+            let column = expect_identifier(tokens, "column name or constraint in CREATE")?;
+            columns.push(parse_column_def(column, tokens)?);
+        }
+        match tokens.next() {
+            Some(Token::Comma) => continue,
+            Some(Token::ParenClose) => break,
+            _ => return Err("Expected , or ) in CREATE".to_string()),
         }
     }
+    expect_end(tokens, "CREATE")?;
+
     Ok(Query::Create(CreateQuery {
         table_name,
         columns,
+        constraints,
     }))
 }
 
-fn parse_expression(tokens: &mut Peekable<std::vec::IntoIter<Token>>) -> Option<Expression> {
-    let left = match tokens.next() {
-        Some(Token::Identifier(column)) => Expression::Column(column),
-        Some(Token::StringLiteral(value)) => Expression::Value(value),
-        _ => return None,
+// words that start a constraint rather than name a type after a column name
+const CONSTRAINT_KEYWORDS: [&str; 8] = ["CONSTRAINT", "PRIMARY", "UNIQUE", "NOT", "NULL", "DEFAULT", "CHECK", "REFERENCES"];
+
+// the optional type and constraints that follow a column name
+fn parse_column_def(name: String, tokens: &mut Peekable<std::vec::IntoIter<Token>>) -> Result<ColumnDef, String> {
+    let data_type = match tokens.peek() {
+        Some(Token::Identifier(type_name)) if !CONSTRAINT_KEYWORDS.iter().any(|k| type_name.eq_ignore_ascii_case(k)) => {
+            let data_type = DataType::from_name(type_name)
+                .ok_or_else(|| format!("Unknown type {} for column {}", type_name, name))?;
+            tokens.next();
+            skip_type_arguments(tokens)?;
+            data_type
+        }
+        _ => DataType::Text,
     };
 
-    if let Some(Token::Operator(op)) = tokens.next() {
-        let right = match tokens.next() {
-            Some(Token::Identifier(column)) => Expression::Column(column),
-            Some(Token::StringLiteral(value)) => Expression::Value(value),
-            _ => return None,
+    let mut column = ColumnDef { name, data_type, not_null: false, default: None, constraints: Vec::new() };
+    loop {
+        let constraint_name = if keyword(tokens, "CONSTRAINT") {
+            Some(expect_identifier(tokens, "constraint name after CONSTRAINT")?)
+        } else {
+            None
         };
+        let kind = if keyword(tokens, "PRIMARY") {
+            expect_keyword(tokens, "KEY", "after PRIMARY")?;
+            ConstraintKind::PrimaryKey(vec![column.name.clone()])
+        } else if keyword(tokens, "UNIQUE") {
+            ConstraintKind::Unique(vec![column.name.clone()])
+        } else if keyword(tokens, "CHECK") {
+            ConstraintKind::Check(parse_bracketed_expression(tokens, "CHECK")?)
+        } else if keyword(tokens, "REFERENCES") {
+            ConstraintKind::ForeignKey(parse_references(vec![column.name.clone()], tokens)?)
+        } else if constraint_name.is_some() {
+            return Err(format!("Expected PRIMARY KEY, UNIQUE, CHECK or REFERENCES after the constraint name on column {}", column.name));
+        } else if keyword(tokens, "NOT") {
+            expect_keyword(tokens, "NULL", "after NOT")?;
+            column.not_null = true;
+            continue;
+        } else if keyword(tokens, "NULL") {
+            continue;
+        } else if keyword(tokens, "DEFAULT") {
+            // comparisons and AND/OR need brackets so a following NOT NULL isnt read as part of the default
+            column.default = Some(parse_additive(tokens)?);
+            continue;
+        } else {
+            break;
+        };
+        column.constraints.push(TableConstraint { name: constraint_name, kind });
+    }
+    Ok(column)
+}
 
-        Some(Expression::BinaryOp {
-            left: Box::new(left),
-            operator: op,
-            right: Box::new(right),
-        })
+// a PRIMARY KEY, UNIQUE or CHECK constraint listed among the columns, None if the next tokens arent one
+fn parse_table_constraint(tokens: &mut Peekable<std::vec::IntoIter<Token>>) -> Result<Option<TableConstraint>, String> {
+    let name = if keyword(tokens, "CONSTRAINT") {
+        Some(expect_identifier(tokens, "constraint name after CONSTRAINT")?)
+    } else {
+        None
+    };
+    let kind = if keyword(tokens, "PRIMARY") {
+        expect_keyword(tokens, "KEY", "after PRIMARY")?;
+        ConstraintKind::PrimaryKey(parse_column_list(tokens, "PRIMARY KEY")?)
+    } else if keyword(tokens, "UNIQUE") {
+        ConstraintKind::Unique(parse_column_list(tokens, "UNIQUE")?)
+    } else if keyword(tokens, "CHECK") {
+        ConstraintKind::Check(parse_bracketed_expression(tokens, "CHECK")?)
+    } else if keyword(tokens, "FOREIGN") {
+        expect_keyword(tokens, "KEY", "after FOREIGN")?;
+        let columns = parse_column_list(tokens, "FOREIGN KEY")?;
+        expect_keyword(tokens, "REFERENCES", "after FOREIGN KEY columns")?;
+        ConstraintKind::ForeignKey(parse_references(columns, tokens)?)
+    } else if name.is_some() {
+        return Err("Expected PRIMARY KEY, UNIQUE, CHECK or FOREIGN KEY after the constraint name".to_string());
+    } else {
+        return Ok(None);
+    };
+    Ok(Some(TableConstraint { name, kind }))
+}
+
+// what follows REFERENCES: "parent [(cols)] [ON DELETE action] [ON UPDATE action] [[NOT] DEFERRABLE]
+// [INITIALLY DEFERRED | INITIALLY IMMEDIATE]"
+fn parse_references(columns: Vec<String>, tokens: &mut Peekable<std::vec::IntoIter<Token>>) -> Result<ForeignKeyDef, String> {
+    let parent_table = expect_identifier(tokens, "table name after REFERENCES")?;
+    let parent_columns = if tokens.peek() == Some(&Token::ParenOpen) {
+        parse_column_list(tokens, "REFERENCES")?
+    } else {
+        Vec::new()
+    };
+
+    let mut fk = ForeignKeyDef {
+        columns,
+        parent_table,
+        parent_columns,
+        on_delete: ReferentialAction::NoAction,
+        on_update: ReferentialAction::NoAction,
+        deferred: false,
+    };
+    loop {
+        if keyword(tokens, "ON") {
+            match tokens.next() {
+                Some(Token::Delete) => fk.on_delete = parse_referential_action(tokens)?,
+                Some(Token::Update) => fk.on_update = parse_referential_action(tokens)?,
+                _ => return Err("Expected DELETE or UPDATE after ON".to_string()),
+            }
+        } else if keyword(tokens, "DEFERRABLE") || keywords_ahead(tokens, &["NOT", "DEFERRABLE"]) {
+            // only INITIALLY DEFERRED changes when the constraint is checked
+        } else if keyword(tokens, "INITIALLY") {
+            if keyword(tokens, "DEFERRED") {
+                fk.deferred = true;
+            } else {
+                expect_keyword(tokens, "IMMEDIATE", "or DEFERRED after INITIALLY")?;
+            }
+        } else {
+            return Ok(fk);
+        }
+    }
+}
+
+fn parse_referential_action(tokens: &mut Peekable<std::vec::IntoIter<Token>>) -> Result<ReferentialAction, String> {
+    if keyword(tokens, "CASCADE") {
+        Ok(ReferentialAction::Cascade)
+    } else if keyword(tokens, "RESTRICT") {
+        Ok(ReferentialAction::Restrict)
+    } else if keyword(tokens, "NO") {
+        expect_keyword(tokens, "ACTION", "after NO")?;
+        Ok(ReferentialAction::NoAction)
+    } else if tokens.peek() == Some(&Token::Set) {
+        tokens.next();
+        if keyword(tokens, "NULL") {
+            Ok(ReferentialAction::SetNull)
+        } else {
+            expect_keyword(tokens, "DEFAULT", "or NULL after SET")?;
+            Ok(ReferentialAction::SetDefault)
+        }
+    } else {
+        Err("Expected CASCADE, RESTRICT, NO ACTION, SET NULL or SET DEFAULT".to_string())
+    }
+}
+
+// "(a, b, c)"
+fn parse_column_list(tokens: &mut Peekable<std::vec::IntoIter<Token>>, context: &str) -> Result<Vec<String>, String> {
+    if tokens.next() != Some(Token::ParenOpen) {
+        return Err(format!("Expected ( after {}", context));
+    }
+    let mut columns = Vec::new();
+    loop {
+        columns.push(expect_identifier(tokens, &format!("column name in {}", context))?);
+        match tokens.next() {
+            Some(Token::Comma) => continue,
+            Some(Token::ParenClose) => return Ok(columns),
+            _ => return Err(format!("Expected , or ) in {} column list", context)),
+        }
+    }
+}
+
+fn parse_bracketed_expression(tokens: &mut Peekable<std::vec::IntoIter<Token>>, context: &str) -> Result<Expression, String> {
+    if tokens.next() != Some(Token::ParenOpen) {
+        return Err(format!("Expected ( after {}", context));
+    }
+    let expr = parse_expression(tokens)?;
+    if tokens.next() != Some(Token::ParenClose) {
+        return Err(format!("Expected ) to close {}", context));
+    }
+    Ok(expr)
+}
+
+// words like TABLE and COLUMN only mean something inside one statement, so rather than being
+// tokens of their own they are identifiers matched without caring about case. consumed if present
+fn keyword(tokens: &mut Peekable<std::vec::IntoIter<Token>>, word: &str) -> bool {
+    match tokens.peek() {
+        Some(Token::Identifier(ident)) if ident.eq_ignore_ascii_case(word) => {
+            tokens.next();
+            true
+        }
+        _ => false,
+    }
+}
+
+// consumes words and returns true only if the next tokens are all of them, for when one word of
+// lookahead isnt enough, like telling NOT DEFERRABLE from NOT NULL
+fn keywords_ahead(tokens: &mut Peekable<std::vec::IntoIter<Token>>, words: &[&str]) -> bool {
+    let mut ahead = tokens.clone();
+    let all_match = words.iter().all(|word| matches!(ahead.next(), Some(Token::Identifier(ident)) if ident.eq_ignore_ascii_case(word)));
+    if all_match {
+        for _ in words {
+            tokens.next();
+        }
+    }
+    all_match
+}
+
+fn expect_keyword(tokens: &mut Peekable<std::vec::IntoIter<Token>>, word: &str, context: &str) -> Result<(), String> {
+    if keyword(tokens, word) {
+        Ok(())
+    } else {
+        Err(format!("Expected {} {}", word, context))
+    }
+}
+
+fn expect_identifier(tokens: &mut Peekable<std::vec::IntoIter<Token>>, what: &str) -> Result<String, String> {
+    match tokens.next() {
+        Some(Token::Identifier(name) | Token::QuotedIdentifier(name)) => Ok(name),
+        _ => Err(format!("Expected {}", what)),
+    }
+}
+
+// nothing but an optional ; may follow the end of a statement
+fn expect_end(tokens: &mut Peekable<std::vec::IntoIter<Token>>, statement: &str) -> Result<(), String> {
+    match tokens.next() {
+        None | Some(Token::Semicolon) => Ok(()),
+        Some(token) => Err(format!("Unexpected {:?} at end of {}", token, statement)),
+    }
+}
+
+fn parse_drop_query(tokens: &mut Peekable<std::vec::IntoIter<Token>>) -> Result<Query, String> {
+    // drop should be like "DROP TABLE [IF EXISTS] table_name"
+    expect_keyword(tokens, "TABLE", "after DROP")?;
+    let if_exists = if keyword(tokens, "IF") {
+        expect_keyword(tokens, "EXISTS", "after IF")?;
+        true
+    } else {
+        false
+    };
+    let table_name = expect_identifier(tokens, "table name after DROP TABLE")?;
+    expect_end(tokens, "DROP TABLE")?;
+    Ok(Query::Drop(DropQuery { table_name, if_exists }))
+}
+
+fn parse_truncate_query(tokens: &mut Peekable<std::vec::IntoIter<Token>>) -> Result<Query, String> {
+    // truncate should be like "TRUNCATE [TABLE] table_name"
+    keyword(tokens, "TABLE");
+    let table_name = expect_identifier(tokens, "table name after TRUNCATE")?;
+    expect_end(tokens, "TRUNCATE")?;
+    Ok(Query::Truncate(TruncateQuery { table_name }))
+}
+
+fn parse_alter_query(tokens: &mut Peekable<std::vec::IntoIter<Token>>) -> Result<Query, String> {
+    // alter should be like one of
+    //  ALTER TABLE t ADD [COLUMN] col TYPE [DEFAULT 'v']
+    //  ALTER TABLE t ADD [CONSTRAINT name] CHECK (expr) or FOREIGN KEY (cols) REFERENCES ...
+    //  ALTER TABLE t DROP [COLUMN] col
+    //  ALTER TABLE t RENAME [COLUMN] col TO new_col
+    //  ALTER TABLE t RENAME TO new_t
+    expect_keyword(tokens, "TABLE", "after ALTER")?;
+    let table_name = expect_identifier(tokens, "table name after ALTER TABLE")?;
+
+    let action = match tokens.next() {
+        Some(Token::Identifier(word)) if word.eq_ignore_ascii_case("ADD") => match parse_table_constraint(tokens)? {
+            Some(constraint) => AlterAction::AddConstraint(constraint),
+            None => {
+                keyword(tokens, "COLUMN");
+                let column = expect_identifier(tokens, "column name or constraint after ADD")?;
+                AlterAction::AddColumn(parse_column_def(column, tokens)?)
+            }
+        },
+        Some(Token::Drop) => {
+            keyword(tokens, "COLUMN");
+            AlterAction::DropColumn(expect_identifier(tokens, "column name after DROP")?)
+        }
+        Some(Token::Identifier(word)) if word.eq_ignore_ascii_case("RENAME") => {
+            if keyword(tokens, "TO") {
+                AlterAction::RenameTable(expect_identifier(tokens, "new table name after RENAME TO")?)
+            } else {
+                keyword(tokens, "COLUMN");
+                let from = expect_identifier(tokens, "column name after RENAME")?;
+                expect_keyword(tokens, "TO", "after column name in RENAME")?;
+                let to = expect_identifier(tokens, "new column name after TO")?;
+                AlterAction::RenameColumn { from, to }
+            }
+        }
+        _ => return Err("Expected ADD, DROP or RENAME after ALTER TABLE table_name".to_string()),
+    };
+    expect_end(tokens, "ALTER TABLE")?;
+
+    Ok(Query::Alter(AlterQuery { table_name, action }))
+}
+
+fn parse_show_query(tokens: &mut Peekable<std::vec::IntoIter<Token>>) -> Result<Query, String> {
+    // show should be like "SHOW TABLES"
+    expect_keyword(tokens, "TABLES", "after SHOW")?;
+    expect_end(tokens, "SHOW TABLES")?;
+    Ok(Query::ShowTables)
+}
+
+fn parse_describe_query(tokens: &mut Peekable<std::vec::IntoIter<Token>>) -> Result<Query, String> {
+    // describe should be like "DESCRIBE table_name"
+    let table_name = expect_identifier(tokens, "table name after DESCRIBE")?;
+    expect_end(tokens, "DESCRIBE")?;
+    Ok(Query::Describe(DescribeQuery { table_name }))
+}
+
+// lengths like the 255 in VARCHAR(255) are accepted but not enforced
+fn skip_type_arguments(tokens: &mut Peekable<std::vec::IntoIter<Token>>) -> Result<(), String> {
+    if tokens.peek() != Some(&Token::ParenOpen) {
+        return Ok(());
+    }
+    for token in tokens.by_ref() {
+        if token == Token::ParenClose {
+            return Ok(());
+        }
+    }
+    Err("Expected ) after type arguments".to_string())
+}
+
+fn parse_vacuum_query(tokens: &mut Peekable<std::vec::IntoIter<Token>>) -> Result<Query, String> {
+    // vacuum should be like "VACUUM" or "VACUUM table_name"
+    let table_name = match tokens.next() {
+        Some(Token::Identifier(name) | Token::QuotedIdentifier(name)) => Some(name),
+        None | Some(Token::Semicolon) => None,
+        _ => return Err("Expected table name or nothing after VACUUM".to_string()),
+    };
+    Ok(Query::Vacuum(VacuumQuery { table_name }))
+}
+
+// WHERE followed by an expression, if the next token is WHERE
+fn parse_where(tokens: &mut Peekable<std::vec::IntoIter<Token>>) -> Result<Option<Expression>, String> {
+    if tokens.peek() != Some(&Token::Where) {
+        return Ok(None);
+    }
+    tokens.next(); // Consume WHERE
+    // a WHERE that fails to parse must not turn into matching every row
+    parse_expression(tokens)
+        .map(Some)
+        .map_err(|e| format!("Failed to parse WHERE expression: {}", e))
+}
+
+// expressions are parsed by precedence, loosest first:
+//  OR, AND, NOT, comparisons and IS [NOT] NULL, + and -, * / and %, unary -
+pub fn parse_expression(tokens: &mut Peekable<std::vec::IntoIter<Token>>) -> Result<Expression, String> {
+    let mut left = parse_and(tokens)?;
+    while keyword(tokens, "OR") {
+        let right = parse_and(tokens)?;
+        left = binary(left, "OR", right);
+    }
+    Ok(left)
+}
+
+fn parse_and(tokens: &mut Peekable<std::vec::IntoIter<Token>>) -> Result<Expression, String> {
+    let mut left = parse_not(tokens)?;
+    while keyword(tokens, "AND") {
+        let right = parse_not(tokens)?;
+        left = binary(left, "AND", right);
+    }
+    Ok(left)
+}
+
+fn parse_not(tokens: &mut Peekable<std::vec::IntoIter<Token>>) -> Result<Expression, String> {
+    if keyword(tokens, "NOT") {
+        return Ok(not(parse_not(tokens)?));
+    }
+    parse_comparison(tokens)
+}
+
+const NEGATABLE_OPERATORS: [&str; 5] = ["IN", "BETWEEN", "LIKE", "ILIKE", "GLOB"];
+
+fn parse_comparison(tokens: &mut Peekable<std::vec::IntoIter<Token>>) -> Result<Expression, String> {
+    let left = parse_concat(tokens)?;
+    // IN, BETWEEN and the pattern operators can have NOT in front, which becomes NOT around them
+    let mut ahead = tokens.clone();
+    let negated = matches!(ahead.next(), Some(Token::Identifier(word)) if word.eq_ignore_ascii_case("NOT"))
+        && matches!(ahead.next(), Some(Token::Identifier(word)) if NEGATABLE_OPERATORS.iter().any(|op| word.eq_ignore_ascii_case(op)));
+    if negated {
+        tokens.next();
+    }
+    let negate = |expr| if negated { not(expr) } else { expr };
+    if keyword(tokens, "IN") {
+        let mut ahead = tokens.clone();
+        if ahead.next() == Some(Token::ParenOpen) && ahead.next() == Some(Token::Select) {
+            let query = parse_subquery(tokens, "IN")?;
+            return Ok(negate(Expression::InSubquery { operand: Box::new(left), query: Box::new(query) }));
+        }
+        return Ok(negate(Expression::InList { operand: Box::new(left), list: parse_in_list(tokens)? }));
+    }
+    if keyword(tokens, "BETWEEN") {
+        // the bounds cant be comparisons so the AND in the middle isnt taken for a logical one
+        let low = parse_concat(tokens)?;
+        expect_keyword(tokens, "AND", "between the bounds of BETWEEN")?;
+        let high = parse_concat(tokens)?;
+        return Ok(negate(Expression::Between { operand: Box::new(left), low: Box::new(low), high: Box::new(high) }));
+    }
+    for operator in ["LIKE", "ILIKE", "GLOB"] {
+        if keyword(tokens, operator) {
+            let pattern = parse_concat(tokens)?;
+            return Ok(negate(binary(left, operator, pattern)));
+        }
+    }
+    if keyword(tokens, "IS") {
+        let negated = keyword(tokens, "NOT");
+        expect_keyword(tokens, "NULL", "after IS")?;
+        return Ok(Expression::IsNull { operand: Box::new(left), negated });
+    }
+    let operator = match tokens.peek() {
+        Some(Token::Operator(op)) if ["=", "!=", "<>", "<", "<=", ">", ">="].contains(&op.as_str()) => {
+            if op == "<>" { "!=".to_string() } else { op.clone() }
+        }
+        _ => return Ok(left),
+    };
+    tokens.next();
+    let right = parse_concat(tokens)?;
+    Ok(binary(left, &operator, right))
+}
+
+// "(a, b, ...)" after IN
+fn parse_in_list(tokens: &mut Peekable<std::vec::IntoIter<Token>>) -> Result<Vec<Expression>, String> {
+    if tokens.next() != Some(Token::ParenOpen) {
+        return Err("Expected ( after IN".to_string());
+    }
+    if tokens.peek() == Some(&Token::ParenClose) {
+        return Err("The list after IN cannot be empty".to_string());
+    }
+    let mut list = Vec::new();
+    loop {
+        list.push(parse_expression(tokens)?);
+        match tokens.next() {
+            Some(Token::Comma) => {}
+            Some(Token::ParenClose) => return Ok(list),
+            _ => return Err("Expected , or ) in the list after IN".to_string()),
+        }
+    }
+}
+
+// "a || b" joins text, it binds looser than arithmetic and tighter than comparisons
+fn parse_concat(tokens: &mut Peekable<std::vec::IntoIter<Token>>) -> Result<Expression, String> {
+    let mut left = parse_additive(tokens)?;
+    while let Some(operator) = next_operator(tokens, &["||"]) {
+        let right = parse_additive(tokens)?;
+        left = binary(left, &operator, right);
+    }
+    Ok(left)
+}
+
+fn parse_additive(tokens: &mut Peekable<std::vec::IntoIter<Token>>) -> Result<Expression, String> {
+    let mut left = parse_multiplicative(tokens)?;
+    while let Some(operator) = next_operator(tokens, &["+", "-"]) {
+        let right = parse_multiplicative(tokens)?;
+        left = binary(left, &operator, right);
+    }
+    Ok(left)
+}
+
+fn parse_multiplicative(tokens: &mut Peekable<std::vec::IntoIter<Token>>) -> Result<Expression, String> {
+    let mut left = parse_unary(tokens)?;
+    while let Some(operator) = next_operator(tokens, &["*", "/", "%"]) {
+        let right = parse_unary(tokens)?;
+        left = binary(left, &operator, right);
+    }
+    Ok(left)
+}
+
+fn parse_unary(tokens: &mut Peekable<std::vec::IntoIter<Token>>) -> Result<Expression, String> {
+    if next_operator(tokens, &["-"]).is_some() {
+        // the smallest INTEGER is one more than the largest, so its digits alone would make a REAL
+        if matches!(tokens.peek(), Some(Token::Number(number)) if number == "9223372036854775808") {
+            tokens.next();
+            return Ok(Expression::Literal(Value::Integer(i64::MIN)));
+        }
+        let operand = parse_unary(tokens)?;
+        return Ok(Expression::UnaryOp { operator: "-".to_string(), operand: Box::new(operand) });
+    }
+    parse_primary(tokens)
+}
+
+fn parse_primary(tokens: &mut Peekable<std::vec::IntoIter<Token>>) -> Result<Expression, String> {
+    match tokens.next() {
+        Some(Token::StringLiteral(value)) => Ok(Expression::Literal(Value::Text(value))),
+        Some(Token::Parameter(Some(n))) => Ok(Expression::Parameter(n)),
+        Some(Token::Number(number)) => {
+            let hex = number.strip_prefix("0x").or_else(|| number.strip_prefix("0X"));
+            let value = match hex {
+                Some(digits) => Value::Integer(i64::from_str_radix(digits, 16).map_err(|_| format!("Hex number {} is too big", number))?),
+                None => match number.parse::<i64>() {
+                    Ok(i) => Value::Integer(i),
+                    Err(_) => Value::Real(number.parse::<f64>().map_err(|_| format!("Invalid number {}", number))?),
+                },
+            };
+            Ok(Expression::Literal(value))
+        }
+        Some(Token::Identifier(name)) => Ok(match name.to_uppercase().as_str() {
+            "NULL" => Expression::Literal(Value::Null),
+            "TRUE" => Expression::Literal(Value::Boolean(true)),
+            "FALSE" => Expression::Literal(Value::Boolean(false)),
+            "EXISTS" if tokens.peek() == Some(&Token::ParenOpen) => Expression::Exists(Box::new(parse_subquery(tokens, "EXISTS")?)),
+            "CASE" => parse_case(tokens)?,
+            "CAST" if tokens.peek() == Some(&Token::ParenOpen) => parse_cast(tokens)?,
+            function if tokens.peek() == Some(&Token::ParenOpen) => parse_function_call(function, tokens)?,
+            _ if tokens.peek() == Some(&Token::Dot) => {
+                tokens.next();
+                let column = expect_identifier(tokens, &format!("column name after {}.", name))?;
+                Expression::Column { table: Some(name), name: column }
+            }
+            _ => Expression::Column { table: None, name },
+        }),
+        Some(Token::QuotedIdentifier(name)) if tokens.peek() == Some(&Token::Dot) => {
+            tokens.next();
+            let column = expect_identifier(tokens, &format!("column name after {}.", quote_identifier(&name)))?;
+            Ok(Expression::Column { table: Some(name), name: column })
+        }
+        Some(Token::QuotedIdentifier(name)) => Ok(Expression::Column { table: None, name }),
+        Some(Token::ParenOpen) if tokens.peek() == Some(&Token::Select) => {
+            tokens.next();
+            let query = parse_select(tokens)?;
+            if tokens.next() != Some(Token::ParenClose) {
+                return Err("Expected ) to close subquery".to_string());
+            }
+            Ok(Expression::Subquery(Box::new(query)))
+        }
+        Some(Token::ParenOpen) => {
+            let expr = parse_expression(tokens)?;
+            if tokens.next() != Some(Token::ParenClose) {
+                return Err("Expected ) to close bracketed expression".to_string());
+            }
+            Ok(expr)
+        }
+        Some(token) => Err(format!("Unexpected {:?} in expression", token)),
+        None => Err("Expected an expression".to_string()),
+    }
+}
+
+// the functions that can be used with OVER and how many arguments they take
+const WINDOW_FUNCTIONS: [(&str, usize, usize); 12] = [
+    ("ROW_NUMBER", 0, 0),
+    ("RANK", 0, 0),
+    ("DENSE_RANK", 0, 0),
+    ("LAG", 1, 3),
+    ("LEAD", 1, 3),
+    ("FIRST_VALUE", 1, 1),
+    ("LAST_VALUE", 1, 1),
+    ("COUNT", 0, 1),
+    ("SUM", 1, 1),
+    ("AVG", 1, 1),
+    ("MIN", 1, 1),
+    ("MAX", 1, 1),
+];
+
+// the window functions that fold rows into one value, usable without OVER over the rows of a group
+const AGGREGATES: [&str; 5] = ["COUNT", "SUM", "AVG", "MIN", "MAX"];
+
+// "function(args)" or "function(args) OVER (...)", with the function name already read. which scalar
+// functions exist is up to the executor, the aggregates are the ones that also work without OVER
+fn parse_function_call(function: &str, tokens: &mut Peekable<std::vec::IntoIter<Token>>) -> Result<Expression, String> {
+    tokens.next();
+    let mut args = Vec::new();
+    if function == "COUNT" && tokens.peek() == Some(&Token::Operator("*".to_string())) {
+        tokens.next();
+    } else if tokens.peek() != Some(&Token::ParenClose) {
+        loop {
+            args.push(parse_expression(tokens)?);
+            if tokens.peek() != Some(&Token::Comma) {
+                break;
+            }
+            tokens.next();
+        }
+    }
+    if tokens.next() != Some(Token::ParenClose) {
+        return Err(format!("Expected ) after the arguments of {}", function));
+    }
+    let window = WINDOW_FUNCTIONS.iter().find(|(name, _, _)| *name == function);
+    if let Some(&(_, min_args, max_args)) = window
+        && (args.len() < min_args || args.len() > max_args)
+    {
+        return Err(format!("Wrong number of arguments for {}", function));
+    }
+    if !keyword(tokens, "OVER") {
+        if AGGREGATES.contains(&function) {
+            if args.iter().any(|arg| arg.contains_aggregate() || arg.contains_window()) {
+                return Err(format!("The argument of {} cannot contain aggregates or window functions", function));
+            }
+            return Ok(Expression::Aggregate { function: function.to_string(), args });
+        }
+        if window.is_some() {
+            return Err(format!("{} needs an OVER clause, it can only be used as a window function", function));
+        }
+        return Ok(Expression::Function { name: function.to_string(), args });
+    }
+    if window.is_none() {
+        return Err(format!("{} is not a window function", function));
+    }
+    if tokens.next() != Some(Token::ParenOpen) {
+        return Err("Expected ( after OVER".to_string());
+    }
+
+    let mut partition_by = Vec::new();
+    if keywords_ahead(tokens, &["PARTITION", "BY"]) {
+        loop {
+            partition_by.push(parse_expression(tokens)?);
+            if tokens.peek() != Some(&Token::Comma) {
+                break;
+            }
+            tokens.next();
+        }
+    }
+    let order_by = if keywords_ahead(tokens, &["ORDER", "BY"]) { parse_order_by(tokens)? } else { Vec::new() };
+    let frame = if keyword(tokens, "ROWS") {
+        Some(parse_frame(false, tokens)?)
+    } else if keyword(tokens, "RANGE") {
+        Some(parse_frame(true, tokens)?)
     } else {
         None
+    };
+    if tokens.next() != Some(Token::ParenClose) {
+        return Err(format!("Expected ) to close the OVER clause of {}", function));
+    }
+
+    let call = WindowCall { function: function.to_string(), args, partition_by, order_by, frame };
+    if call.expressions().any(Expression::contains_window) {
+        return Err("Window functions cannot be nested".to_string());
+    }
+    Ok(Expression::Window(Box::new(call)))
+}
+
+// "CASE [operand] WHEN ... THEN ... [ELSE ...] END" after CASE
+fn parse_case(tokens: &mut Peekable<std::vec::IntoIter<Token>>) -> Result<Expression, String> {
+    let operand = match tokens.peek() {
+        Some(Token::Identifier(word)) if word.eq_ignore_ascii_case("WHEN") => None,
+        _ => Some(Box::new(parse_expression(tokens)?)),
+    };
+    let mut branches = Vec::new();
+    while keyword(tokens, "WHEN") {
+        let when = parse_expression(tokens)?;
+        expect_keyword(tokens, "THEN", "after WHEN in CASE")?;
+        branches.push((when, parse_expression(tokens)?));
+    }
+    if branches.is_empty() {
+        return Err("Expected WHEN in CASE".to_string());
+    }
+    let otherwise = if keyword(tokens, "ELSE") { Some(Box::new(parse_expression(tokens)?)) } else { None };
+    expect_keyword(tokens, "END", "to close CASE")?;
+    Ok(Expression::Case { operand, branches, otherwise })
+}
+
+// "(expr AS type)" after CAST
+fn parse_cast(tokens: &mut Peekable<std::vec::IntoIter<Token>>) -> Result<Expression, String> {
+    tokens.next();
+    let operand = parse_expression(tokens)?;
+    expect_keyword(tokens, "AS", "in CAST")?;
+    let type_name = expect_identifier(tokens, "type name in CAST")?;
+    let data_type = DataType::from_name(&type_name).ok_or_else(|| format!("Unknown type {} in CAST", type_name))?;
+    skip_type_arguments(tokens)?;
+    if tokens.next() != Some(Token::ParenClose) {
+        return Err("Expected ) to close CAST".to_string());
+    }
+    Ok(Expression::Cast { operand: Box::new(operand), data_type })
+}
+
+// "BETWEEN start AND end" or just "start", which runs to the current row
+fn parse_frame(range: bool, tokens: &mut Peekable<std::vec::IntoIter<Token>>) -> Result<WindowFrame, String> {
+    let (start, end) = if keyword(tokens, "BETWEEN") {
+        let start = parse_frame_bound(tokens)?;
+        expect_keyword(tokens, "AND", "between the frame bounds")?;
+        (start, parse_frame_bound(tokens)?)
+    } else {
+        (parse_frame_bound(tokens)?, FrameBound::CurrentRow)
+    };
+    if matches!(start, FrameBound::UnboundedFollowing) || matches!(end, FrameBound::UnboundedPreceding) {
+        return Err("A frame cannot start at UNBOUNDED FOLLOWING or end at UNBOUNDED PRECEDING".to_string());
+    }
+    Ok(WindowFrame { range, start, end })
+}
+
+fn parse_frame_bound(tokens: &mut Peekable<std::vec::IntoIter<Token>>) -> Result<FrameBound, String> {
+    if keyword(tokens, "UNBOUNDED") {
+        if keyword(tokens, "PRECEDING") {
+            return Ok(FrameBound::UnboundedPreceding);
+        }
+        expect_keyword(tokens, "FOLLOWING", "or PRECEDING after UNBOUNDED")?;
+        return Ok(FrameBound::UnboundedFollowing);
+    }
+    if keyword(tokens, "CURRENT") {
+        expect_keyword(tokens, "ROW", "after CURRENT")?;
+        return Ok(FrameBound::CurrentRow);
+    }
+    let offset = parse_additive(tokens)?;
+    if keyword(tokens, "PRECEDING") {
+        Ok(FrameBound::Preceding(offset))
+    } else {
+        expect_keyword(tokens, "FOLLOWING", "or PRECEDING after a frame offset")?;
+        Ok(FrameBound::Following(offset))
+    }
+}
+
+// consumes and returns the next token if its one of these operators
+fn next_operator(tokens: &mut Peekable<std::vec::IntoIter<Token>>, operators: &[&str]) -> Option<String> {
+    match tokens.peek() {
+        Some(Token::Operator(op)) if operators.contains(&op.as_str()) => {
+            let op = op.clone();
+            tokens.next();
+            Some(op)
+        }
+        _ => None,
+    }
+}
+
+// "(SELECT ...)" after IN or EXISTS
+fn parse_subquery(tokens: &mut Peekable<std::vec::IntoIter<Token>>, context: &str) -> Result<SelectQuery, String> {
+    if tokens.next() != Some(Token::ParenOpen) || tokens.next() != Some(Token::Select) {
+        return Err(format!("Expected (SELECT ...) after {}", context));
+    }
+    let query = parse_select(tokens)?;
+    if tokens.next() != Some(Token::ParenClose) {
+        return Err(format!("Expected ) to close the subquery after {}", context));
+    }
+    Ok(query)
+}
+
+fn not(operand: Expression) -> Expression {
+    Expression::UnaryOp { operator: "NOT".to_string(), operand: Box::new(operand) }
+}
+
+fn binary(left: Expression, operator: &str, right: Expression) -> Expression {
+    Expression::BinaryOp {
+        left: Box::new(left),
+        operator: operator.to_string(),
+        right: Box::new(right),
     }
 }
@@ -1,17 +1,30 @@
-#[derive(Debug, PartialEq)]
+use crate::error::{Error, Position};
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum Token {
     Select,
     Insert,
-    // Update,
+    Update,
     Delete,
     Create,
     Where,
     Values,
     Set,
+    Vacuum,
+    Drop,
+    Truncate,
+    Alter,
+    Show,
+    Describe,
     Identifier(String),
+    QuotedIdentifier(String), // "name", never a keyword and kept exactly as written
     StringLiteral(String),
+    Number(String),
+    // a value given later by EXECUTE, $1 is Some(1). a plain ? is None until the parser numbers it
+    Parameter(Option<usize>),
     Operator(String),
     Comma,
+    Dot, // between a table and column name
     Semicolon,
     ParenOpen,
     ParenClose,
@@ -27,97 +40,314 @@ impl Lexer {
         Self { input, position: 0 }
     }
 
-    pub fn next_token(&mut self) -> Option<Token> {
-        // Loop so we can skip unknown characters (for example stray backslashes
-        // introduced by shell escaping) instead of returning None which would
-        // terminate tokenization early.
+    // the next token and the byte offset it starts at, None once the input is used up. offsets
+    // are in bytes and always on a char boundary
+    pub fn next_token(&mut self) -> Result<Option<(usize, Token)>, Error> {
+        self.skip_whitespace_and_comments()?;
+        let Some(current_char) = self.peek() else {
+            return Ok(None);
+        };
+        let start = self.position;
+
+        let token = match current_char {
+            ',' => self.single(Token::Comma),
+            '.' if self.peek_nth(1).is_some_and(|c| c.is_ascii_digit()) => self.parse_number()?,
+            '.' => self.single(Token::Dot),
+            ';' => self.single(Token::Semicolon),
+            '(' => self.single(Token::ParenOpen),
+            ')' => self.single(Token::ParenClose),
+            '?' => self.single(Token::Parameter(None)),
+            '=' | '<' | '>' | '!' | '+' | '-' | '*' | '/' | '%' | '|' => self.parse_operator(),
+            '\'' => Token::StringLiteral(self.parse_quoted('\'', "String")?),
+            '"' => {
+                let name = self.parse_quoted('"', "Quoted identifier")?;
+                if name.is_empty() {
+                    return Err(self.error("Quoted identifiers cant be empty", start));
+                }
+                Token::QuotedIdentifier(name)
+            }
+            '$' => self.parse_parameter(),
+            _ if current_char.is_ascii_digit() => self.parse_number()?,
+            _ if current_char.is_alphabetic() || current_char == '_' => self.parse_identifier_or_keyword(),
+            _ => return Err(self.error(&format!("Unexpected character {:?}", current_char), start)),
+        };
+        Ok(Some((start, token)))
+    }
+
+    // whether all of the input has been read. after an error it means the input ran out inside a
+    // string, quoted name or comment, so more of it could still close it
+    pub fn at_end(&self) -> bool {
+        self.position >= self.input.len()
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.input[self.position..].chars().next()
+    }
+
+    fn peek_nth(&self, n: usize) -> Option<char> {
+        self.input[self.position..].chars().nth(n)
+    }
+
+    // moves past the next char, which has to be there
+    fn advance(&mut self) -> char {
+        let c = self.peek().expect("advance past the end of the input");
+        self.position += c.len_utf8();
+        c
+    }
+
+    fn error(&self, message: &str, offset: usize) -> Error {
+        Error::Parse { message: message.to_string(), position: Some(Position::in_text(&self.input, offset)) }
+    }
+
+    fn single(&mut self, token: Token) -> Token {
+        self.advance();
+        token
+    }
+
+    // -- runs to the end of the line, /* to the next */ (they dont nest)
+    fn skip_whitespace_and_comments(&mut self) -> Result<(), Error> {
         loop {
-            self.skip_whitespace();
-            if self.position >= self.input.len() {
-                return None;
+            let rest = &self.input[self.position..];
+            if rest.starts_with("--") {
+                self.position += rest.find('\n').unwrap_or(rest.len());
+            } else if let Some(comment) = rest.strip_prefix("/*") {
+                let Some(end) = comment.find("*/") else {
+                    let error = self.error("Comment starting with /* is never closed", self.position);
+                    self.position = self.input.len();
+                    return Err(error);
+                };
+                self.position += end + 4;
+            } else if self.peek().is_some_and(char::is_whitespace) {
+                self.advance();
+            } else {
+                return Ok(());
             }
+        }
+    }
 
-            let current_char = self.input.as_bytes()[self.position] as char;
+    fn parse_operator(&mut self) -> Token {
+        let rest = &self.input[self.position..];
+        let operator = ["<=", ">=", "!=", "<>", "||"].into_iter()
+            .find(|op| rest.starts_with(op))
+            .unwrap_or(&rest[..1]);
+        self.position += operator.len();
+        Token::Operator(operator.to_string())
+    }
 
-            match current_char {
-                ',' => {
-                    self.position += 1;
-                    return Some(Token::Comma);
-                }
-                ';' => {
-                    self.position += 1;
-                    return Some(Token::Semicolon);
-                }
-                '(' => {
-                    self.position += 1;
-                    return Some(Token::ParenOpen);
-                }
-                ')' => {
-                    self.position += 1;
-                    return Some(Token::ParenClose);
-                }
-                '=' | '<' | '>' => {
-                    self.position += 1;
-                    return Some(Token::Operator(current_char.to_string()));
-                }
-                '"' | '\'' => return self.parse_string_literal(current_char),
-                _ if current_char.is_alphabetic() => return self.parse_identifier_or_keyword(),
-                // Unknown / unhandled characters (backslashes, stray escapes, etc.)
-                // will be skipped and tokenization continues.
-                _ => {
-                    self.position += 1;
-                    continue;
-                }
+    // 12, 1.5, .5, 1e10, 2.5E-3 and 0x1F. signs are left to the parser, which also works out
+    // whether the text is an integer or a real
+    fn parse_number(&mut self) -> Result<Token, Error> {
+        let start = self.position;
+        if self.input[start..].starts_with("0x") || self.input[start..].starts_with("0X") {
+            self.position += 2;
+            let digits = self.eat_while(|c| c.is_ascii_hexdigit());
+            if digits == 0 {
+                return Err(self.error("Expected hex digits after 0x", start));
+            }
+        } else {
+            self.eat_while(|c| c.is_ascii_digit());
+            if self.peek() == Some('.') {
+                self.advance();
+                self.eat_while(|c| c.is_ascii_digit());
             }
+            // only an e followed by digits makes an exponent, 1e alone is 1 and the name e
+            let exponent = match (self.peek(), self.peek_nth(1), self.peek_nth(2)) {
+                (Some('e' | 'E'), Some(d), _) if d.is_ascii_digit() => 1,
+                (Some('e' | 'E'), Some('+' | '-'), Some(d)) if d.is_ascii_digit() => 2,
+                _ => 0,
+            };
+            if exponent > 0 {
+                self.position += exponent;
+                self.eat_while(|c| c.is_ascii_digit());
+            }
+        }
+        if self.peek().is_some_and(|c| c.is_alphanumeric() || c == '_') {
+            return Err(self.error(&format!("Invalid number {}{}", &self.input[start..self.position], self.peek().unwrap_or_default()), start));
         }
+        Ok(Token::Number(self.input[start..self.position].to_string()))
+    }
+
+    // moves past the chars that match and says how many there were
+    fn eat_while(&mut self, matches: impl Fn(char) -> bool) -> usize {
+        let mut count = 0;
+        while self.peek().is_some_and(&matches) {
+            self.advance();
+            count += 1;
+        }
+        count
+    }
+
+    // $ and its number. $ without digits is kept as $0 so the parser can say parameters start at $1
+    fn parse_parameter(&mut self) -> Token {
+        self.advance(); // skip $
+        let start = self.position;
+        self.eat_while(|c| c.is_ascii_digit());
+        Token::Parameter(Some(self.input[start..self.position].parse().unwrap_or(0)))
     }
 
-    fn skip_whitespace(&mut self) {
-        while self.position < self.input.len()
-            && self.input.as_bytes()[self.position].is_ascii_whitespace()
-        {
-            self.position += 1;
+    // text between two quotes, where the quote written twice stands for itself
+    fn parse_quoted(&mut self, quote: char, what: &str) -> Result<String, Error> {
+        let start = self.position;
+        self.advance(); // skip first quote
+        let mut text = String::new();
+        loop {
+            match self.peek() {
+                None => return Err(self.error(&format!("{} starting with {} is never closed", what, quote), start)),
+                Some(c) if c == quote => {
+                    self.advance();
+                    if self.peek() != Some(quote) {
+                        return Ok(text);
+                    }
+                    self.advance();
+                    text.push(quote);
+                }
+                Some(_) => text.push(self.advance()),
+            }
         }
     }
 
-    fn parse_string_literal(&mut self, quote: char) -> Option<Token> {
-        self.position += 1; // skip first quote
+    // letters in any language, digits, _ and $
+    fn parse_identifier_or_keyword(&mut self) -> Token {
         let start = self.position;
+        self.eat_while(is_identifier_char);
+
+        let identifier = &self.input[start..self.position];
+        keyword_token(identifier).unwrap_or_else(|| Token::Identifier(identifier.to_string()))
+    }
+}
+
+fn is_identifier_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '$'
+}
+
+// the token of a word that has one of its own
+fn keyword_token(word: &str) -> Option<Token> {
+    match word.to_uppercase().as_str() {
+        "SELECT" => Some(Token::Select),
+        "INSERT" => Some(Token::Insert),
+        "UPDATE" => Some(Token::Update),
+        "DELETE" => Some(Token::Delete),
+        "CREATE" => Some(Token::Create),
+        "WHERE" => Some(Token::Where),
+        "VALUES" => Some(Token::Values),
+        "SET" => Some(Token::Set),
+        "VACUUM" => Some(Token::Vacuum),
+        "DROP" => Some(Token::Drop),
+        "TRUNCATE" => Some(Token::Truncate),
+        "ALTER" => Some(Token::Alter),
+        "SHOW" => Some(Token::Show),
+        "DESCRIBE" => Some(Token::Describe),
+        _ => None,
+    }
+}
+// whether a name has to be written in double quotes to be read back as the same name, because it
+// isnt a plain word or is one that means something on its own
+fn needs_quotes(name: &str) -> bool {
+    const WORDS: [&str; 19] = ["NULL", "TRUE", "FALSE", "NOT", "AND", "OR", "IS", "IN", "LIKE", "ILIKE", "GLOB", "BETWEEN", "CASE", "WHEN", "THEN", "ELSE", "END", "CAST", "EXISTS"];
+    let plain = name.starts_with(|c: char| c.is_alphabetic() || c == '_') && name.chars().all(is_identifier_char);
+    !plain || keyword_token(name).is_some() || WORDS.iter().any(|word| name.eq_ignore_ascii_case(word))
+}
+
+// name as it has to be written in SQL
+pub fn quote_identifier(name: &str) -> String {
+    if needs_quotes(name) {
+        format!("\"{}\"", name.replace('"', "\"\""))
+    } else {
+        name.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        while self.position < self.input.len()
-            && self.input.as_bytes()[self.position] as char != quote
-        {
-            self.position += 1;
+    fn tokens(input: &str) -> Vec<(usize, Token)> {
+        let mut lexer = Lexer::new(input.to_string());
+        let mut tokens = Vec::new();
+        while let Some(token) = lexer.next_token().unwrap() {
+            tokens.push(token);
         }
+        tokens
+    }
+
+    fn only_tokens(input: &str) -> Vec<Token> {
+        tokens(input).into_iter().map(|(_, token)| token).collect()
+    }
 
-        if self.position >= self.input.len() {
-            return None; // string wasnt terminated
+    // the position of the error the input fails to lex with
+    fn error_at(input: &str) -> (String, usize, usize, usize) {
+        let mut lexer = Lexer::new(input.to_string());
+        loop {
+            match lexer.next_token() {
+                Ok(Some(_)) => {}
+                Ok(None) => panic!("{:?} lexed without an error", input),
+                Err(Error::Parse { message, position: Some(at) }) => return (message, at.offset, at.line, at.column),
+                Err(e) => panic!("{:?} failed with {:?}", input, e),
+            }
         }
+    }
 
-        let literal = &self.input[start..self.position];
-        self.position += 1; // skip end quote
-        Some(Token::StringLiteral(literal.to_string()))
+    fn number(text: &str) -> Token {
+        Token::Number(text.to_string())
     }
 
-    fn parse_identifier_or_keyword(&mut self) -> Option<Token> {
-        let start = self.position;
+    #[test]
+    fn quotes_written_twice_stand_for_themselves() {
+        assert_eq!(only_tokens("'it''s' '' ''''"), [
+            Token::StringLiteral("it's".to_string()),
+            Token::StringLiteral(String::new()),
+            Token::StringLiteral("'".to_string()),
+        ]);
+        assert_eq!(only_tokens(r#""my ""table""" "select""#), [
+            Token::QuotedIdentifier(r#"my "table""#.to_string()),
+            Token::QuotedIdentifier("select".to_string()),
+        ]);
+        assert_eq!(error_at("SELECT 'abc").0, "String starting with ' is never closed");
+        assert_eq!(error_at(r#"SELECT """#).0, "Quoted identifiers cant be empty");
+    }
 
-        while self.position < self.input.len()
-            && self.input.as_bytes()[self.position].is_ascii_alphanumeric()
-        {
-            self.position += 1;
-        }
+    #[test]
+    fn comments_are_skipped() {
+        assert_eq!(only_tokens("SELECT -- the rest; of the line\n1 /* a ; b\n c */ ; /**/"), [Token::Select, number("1"), Token::Semicolon]);
+        assert_eq!(only_tokens("'--' '/* */'"), [Token::StringLiteral("--".to_string()), Token::StringLiteral("/* */".to_string())]);
+        assert_eq!(only_tokens("1 - -1"), [number("1"), Token::Operator("-".to_string()), Token::Operator("-".to_string()), number("1")]);
+        assert_eq!(error_at("SELECT 1 /* never closed").1, 9);
+    }
 
-        let identifier = &self.input[start..self.position];
-        match identifier.to_uppercase().as_str() {
-            "SELECT" => Some(Token::Select),
-            "INSERT" => Some(Token::Insert),
-            "DELETE" => Some(Token::Delete),
-            "CREATE" => Some(Token::Create),
-            "WHERE" => Some(Token::Where),
-            "VALUES" => Some(Token::Values),
-            "SET" => Some(Token::Set),
-            _ => Some(Token::Identifier(identifier.to_string())),
+    #[test]
+    fn numbers() {
+        for text in ["12", "1.5", ".5", "5.", "1e10", "2.5E-3", "1e+2", "0x1F", "0XfF"] {
+            assert_eq!(only_tokens(text), [number(text)], "{}", text);
         }
+        // an e without digits after it is a name of its own
+        assert_eq!(only_tokens("1 e"), [number("1"), Token::Identifier("e".to_string())]);
+        assert_eq!(error_at("SELECT 0x").0, "Expected hex digits after 0x");
+        assert_eq!(error_at("SELECT 12abc").0, "Invalid number 12a");
+        // a second . starts a number of its own, the parser is the one that says thats wrong
+        assert_eq!(only_tokens("1.2.3"), [number("1.2"), number(".3")]);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn names_can_be_in_any_language() {
+        assert_eq!(only_tokens("SELECT naïve_名前, $x$1 FROM _t"), [
+            Token::Select,
+            Token::Identifier("naïve_名前".to_string()),
+            Token::Comma,
+            Token::Parameter(Some(0)),
+            Token::Identifier("x$1".to_string()),
+            Token::Identifier("FROM".to_string()),
+            Token::Identifier("_t".to_string()),
+        ]);
+        assert_eq!(only_tokens("'Zoë 👋'"), [Token::StringLiteral("Zoë 👋".to_string())]);
+    }
+
+    #[test]
+    fn offsets_are_in_bytes_and_columns_in_chars() {
+        let offsets: Vec<usize> = tokens("'é' = 名").into_iter().map(|(offset, _)| offset).collect();
+        assert_eq!(offsets, [0, 5, 7]);
+        // é and 名 are 2 and 3 bytes but one column each
+        let (message, offset, line, column) = error_at("SELECT 'é',\n  名 # 1");
+        assert_eq!(message, "Unexpected character '#'");
+        assert_eq!((offset, line, column), (19, 2, 5));
+    }
+}
@@ -1,25 +1,225 @@
 mod lexer;
 pub mod ast;
 
-use lexer::Lexer;
+use crate::error::{Error, Position};
+use lexer::{Lexer, Token};
 pub use ast::Query;
+pub use lexer::quote_identifier;
 
 /// Parses a SQL query string into a general `Query` structure.
 ///
 /// # Arguments
-/// - `input`: The SQL query string to parse.
+/// - `input`: The SQL query string to parse, one statement with an optional `;` after it.
 ///
 /// # Returns
-/// - `Result<Query, String>`: A `Query` structure on success, or an error message on failure.
-pub fn parse_query(input: &str) -> Result<Query, String> {
+/// - `Result<Query, Error>`: A `Query` structure on success, or a parse error with the position
+///   the parser gave up at on failure.
+pub fn parse_query(input: &str) -> Result<Query, Error> {
+    let (tokens, offsets) = single_statement(input)?;
+    parse_unprepared(input, tokens, &offsets)
+}
+
+/// Parses a script of statements separated by `;`, like a schema migration.
+///
+/// # Returns
+/// - `Result<Vec<Query>, Error>`: Every statement in the order they appear, or the first parse error
+///   with its position in the whole script. Empty statements between two `;` are skipped.
+pub fn parse_script(input: &str) -> Result<Vec<Query>, Error> {
+    statements(input)?.into_iter()
+        .map(|(tokens, offsets)| parse_unprepared(input, tokens, &offsets))
+        .collect()
+}
+
+/// Parses a statement that is prepared over the protocol rather than by PREPARE.
+///
+/// # Returns
+/// - `Result<(Query, usize), Error>`: The statement and how many parameters it takes.
+pub fn parse_prepared(input: &str) -> Result<(Query, usize), Error> {
+    let (mut tokens, offsets) = single_statement(input)?;
+    let parameters = number_parameters(input, &mut tokens, &offsets)?;
+    Ok((parse_statement(input, tokens, &offsets)?, parameters))
+}
+
+/// Parses a single expression, as stored in the catalog for CHECK constraints and column defaults.
+pub fn parse_expression(input: &str) -> Result<ast::Expression, Error> {
+    let (tokens, offsets) = lex(input)?;
+    let total = tokens.len();
+    let mut tokens_iter = tokens.into_iter().peekable();
+    let parsed = ast::parse_expression(&mut tokens_iter).and_then(|expr| match tokens_iter.next() {
+        None => Ok(expr),
+        Some(token) => Err(format!("Unexpected {:?} after expression", token)),
+    });
+    parsed.map_err(|message| parse_error(input, &message, error_offset(&offsets, total - tokens_iter.len())))
+}
+
+// a statement run as it is, which can only have parameters if its a PREPARE
+fn parse_unprepared(input: &str, mut tokens: Vec<Token>, offsets: &[usize]) -> Result<Query, Error> {
+    number_parameters(input, &mut tokens, offsets)?;
+    let first_parameter = tokens.iter().position(|token| matches!(token, Token::Parameter(_)));
+
+    // parse tokens using AST module
+    let query = parse_statement(input, tokens, offsets)?;
+    if let Some(i) = first_parameter
+        && !matches!(query, Query::Prepare(_))
+    {
+        return Err(parse_error(input, "Parameters like $1 or ? can only be used in a statement given to PREPARE", offsets[i]));
+    }
+    Ok(query)
+}
+
+/// Whether `input` ends with a `;` that is outside any string, quoted name or comment, which is how
+/// an interactive prompt knows that what has been typed so far is ready to be run.
+pub fn ends_statement(input: &str) -> bool {
     let mut lexer = Lexer::new(input.to_string());
-    let mut tokens = Vec::new();
+    let mut last = None;
+    loop {
+        match lexer.next_token() {
+            Ok(Some((_, token))) => last = Some(token),
+            Ok(None) => return last == Some(Token::Semicolon),
+            // a character the lexer doesnt know wont be fixed by more input, so the statement goes
+            // to the server as it is once the line ends with ; and the error comes back from there
+            Err(_) if lexer.at_end() => return false,
+            Err(_) => return input.trim_end().ends_with(';'),
+        }
+    }
+}
+
+fn parse_statement(input: &str, tokens: Vec<Token>, offsets: &[usize]) -> Result<Query, Error> {
+    ast::parse_tokens_counted(tokens).map_err(|(message, read)| parse_error(input, &message, error_offset(offsets, read)))
+}
 
-    // Tokenize the input query
-    while let Some(token) = lexer.next_token() {
+// errors are put at the last token the parser read before giving up, which is the one it didnt
+// expect or the last one there was when the statement ended too soon
+fn error_offset(offsets: &[usize], read: usize) -> usize {
+    read.checked_sub(1).and_then(|i| offsets.get(i)).copied().unwrap_or(0)
+}
+
+fn parse_error(input: &str, message: &str, offset: usize) -> Error {
+    Error::Parse { message: message.to_string(), position: Some(Position::in_text(input, offset)) }
+}
+
+// every token of input and the byte offset it starts at
+fn lex(input: &str) -> Result<(Vec<Token>, Vec<usize>), Error> {
+    let mut lexer = Lexer::new(input.to_string());
+    let (mut tokens, mut offsets) = (Vec::new(), Vec::new());
+    while let Some((offset, token)) = lexer.next_token()? {
         tokens.push(token);
+        offsets.push(offset);
     }
+    Ok((tokens, offsets))
+}
 
-    // parse tokens using AST module
-    ast::parse_tokens(tokens)
-}
\ No newline at end of file
+// the tokens of each statement, split at the semicolons between them. a ; can only be a token
+// between statements since one inside a string is part of the string token
+type Statement = (Vec<Token>, Vec<usize>);
+
+fn statements(input: &str) -> Result<Vec<Statement>, Error> {
+    let (tokens, offsets) = lex(input)?;
+    let mut statements = Vec::new();
+    let mut current: Statement = (Vec::new(), Vec::new());
+    for (token, offset) in tokens.into_iter().zip(offsets) {
+        if token == Token::Semicolon {
+            if !current.0.is_empty() {
+                statements.push(std::mem::take(&mut current));
+            }
+            continue;
+        }
+        current.0.push(token);
+        current.1.push(offset);
+    }
+    if !current.0.is_empty() {
+        statements.push(current);
+    }
+    Ok(statements)
+}
+
+// the one statement in input, anything after its ; is an error rather than being ignored
+fn single_statement(input: &str) -> Result<Statement, Error> {
+    let mut statements = statements(input)?.into_iter();
+    let statement = statements.next().unwrap_or_default();
+    if let Some((_, next)) = statements.next() {
+        return Err(parse_error(input, "Only one statement can be run at a time, run a script for more", next[0]));
+    }
+    Ok(statement)
+}
+
+// numbers each ? in the order they appear and returns the highest parameter number
+fn number_parameters(input: &str, tokens: &mut [Token], offsets: &[usize]) -> Result<usize, Error> {
+    let (mut numbered, mut unnumbered, mut highest) = (false, 0, 0);
+    for (token, &offset) in tokens.iter_mut().zip(offsets) {
+        match token {
+            Token::Parameter(Some(0)) => return Err(parse_error(input, "Parameters are numbered from $1", offset)),
+            Token::Parameter(Some(n)) => {
+                numbered = true;
+                highest = highest.max(*n);
+            }
+            Token::Parameter(number) => {
+                unnumbered += 1;
+                highest = unnumbered;
+                *number = Some(unnumbered);
+            }
+            _ => {}
+        }
+        if numbered && unnumbered > 0 {
+            return Err(parse_error(input, "A statement can use $1, $2, ... or ? for its parameters but not both", offset));
+        }
+    }
+    Ok(highest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::Value;
+    use ast::DeleteQuery;
+
+    #[test]
+    fn delete_takes_an_optional_from() {
+        for sql in ["DELETE FROM t WHERE id = 1 RETURNING id", "DELETE t WHERE id = 1 RETURNING id", "delete from t"] {
+            let Ok(Query::Delete(DeleteQuery { table_name, .. })) = parse_query(sql) else {
+                panic!("{} didnt parse as a DELETE", sql);
+            };
+            assert_eq!(table_name, "t");
+        }
+        assert!(parse_query("DELETE FROM").is_err());
+    }
+
+    #[test]
+    fn the_smallest_integer_stays_an_integer() {
+        let Ok(ast::Expression::Literal(Value::Integer(n))) = parse_expression("-9223372036854775808") else {
+            panic!("-9223372036854775808 isnt an INTEGER literal");
+        };
+        assert_eq!(n, i64::MIN);
+        let Ok(ast::Expression::UnaryOp { operand, .. }) = parse_expression("-9223372036854775809") else {
+            panic!("-9223372036854775809 isnt negated");
+        };
+        assert!(matches!(*operand, ast::Expression::Literal(Value::Real(_))));
+    }
+
+    // the line and column a parse error points at
+    fn error_at(result: Result<impl std::fmt::Debug, Error>) -> (usize, usize) {
+        match result {
+            Err(Error::Parse { position: Some(position), .. }) => (position.line, position.column),
+            other => panic!("expected a parse error with a position, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parse_errors_point_at_the_token_the_parser_gave_up_at() {
+        assert_eq!(error_at(parse_query("SELECT * FORM t")), (1, 10));
+        assert_eq!(error_at(parse_query("SELECT *\n  FROM t\n  WHERE")), (3, 3));
+        assert_eq!(error_at(parse_query("INSERT INTO t VALUES (1, 'é') garbage")), (1, 31));
+        assert_eq!(error_at(parse_query("SELECT 1; SELECT 2")), (1, 11));
+        assert_eq!(error_at(parse_query("SELECT $0")), (1, 8));
+        assert_eq!(error_at(parse_query("SELECT ?")), (1, 8));
+        assert_eq!(error_at(parse_expression("1 +")), (1, 3));
+    }
+
+    #[test]
+    fn script_errors_are_placed_in_the_whole_script() {
+        assert_eq!(error_at(parse_script("CREATE TABLE t (id INTEGER);\nINSERT INTO t VALUES (1);\nINSERT t VALUE (2);")), (3, 10));
+        assert_eq!(parse_script("SELECT 1;; SELECT 2;").unwrap().len(), 2);
+        assert!(parse_query("").is_err());
+        assert_eq!(parse_query("").unwrap_err().code(), "42601");
+    }
+}
@@ -0,0 +1,304 @@
+use std::io::{self, Read, Write};
+
+use crate::error::{Error, Position};
+use crate::executor::Column;
+use crate::storage::codec::{self, Reader};
+use crate::types::{DataType, Value};
+
+// the framed protocol, which a client picks by sending MAGIC first. without it the server reads one
+// statement until the client closes its side, like it always has. after it, every message either way
+// is a type byte, a u32 length and that many bytes, and the connection stays open for more. prepared
+// statements last until the connection closes, and values given to BIND never go through the parser
+
+pub const MAGIC: &[u8; 4] = b"\0TNY";
+
+// client messages
+pub const QUERY: u8 = b'Q'; // statement text
+pub const PREPARE: u8 = b'P'; // name, statement text with $1 or ? parameters
+pub const BIND: u8 = b'B'; // name, u16 count, values
+pub const EXECUTE: u8 = b'E'; // name
+pub const SCRIPT: u8 = b'S'; // u8 1 to keep going after a statement fails, statements separated by ;
+pub const DUMP: u8 = b'D'; // nothing, answered by CHUNK frames with the SQL of every table and its rows and DONE
+
+// server messages
+pub const RESPONSE: u8 = b'R'; // the message the statement returned
+pub const ROWS: u8 = b'T'; // u16 count of columns, name and type code of each, the rows follow in BATCH frames
+pub const BATCH: u8 = b'W'; // u32 count of rows, values of each
+pub const CHUNK: u8 = b'K'; // the next part of a dump, which can end anywhere, even in a statement
+pub const DONE: u8 = b'C'; // nothing, every BATCH of a result or CHUNK of a dump has been sent
+// code, message, u8 1 if a position follows, u32 offset, line and column. it can also end a result
+// early in place of DONE
+pub const ERROR: u8 = b'X';
+// u32 count of the statements of a script that were run, followed by a response or error frame for
+// each. a script that doesnt parse is answered by one error frame instead
+pub const RESULTS: u8 = b'N';
+
+// bigger frames are refused rather than read into memory
+const MAX_FRAME: u32 = 64 * 1024 * 1024;
+
+// results are split into frames of about this size, so one of any size can be sent
+pub const BATCH_BYTES: usize = 1024 * 1024;
+
+// a frame the other side would refuse isnt sent, the error is InvalidInput so the sender can tell it
+// apart from the connection failing and answer with an error frame instead
+pub fn write_frame(stream: &mut impl Write, kind: u8, payload: &[u8]) -> io::Result<()> {
+    if payload.len() > MAX_FRAME as usize {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("frame of {} bytes is over the {} byte limit", payload.len(), MAX_FRAME)));
+    }
+    let mut frame = Vec::with_capacity(payload.len() + 5);
+    codec::put_u8(&mut frame, kind);
+    codec::put_u32(&mut frame, payload.len() as u32);
+    frame.extend_from_slice(payload);
+    stream.write_all(&frame)
+}
+
+// the next frame, None if the other side closed the connection between frames
+pub fn read_frame(stream: &mut impl Read) -> io::Result<Option<(u8, Vec<u8>)>> {
+    let mut header = [0u8; 5];
+    match stream.read_exact(&mut header[..1]) {
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        result => result?,
+    }
+    stream.read_exact(&mut header[1..])?;
+    let mut reader = Reader::new(&header);
+    let kind = reader.u8()?;
+    let len = reader.u32()?;
+    if len > MAX_FRAME {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("frame of {} bytes is over the {} byte limit", len, MAX_FRAME)));
+    }
+    let mut payload = vec![0u8; len as usize];
+    stream.read_exact(&mut payload)?;
+    Ok(Some((kind, payload)))
+}
+
+// values are a tag, 0 for NULL or one more than the type code, then the value as text
+pub fn put_values(buf: &mut Vec<u8>, values: &[Value]) {
+    codec::put_u16(buf, values.len() as u16);
+    for value in values {
+        let tag = match value {
+            Value::Null => 0,
+            Value::Integer(_) => DataType::Integer as u8 + 1,
+            Value::Real(_) => DataType::Real as u8 + 1,
+            Value::Text(_) => DataType::Text as u8 + 1,
+            Value::Boolean(_) => DataType::Boolean as u8 + 1,
+        };
+        codec::put_u8(buf, tag);
+        if tag != 0 {
+            codec::put_str(buf, &value.to_string());
+        }
+    }
+}
+
+pub(crate) fn read_values(reader: &mut Reader) -> io::Result<Vec<Value>> {
+    let count = reader.u16()?;
+    (0..count)
+        .map(|_| {
+            let tag = reader.u8()?;
+            if tag == 0 {
+                return Ok(Value::Null);
+            }
+            let data_type = DataType::from_code(tag - 1).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("unknown value tag {}", tag)))?;
+            let text = reader.str()?;
+            data_type.parse(&text).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("'{}' isnt a valid {}", text, data_type)))
+        })
+        .collect()
+}
+
+// a ROWS frame with the columns, the rows in BATCH frames of about BATCH_BYTES and then DONE. a row
+// too big for a frame on its own fails with InvalidInput once the rows before it have been sent
+pub fn write_rows(stream: &mut impl Write, columns: &[Column], rows: &[Vec<Value>]) -> io::Result<()> {
+    let mut header = Vec::new();
+    codec::put_u16(&mut header, columns.len() as u16);
+    for column in columns {
+        codec::put_str(&mut header, &column.name);
+        codec::put_u8(&mut header, column.data_type as u8);
+    }
+    write_frame(stream, ROWS, &header)?;
+
+    let mut batch = Vec::new();
+    let mut count = 0;
+    for row in rows {
+        put_values(&mut batch, row);
+        count += 1;
+        if batch.len() >= BATCH_BYTES {
+            write_batch(stream, count, &mut batch)?;
+            count = 0;
+        }
+    }
+    if count > 0 {
+        write_batch(stream, count, &mut batch)?;
+    }
+    write_frame(stream, DONE, &[])
+}
+
+// sends what is written to it as frames of kind of at most BATCH_BYTES, for output of any size that
+// is made bit by bit. finish sends what is left and then DONE
+pub struct FrameWriter<'a, W: Write> {
+    stream: &'a mut W,
+    kind: u8,
+    buffer: Vec<u8>,
+}
+
+impl<'a, W: Write> FrameWriter<'a, W> {
+    pub fn new(stream: &'a mut W, kind: u8) -> Self {
+        FrameWriter { stream, kind, buffer: Vec::new() }
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        self.flush()?;
+        write_frame(self.stream, DONE, &[])
+    }
+}
+
+impl<W: Write> Write for FrameWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        if self.buffer.len() >= BATCH_BYTES {
+            self.flush()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        for chunk in self.buffer.chunks(BATCH_BYTES) {
+            write_frame(self.stream, self.kind, chunk)?;
+        }
+        self.buffer.clear();
+        self.stream.flush()
+    }
+}
+
+fn write_batch(stream: &mut impl Write, count: u32, rows: &mut Vec<u8>) -> io::Result<()> {
+    let mut payload = Vec::with_capacity(rows.len() + 4);
+    codec::put_u32(&mut payload, count);
+    payload.append(rows);
+    write_frame(stream, BATCH, &payload)
+}
+
+pub fn read_columns(payload: &[u8]) -> io::Result<Vec<Column>> {
+    let mut reader = Reader::new(payload);
+    let count = reader.u16()?;
+    (0..count)
+        .map(|_| {
+            let name = reader.str()?;
+            let code = reader.u8()?;
+            let data_type = DataType::from_code(code).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("unknown type code {} for column {}", code, name)))?;
+            Ok(Column { name, data_type })
+        })
+        .collect()
+}
+
+// adds the rows of a BATCH frame to rows
+pub fn read_batch(payload: &[u8], rows: &mut Vec<Vec<Value>>) -> io::Result<()> {
+    let mut reader = Reader::new(payload);
+    let count = reader.u32()?;
+    for _ in 0..count {
+        rows.push(read_values(&mut reader)?);
+    }
+    Ok(())
+}
+
+pub fn put_error(buf: &mut Vec<u8>, error: &Error) {
+    codec::put_str(buf, error.code());
+    codec::put_str(buf, error.message());
+    match error.position() {
+        Some(position) => {
+            codec::put_u8(buf, 1);
+            for n in [position.offset, position.line, position.column] {
+                codec::put_u32(buf, n as u32);
+            }
+        }
+        None => codec::put_u8(buf, 0),
+    }
+}
+
+pub fn read_error(payload: &[u8]) -> io::Result<Error> {
+    let mut reader = Reader::new(payload);
+    let code = reader.str()?;
+    let message = reader.str()?;
+    let position = match reader.u8()? {
+        0 => None,
+        _ => Some(Position { offset: reader.u32()? as usize, line: reader.u32()? as usize, column: reader.u32()? as usize }),
+    };
+    Ok(Error::from_code(&code, message, position))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Violation;
+
+    #[test]
+    fn big_results_are_split_into_batches() {
+        let columns = vec![Column { name: "text".to_string(), data_type: DataType::Text }];
+        let rows: Vec<Vec<Value>> = (0..3000).map(|i| vec![Value::Text(format!("{:01000}", i))]).collect();
+        let mut sent = Vec::new();
+        write_rows(&mut sent, &columns, &rows).unwrap();
+
+        let mut stream = sent.as_slice();
+        let (kind, header) = read_frame(&mut stream).unwrap().unwrap();
+        assert_eq!((kind, read_columns(&header).unwrap()), (ROWS, columns));
+        let mut read = Vec::new();
+        let mut batches = 0;
+        loop {
+            match read_frame(&mut stream).unwrap() {
+                Some((BATCH, batch)) => {
+                    assert!(batch.len() < 2 * BATCH_BYTES);
+                    read_batch(&batch, &mut read).unwrap();
+                    batches += 1;
+                }
+                Some((DONE, _)) => break,
+                frame => panic!("expected a batch or the end, got {:?}", frame.map(|(kind, _)| kind)),
+            }
+        }
+        assert!(batches > 1);
+        assert_eq!(read, rows);
+        assert!(stream.is_empty());
+    }
+
+    #[test]
+    fn frames_over_the_limit_arent_sent() {
+        let mut sent = Vec::new();
+        let e = write_frame(&mut sent, RESPONSE, &vec![0; MAX_FRAME as usize + 1]).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        assert!(sent.is_empty());
+    }
+
+    #[test]
+    fn written_output_goes_out_in_chunks() {
+        let text: Vec<u8> = (0..3 * BATCH_BYTES + 10).map(|i| b'a' + (i % 26) as u8).collect();
+        let mut sent = Vec::new();
+        let mut chunks = FrameWriter::new(&mut sent, CHUNK);
+        for piece in text.chunks(1000) {
+            chunks.write_all(piece).unwrap();
+        }
+        chunks.finish().unwrap();
+
+        let mut stream = sent.as_slice();
+        let mut read = Vec::new();
+        while let Some((kind, payload)) = read_frame(&mut stream).unwrap() {
+            if kind == DONE {
+                break;
+            }
+            assert_eq!(kind, CHUNK);
+            assert!(payload.len() <= BATCH_BYTES);
+            read.extend(payload);
+        }
+        assert_eq!(read, text);
+        assert!(stream.is_empty());
+    }
+
+    #[test]
+    fn errors_keep_their_code_and_position_over_the_wire() {
+        let errors = [
+            Error::Parse { message: "Unexpected FORM".to_string(), position: Some(Position { offset: 9, line: 1, column: 10 }) },
+            Error::Constraint { violation: Violation::Unique, message: "duplicate key".to_string() },
+            Error::Corruption("bad page".to_string()),
+        ];
+        for e in errors {
+            let mut payload = Vec::new();
+            put_error(&mut payload, &e);
+            assert_eq!(read_error(&payload).unwrap(), e);
+        }
+    }
+}
@@ -0,0 +1,381 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use super::page::PAGE_SIZE;
+use super::wal::{self, Record};
+
+// an online backup is a copy of the database file taken page by page while statements keep writing
+// to it, so pages in the copy can be from before or after any statement that ran during it. the log
+// has every one of those writes as a whole page, so replaying the records from the LSN the copy
+// started at to the one it finished at makes it what the database was at that point. the label
+// next to the copy says which LSNs those are. replaying further, up to a time or LSN, is point in
+// time recovery
+
+pub struct Label {
+    pub start_lsn: u64,
+    pub end_lsn: u64,
+    pub time: u64, // milliseconds since the unix epoch when the copy finished
+}
+
+impl Label {
+    pub fn path(backup: &Path) -> PathBuf {
+        let mut path = backup.as_os_str().to_owned();
+        path.push(".label");
+        PathBuf::from(path)
+    }
+
+    pub fn write(&self, backup: &Path) -> io::Result<()> {
+        let text = format!("start_lsn {}\nend_lsn {}\ntime {} ({})\n", self.start_lsn, self.end_lsn, self.time, format_time(self.time));
+        fs::write(Self::path(backup), text)
+    }
+
+    pub fn read(backup: &Path) -> io::Result<Self> {
+        let path = Self::path(backup);
+        let text = fs::read_to_string(&path)
+            .map_err(|e| io::Error::new(e.kind(), format!("cant read the backup label {}: {}", path.display(), e)))?;
+        let field = |name: &str| {
+            text.lines()
+                .find_map(|line| line.strip_prefix(name)?.strip_prefix(' ')?.split_whitespace().next()?.parse().ok())
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("backup label {} has no {}", path.display(), name)))
+        };
+        Ok(Label { start_lsn: field("start_lsn")?, end_lsn: field("end_lsn")?, time: field("time")? })
+    }
+}
+
+// copies the database file as it is right now, without waiting for statements writing to it. returns
+// how many pages were copied
+pub fn copy_pages(from: &Path, to: &Path) -> io::Result<u64> {
+    let mut source = File::open(from)?;
+    let mut copy = File::create(to)?;
+    let bytes = io::copy(&mut source, &mut copy)?;
+    copy.flush()?;
+    Ok(bytes.div_ceil(PAGE_SIZE as u64))
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Target {
+    Lsn(u64),
+    Time(u64), // milliseconds since the unix epoch
+}
+
+pub struct Recovered {
+    pub statements: u64, // replayed after the backup was consistent
+    pub lsn: u64,
+    pub time: u64, // of the last statement replayed, or the end of the backup when there were none
+}
+
+// where the server keeps its log
+pub fn wal_dir() -> io::Result<PathBuf> {
+    wal::dir()
+}
+
+// writes the backup to output and replays the log in wal_dir over it, a statement at a time, up to the
+// last statement that finished at or before target, or to the end of the log without one. replaying
+// stops where the database file was deleted, the log after that is for a different database
+pub fn recover(backup: &Path, wal_dir: &Path, output: &Path, target: Option<Target>) -> io::Result<Recovered> {
+    let label = Label::read(backup)?;
+    match target {
+        Some(Target::Lsn(lsn)) if lsn < label.end_lsn => {
+            return Err(invalid(format!("the backup is only consistent from LSN {}, it cant be recovered to LSN {}", label.end_lsn, lsn)));
+        }
+        Some(Target::Time(time)) if time < label.time => {
+            return Err(invalid(format!("the backup finished at {}, it cant be recovered to {}", format_time(label.time), format_time(time))));
+        }
+        _ => {}
+    }
+    fs::copy(backup, output)?;
+    let mut file = OpenOptions::new().write(true).open(output)?;
+
+    // segments that end before the backup started arent needed
+    let segments = wal::segments(wal_dir)?;
+    let first = segments.iter().rposition(|(first_lsn, _)| *first_lsn <= label.start_lsn + 1).unwrap_or(0);
+    let mut recovered = Recovered { statements: 0, lsn: label.start_lsn, time: label.time };
+    let mut expected = label.start_lsn + 1;
+    let mut pending = Vec::new();
+    'segments: for (_, path) in &segments[first..] {
+        for record in wal::read_segment(path)? {
+            if record.lsn() < expected {
+                continue;
+            }
+            if record.lsn() > expected {
+                return Err(invalid(format!("the log is missing records {} to {}", expected, record.lsn() - 1)));
+            }
+            expected += 1;
+            match record {
+                Record::Page { .. } | Record::Truncate { .. } => pending.push(record),
+                Record::Commit { lsn, time } => {
+                    let past = match target {
+                        Some(Target::Lsn(target)) => lsn > target,
+                        Some(Target::Time(target)) => time > target,
+                        None => false,
+                    };
+                    if past {
+                        // the clock went back while the backup was taken
+                        if lsn <= label.end_lsn {
+                            return Err(invalid(format!("statement {} inside the backup finished after the time to recover to", lsn)));
+                        }
+                        break 'segments;
+                    }
+                    for record in pending.drain(..) {
+                        apply(&mut file, record)?;
+                    }
+                    recovered.lsn = lsn;
+                    if lsn > label.end_lsn {
+                        recovered.statements += 1;
+                        recovered.time = time;
+                    }
+                }
+                Record::Reset { lsn, .. } => {
+                    if lsn <= label.end_lsn {
+                        return Err(invalid("the database was deleted while the backup was being taken".to_string()));
+                    }
+                    break 'segments;
+                }
+            }
+        }
+    }
+    if recovered.lsn < label.end_lsn {
+        return Err(invalid(format!("the log ends at LSN {}, before the backup is consistent at LSN {}", recovered.lsn, label.end_lsn)));
+    }
+    file.flush()?;
+    Ok(recovered)
+}
+
+fn apply(file: &mut File, record: Record) -> io::Result<()> {
+    match record {
+        Record::Page { page_num, data, .. } => {
+            file.seek(SeekFrom::Start(page_num as u64 * PAGE_SIZE as u64))?;
+            file.write_all(&data)
+        }
+        Record::Truncate { page_count, .. } => file.set_len(page_count as u64 * PAGE_SIZE as u64),
+        Record::Commit { .. } | Record::Reset { .. } => Ok(()),
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+const MILLIS_PER_DAY: u64 = 24 * 60 * 60 * 1000;
+
+// YYYY-MM-DD HH:MM:SS.fff UTC
+pub fn format_time(millis: u64) -> String {
+    let (year, month, day) = civil_from_days((millis / MILLIS_PER_DAY) as i64);
+    let of_day = millis % MILLIS_PER_DAY;
+    format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02}.{:03} UTC", year, month, day,
+        of_day / 3_600_000, of_day / 60_000 % 60, of_day / 1000 % 60, of_day % 1000)
+}
+
+// milliseconds since the unix epoch from YYYY-MM-DD HH:MM:SS[.fff] in UTC, with a T in the middle
+// too and the UTC format_time puts on the end or not, or from a count of seconds since the epoch
+pub fn parse_time(text: &str) -> Option<u64> {
+    let text = text.trim();
+    let text = text.strip_suffix(" UTC").unwrap_or(text);
+    if text.chars().all(|c| c.is_ascii_digit()) {
+        return text.parse::<u64>().ok()?.checked_mul(1000);
+    }
+    let (date, time) = text.split_once([' ', 'T'])?;
+    let mut date = date.split('-').map(|part| part.parse::<i64>().ok());
+    let (year, month, day) = (date.next()??, date.next()??, date.next()??);
+    let (time, fraction) = time.split_once('.').unwrap_or((time, "0"));
+    let mut time = time.split(':').map(|part| part.parse::<u64>().ok());
+    let (hour, minute, second) = (time.next()??, time.next()??, time.next()??);
+    if date.next().is_some() || time.next().is_some() || !(1..=12).contains(&month) || !(1..=31).contains(&day)
+        || hour > 23 || minute > 59 || second > 59 || fraction.is_empty() || fraction.len() > 3
+    {
+        return None;
+    }
+    let millis = fraction.parse::<u64>().ok()? * 10u64.pow(3 - fraction.len() as u32);
+    let days = u64::try_from(days_from_civil(year, month, day)).ok()?;
+    Some(days * MILLIS_PER_DAY + ((hour * 60 + minute) * 60 + second) * 1000 + millis)
+}
+
+// days since 1970-01-01 of a date in the proleptic gregorian calendar and back, the years are counted
+// in 400 year eras from March so the leap day falls at the end of one
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::wal::Wal;
+
+    // a backup, its log and what was recovered from them, deleted again when the test is done
+    struct Scratch {
+        dir: PathBuf,
+        wal: Wal,
+    }
+
+    impl Scratch {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("tony_db_backup_{}_{}", std::process::id(), name));
+            let _ = fs::remove_dir_all(&dir);
+            let wal = Wal::open(dir.join("wal")).unwrap();
+            Scratch { dir, wal }
+        }
+
+        fn backup(&self, pages: &[u8], label: Label) -> PathBuf {
+            let path = self.dir.join("backup.db");
+            fs::write(&path, pages.iter().flat_map(|&fill| page(fill)).collect::<Vec<_>>()).unwrap();
+            label.write(&path).unwrap();
+            path
+        }
+
+        fn statement(&mut self, pages: &[(u32, u8)], time: u64) {
+            for &(page_num, fill) in pages {
+                self.wal.log_page(page_num, &page(fill)).unwrap();
+            }
+            self.wal.commit(time).unwrap();
+        }
+
+        fn recover(&self, backup: &Path, target: Option<Target>) -> io::Result<(Recovered, Vec<u8>)> {
+            let output = self.dir.join("recovered.db");
+            let _ = fs::remove_file(&output);
+            let recovered = recover(backup, &self.dir.join("wal"), &output, target)?;
+            Ok((recovered, pages_of(&fs::read(&output).unwrap())))
+        }
+    }
+
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    fn page(fill: u8) -> [u8; PAGE_SIZE] {
+        [fill; PAGE_SIZE]
+    }
+
+    // what each page of a file was filled with
+    fn pages_of(file: &[u8]) -> Vec<u8> {
+        file.chunks(PAGE_SIZE)
+            .map(|chunk| {
+                assert!(chunk.len() == PAGE_SIZE && chunk.iter().all(|&b| b == chunk[0]), "page isnt a whole page of one byte");
+                chunk[0]
+            })
+            .collect()
+    }
+
+    // the backup was copied while the first statement was writing, so it has page 1 half written.
+    // the log after it has
+    //   LSN 1-2  page 1 = b, commit at 1000  (during the copy)
+    //   LSN 3-4  page 2 = c, commit at 2000
+    //   LSN 5-7  truncate to 2 pages, page 0 = d, commit at 3000
+    //   LSN 8    page 1 = e, never committed
+    fn history(name: &str) -> (Scratch, PathBuf) {
+        let mut scratch = Scratch::new(name);
+        let backup = scratch.backup(b"aX", Label { start_lsn: 0, end_lsn: 2, time: 1500 });
+        let mut torn = fs::read(&backup).unwrap();
+        torn[PAGE_SIZE + PAGE_SIZE / 2..].fill(b'a');
+        fs::write(&backup, torn).unwrap();
+
+        scratch.statement(&[(1, b'b')], 1000);
+        scratch.statement(&[(2, b'c')], 2000);
+        scratch.wal.log_truncate(2).unwrap();
+        scratch.statement(&[(0, b'd')], 3000);
+        scratch.wal.log_page(1, &page(b'e')).unwrap();
+        (scratch, backup)
+    }
+
+    #[test]
+    fn recovers_to_the_end_of_the_log() {
+        let (scratch, backup) = history("latest");
+        let (recovered, pages) = scratch.recover(&backup, None).unwrap();
+        assert_eq!(pages, b"db");
+        assert_eq!((recovered.lsn, recovered.statements, recovered.time), (7, 2, 3000));
+    }
+
+    #[test]
+    fn recovers_to_an_intermediate_lsn() {
+        let (scratch, backup) = history("lsn");
+        let (recovered, pages) = scratch.recover(&backup, Some(Target::Lsn(4))).unwrap();
+        assert_eq!(pages, b"abc");
+        assert_eq!((recovered.lsn, recovered.statements, recovered.time), (4, 1, 2000));
+
+        // an LSN in the middle of a statement stops before it
+        let (recovered, pages) = scratch.recover(&backup, Some(Target::Lsn(6))).unwrap();
+        assert_eq!(pages, b"abc");
+        assert_eq!(recovered.lsn, 4);
+    }
+
+    #[test]
+    fn recovers_to_the_end_of_the_backup() {
+        let (scratch, backup) = history("backup_end");
+        let (recovered, pages) = scratch.recover(&backup, Some(Target::Lsn(2))).unwrap();
+        // the half written page is whole again
+        assert_eq!(pages, b"ab");
+        assert_eq!((recovered.lsn, recovered.statements, recovered.time), (2, 0, 1500));
+    }
+
+    #[test]
+    fn recovers_to_a_time() {
+        let (scratch, backup) = history("time");
+        let (recovered, pages) = scratch.recover(&backup, Some(Target::Time(2999))).unwrap();
+        assert_eq!(pages, b"abc");
+        assert_eq!(recovered.lsn, 4);
+    }
+
+    #[test]
+    fn cant_recover_to_before_the_backup_is_consistent() {
+        let (scratch, backup) = history("too_early");
+        assert!(scratch.recover(&backup, Some(Target::Lsn(1))).is_err());
+        assert!(scratch.recover(&backup, Some(Target::Time(1499))).is_err());
+    }
+
+    #[test]
+    fn stops_at_a_reset() {
+        let (mut scratch, backup) = history("reset");
+        scratch.wal.reset(4000).unwrap();
+        scratch.statement(&[(0, b'z')], 5000);
+        let (recovered, pages) = scratch.recover(&backup, None).unwrap();
+        assert_eq!(pages, b"db");
+        assert_eq!(recovered.lsn, 7);
+    }
+
+    #[test]
+    fn a_missing_segment_is_an_error() {
+        let mut scratch = Scratch::new("gap");
+        let backup = scratch.backup(b"aa", Label { start_lsn: 0, end_lsn: 0, time: 0 });
+        scratch.wal.segment_size = PAGE_SIZE as u64;
+        scratch.statement(&[(0, b'b')], 1000);
+        scratch.statement(&[(1, b'c')], 2000);
+        scratch.statement(&[(2, b'd')], 3000);
+        assert_eq!(scratch.recover(&backup, None).unwrap().1, b"bcd");
+
+        let segments = wal::segments(&scratch.dir.join("wal")).unwrap();
+        assert_eq!(segments.len(), 3);
+        fs::remove_file(&segments[1].1).unwrap();
+        let Err(err) = scratch.recover(&backup, None) else { panic!("recovered over a gap in the log") };
+        assert!(err.to_string().contains("missing records 3 to 4"), "{}", err);
+    }
+
+    #[test]
+    fn times_format_and_parse_back() {
+        assert_eq!(format_time(0), "1970-01-01 00:00:00.000 UTC");
+        assert_eq!(format_time(951_868_799_500), "2000-02-29 23:59:59.500 UTC");
+        assert_eq!(parse_time("2000-02-29T23:59:59.5"), Some(951_868_799_500));
+        assert_eq!(parse_time(&format_time(1_792_351_544_728)), Some(1_792_351_544_728));
+        assert_eq!(parse_time("1792351544"), Some(1_792_351_544_000));
+        assert_eq!(parse_time("2026-13-01 00:00:00"), None);
+    }
+}
@@ -1,181 +1,338 @@
 use std::io;
+use super::codec::{self, Reader};
 use super::storage::StorageEngine;
-use crate::storage::page::{Page, PAGE_SIZE, HEADER_SIZE, PageHeader, PageType};
+use super::tree::{BTree, RecordId};
+use crate::storage::page::{PageType, PAGE_SIZE};
+use crate::types::{DataType, ReferentialAction};
 
-// catalog that stores the table names mapped to the root node for that table
+// the catalog is a system table: a heap chain starting on the page after the file header
+// with one binary record per table
+pub const CATALOG_ROOT_PAGE: u32 = 1;
+
+// first byte of every catalog record so a file written with another layout is caught
+// instead of being read as garbage. it goes up when the way index keys are written changes too,
+// as the tables indexes would be read wrong then
+const CATALOG_FORMAT_VERSION: u8 = 6;
+
+// catalog that stores the tables, their columns and the indexes over them
 pub struct Catalog;
 
-pub struct CatalogEntry {
-    pub table_name: String,
+// rows store values against the column id rather than the position, so columns can be added,
+// dropped and renamed without rewriting the rows already stored
+#[derive(Debug, Clone)]
+pub struct ColumnSchema {
+    pub id: u16,
+    pub name: String,
+    pub data_type: DataType,
+    pub not_null: bool,
+    pub default: Option<String>, // SQL text of the DEFAULT expression
+    pub missing: Option<String>, // what rows written before the column existed read as, None is NULL
+}
+
+// unique indexes back the PRIMARY KEY and UNIQUE constraints and share their name
+#[derive(Debug, Clone)]
+pub struct IndexSchema {
+    pub name: String,
+    pub columns: Vec<String>,
     pub root_page_id: u32,
+    pub unique: bool,
+    pub primary: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct CheckSchema {
+    pub name: String,
+    pub expr: String, // SQL text of the expression, parsed again when the table is loaded
+}
+
+// columns of this table that have to match parent_columns of a row in parent_table. the parent
+// columns are always covered by one of the parents unique indexes
+#[derive(Debug, Clone)]
+pub struct ForeignKeySchema {
+    pub name: String,
     pub columns: Vec<String>,
+    pub parent_table: String,
+    pub parent_columns: Vec<String>,
+    pub on_delete: ReferentialAction,
+    pub on_update: ReferentialAction,
+    pub deferred: bool, // checked when the statement finishes rather than row by row
 }
 
-impl CatalogEntry {
-    pub fn get_entry_size(&self) -> u32 {
-        let entry_string = self.to_entry_string();
-        entry_string.as_bytes().len() as u32
+#[derive(Debug, Clone)]
+pub struct TableSchema {
+    pub name: String,
+    pub heap_page_id: u32, // first page of the heap chain holding the rows
+    pub columns: Vec<ColumnSchema>,
+    pub indexes: Vec<IndexSchema>,
+    pub checks: Vec<CheckSchema>,
+    pub foreign_keys: Vec<ForeignKeySchema>,
+    pub next_column_id: u16, // ids of dropped columns are never reused
+}
+
+impl TableSchema {
+    pub fn column_index(&self, name: &str) -> Option<usize> {
+        self.columns.iter().position(|c| c.name == name)
     }
 
-    pub fn to_entry_string(&self) -> String {
-        let cols = self.columns.join(",");
-        format!("{}:{}:{}\n", self.table_name, self.root_page_id, cols)
+    pub fn primary_key(&self) -> Option<&IndexSchema> {
+        self.indexes.iter().find(|i| i.primary)
     }
-}
 
-impl Catalog {
-    // create catalog page if file is empty
-    pub fn init_if_missing(engine: &mut StorageEngine) -> io::Result<()> {
-        println!("Running init if missing");
-        if engine.file_len()? == 0 {
-            let root_id = engine.allocate_page(PageType::Catalog)?;
-            if root_id != 1 {
-                return Err(io::Error::new(io::ErrorKind::Other, "expected first allocated page to be page 0"));
-            }
-        }
-        Ok(())
+    // the unique index over exactly these columns, in any order
+    pub fn unique_index_on(&self, columns: &[String]) -> Option<&IndexSchema> {
+        self.indexes.iter().find(|i| i.unique && i.columns.len() == columns.len() && columns.iter().all(|c| i.columns.contains(c)))
     }
 
-    // add an entry mapping table_name -> root page for that table
-    pub fn add_table(engine: &mut StorageEngine, table_name: &str, columns: &Vec<String>) -> io::Result<()> {
-        let table_root_id = engine.allocate_page(PageType::Index).unwrap();
-        let entry = CatalogEntry {
-            table_name: table_name.to_string(),
-            root_page_id: table_root_id,
-            columns: columns.clone()
-        };
-        let entry_size = entry.get_entry_size();
-
-        let mut page_id = 0;
-        let head_buf = &mut [0u8; HEADER_SIZE];
-        engine.read_page_header(page_id, head_buf);
-        let mut header = PageHeader::from_bytes(head_buf);
-
-        let mut allocate_new = false;
-        while (header.free_space as u32) < entry_size {
-            let next = header.next_page;
-            if next == 0 {
-                allocate_new = true;
-                break
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        codec::put_u8(&mut buf, CATALOG_FORMAT_VERSION);
+        codec::put_str(&mut buf, &self.name);
+        codec::put_u32(&mut buf, self.heap_page_id);
+        codec::put_u16(&mut buf, self.next_column_id);
+
+        codec::put_u16(&mut buf, self.columns.len() as u16);
+        for column in &self.columns {
+            codec::put_u16(&mut buf, column.id);
+            codec::put_str(&mut buf, &column.name);
+            codec::put_u8(&mut buf, column.data_type as u8);
+            codec::put_u8(&mut buf, column.not_null as u8);
+            put_optional_str(&mut buf, &column.default);
+            put_optional_str(&mut buf, &column.missing);
+        }
+
+        codec::put_u16(&mut buf, self.indexes.len() as u16);
+        for index in &self.indexes {
+            codec::put_str(&mut buf, &index.name);
+            codec::put_u32(&mut buf, index.root_page_id);
+            codec::put_u8(&mut buf, index.unique as u8);
+            codec::put_u8(&mut buf, index.primary as u8);
+            codec::put_u16(&mut buf, index.columns.len() as u16);
+            for column in &index.columns {
+                codec::put_str(&mut buf, column);
             }
-            engine.read_page_header(next, head_buf);
-            header = PageHeader::from_bytes(head_buf);
         }
 
-        if allocate_new {
-            page_id = engine.allocate_page(PageType::Catalog)?;
-            header.next_page = page_id;
+        codec::put_u16(&mut buf, self.checks.len() as u16);
+        for check in &self.checks {
+            codec::put_str(&mut buf, &check.name);
+            codec::put_str(&mut buf, &check.expr);
         }
 
-        // write entry to page
-        let page_buf = &mut [0u8; PAGE_SIZE];
-        engine.read_page(page_id, page_buf)?;
-        let mut catalog_page = Page::from_bytes(page_buf);
-        let record = entry.to_entry_string();
-        catalog_page.write_record(&record);
-        
-        Ok(())
+        codec::put_u16(&mut buf, self.foreign_keys.len() as u16);
+        for fk in &self.foreign_keys {
+            codec::put_str(&mut buf, &fk.name);
+            put_str_list(&mut buf, &fk.columns);
+            codec::put_str(&mut buf, &fk.parent_table);
+            put_str_list(&mut buf, &fk.parent_columns);
+            codec::put_u8(&mut buf, fk.on_delete as u8);
+            codec::put_u8(&mut buf, fk.on_update as u8);
+            codec::put_u8(&mut buf, fk.deferred as u8);
+        }
+        buf
     }
 
-    pub fn table_exists(engine: &mut StorageEngine, table_name: &str) -> io::Result<bool> {
-        if let Some(_root) = Self::lookup_root(engine, table_name)? {
-            return Ok(true);
+    pub fn from_bytes(buf: &[u8]) -> io::Result<Self> {
+        let mut reader = Reader::new(buf);
+        let version = reader.u8()?;
+        if version != CATALOG_FORMAT_VERSION {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unknown catalog format version {}", version)));
         }
-        Ok(false)
-    }
+        let name = reader.str()?;
+        let heap_page_id = reader.u32()?;
+        let next_column_id = reader.u16()?;
 
-    pub fn get_entry(engine: &mut StorageEngine, table_name: &str) -> Option<CatalogEntry> {
-        let mut page_id = 0;
-        let page_buf = &mut [0u8; PAGE_SIZE];
-        engine.read_page(page_id, page_buf);
-        let mut page = Page::from_bytes(page_buf);
+        let column_count = reader.u16()?;
+        let mut columns = Vec::with_capacity(column_count as usize);
+        for _ in 0..column_count {
+            let id = reader.u16()?;
+            let column_name = reader.str()?;
+            let code = reader.u8()?;
+            let data_type = DataType::from_code(code)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("unknown type code {} for column {}", code, column_name)))?;
+            let not_null = reader.u8()? == 1;
+            let default = read_optional_str(&mut reader)?;
+            let missing = read_optional_str(&mut reader)?;
+            columns.push(ColumnSchema { id, name: column_name, data_type, not_null, default, missing });
+        }
 
-        if let Some(entry) = Self::get_entry_from_page(table_name, &page) {
-            return Some(entry);
+        let index_count = reader.u16()?;
+        let mut indexes = Vec::with_capacity(index_count as usize);
+        for _ in 0..index_count {
+            let index_name = reader.str()?;
+            let root_page_id = reader.u32()?;
+            let unique = reader.u8()? == 1;
+            let primary = reader.u8()? == 1;
+            let index_column_count = reader.u16()?;
+            let mut index_columns = Vec::with_capacity(index_column_count as usize);
+            for _ in 0..index_column_count {
+                index_columns.push(reader.str()?);
+            }
+            indexes.push(IndexSchema { name: index_name, columns: index_columns, root_page_id, unique, primary });
         }
 
-        while page.header.next_page != 0 {
-            page_id = page.header.next_page;
-            engine.read_page(page_id, page_buf);
-            page = Page::from_bytes(page_buf);
+        let check_count = reader.u16()?;
+        let mut checks = Vec::with_capacity(check_count as usize);
+        for _ in 0..check_count {
+            checks.push(CheckSchema { name: reader.str()?, expr: reader.str()? });
+        }
 
-            if let Some(root) = Self::get_entry_from_page(table_name, &page) {
-                return Some(root)
-            } 
+        let fk_count = reader.u16()?;
+        let mut foreign_keys = Vec::with_capacity(fk_count as usize);
+        for _ in 0..fk_count {
+            foreign_keys.push(ForeignKeySchema {
+                name: reader.str()?,
+                columns: read_str_list(&mut reader)?,
+                parent_table: reader.str()?,
+                parent_columns: read_str_list(&mut reader)?,
+                on_delete: read_action(&mut reader)?,
+                on_update: read_action(&mut reader)?,
+                deferred: reader.u8()? == 1,
+            });
         }
-        None 
+
+        Ok(TableSchema { name, heap_page_id, columns, indexes, checks, foreign_keys, next_column_id })
     }
+}
 
-    pub fn get_entry_from_page(table_name: &str, page: &Page) -> Option<CatalogEntry> {
-        let text = std::str::from_utf8(&page.data).unwrap_or("");
-        for line in text.split_terminator('\n') {
-            let mut parts = line.split(':');
-            let name = parts.next().unwrap_or("");
-            if name == table_name {
-                continue;
-            }
-            let pid_str = parts.next().unwrap_or("");
-            let cols = parts.next().unwrap_or("");
+fn put_optional_str(buf: &mut Vec<u8>, s: &Option<String>) {
+    match s {
+        Some(s) => {
+            codec::put_u8(buf, 1);
+            codec::put_str(buf, s);
+        }
+        None => codec::put_u8(buf, 0),
+    }
+}
 
-            let cols_vec = cols.split(',').map(|s| s.to_string()).collect();
+fn read_optional_str(reader: &mut Reader) -> io::Result<Option<String>> {
+    Ok(if reader.u8()? == 1 { Some(reader.str()?) } else { None })
+}
 
-            return Some(
-            CatalogEntry {
-                table_name: name.to_owned(),
-                root_page_id: pid_str.parse::<u32>().unwrap(),
-                columns: cols_vec
-            })
-        }
-        None 
+fn put_str_list(buf: &mut Vec<u8>, list: &[String]) {
+    codec::put_u16(buf, list.len() as u16);
+    for s in list {
+        codec::put_str(buf, s);
     }
+}
 
-    // find the root for a table
-    pub fn lookup_root(engine: &mut StorageEngine, table_name: &str) -> io::Result<Option<u32>> {
-        let mut page_id = 0;
-        let page_buf = &mut [0u8; PAGE_SIZE];
-        engine.read_page(page_id, page_buf);
-        let mut page = Page::from_bytes(page_buf);
+fn read_str_list(reader: &mut Reader) -> io::Result<Vec<String>> {
+    let count = reader.u16()?;
+    (0..count).map(|_| reader.str()).collect()
+}
+
+fn read_action(reader: &mut Reader) -> io::Result<ReferentialAction> {
+    let code = reader.u8()?;
+    ReferentialAction::from_code(code)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("unknown referential action code {}", code)))
+}
 
-        if let Some(root) = Self::get_root_for_table(&page, table_name)? {
-            return Ok(Some(root))
+impl Catalog {
+    // create catalog page if file only has its header
+    pub fn init_if_missing(engine: &mut StorageEngine) -> io::Result<()> {
+        if engine.file_len()? <= CATALOG_ROOT_PAGE as u64 * PAGE_SIZE as u64 {
+            let root_id = engine.allocate_page(PageType::Heap)?;
+            if root_id != CATALOG_ROOT_PAGE {
+                return Err(io::Error::other(format!("expected first allocated page to be page {}", CATALOG_ROOT_PAGE)));
+            }
         }
-        while page.header.next_page != 0 {
-            page_id = page.header.next_page;
-            engine.read_page(page_id, page_buf);
-            page = Page::from_bytes(page_buf);
+        Ok(())
+    }
 
-            if let Some(root) = Self::get_root_for_table(&page, table_name)? {
-                return Ok(Some(root))
-            } 
+    // create the heap and indexes for a new table and record it in the catalog. column ids and index
+    // root pages are given out here. the primary key is the tables main index, a table without one
+    // gets an index on its first column instead
+    pub fn add_table(engine: &mut StorageEngine, table_name: &str, mut columns: Vec<ColumnSchema>, mut indexes: Vec<IndexSchema>, checks: Vec<CheckSchema>, foreign_keys: Vec<ForeignKeySchema>) -> io::Result<TableSchema> {
+        for (id, column) in columns.iter_mut().enumerate() {
+            column.id = id as u16;
+        }
+        if let Some(pos) = indexes.iter().position(|i| i.primary) {
+            let primary = indexes.remove(pos);
+            indexes.insert(0, primary);
+        } else if let Some(first) = columns.first() {
+            indexes.insert(0, IndexSchema {
+                name: format!("{}_{}_idx", table_name, first.name),
+                columns: vec![first.name.clone()],
+                root_page_id: 0,
+                unique: false,
+                primary: false,
+            });
         }
 
-        Ok(None)
+        let heap_page_id = engine.allocate_page(PageType::Heap)?;
+        for index in &mut indexes {
+            index.root_page_id = BTree::create(engine)?.root();
+        }
+
+        let schema = TableSchema {
+            name: table_name.to_string(),
+            heap_page_id,
+            next_column_id: columns.len() as u16,
+            columns,
+            indexes,
+            checks,
+            foreign_keys,
+        };
+        engine.insert_record(CATALOG_ROOT_PAGE, &schema.to_bytes())?;
+        engine.bump_schema_version()?;
+
+        Ok(schema)
     }
 
-    fn get_root_for_table(page: &Page, table_name: &str) -> io::Result<Option<u32>> {
-        let text = std::str::from_utf8(&page.data).unwrap_or("");
-        for line in text.split_terminator('\n') {
-            let mut parts = line.split(':');
-            let name = parts.next().unwrap_or("");
-            if name == table_name {
-                continue;
-            }
-            let pid_str = parts.next().unwrap_or("");
-            if let Ok(root) = pid_str.parse::<u32>() {
-                return Ok(Some(root));
-            }
-        }
-        Ok(None) 
+    // replace the catalog record of table_name, which may have been renamed in schema
+    pub fn update_table(engine: &mut StorageEngine, table_name: &str, schema: &TableSchema) -> io::Result<()> {
+        let (rid, _) = Self::find(engine, table_name)?
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("table {} not in catalog", table_name)))?;
+        engine.update_record(CATALOG_ROOT_PAGE, rid, &schema.to_bytes())?;
+        engine.bump_schema_version()?;
+        Ok(())
     }
 
-    pub fn get_cols_for_table(engine: &mut StorageEngine, table_name: &str) -> Option<Vec<String>> {
-        if let Some(entry) = Self::get_entry(engine, table_name) {
-            return Some(entry.columns);
+    // remove a table from the catalog and give its heap and index pages back to the freelist.
+    // returns false if there was no such table
+    pub fn drop_table(engine: &mut StorageEngine, table_name: &str) -> io::Result<bool> {
+        let Some((rid, schema)) = Self::find(engine, table_name)? else {
+            return Ok(false);
+        };
+        engine.free_heap(schema.heap_page_id, false)?;
+        for index in &schema.indexes {
+            BTree::open(engine, index.root_page_id).destroy()?;
         }
-        return None
+        engine.delete_record(rid)?;
+        engine.bump_schema_version()?;
+        Ok(true)
+    }
+
+    pub fn table_exists(engine: &mut StorageEngine, table_name: &str) -> io::Result<bool> {
+        Ok(Self::find(engine, table_name)?.is_some())
+    }
+
+    pub fn get_table(engine: &mut StorageEngine, table_name: &str) -> io::Result<Option<TableSchema>> {
+        Ok(Self::find(engine, table_name)?.map(|(_, schema)| schema))
     }
 
-    pub fn list_tables(_eng: &mut StorageEngine) -> io::Result<Vec<(String, u32)>> {
-        todo!()
+    pub fn list_tables(engine: &mut StorageEngine) -> io::Result<Vec<TableSchema>> {
+        engine.scan_records(CATALOG_ROOT_PAGE)?
+            .into_iter()
+            .map(|(_, bytes)| TableSchema::from_bytes(&bytes))
+            .collect()
     }
-}
\ No newline at end of file
+
+    // tables with a foreign key referencing table_name, which may include the table itself
+    pub fn referencing_tables(engine: &mut StorageEngine, table_name: &str) -> io::Result<Vec<TableSchema>> {
+        Ok(Self::list_tables(engine)?
+            .into_iter()
+            .filter(|schema| schema.foreign_keys.iter().any(|fk| fk.parent_table == table_name))
+            .collect())
+    }
+
+    // the table's schema and where its record sits in the catalog heap
+    fn find(engine: &mut StorageEngine, table_name: &str) -> io::Result<Option<(RecordId, TableSchema)>> {
+        for (rid, bytes) in engine.scan_records(CATALOG_ROOT_PAGE)? {
+            let schema = TableSchema::from_bytes(&bytes)?;
+            if schema.name == table_name {
+                return Ok(Some((rid, schema)));
+            }
+        }
+        Ok(None)
+    }
+}
@@ -0,0 +1,166 @@
+// small lz77 style compressor for overflow data. the output is a stream of ops:
+//  0x00..=0x7f  literal run, the next (op + 1) bytes are copied as is
+//  0x80..=0xff  match, copy (op - 0x80 + MIN_MATCH) bytes starting u16 offset bytes back in the output
+
+const MIN_MATCH: usize = 4;
+const MAX_MATCH: usize = 0x7f + MIN_MATCH;
+const MAX_LITERAL_RUN: usize = 0x80;
+const MAX_OFFSET: usize = u16::MAX as usize;
+const HASH_BITS: u32 = 12;
+
+fn hash(bytes: &[u8]) -> usize {
+    let v = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    (v.wrapping_mul(2654435761) >> (32 - HASH_BITS)) as usize
+}
+
+fn flush_literals(out: &mut Vec<u8>, literals: &[u8]) {
+    for chunk in literals.chunks(MAX_LITERAL_RUN) {
+        out.push((chunk.len() - 1) as u8);
+        out.extend_from_slice(chunk);
+    }
+}
+
+pub fn compress(input: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(input.len() / 2);
+    // last position each 4 byte sequence was seen at
+    let mut table = vec![usize::MAX; 1 << HASH_BITS];
+    let mut literal_start = 0;
+    let mut pos = 0;
+
+    while pos + MIN_MATCH <= input.len() {
+        let h = hash(&input[pos..]);
+        let candidate = table[h];
+        table[h] = pos;
+
+        if candidate != usize::MAX
+            && pos - candidate <= MAX_OFFSET
+            && input[candidate..candidate + MIN_MATCH] == input[pos..pos + MIN_MATCH]
+        {
+            let mut len = MIN_MATCH;
+            while len < MAX_MATCH && pos + len < input.len() && input[candidate + len] == input[pos + len] {
+                len += 1;
+            }
+
+            flush_literals(&mut out, &input[literal_start..pos]);
+            out.push(0x80 | (len - MIN_MATCH) as u8);
+            out.extend_from_slice(&((pos - candidate) as u16).to_le_bytes());
+
+            pos += len;
+            literal_start = pos;
+        } else {
+            pos += 1;
+        }
+    }
+
+    flush_literals(&mut out, &input[literal_start..]);
+    out
+}
+
+pub fn decompress(input: &[u8], expected_len: usize) -> Result<Vec<u8>, String> {
+    let mut out = Vec::with_capacity(expected_len);
+    let mut pos = 0;
+
+    while pos < input.len() {
+        let op = input[pos] as usize;
+        pos += 1;
+        if op < 0x80 {
+            let len = op + 1;
+            if pos + len > input.len() {
+                return Err("literal run past end of compressed data".to_string());
+            }
+            out.extend_from_slice(&input[pos..pos + len]);
+            pos += len;
+        } else {
+            if pos + 2 > input.len() {
+                return Err("match offset past end of compressed data".to_string());
+            }
+            let len = op - 0x80 + MIN_MATCH;
+            let offset = u16::from_le_bytes([input[pos], input[pos + 1]]) as usize;
+            pos += 2;
+            if offset == 0 || offset > out.len() {
+                return Err(format!("invalid match offset {} in compressed data", offset));
+            }
+            // copy byte by byte as the match can overlap the bytes its producing
+            let start = out.len() - offset;
+            for i in 0..len {
+                out.push(out[start + i]);
+            }
+        }
+    }
+
+    if out.len() != expected_len {
+        return Err(format!("decompressed {} bytes but expected {}", out.len(), expected_len));
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // xorshift bytes, which the compressor cant find anything to match in
+    fn noise(len: usize) -> Vec<u8> {
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    fn round_trip(input: &[u8]) -> Vec<u8> {
+        let compressed = compress(input);
+        assert_eq!(decompress(&compressed, input.len()).unwrap(), input);
+        compressed
+    }
+
+    #[test]
+    fn empty_input() {
+        assert!(round_trip(&[]).is_empty());
+    }
+
+    #[test]
+    fn input_shorter_than_a_match() {
+        round_trip(b"abc");
+    }
+
+    #[test]
+    fn incompressible_input() {
+        let input = noise(20_000);
+        let compressed = round_trip(&input);
+        // every byte is a literal, with an op byte in front of each run of 128
+        assert_eq!(compressed.len(), input.len() + input.len().div_ceil(MAX_LITERAL_RUN));
+    }
+
+    #[test]
+    fn repetitive_input() {
+        let input = b"abcdefgh".repeat(10_000);
+        let compressed = round_trip(&input);
+        assert!(compressed.len() * 20 < input.len(), "{} bytes compressed to {}", input.len(), compressed.len());
+    }
+
+    #[test]
+    fn run_of_one_byte() {
+        // matches one byte back that overlap the bytes they produce
+        let compressed = round_trip(&[7u8; 5000]);
+        assert!(compressed.len() < 200);
+    }
+
+    #[test]
+    fn repeats_further_back_than_an_offset_can_reach() {
+        let block = noise(MAX_OFFSET + 1000);
+        round_trip(&[block.as_slice(), block.as_slice()].concat());
+    }
+
+    #[test]
+    fn wrong_length_or_truncated_data_is_an_error() {
+        let input = b"abcdefgh".repeat(100);
+        let compressed = compress(&input);
+        assert!(decompress(&compressed, input.len() + 1).is_err());
+        assert!(decompress(&compressed[..compressed.len() - 1], input.len()).is_err());
+        assert!(decompress(&[0x80, 1, 0], 4).is_err()); // match before there is any output
+    }
+}
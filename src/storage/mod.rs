@@ -1,7 +1,14 @@
+#[allow(clippy::module_inception)]
 pub mod storage;
 pub mod tree;
 pub mod catalog;
 pub mod page;
+pub mod overflow;
+pub mod record;
+pub(crate) mod codec;
+mod compress;
+pub mod wal;
+pub mod backup;
 // use std::sync::{Arc, RwLock};
 // use once_cell::sync::Lazy;
 
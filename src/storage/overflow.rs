@@ -0,0 +1,205 @@
+use std::io;
+use super::compress;
+use super::storage::StorageEngine;
+use crate::storage::page::{OverflowPage, OverflowRef, PageType, OVERFLOW_DATA_SIZE, PAGE_SIZE};
+
+// stores byte strings that dont fit in a heap page or index node in a chain of overflow pages
+pub struct Overflow;
+
+impl Overflow {
+    // write the bytes out to a new chain, compressing them first if the engine allows it and it actually helps
+    pub fn write(engine: &mut StorageEngine, bytes: &[u8]) -> io::Result<OverflowRef> {
+        let compressed = if engine.compress_overflow {
+            Some(compress::compress(bytes)).filter(|c| c.len() < bytes.len())
+        } else {
+            None
+        };
+        let stored = compressed.as_deref().unwrap_or(bytes);
+
+        // allocate every link up front so each page knows where the next one is when its written
+        let chunks: Vec<&[u8]> = stored.chunks(OVERFLOW_DATA_SIZE).collect();
+        let mut pages = Vec::with_capacity(chunks.len());
+        for _ in 0..chunks.len().max(1) {
+            pages.push(engine.allocate_page(PageType::Overflow)?);
+        }
+
+        for (i, chunk) in chunks.iter().enumerate() {
+            let next = pages.get(i + 1).copied().unwrap_or(0);
+            engine.write_page(pages[i], &OverflowPage::new(chunk, next).to_bytes())?;
+        }
+
+        Ok(OverflowRef {
+            head_page: pages[0],
+            len: bytes.len() as u32,
+            compressed: compressed.is_some(),
+        })
+    }
+
+    pub fn read(engine: &mut StorageEngine, overflow: &OverflowRef) -> io::Result<Vec<u8>> {
+        let mut stored = Vec::new();
+        let mut buf = [0u8; PAGE_SIZE];
+        let mut current = overflow.head_page;
+        while current != 0 {
+            engine.read_page(current, &mut buf)?;
+            let page = OverflowPage::from_bytes(&buf)?;
+            if page.common.page_type != PageType::Overflow {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("page {} in overflow chain is not an overflow page", current)));
+            }
+            stored.extend_from_slice(&page.data);
+            current = page.common.next_page;
+        }
+
+        if overflow.compressed {
+            return compress::decompress(&stored, overflow.len as usize)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e));
+        }
+        stored.truncate(overflow.len as usize);
+        Ok(stored)
+    }
+
+    // hand every page of the chain back to the freelist
+    pub fn free(engine: &mut StorageEngine, overflow: &OverflowRef) -> io::Result<()> {
+        let mut buf = [0u8; PAGE_SIZE];
+        let mut current = overflow.head_page;
+        while current != 0 {
+            engine.read_page(current, &mut buf)?;
+            let next = OverflowPage::from_bytes(&buf)?.common.next_page;
+            engine.free_page(current)?;
+            current = next;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::page::{FileHeader, FILE_HEADER_PAGE};
+    use std::path::PathBuf;
+
+    // an engine on a file of its own that is deleted again when the test is done
+    struct Scratch {
+        engine: Option<StorageEngine>,
+        path: PathBuf,
+    }
+
+    impl Scratch {
+        fn new(name: &str, compress: bool) -> Self {
+            let path = std::env::temp_dir().join(format!("tony_db_overflow_{}_{}.db", std::process::id(), name));
+            let _ = std::fs::remove_file(&path);
+            let mut engine = StorageEngine::open_at(&path).unwrap();
+            engine.compress_overflow = compress;
+            Scratch { engine: Some(engine), path }
+        }
+
+        fn engine(&mut self) -> &mut StorageEngine {
+            self.engine.as_mut().unwrap()
+        }
+
+        fn free_count(&mut self) -> u32 {
+            let mut buf = [0u8; PAGE_SIZE];
+            self.engine().read_page(FILE_HEADER_PAGE, &mut buf).unwrap();
+            FileHeader::from_bytes(&buf).unwrap().free_count
+        }
+    }
+
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            self.engine = None;
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+
+    fn noise(len: usize) -> Vec<u8> {
+        let mut state = 0x9e37_79b9_7f4a_7c15_u64;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    fn chain_len(engine: &mut StorageEngine, overflow: &OverflowRef) -> usize {
+        let mut buf = [0u8; PAGE_SIZE];
+        let mut current = overflow.head_page;
+        let mut len = 0;
+        while current != 0 {
+            engine.read_page(current, &mut buf).unwrap();
+            current = OverflowPage::from_bytes(&buf).unwrap().common.next_page;
+            len += 1;
+        }
+        len
+    }
+
+    #[test]
+    fn incompressible_bytes_are_stored_as_they_are() {
+        let mut scratch = Scratch::new("noise", true);
+        let bytes = noise(3 * OVERFLOW_DATA_SIZE + 10);
+        let overflow = Overflow::write(scratch.engine(), &bytes).unwrap();
+        assert!(!overflow.compressed);
+        assert_eq!(overflow.len as usize, bytes.len());
+        assert_eq!(chain_len(scratch.engine(), &overflow), 4);
+        assert_eq!(Overflow::read(scratch.engine(), &overflow).unwrap(), bytes);
+    }
+
+    #[test]
+    fn repetitive_bytes_are_compressed() {
+        let mut scratch = Scratch::new("repetitive", true);
+        let bytes = b"{\"key\": \"value\"} ".repeat(2000);
+        let overflow = Overflow::write(scratch.engine(), &bytes).unwrap();
+        assert!(overflow.compressed);
+        assert_eq!(chain_len(scratch.engine(), &overflow), 1);
+        assert_eq!(Overflow::read(scratch.engine(), &overflow).unwrap(), bytes);
+    }
+
+    #[test]
+    fn compression_can_be_turned_off() {
+        let mut scratch = Scratch::new("uncompressed", false);
+        let bytes = b"{\"key\": \"value\"} ".repeat(2000);
+        let overflow = Overflow::write(scratch.engine(), &bytes).unwrap();
+        assert!(!overflow.compressed);
+        assert_eq!(chain_len(scratch.engine(), &overflow), bytes.len().div_ceil(OVERFLOW_DATA_SIZE));
+        assert_eq!(Overflow::read(scratch.engine(), &overflow).unwrap(), bytes);
+    }
+
+    #[test]
+    fn exact_page_multiple_and_empty() {
+        let mut scratch = Scratch::new("sizes", false);
+        for bytes in [noise(2 * OVERFLOW_DATA_SIZE), Vec::new()] {
+            let overflow = Overflow::write(scratch.engine(), &bytes).unwrap();
+            assert_eq!(Overflow::read(scratch.engine(), &overflow).unwrap(), bytes);
+        }
+    }
+
+    #[test]
+    fn free_hands_the_chain_back_for_reuse() {
+        let mut scratch = Scratch::new("free", false);
+        let first = Overflow::write(scratch.engine(), &noise(3 * OVERFLOW_DATA_SIZE)).unwrap();
+        let kept_bytes = noise(OVERFLOW_DATA_SIZE + 1);
+        let kept = Overflow::write(scratch.engine(), &kept_bytes).unwrap();
+        let pages = scratch.engine().file_len().unwrap();
+
+        Overflow::free(scratch.engine(), &first).unwrap();
+        assert_eq!(scratch.free_count(), 3);
+
+        // the freed pages are used again instead of growing the file
+        let bytes = noise(2 * OVERFLOW_DATA_SIZE);
+        let second = Overflow::write(scratch.engine(), &bytes).unwrap();
+        assert_eq!(scratch.free_count(), 1);
+        assert_eq!(scratch.engine().file_len().unwrap(), pages);
+        assert_eq!(Overflow::read(scratch.engine(), &second).unwrap(), bytes);
+        assert_eq!(Overflow::read(scratch.engine(), &kept).unwrap(), kept_bytes);
+    }
+
+    #[test]
+    fn reading_a_page_that_isnt_in_a_chain_is_an_error() {
+        let mut scratch = Scratch::new("bad_chain", false);
+        let heap_page = scratch.engine().allocate_page(PageType::Heap).unwrap();
+        let overflow = OverflowRef { head_page: heap_page, len: 10, compressed: false };
+        let err = Overflow::read(scratch.engine(), &overflow).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
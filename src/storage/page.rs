@@ -5,27 +5,39 @@ pub const HEADER_SIZE: usize = 9; // 1 byte page type + 4 bytes next page + 2 by
 pub const COMMON_HEADER_SIZE: usize = 5; // 1 byte type + 4 bytes next_page
 pub const HEAP_HEADER_SIZE: usize = COMMON_HEADER_SIZE + 2 + 2; // + slot_count + free_start
 
-// a page can be a header, contain data, be an index for tree search, be free space, or be a catalog for storing tables
-#[derive(Debug, Clone, Copy)]
+// a page can be a header, contain data, be an index for tree search, be free space, be a catalog for storing tables
+// or hold the out of line bytes of a record or key too big to live in its own page
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum PageType {
     Heap = 0,
     Index = 1,
     Free = 2,
     Catalog = 3,
+    Overflow = 4,
+    Header = 5,
 }
 
-impl From<u8> for PageType {
-    fn from(value: u8) -> Self {
+impl TryFrom<u8> for PageType {
+    type Error = io::Error;
+
+    fn try_from(value: u8) -> io::Result<Self> {
         match value {
-            0 => PageType::Heap,
-            1 => PageType::Index,
-            2 => PageType::Free,
-            3 => PageType::Catalog,
-            _ => panic!("Unknown page type"),
+            0 => Ok(PageType::Heap),
+            1 => Ok(PageType::Index),
+            2 => Ok(PageType::Free),
+            3 => Ok(PageType::Catalog),
+            4 => Ok(PageType::Overflow),
+            5 => Ok(PageType::Header),
+            _ => Err(corrupt(format!("unknown page type {}", value))),
         }
     }
 }
 
+// pages come from the file, so anything in them that doesnt add up is corruption and not a bug to panic on
+fn corrupt(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
 pub struct CommonHeader {
     pub page_type: PageType,
     pub next_page: u32,
@@ -38,9 +50,52 @@ impl CommonHeader {
         b[1..5].copy_from_slice(&self.next_page.to_le_bytes());
         b
     }
-    pub fn from_bytes(buf: &[u8]) -> Self {
+    pub fn from_bytes(buf: &[u8]) -> io::Result<Self> {
         let mut np = [0u8;4]; np.copy_from_slice(&buf[1..5]);
-        Self { page_type: PageType::from(buf[0]), next_page: u32::from_le_bytes(np) }
+        Ok(Self { page_type: PageType::try_from(buf[0])?, next_page: u32::from_le_bytes(np) })
+    }
+}
+
+pub const FILE_HEADER_PAGE: u32 = 0;
+
+// first page of the file, holds the freelist head, free page count and schema version after the common header.
+// freed pages are chained together through their next_page (as Free pages)
+// starting at freelist_head so they can be handed out again before the file grows
+pub struct FileHeader {
+    pub common: CommonHeader,
+    pub freelist_head: u32,
+    pub free_count: u32,
+    pub schema_version: u32, // bumped by every change to the catalog
+}
+
+impl FileHeader {
+    pub fn new() -> Self {
+        Self {
+            common: CommonHeader { page_type: PageType::Header, next_page: 0 },
+            freelist_head: 0,
+            free_count: 0,
+            schema_version: 0,
+        }
+    }
+    pub fn to_bytes(&self) -> [u8; PAGE_SIZE] {
+        let mut b = [0u8; PAGE_SIZE];
+        b[..COMMON_HEADER_SIZE].copy_from_slice(&self.common.to_bytes());
+        b[COMMON_HEADER_SIZE..COMMON_HEADER_SIZE+4].copy_from_slice(&self.freelist_head.to_le_bytes());
+        b[COMMON_HEADER_SIZE+4..COMMON_HEADER_SIZE+8].copy_from_slice(&self.free_count.to_le_bytes());
+        b[COMMON_HEADER_SIZE+8..COMMON_HEADER_SIZE+12].copy_from_slice(&self.schema_version.to_le_bytes());
+        b
+    }
+    pub fn from_bytes(buf: &[u8]) -> io::Result<Self> {
+        let common = CommonHeader::from_bytes(&buf[..COMMON_HEADER_SIZE])?;
+        let mut fhb = [0u8;4]; fhb.copy_from_slice(&buf[COMMON_HEADER_SIZE..COMMON_HEADER_SIZE+4]);
+        let mut fcb = [0u8;4]; fcb.copy_from_slice(&buf[COMMON_HEADER_SIZE+4..COMMON_HEADER_SIZE+8]);
+        let mut svb = [0u8;4]; svb.copy_from_slice(&buf[COMMON_HEADER_SIZE+8..COMMON_HEADER_SIZE+12]);
+        Ok(Self {
+            common,
+            freelist_head: u32::from_le_bytes(fhb),
+            free_count: u32::from_le_bytes(fcb),
+            schema_version: u32::from_le_bytes(svb),
+        })
     }
 }
 
@@ -71,30 +126,6 @@ impl PageHeader {
         buf[7..9].copy_from_slice(&self.free_space.to_le_bytes());
         buf
     }
-
-    pub fn from_bytes(buf: &[u8]) -> Self {
-        assert!(buf.len() == HEADER_SIZE, "wrong buffer size for header. should be {}", HEADER_SIZE);
-        let page_type = PageType::from(buf[0]);
-
-        let mut next_page_bytes = [0u8; 4];
-        next_page_bytes.copy_from_slice(&buf[1..5]);
-        let next_page = u32::from_le_bytes(next_page_bytes);
-
-        let mut record_count_bytes = [0u8; 2];
-        record_count_bytes.copy_from_slice(&buf[5..7]);
-        let record_count = u16::from_le_bytes(record_count_bytes);
-
-        let mut free_space_bytes = [0u8; 2];
-        free_space_bytes.copy_from_slice(&buf[7..9]);
-        let free_space = u16::from_le_bytes(free_space_bytes);
-
-        Self {
-            page_type,
-            next_page,
-            record_count,
-            free_space,
-        }
-    }
 }
 
 pub struct Page {
@@ -111,31 +142,6 @@ impl Page {
         }
     }
 
-    pub fn from_bytes(buf: &[u8; PAGE_SIZE]) -> Self {
-        let header = PageHeader::from_bytes(&buf[..HEADER_SIZE]);
-
-        let data_area = &buf[HEADER_SIZE..];
-        // can cast to usize as u16 < usize
-        let used_len = data_area.len() - (header.free_space as usize);
-        let data = data_area[..used_len].to_vec();
-
-        Self { header, data }
-    }
-
-    pub fn write_record(&mut self, record: &str) {
-        let bytes = record.as_bytes();
-        let len = bytes.len() as u32;
-        let mut rec = Vec::new();
-        // add the len then the record
-        rec.extend_from_slice(&len.to_le_bytes());
-        rec.extend_from_slice(bytes);
-
-        // extend the data with the new record
-        self.data.extend_from_slice(&rec);
-        self.header.record_count += 1;
-        self.header.free_space -= rec.len() as u16;
-    }
-
     pub fn to_bytes(&self) -> [u8; PAGE_SIZE] {
         let mut buf = [0u8; PAGE_SIZE];
         buf[..HEADER_SIZE].copy_from_slice(&self.header.to_bytes());
@@ -175,17 +181,26 @@ impl HeapPageHeader {
         b[COMMON_HEADER_SIZE+2..COMMON_HEADER_SIZE+4].copy_from_slice(&self.free_start.to_le_bytes());
         b
     }
-    pub fn from_bytes(buf: &[u8]) -> Self {
-        let common = CommonHeader::from_bytes(&buf[..COMMON_HEADER_SIZE]);
+    pub fn from_bytes(buf: &[u8]) -> io::Result<Self> {
+        let common = CommonHeader::from_bytes(&buf[..COMMON_HEADER_SIZE])?;
         let mut scb=[0u8;2]; scb.copy_from_slice(&buf[COMMON_HEADER_SIZE..COMMON_HEADER_SIZE+2]);
         let mut fsb=[0u8;2]; fsb.copy_from_slice(&buf[COMMON_HEADER_SIZE+2..COMMON_HEADER_SIZE+4]);
-        Self { common, slot_count: u16::from_le_bytes(scb), free_start: u16::from_le_bytes(fsb) }
+        let header = Self { common, slot_count: u16::from_le_bytes(scb), free_start: u16::from_le_bytes(fsb) };
+        // the records and the slot directory cant run into each other
+        let slot_dir_start = PAGE_SIZE.checked_sub(header.slot_count as usize * SLOT_ENTRY_SIZE);
+        if header.common.page_type != PageType::Heap
+            || (header.free_start as usize) < HEAP_HEADER_SIZE
+            || slot_dir_start.is_none_or(|start| header.free_start as usize > start)
+        {
+            return Err(corrupt(format!("heap page header has type {:?}, {} slots and free space from {}", header.common.page_type, header.slot_count, header.free_start)));
+        }
+        Ok(header)
     }
     pub fn free_space(&self) -> usize {
         // slot directory grows downward from end of page
         let slot_dir_bytes = self.slot_count as usize * SLOT_ENTRY_SIZE;
         let slot_dir_start = PAGE_SIZE - slot_dir_bytes;
-        if slot_dir_start < self.free_start as usize { 0 } else { slot_dir_start - self.free_start as usize }
+        slot_dir_start.saturating_sub(self.free_start as usize)
     }
 }
 
@@ -205,8 +220,8 @@ impl HeapPage {
         }
     }
 
-    pub fn from_bytes(buf: &[u8; PAGE_SIZE]) -> Self {
-        let header = HeapPageHeader::from_bytes(&buf[..HEAP_HEADER_SIZE]);
+    pub fn from_bytes(buf: &[u8; PAGE_SIZE]) -> io::Result<Self> {
+        let header = HeapPageHeader::from_bytes(&buf[..HEAP_HEADER_SIZE])?;
 
         let mut slots = Vec::with_capacity(header.slot_count as usize);
         // slots from end of the page
@@ -215,22 +230,27 @@ impl HeapPage {
             let mut idb = [0u8;2]; idb.copy_from_slice(&buf[start..start+2]);
             let mut offb = [0u8;2]; offb.copy_from_slice(&buf[start+2..start+4]);
             let mut lenb = [0u8;2]; lenb.copy_from_slice(&buf[start+4..start+6]);
-            slots.push(SlotEntry {
+            let slot = SlotEntry {
                 id: u16::from_le_bytes(idb),
                 offset: u16::from_le_bytes(offb),
                 len: u16::from_le_bytes(lenb),
-            });
+            };
+            // a live record has to be somewhere in the data area. dead slots keep whatever offset they had
+            if slot.len != 0 && ((slot.offset as usize) < HEAP_HEADER_SIZE || slot.offset as usize + slot.len as usize > header.free_start as usize) {
+                return Err(corrupt(format!("slot {} points at {} bytes from {}, outside the records that end at {}", i, slot.len, slot.offset, header.free_start)));
+            }
+            slots.push(slot);
         }
 
         // entries
         let data_end = header.free_start as usize;
         let records = buf[HEAP_HEADER_SIZE..data_end].to_vec();
 
-        Self {
+        Ok(Self {
             header,
             slots,
             data: records
-        }
+        })
     }
 
     pub fn to_bytes(&self) -> [u8; PAGE_SIZE] {
@@ -250,26 +270,308 @@ impl HeapPage {
         buf
     }
 
-    pub fn write_record(&mut self, record: &str) -> io::Result<u16> {
-        let bytes = record.as_bytes();
-        let len = bytes.len() as u32;
-        let mut rec = Vec::with_capacity(4+bytes.len());
-        // add the len then the record
-        rec.extend_from_slice(&len.to_le_bytes());
-        rec.extend_from_slice(bytes);
+    // records are stored as a u32 length followed by the bytes. if the high bit of the length is set the
+    // bytes are an OverflowRef pointing at the real record in a chain of overflow pages.
+    // slots of deleted records are kept with a len of 0 so the ids of the slots after them dont change,
+    // and get handed out again to new records
+    pub fn write_record(&mut self, record: &HeapRecord) -> io::Result<u16> {
+        let rec = record.to_stored()?;
+        let dead_slot = self.slots.iter().position(|s| s.len == 0);
+        let slot_bytes = if dead_slot.is_some() { 0 } else { SLOT_ENTRY_SIZE };
+        if self.header.free_space() < rec.len() + slot_bytes {
+            return Err(io::Error::other("not enough free space in heap page"));
+        }
+
+        let offset = self.append_data(&rec);
+        let id = match dead_slot {
+            Some(idx) => idx as u16,
+            None => {
+                self.slots.push(SlotEntry { id: self.slots.len() as u16, offset: 0, len: 0 });
+                self.header.slot_count = self.slots.len() as u16;
+                self.slots.len() as u16 - 1
+            }
+        };
+        self.slots[id as usize].offset = offset;
+        self.slots[id as usize].len = rec.len() as u16;
+
+        Ok(id)
+    }
+
+    // point an existing slot at a new version of its record so the record id stays the same.
+    // returns false if theres no room for it in this page, the old version is left alone then
+    pub fn replace_record(&mut self, slot: u16, record: &HeapRecord) -> io::Result<bool> {
+        self.live_slot(slot)?;
+        let rec = record.to_stored()?;
+        if self.header.free_space() < rec.len() {
+            return Ok(false);
+        }
+        let offset = self.append_data(&rec);
+        self.slots[slot as usize].offset = offset;
+        self.slots[slot as usize].len = rec.len() as u16;
+        Ok(true)
+    }
 
+    // the bytes stay where they are until the page is compacted
+    pub fn delete_record(&mut self, slot: u16) -> io::Result<HeapRecord> {
+        let record = self.read_record(slot)?;
+        self.slots[slot as usize].len = 0;
+        Ok(record)
+    }
+
+    fn append_data(&mut self, rec: &[u8]) -> u16 {
         let offset = (HEAP_HEADER_SIZE + self.data.len()) as u16;
-        self.data.extend_from_slice(&rec); // record into the data area
+        self.data.extend_from_slice(rec); // record into the data area
         self.header.free_start = offset + rec.len() as u16;
+        offset
+    }
+
+    fn live_slot(&self, slot: u16) -> io::Result<&SlotEntry> {
+        self.slots.get(slot as usize)
+            .filter(|s| s.len != 0)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("slot {} not found", slot)))
+    }
+
+    pub fn is_live(&self, slot: u16) -> bool {
+        self.live_slot(slot).is_ok()
+    }
+
+    pub fn live_count(&self) -> usize {
+        self.slots.iter().filter(|s| s.len != 0).count()
+    }
+
+    pub fn read_record(&self, slot: u16) -> io::Result<HeapRecord> {
+        let entry = self.live_slot(slot)?;
+        // slot offsets are from the start of the page, data starts after the header
+        let start = entry.offset as usize - HEAP_HEADER_SIZE;
+        let rec = self.data.get(start..start + entry.len as usize)
+            .ok_or_else(|| corrupt(format!("slot {} points past the end of the records", slot)))?;
+
+        let len_field = match rec.get(..4) {
+            Some(lenb) => u32::from_le_bytes([lenb[0], lenb[1], lenb[2], lenb[3]]),
+            None => return Err(corrupt(format!("record in slot {} is {} bytes, too short for its length", slot, rec.len()))),
+        };
+        if len_field & OVERFLOW_FLAG != 0 {
+            return Ok(HeapRecord::Overflow(OverflowRef::from_bytes(&rec[4..])?));
+        }
+        rec.get(4..4 + len_field as usize)
+            .map(|bytes| HeapRecord::Inline(bytes.to_vec()))
+            .ok_or_else(|| corrupt(format!("record in slot {} says it has {} bytes but its slot only has {}", slot, len_field, rec.len() - 4)))
+    }
+
+    // slide the live records up against the header, dropping the bytes of deleted and replaced records.
+    // slot ids dont change so record ids stay valid, dead slots at the end of the directory are dropped
+    // as nothing can point at them. returns how many bytes were freed up
+    pub fn compact(&mut self) -> usize {
+        let free_before = self.header.free_space();
+
+        let mut data = Vec::with_capacity(self.data.len());
+        for slot in self.slots.iter_mut().filter(|s| s.len != 0) {
+            let start = slot.offset as usize - HEAP_HEADER_SIZE;
+            slot.offset = (HEAP_HEADER_SIZE + data.len()) as u16;
+            data.extend_from_slice(&self.data[start..start + slot.len as usize]);
+        }
+        while self.slots.last().is_some_and(|s| s.len == 0) {
+            self.slots.pop();
+        }
 
-        let id = self.slots.len() as u16;
-        self.slots.push(SlotEntry {
-            id,
-            offset: offset,
-            len: rec.len() as u16,
-        });
+        self.header.free_start = (HEAP_HEADER_SIZE + data.len()) as u16;
         self.header.slot_count = self.slots.len() as u16;
+        self.data = data;
 
-        Ok(id)
+        self.header.free_space() - free_before
+    }
+}
+
+// records bigger than this go to overflow pages so a single row cant hog a heap page
+pub const MAX_INLINE_RECORD: usize = 1024;
+const OVERFLOW_FLAG: u32 = 1 << 31;
+
+pub enum HeapRecord {
+    Inline(Vec<u8>),
+    Overflow(OverflowRef),
+}
+
+impl HeapRecord {
+    // bytes as they sit in the page, length first
+    fn to_stored(&self) -> io::Result<Vec<u8>> {
+        let (len_field, bytes) = match self {
+            HeapRecord::Inline(bytes) => {
+                if bytes.len() > MAX_INLINE_RECORD {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("record of {} bytes is too large to store inline", bytes.len())));
+                }
+                (bytes.len() as u32, bytes.clone())
+            }
+            HeapRecord::Overflow(overflow) => (OVERFLOW_FLAG | overflow.len, overflow.to_bytes().to_vec()),
+        };
+        let mut rec = Vec::with_capacity(4+bytes.len());
+        // add the len then the record
+        rec.extend_from_slice(&len_field.to_le_bytes());
+        rec.extend_from_slice(&bytes);
+        Ok(rec)
+    }
+
+    pub fn stored_len(&self) -> usize {
+        4 + match self {
+            HeapRecord::Inline(bytes) => bytes.len(),
+            HeapRecord::Overflow(_) => OVERFLOW_REF_SIZE,
+        }
+    }
+}
+
+pub const OVERFLOW_REF_SIZE: usize = 9; // 4 bytes head page + 4 bytes len + 1 byte compressed flag
+
+// points at the first page of an overflow chain. len is the length of the original bytes before compression
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OverflowRef {
+    pub head_page: u32,
+    pub len: u32,
+    pub compressed: bool,
+}
+
+impl OverflowRef {
+    pub fn to_bytes(&self) -> [u8; OVERFLOW_REF_SIZE] {
+        let mut b = [0u8; OVERFLOW_REF_SIZE];
+        b[0..4].copy_from_slice(&self.head_page.to_le_bytes());
+        b[4..8].copy_from_slice(&self.len.to_le_bytes());
+        b[8] = self.compressed as u8;
+        b
+    }
+    pub fn from_bytes(buf: &[u8]) -> io::Result<Self> {
+        if buf.len() < OVERFLOW_REF_SIZE {
+            return Err(corrupt(format!("overflow reference is {} bytes, it needs {}", buf.len(), OVERFLOW_REF_SIZE)));
+        }
+        let mut hpb = [0u8;4]; hpb.copy_from_slice(&buf[0..4]);
+        let mut lb = [0u8;4]; lb.copy_from_slice(&buf[4..8]);
+        Ok(Self { head_page: u32::from_le_bytes(hpb), len: u32::from_le_bytes(lb), compressed: buf[8] == 1 })
+    }
+}
+
+pub const OVERFLOW_HEADER_SIZE: usize = COMMON_HEADER_SIZE + 2; // + used bytes in this page
+pub const OVERFLOW_DATA_SIZE: usize = PAGE_SIZE - OVERFLOW_HEADER_SIZE;
+
+// one link in an overflow chain. next_page in the common header points at the next link, 0 ends the chain
+pub struct OverflowPage {
+    pub common: CommonHeader,
+    pub data: Vec<u8>,
+}
+
+impl OverflowPage {
+    pub fn new(data: &[u8], next_page: u32) -> Self {
+        assert!(data.len() <= OVERFLOW_DATA_SIZE, "overflow page data should be at most {} bytes", OVERFLOW_DATA_SIZE);
+        Self {
+            common: CommonHeader { page_type: PageType::Overflow, next_page },
+            data: data.to_vec(),
+        }
     }
-}
\ No newline at end of file
+
+    pub fn to_bytes(&self) -> [u8; PAGE_SIZE] {
+        let mut buf = [0u8; PAGE_SIZE];
+        buf[..COMMON_HEADER_SIZE].copy_from_slice(&self.common.to_bytes());
+        buf[COMMON_HEADER_SIZE..OVERFLOW_HEADER_SIZE].copy_from_slice(&(self.data.len() as u16).to_le_bytes());
+        buf[OVERFLOW_HEADER_SIZE..OVERFLOW_HEADER_SIZE + self.data.len()].copy_from_slice(&self.data);
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8; PAGE_SIZE]) -> io::Result<Self> {
+        let common = CommonHeader::from_bytes(&buf[..COMMON_HEADER_SIZE])?;
+        let mut ub = [0u8;2]; ub.copy_from_slice(&buf[COMMON_HEADER_SIZE..OVERFLOW_HEADER_SIZE]);
+        let used = u16::from_le_bytes(ub) as usize;
+        if used > OVERFLOW_DATA_SIZE {
+            return Err(corrupt(format!("overflow page says it holds {} bytes, it only has room for {}", used, OVERFLOW_DATA_SIZE)));
+        }
+        Ok(Self { common, data: buf[OVERFLOW_HEADER_SIZE..OVERFLOW_HEADER_SIZE + used].to_vec() })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn heap_page_with(records: &[&[u8]]) -> [u8; PAGE_SIZE] {
+        let mut page = HeapPage::new();
+        for record in records {
+            page.write_record(&HeapRecord::Inline(record.to_vec())).unwrap();
+        }
+        page.to_bytes()
+    }
+
+    fn is_corrupt<T>(result: io::Result<T>) -> bool {
+        result.is_err_and(|e| e.kind() == io::ErrorKind::InvalidData)
+    }
+
+    #[test]
+    fn records_read_back() {
+        let page = HeapPage::from_bytes(&heap_page_with(&[b"one", b"", b"three"])).unwrap();
+        let inline = |slot| match page.read_record(slot).unwrap() {
+            HeapRecord::Inline(bytes) => bytes,
+            HeapRecord::Overflow(_) => panic!("record went to overflow"),
+        };
+        assert_eq!(inline(0), b"one");
+        assert_eq!(inline(1), b"");
+        assert_eq!(inline(2), b"three");
+        assert!(page.read_record(3).is_err_and(|e| e.kind() == io::ErrorKind::NotFound));
+    }
+
+    #[test]
+    fn unknown_page_type_is_corruption() {
+        let mut buf = heap_page_with(&[]);
+        buf[0] = 200;
+        assert!(is_corrupt(HeapPage::from_bytes(&buf)));
+        assert!(is_corrupt(CommonHeader::from_bytes(&buf)));
+        assert!(is_corrupt(PageType::try_from(6)));
+    }
+
+    #[test]
+    fn page_of_another_type_isnt_a_heap_page() {
+        let buf = FileHeader::new().to_bytes();
+        assert!(is_corrupt(HeapPage::from_bytes(&buf)));
+    }
+
+    #[test]
+    fn header_running_into_the_slots_is_corruption() {
+        let mut buf = heap_page_with(&[b"record"]);
+        buf[COMMON_HEADER_SIZE..COMMON_HEADER_SIZE + 2].copy_from_slice(&u16::MAX.to_le_bytes());
+        assert!(is_corrupt(HeapPage::from_bytes(&buf)));
+
+        let mut buf = heap_page_with(&[b"record"]);
+        buf[COMMON_HEADER_SIZE + 2..COMMON_HEADER_SIZE + 4].copy_from_slice(&(PAGE_SIZE as u16).to_le_bytes());
+        assert!(is_corrupt(HeapPage::from_bytes(&buf)));
+    }
+
+    #[test]
+    fn slot_outside_the_records_is_corruption() {
+        let slot = PAGE_SIZE - SLOT_ENTRY_SIZE;
+        let mut buf = heap_page_with(&[b"record"]);
+        buf[slot + 2..slot + 4].copy_from_slice(&2u16.to_le_bytes()); // offset inside the header
+        assert!(is_corrupt(HeapPage::from_bytes(&buf)));
+
+        let mut buf = heap_page_with(&[b"record"]);
+        buf[slot + 4..slot + 6].copy_from_slice(&100u16.to_le_bytes()); // past the end of the records
+        assert!(is_corrupt(HeapPage::from_bytes(&buf)));
+    }
+
+    #[test]
+    fn record_length_longer_than_its_slot_is_corruption() {
+        let mut buf = heap_page_with(&[b"record"]);
+        buf[HEAP_HEADER_SIZE..HEAP_HEADER_SIZE + 4].copy_from_slice(&1000u32.to_le_bytes());
+        assert!(is_corrupt(HeapPage::from_bytes(&buf).unwrap().read_record(0)));
+
+        // too short to even hold the length
+        let mut buf = heap_page_with(&[b"record"]);
+        let slot = PAGE_SIZE - SLOT_ENTRY_SIZE;
+        buf[slot + 4..slot + 6].copy_from_slice(&2u16.to_le_bytes());
+        assert!(is_corrupt(HeapPage::from_bytes(&buf).unwrap().read_record(0)));
+
+        // an overflow reference cut short
+        let mut buf = heap_page_with(&[b"record"]);
+        buf[HEAP_HEADER_SIZE..HEAP_HEADER_SIZE + 4].copy_from_slice(&(OVERFLOW_FLAG | 5000).to_le_bytes());
+        assert!(is_corrupt(HeapPage::from_bytes(&buf).unwrap().read_record(0)));
+    }
+
+    #[test]
+    fn overflow_page_holding_more_than_a_page_is_corruption() {
+        let mut buf = OverflowPage::new(b"bytes", 0).to_bytes();
+        buf[COMMON_HEADER_SIZE..OVERFLOW_HEADER_SIZE].copy_from_slice(&u16::MAX.to_le_bytes());
+        assert!(is_corrupt(OverflowPage::from_bytes(&buf)));
+    }
+}
@@ -0,0 +1,60 @@
+use std::collections::HashMap;
+use std::io;
+use super::catalog::TableSchema;
+use super::codec::{self, Reader};
+use crate::types::Value;
+
+// rows are stored in the heap as a format byte and a u16 value count, then for each value the id of its
+// column, a byte saying whether its NULL and, if not, a u32 length and the bytes of its text form. keying
+// values by column id means a row written under an older version of the table still reads correctly:
+// dropped columns are skipped and added ones take the value they were added with
+const ROW_FORMAT_VERSION: u8 = 2;
+
+pub fn encode_row(schema: &TableSchema, row: &[Value]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(3 + row.len() * 8);
+    codec::put_u8(&mut buf, ROW_FORMAT_VERSION);
+    codec::put_u16(&mut buf, row.len() as u16);
+    for (column, value) in schema.columns.iter().zip(row) {
+        codec::put_u16(&mut buf, column.id);
+        match value.to_stored() {
+            Some(text) => {
+                codec::put_u8(&mut buf, 1);
+                codec::put_str(&mut buf, &text);
+            }
+            None => codec::put_u8(&mut buf, 0),
+        }
+    }
+    buf
+}
+
+// the row laid out in the tables current column order
+pub fn decode_row(schema: &TableSchema, buf: &[u8]) -> io::Result<Vec<Value>> {
+    let mut reader = Reader::new(buf);
+    let version = reader.u8()?;
+    if version != ROW_FORMAT_VERSION {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unknown row format version {}", version)));
+    }
+    let count = reader.u16()? as usize;
+    let mut stored = HashMap::with_capacity(count);
+    for _ in 0..count {
+        let id = reader.u16()?;
+        let present = reader.u8()? == 1;
+        stored.insert(id, if present { Some(reader.str()?) } else { None });
+    }
+
+    schema.columns.iter()
+        .map(|column| {
+            let text = match stored.remove(&column.id) {
+                Some(text) => text,
+                None => column.missing.clone(),
+            };
+            match text {
+                Some(text) => column.data_type.parse(&text).ok_or_else(|| io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("stored value '{}' is not a valid {} for column {}", text, column.data_type, column.name),
+                )),
+                None => Ok(Value::Null),
+            }
+        })
+        .collect()
+}
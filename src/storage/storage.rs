@@ -1,39 +1,79 @@
 // page based storage system
 
+use std::collections::BTreeMap;
 use std::fs::{File, OpenOptions};
 use std::io::{Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
 use std::env;
-use crate::storage::page::{self, HEADER_SIZE, HEAP_HEADER_SIZE, HeapPage, HeapPageHeader, PAGE_SIZE, Page, PageHeader, PageType, SLOT_ENTRY_SIZE, SlotEntry};
+use std::sync::atomic::{AtomicBool, Ordering};
+use crate::storage::overflow::Overflow;
+use crate::storage::page::{COMMON_HEADER_SIZE, CommonHeader, FILE_HEADER_PAGE, FileHeader, HEAP_HEADER_SIZE, HeapPage, HeapPageHeader, HeapRecord, MAX_INLINE_RECORD, PAGE_SIZE, Page, PageType, SLOT_ENTRY_SIZE};
+use crate::storage::tree::RecordId;
+use crate::storage::wal;
 
 pub const DB_SUBPATH: &str = "tony.db";
 
+// whether engines compress what they push out to overflow pages, set when the server starts. chains
+// say whether they were compressed, so turning it off or on doesnt change how existing ones are read
+static COMPRESS_OVERFLOW: AtomicBool = AtomicBool::new(true);
+
+pub fn set_compress_overflow(compress: bool) {
+    COMPRESS_OVERFLOW.store(compress, Ordering::Relaxed);
+}
+
 pub fn default_db_path() -> std::io::Result<PathBuf> {
     println!("getting the default db path");
     let exe_dir = env::current_exe()?
         .parent()
-        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::Other, "executable has no parent dir"))?
+        .ok_or_else(|| std::io::Error::other("executable has no parent dir"))?
         .to_path_buf();
     Ok(exe_dir.join(DB_SUBPATH))
 }
 
 pub struct StorageEngine {
     file: File,
+    // whether data pushed out to overflow pages gets compressed
+    pub compress_overflow: bool,
+    // whether writes go into the write ahead log, which only the servers own database file has
+    logged: bool,
+    // pages written since the last commit. they stay here until commit puts them into the log and the
+    // file, so a statement that fails is undone by forgetting them with rollback
+    dirty: BTreeMap<u32, Box<[u8; PAGE_SIZE]>>,
+    // how many pages the file has as far as the statement can see, which differs from the file on disk
+    // once the statement has grown it or cut free pages off the end
+    page_count: u32,
 }
 
 // manages pages in a single file
 impl StorageEngine {
     pub fn open() -> std::io::Result<Self> {
-        println!("opening file at path {}", default_db_path().unwrap().to_str().unwrap());
+        let path = default_db_path()?;
+        println!("opening file at path {}", path.display());
+        Self::open_file(&path, true)
+    }
+
+    // a database file that isnt the servers, for tests
+    #[cfg(test)]
+    pub(crate) fn open_at(path: &Path) -> std::io::Result<Self> {
+        Self::open_file(path, false)
+    }
+
+    fn open_file(path: &Path, logged: bool) -> std::io::Result<Self> {
         let file = OpenOptions::new()
             .read(true)
             .write(true)
             .create(true)
-            .open(default_db_path().unwrap())?;
+            .truncate(false)
+            .open(path)?;
 
         println!("Opened file successfully");
 
-        Ok(Self { file })
+        let page_count = (file.metadata()?.len() / PAGE_SIZE as u64) as u32;
+        let mut engine = Self { file, compress_overflow: COMPRESS_OVERFLOW.load(Ordering::Relaxed), logged, dirty: BTreeMap::new(), page_count };
+        if engine.file_len()? == 0 {
+            engine.write_page(FILE_HEADER_PAGE, &FileHeader::new().to_bytes())?;
+        }
+        Ok(engine)
     }
 
     pub fn wipe() -> std::io::Result<bool> {
@@ -41,6 +81,7 @@ impl StorageEngine {
         let path = default_db_path()?;
         if Path::new(&path).exists() {
             std::fs::remove_file(path)?;
+            wal::reset()?;
             Ok(true)
         } else {
             Ok(false)
@@ -48,7 +89,7 @@ impl StorageEngine {
     }
 
     pub fn file_len(&self) -> std::io::Result<u64> {
-        Ok(self.file.metadata()?.len())
+        Ok(Self::page_offset(self.page_count))
     }
 
     fn page_offset(page_num: u32) -> u64 {
@@ -56,22 +97,66 @@ impl StorageEngine {
     }
 
     pub fn read_page(&mut self, page_num: u32, buf: &mut [u8; PAGE_SIZE]) -> std::io::Result<[u8; PAGE_SIZE]> {
+        if let Some(page) = self.dirty.get(&page_num) {
+            buf.copy_from_slice(page.as_slice());
+            return Ok(*buf);
+        }
+        if page_num >= self.page_count {
+            return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, format!("page {} is past the end of the file", page_num)));
+        }
         // ? propagates any error up, otherwise it will unwrap the io::Result Ok value and continue
         self.file.seek(SeekFrom::Start(Self::page_offset(page_num)))?;
         self.file.read_exact(buf)?;
         Ok(*buf)
     }
 
-    pub fn read_page_header(&mut self, page_num: u32, buf: &mut [u8; HEADER_SIZE]) -> std::io::Result<[u8; HEADER_SIZE]> {
-        self.file.seek(SeekFrom::Start(Self::page_offset(page_num)))?;
-        self.file.read_exact(buf)?;
-        Ok(*buf)
+    // the page only reaches the log and the file at the next commit
+    pub fn write_page(&mut self, page_num: u32, buf: &[u8; PAGE_SIZE]) -> std::io::Result<()> {
+        self.dirty.insert(page_num, Box::new(*buf));
+        self.page_count = self.page_count.max(page_num + 1);
+        Ok(())
     }
 
-    pub fn write_page(&mut self, page_num: u32, buf: & [u8; PAGE_SIZE]) -> std::io::Result<()> {
-        self.file.seek(SeekFrom::Start(Self::page_offset(page_num)))?;
-        self.file.write_all(buf)?;
+    // puts every page written since the last commit into the write ahead log, ends the statement there
+    // and then writes the pages to the file. a page written more than once is logged once, as it ended up.
+    // when the log asks for a checkpoint the file is synced first, then the log can let go of the
+    // segments that are safe in it
+    pub fn commit(&mut self) -> std::io::Result<()> {
+        let disk_count = (self.file.metadata()?.len() / PAGE_SIZE as u64) as u32;
+        let cut = self.page_count < disk_count;
+        if self.dirty.is_empty() && !cut {
+            return Ok(());
+        }
+        let mut checkpoint = None;
+        if self.logged {
+            for (&page_num, page) in &self.dirty {
+                wal::log_page(page_num, page)?;
+            }
+            if cut {
+                wal::log_truncate(self.page_count)?;
+            }
+            checkpoint = wal::commit()?;
+        }
+        for (&page_num, page) in &self.dirty {
+            self.file.seek(SeekFrom::Start(Self::page_offset(page_num)))?;
+            self.file.write_all(page.as_slice())?;
+        }
+        if cut {
+            self.file.set_len(Self::page_offset(self.page_count))?;
+        }
         self.file.flush()?;
+        self.dirty.clear();
+        if let Some(lsn) = checkpoint {
+            self.file.sync_data()?;
+            wal::checkpoint(lsn)?;
+        }
+        Ok(())
+    }
+
+    // forgets everything written since the last commit, leaving the file as it was
+    pub fn rollback(&mut self) -> std::io::Result<()> {
+        self.dirty.clear();
+        self.page_count = (self.file.metadata()?.len() / PAGE_SIZE as u64) as u32;
         Ok(())
     }
 
@@ -80,11 +165,10 @@ impl StorageEngine {
 
        let mut current = head_page;
        loop {
-            let mut header_buf = [0u8; HEAP_HEADER_SIZE];
-            self.file.seek(SeekFrom::Start(Self::page_offset(current)))?;
-            self.file.read_exact(&mut header_buf)?;
+            let mut buf = [0u8; PAGE_SIZE];
+            self.read_page(current, &mut buf)?;
 
-            let mut heap_hdr = HeapPageHeader::from_bytes(&mut header_buf);
+            let mut heap_hdr = HeapPageHeader::from_bytes(&buf[..HEAP_HEADER_SIZE])?;
             if heap_hdr.free_space() >= need {
                 return Ok(current);
             }
@@ -97,36 +181,343 @@ impl StorageEngine {
             let new_page = self.allocate_page(PageType::Heap)?;
             heap_hdr.common.next_page = new_page;
 
-            // need to overwrite the heap_hdr after updating its next_page
-            self.file.seek(SeekFrom::Start(Self::page_offset(current)))?;
-            self.file.write_all(&heap_hdr.to_bytes())?;
-            self.file.flush()?;
+            // need to overwrite the heap_hdr after updating its next_page. the whole page is written
+            // back so the log has all of it
+            buf[..HEAP_HEADER_SIZE].copy_from_slice(&heap_hdr.to_bytes());
+            self.write_page(current, &buf)?;
 
             return Ok(new_page);
        }
     }
 
+    fn read_file_header(&mut self) -> std::io::Result<FileHeader> {
+        let mut buf = [0u8; PAGE_SIZE];
+        self.read_page(FILE_HEADER_PAGE, &mut buf)?;
+        FileHeader::from_bytes(&buf)
+    }
+
+    // how many times the catalog has changed since the file was made, every CREATE, ALTER and DROP adds one
+    pub fn schema_version(&mut self) -> std::io::Result<u32> {
+        Ok(self.read_file_header()?.schema_version)
+    }
+
+    // returns the new version
+    pub fn bump_schema_version(&mut self) -> std::io::Result<u32> {
+        let mut file_header = self.read_file_header()?;
+        file_header.schema_version += 1;
+        self.write_page(FILE_HEADER_PAGE, &file_header.to_bytes())?;
+        Ok(file_header.schema_version)
+    }
+
+    // reuses a page from the freelist if there is one, otherwise grows the file
     pub fn allocate_page(&mut self, page_type: PageType) -> std::io::Result<u32> {
-        let page_num = (self.file.metadata()?.len() / PAGE_SIZE as u64) as u32;
+        let mut file_header = self.read_file_header()?;
+        let page_num = if file_header.freelist_head != 0 {
+            let page_num = file_header.freelist_head;
+            let mut buf = [0u8; PAGE_SIZE];
+            self.read_page(page_num, &mut buf)?;
+            file_header.freelist_head = CommonHeader::from_bytes(&buf)?.next_page;
+            file_header.free_count -= 1;
+            self.write_page(FILE_HEADER_PAGE, &file_header.to_bytes())?;
+            page_num
+        } else {
+            self.page_count
+        };
+
         match page_type {
             PageType::Heap => {
                 let heap_page = HeapPage::new();
                 let mut buf = [0u8; PAGE_SIZE];
-                buf[..HEADER_SIZE].copy_from_slice(&heap_page.header.to_bytes());
+                buf[..HEAP_HEADER_SIZE].copy_from_slice(&heap_page.header.to_bytes());
                 self.write_page(page_num, &buf)?;
-                return Ok(page_num);
             },
             _ => {
                 let page = Page::new(page_type);
-                self.write_page(page_num, &mut page.to_bytes())?;
-                return Ok(page_num);
+                self.write_page(page_num, &page.to_bytes())?;
+            }
+        }
+        Ok(page_num)
+    }
+
+    // put a page on the freelist so allocate_page can hand it out again
+    pub fn free_page(&mut self, page_num: u32) -> std::io::Result<()> {
+        let mut file_header = self.read_file_header()?;
+        let mut buf = [0u8; PAGE_SIZE];
+        buf[..COMMON_HEADER_SIZE].copy_from_slice(&CommonHeader { page_type: PageType::Free, next_page: file_header.freelist_head }.to_bytes());
+        self.write_page(page_num, &buf)?;
+
+        file_header.freelist_head = page_num;
+        file_header.free_count += 1;
+        self.write_page(FILE_HEADER_PAGE, &file_header.to_bytes())
+    }
+
+    // shrink the file by dropping free pages sitting at the end of it. returns how many bytes were cut off
+    pub fn truncate_free_tail(&mut self) -> std::io::Result<u64> {
+        let mut file_header = self.read_file_header()?;
+        let mut free_pages = Vec::with_capacity(file_header.free_count as usize);
+        let mut buf = [0u8; PAGE_SIZE];
+        let mut current = file_header.freelist_head;
+        while current != 0 {
+            free_pages.push(current);
+            self.read_page(current, &mut buf)?;
+            current = CommonHeader::from_bytes(&buf)?.next_page;
+        }
+
+        let page_count = (self.file_len()? / PAGE_SIZE as u64) as u32;
+        let mut new_count = page_count;
+        while new_count > 0 && free_pages.contains(&(new_count - 1)) {
+            new_count -= 1;
+        }
+        if new_count == page_count {
+            return Ok(0);
+        }
+
+        // relink whats left of the freelist without the pages past the new end of the file
+        free_pages.retain(|&p| p < new_count);
+        for (i, &page) in free_pages.iter().enumerate() {
+            let next = free_pages.get(i + 1).copied().unwrap_or(0);
+            buf.fill(0);
+            buf[..COMMON_HEADER_SIZE].copy_from_slice(&CommonHeader { page_type: PageType::Free, next_page: next }.to_bytes());
+            self.write_page(page, &buf)?;
+        }
+        file_header.freelist_head = free_pages.first().copied().unwrap_or(0);
+        file_header.free_count = free_pages.len() as u32;
+        self.write_page(FILE_HEADER_PAGE, &file_header.to_bytes())?;
+
+        self.dirty.retain(|&page_num, _| page_num < new_count);
+        self.page_count = new_count;
+        Ok(Self::page_offset(page_count - new_count))
+    }
+
+    // records too big to sit in a heap page are written to an overflow chain and the heap page
+    // only keeps a reference to it
+    fn make_heap_record(&mut self, record: &[u8]) -> std::io::Result<HeapRecord> {
+        if record.len() > MAX_INLINE_RECORD {
+            return Ok(HeapRecord::Overflow(Overflow::write(self, record)?));
+        }
+        Ok(HeapRecord::Inline(record.to_vec()))
+    }
+
+    // write a record to the heap chain starting at head_page
+    pub fn insert_record(&mut self, head_page: u32, record: &[u8]) -> std::io::Result<RecordId> {
+        let heap_record = self.make_heap_record(record)?;
+        self.place_record(head_page, &heap_record)
+    }
+
+    // write many records to the heap chain starting at head_page. the page being filled is kept in
+    // memory and only written once its full, rather than read and written again for every record
+    pub fn insert_records(&mut self, head_page: u32, records: &[Vec<u8>]) -> std::io::Result<Vec<RecordId>> {
+        let mut rids = Vec::with_capacity(records.len());
+        let mut current: Option<(u32, HeapPage)> = None;
+        // pages before the one being filled were already too full, so the search for room starts here
+        let mut start = head_page;
+        for record in records {
+            let heap_record = self.make_heap_record(record)?;
+            let need = heap_record.stored_len();
+            let (page_id, mut heap_page) = match current.take() {
+                Some((page_id, heap_page)) if heap_page.header.free_space() >= need + SLOT_ENTRY_SIZE => (page_id, heap_page),
+                full => {
+                    if let Some((page_id, heap_page)) = full {
+                        self.write_page(page_id, &heap_page.to_bytes())?;
+                        start = page_id;
+                    }
+                    let page_id = self.find_or_allocate_heap_page(start, need)?;
+                    let mut buf = [0u8; PAGE_SIZE];
+                    self.read_page(page_id, &mut buf)?;
+                    (page_id, HeapPage::from_bytes(&buf)?)
+                }
+            };
+            let slot = heap_page.write_record(&heap_record)?;
+            rids.push(RecordId { page_id, slot });
+            current = Some((page_id, heap_page));
+        }
+        if let Some((page_id, heap_page)) = current {
+            self.write_page(page_id, &heap_page.to_bytes())?;
+        }
+        Ok(rids)
+    }
+
+    fn place_record(&mut self, head_page: u32, heap_record: &HeapRecord) -> std::io::Result<RecordId> {
+        let page_id = self.find_or_allocate_heap_page(head_page, heap_record.stored_len())?;
+        let mut buf = [0u8; PAGE_SIZE];
+        self.read_page(page_id, &mut buf)?;
+        let mut heap_page = HeapPage::from_bytes(&buf)?;
+        let slot = heap_page.write_record(heap_record)?;
+        self.write_page(page_id, &heap_page.to_bytes())?;
+
+        Ok(RecordId { page_id, slot })
+    }
+
+    pub fn read_record(&mut self, rid: RecordId) -> std::io::Result<Vec<u8>> {
+        let mut buf = [0u8; PAGE_SIZE];
+        self.read_page(rid.page_id, &mut buf)?;
+        let heap_page = HeapPage::from_bytes(&buf)?;
+        self.resolve_record(heap_page.read_record(rid.slot)?)
+    }
+
+    pub fn delete_record(&mut self, rid: RecordId) -> std::io::Result<()> {
+        let mut buf = [0u8; PAGE_SIZE];
+        self.read_page(rid.page_id, &mut buf)?;
+        let mut heap_page = HeapPage::from_bytes(&buf)?;
+        let old = heap_page.delete_record(rid.slot)?;
+        self.write_page(rid.page_id, &heap_page.to_bytes())?;
+        self.free_heap_record(old)
+    }
+
+    // replace a record, keeping its record id if the new version fits in the same page.
+    // returns where the record ended up
+    pub fn update_record(&mut self, head_page: u32, rid: RecordId, record: &[u8]) -> std::io::Result<RecordId> {
+        let heap_record = self.make_heap_record(record)?;
+
+        let mut buf = [0u8; PAGE_SIZE];
+        self.read_page(rid.page_id, &mut buf)?;
+        let mut heap_page = HeapPage::from_bytes(&buf)?;
+        let old = heap_page.read_record(rid.slot)?;
+
+        if heap_page.replace_record(rid.slot, &heap_record)? {
+            self.write_page(rid.page_id, &heap_page.to_bytes())?;
+            self.free_heap_record(old)?;
+            return Ok(rid);
+        }
+
+        heap_page.delete_record(rid.slot)?;
+        self.write_page(rid.page_id, &heap_page.to_bytes())?;
+        self.free_heap_record(old)?;
+        self.place_record(head_page, &heap_record)
+    }
+
+    fn free_heap_record(&mut self, record: HeapRecord) -> std::io::Result<()> {
+        match record {
+            HeapRecord::Inline(_) => Ok(()),
+            HeapRecord::Overflow(overflow) => Overflow::free(self, &overflow),
+        }
+    }
+
+    // every record in the heap chain starting at head_page, in storage order
+    pub fn scan_records(&mut self, head_page: u32) -> std::io::Result<Vec<(RecordId, Vec<u8>)>> {
+        let mut records = Vec::new();
+        let mut buf = [0u8; PAGE_SIZE];
+        let mut current = head_page;
+        while current != 0 {
+            self.read_page(current, &mut buf)?;
+            let heap_page = HeapPage::from_bytes(&buf)?;
+            for slot in heap_page.slots.iter().filter(|s| heap_page.is_live(s.id)) {
+                let record = self.resolve_record(heap_page.read_record(slot.id)?)?;
+                records.push((RecordId { page_id: current, slot: slot.id }, record));
+            }
+            current = heap_page.header.common.next_page;
+        }
+        Ok(records)
+    }
+
+    fn resolve_record(&mut self, record: HeapRecord) -> std::io::Result<Vec<u8>> {
+        match record {
+            HeapRecord::Inline(bytes) => Ok(bytes),
+            HeapRecord::Overflow(overflow) => Overflow::read(self, &overflow),
+        }
+    }
+
+    // give every page of the heap chain starting at head_page, and the overflow chains of its records,
+    // back to the freelist. with keep_head the head page stays allocated as an empty heap page
+    pub fn free_heap(&mut self, head_page: u32, keep_head: bool) -> std::io::Result<()> {
+        let mut buf = [0u8; PAGE_SIZE];
+        let mut current = head_page;
+        while current != 0 {
+            self.read_page(current, &mut buf)?;
+            let heap_page = HeapPage::from_bytes(&buf)?;
+            for slot in heap_page.slots.iter().filter(|s| heap_page.is_live(s.id)) {
+                self.free_heap_record(heap_page.read_record(slot.id)?)?;
+            }
+
+            if current == head_page && keep_head {
+                let mut empty = [0u8; PAGE_SIZE];
+                empty[..HEAP_HEADER_SIZE].copy_from_slice(&HeapPage::new().header.to_bytes());
+                self.write_page(current, &empty)?;
+            } else {
+                self.free_page(current)?;
+            }
+            current = heap_page.header.common.next_page;
+        }
+        Ok(())
+    }
+
+    // how much of the heap chain starting at head_page is in use
+    pub fn heap_stats(&mut self, head_page: u32) -> std::io::Result<HeapStats> {
+        let mut stats = HeapStats::default();
+        let mut buf = [0u8; PAGE_SIZE];
+        let mut current = head_page;
+        while current != 0 {
+            self.read_page(current, &mut buf)?;
+            let heap_page = HeapPage::from_bytes(&buf)?;
+            let live = heap_page.live_count() as u64;
+            stats.pages += 1;
+            stats.live_records += live;
+            stats.dead_slots += heap_page.slots.len() as u64 - live;
+            stats.free_bytes += heap_page.header.free_space() as u64;
+            current = heap_page.header.common.next_page;
+        }
+        Ok(stats)
+    }
+
+    // compact every page in the heap chain starting at head_page and give pages with nothing
+    // left in them back to the freelist. the head page always stays as the catalog points at it
+    pub fn vacuum_heap(&mut self, head_page: u32) -> std::io::Result<VacuumStats> {
+        let mut stats = VacuumStats::default();
+        let mut buf = [0u8; PAGE_SIZE];
+        let mut prev = 0;
+        let mut current = head_page;
+        while current != 0 {
+            self.read_page(current, &mut buf)?;
+            let mut heap_page = HeapPage::from_bytes(&buf)?;
+            let next = heap_page.header.common.next_page;
+
+            if current != head_page && heap_page.live_count() == 0 {
+                self.read_page(prev, &mut buf)?;
+                let mut prev_page = HeapPage::from_bytes(&buf)?;
+                prev_page.header.common.next_page = next;
+                self.write_page(prev, &prev_page.to_bytes())?;
+
+                self.free_page(current)?;
+                stats.pages_freed += 1;
+                stats.bytes_reclaimed += (PAGE_SIZE - HEAP_HEADER_SIZE) as u64 - heap_page.header.free_space() as u64;
+                current = next;
+                continue;
             }
+
+            let reclaimed = heap_page.compact();
+            if reclaimed > 0 {
+                self.write_page(current, &heap_page.to_bytes())?;
+                stats.pages_compacted += 1;
+                stats.bytes_reclaimed += reclaimed as u64;
+            }
+            prev = current;
+            current = next;
         }
+        Ok(stats)
     }
+}
 
-    pub fn close(self) -> std::io::Result<()> {
-        drop(self);
-        return Ok(());
+// an engine lives for one statement, so whatever it wrote and wasnt rolled back is committed when its
+// dropped. the executor commits itself first so it can report a failure
+impl Drop for StorageEngine {
+    fn drop(&mut self) {
+        if let Err(e) = self.commit() {
+            eprintln!("Failed to commit: {}", e);
+        }
     }
-    
-}
\ No newline at end of file
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct HeapStats {
+    pub pages: u64,
+    pub live_records: u64,
+    pub dead_slots: u64, // deleted records waiting for their slot to be reused or vacuumed
+    pub free_bytes: u64,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct VacuumStats {
+    pub pages_compacted: u64,
+    pub pages_freed: u64,
+    pub bytes_reclaimed: u64, // dead record bytes and slots made usable again
+}
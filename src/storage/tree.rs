@@ -1,5 +1,8 @@
+use crate::storage::codec::Reader;
 use crate::storage::storage::StorageEngine;
-use crate::storage::page::{PageType, PageHeader, PAGE_SIZE, HEADER_SIZE};
+use crate::storage::overflow::Overflow;
+use crate::storage::page::{OverflowRef, OVERFLOW_REF_SIZE, PageType, PageHeader, PAGE_SIZE, HEADER_SIZE};
+use std::ops::Bound;
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct RecordId {
@@ -13,6 +16,7 @@ struct Node {
     page_id: u32,
     is_leaf: bool,
     keys: Vec<String>,
+    key_overflow: Vec<Option<OverflowRef>>, // where each oversized key lives once persisted
     rids: Vec<RecordId>, // tie tree leaf nodes to records
     children: Vec<u32>,
     next_leaf: u32, // for scans
@@ -20,12 +24,16 @@ struct Node {
 
 const NODE_HDR_SIZE: usize = 1 + 2 + 4; // is_leaf + key_count + next_leaf
 const MAX_KEYS: usize = 4;
+// keys longer than this are moved to an overflow chain so a full node always fits in a page
+const MAX_INLINE_KEY: usize = 512;
+const OVERFLOW_KEY: u16 = u16::MAX; // key length marker meaning an OverflowRef follows instead of the key
 
 impl Node {
     fn new_leaf(page_id: u32) -> Self {
         Node {
             page_id,
             keys: Vec::new(),
+            key_overflow: Vec::new(),
             rids: Vec::new(),
             children: Vec::new(),
             is_leaf: true,
@@ -37,6 +45,7 @@ impl Node {
         Node {
             page_id,
             keys: Vec::new(),
+            key_overflow: Vec::new(),
             rids: Vec::new(),
             children: Vec::new(),
             is_leaf: false,
@@ -48,49 +57,42 @@ impl Node {
     fn load(engine: &mut StorageEngine, page_id: u32) -> std::io::Result<Self> {
         let mut buf = [0u8; PAGE_SIZE];
         engine.read_page(page_id, &mut buf)?;
-        let content = &buf[HEADER_SIZE..];
-        let is_leaf = content[0] == 1;
-        let key_count = u16::from_le_bytes([content[1], content[2]]) as usize; // 2 bytes for u16
-        let next_leaf = u32::from_le_bytes([content[3], content[4], content[5], content[6]]); // 4 bytes for u32
-        let mut offset = NODE_HDR_SIZE;
+        if PageType::try_from(buf[0])? != PageType::Index {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("page {} in the index isnt an index page", page_id)));
+        }
+        // read through codec::Reader so a node that runs off the end of its page is an error, not a panic
+        let mut content = Reader::new(&buf[HEADER_SIZE..]);
+        let is_leaf = content.u8()? == 1;
+        let key_count = content.u16()? as usize;
+        let next_leaf = content.u32()?;
 
         let mut keys = Vec::with_capacity(key_count);
+        let mut key_overflow = Vec::with_capacity(key_count);
         for _ in 0..key_count {
-            let key_len = u16::from_le_bytes([content[offset], content[offset + 1]]) as usize;
-            offset += 2;
-            let key = String::from_utf8(content[offset..offset + key_len].to_vec()).unwrap();
+            let key_len = content.u16()?;
+            let (key_bytes, overflow) = if key_len == OVERFLOW_KEY {
+                let overflow = OverflowRef::from_bytes(content.bytes(OVERFLOW_REF_SIZE)?)?;
+                (Overflow::read(engine, &overflow)?, Some(overflow))
+            } else {
+                (content.bytes(key_len as usize)?.to_vec(), None)
+            };
+            let key = String::from_utf8(key_bytes)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
             keys.push(key);
-            offset += key_len;
+            key_overflow.push(overflow);
         }
 
         let mut rids = Vec::new();
         let mut children = Vec::new();
         if !is_leaf {
             for _ in 0..key_count+1 { // +1 cos internal nodes have n+1 children for n keys
-                let id = u32::from_le_bytes([
-                    content[offset],
-                    content[offset + 1],
-                    content[offset + 2],
-                    content[offset + 3],
-                ]);
-                offset += 4;
-                children.push(id);
+                children.push(content.u32()?);
             }
         } else {
             // one record per key
             for _ in 0..key_count {
-                let page_id = u32::from_le_bytes([
-                    content[offset],
-                    content[offset + 1],
-                    content[offset + 2],
-                    content[offset + 3],
-                ]);
-                offset += 4;
-                let slot = u16::from_le_bytes([
-                    content[offset],
-                    content[offset + 1],
-                ]);
-                offset += 4;
+                let page_id = content.u32()?;
+                let slot = content.u16()?;
                 rids.push(RecordId { page_id, slot } );
             }
         }
@@ -99,14 +101,21 @@ impl Node {
             page_id,
             is_leaf,
             keys,
+            key_overflow,
             rids,
             children,
             next_leaf,
         })
     }
 
-    // persist this in the storage
-    fn persist(&self, engine: &mut StorageEngine) -> std::io::Result<()> {
+    // persist this in the storage. oversized keys that havent been written out yet get their overflow chain here
+    fn persist(&mut self, engine: &mut StorageEngine) -> std::io::Result<()> {
+        for (key, overflow) in self.keys.iter().zip(self.key_overflow.iter_mut()) {
+            if key.len() > MAX_INLINE_KEY && overflow.is_none() {
+                *overflow = Some(Overflow::write(engine, key.as_bytes())?);
+            }
+        }
+
         let mut buf = [0u8; PAGE_SIZE];
         let hdr = PageHeader::new(PageType::Index);
         buf[..HEADER_SIZE].copy_from_slice(&hdr.to_bytes());
@@ -118,7 +127,14 @@ impl Node {
         content[3..7].copy_from_slice(&self.next_leaf.to_le_bytes()); // 4 bytes for u32
         let mut offset = NODE_HDR_SIZE;
 
-        for key in &self.keys {
+        for (key, overflow) in self.keys.iter().zip(&self.key_overflow) {
+            if let Some(overflow) = overflow {
+                content[offset..offset + 2].copy_from_slice(&OVERFLOW_KEY.to_le_bytes());
+                offset += 2;
+                content[offset..offset + OVERFLOW_REF_SIZE].copy_from_slice(&overflow.to_bytes());
+                offset += OVERFLOW_REF_SIZE;
+                continue;
+            }
             let key_bytes = key.as_bytes();
             let key_len = key_bytes.len() as u16;
             content[offset..offset + 2].copy_from_slice(&key_len.to_le_bytes()); // +2 cos u16
@@ -141,50 +157,96 @@ impl Node {
             }
         }
 
-        engine.write_page(self.page_id, &buf);
-        Ok(())
+        engine.write_page(self.page_id, &buf)
     }
-
-        
 }
 
 
-struct BTree {
-    storage: StorageEngine,
+// the root page never moves so the catalog can keep pointing at it. when the root fills up its contents
+// are copied out to a new page and the root becomes the internal node above it
+pub struct BTree<'a> {
+    storage: &'a mut StorageEngine,
     root: u32,
-    column: String,
 }
 
-impl BTree {
-    fn new(mut storage: StorageEngine, column: String) -> std::io::Result<Self> {
+impl<'a> BTree<'a> {
+    pub fn create(storage: &'a mut StorageEngine) -> std::io::Result<Self> {
         let root_page = storage.allocate_page(PageType::Index)?;
-        let root_node = Node::new_leaf(root_page);
-        root_node.persist(&mut storage)?;
-        Ok(BTree {storage, root: root_page, column })
+        let mut root_node = Node::new_leaf(root_page);
+        root_node.persist(storage)?;
+        Ok(BTree { storage, root: root_page })
+    }
+
+    pub fn open(storage: &'a mut StorageEngine, root: u32) -> Self {
+        BTree { storage, root }
     }
 
-    fn insert(&mut self, key: String, rid: RecordId) -> std::io::Result<()> {
-        let root = Node::load(&mut self.storage, self.root).unwrap();
-        
+    pub fn root(&self) -> u32 {
+        self.root
+    }
+
+    // free every node and key overflow chain of the tree, root included
+    pub fn destroy(mut self) -> std::io::Result<()> {
+        let root = self.root;
+        self.free_subtree(root)?;
+        self.storage.free_page(root)
+    }
+
+    // number of pages the tree is made of, not counting key overflow chains
+    pub fn page_count(&mut self) -> std::io::Result<u64> {
+        let mut count = 0;
+        let mut pending = vec![self.root];
+        while let Some(page_id) = pending.pop() {
+            count += 1;
+            pending.extend(Node::load(self.storage, page_id)?.children);
+        }
+        Ok(count)
+    }
+
+    // remove every entry, leaving the root as an empty leaf
+    pub fn clear(&mut self) -> std::io::Result<()> {
+        self.free_subtree(self.root)?;
+        Node::new_leaf(self.root).persist(self.storage)
+    }
+
+    // frees the key overflow chains of page_id and everything below it, and the pages below it
+    fn free_subtree(&mut self, page_id: u32) -> std::io::Result<()> {
+        let node = Node::load(self.storage, page_id)?;
+        for overflow in node.key_overflow.iter().flatten() {
+            Overflow::free(self.storage, overflow)?;
+        }
+        for &child in &node.children {
+            self.free_subtree(child)?;
+            self.storage.free_page(child)?;
+        }
+        Ok(())
+    }
+
+    pub fn insert(&mut self, key: String, rid: RecordId) -> std::io::Result<()> {
+        let root = Node::load(self.storage, self.root)?;
+
         if root.keys.len() == MAX_KEYS {
-            let new_root_page = self.storage.allocate_page(PageType::Index).unwrap();
-            let mut new_root = Node::new_internal(new_root_page);
-            new_root.children.push(root.page_id);
-            self.split_child(&mut new_root, 0);
-            new_root.persist(&mut self.storage).unwrap();
-            self.root = new_root_page;
+            let moved_page = self.storage.allocate_page(PageType::Index)?;
+            let mut moved = root;
+            moved.page_id = moved_page;
+            moved.persist(self.storage)?;
+
+            let mut new_root = Node::new_internal(self.root);
+            new_root.children.push(moved_page);
+            self.split_child(&mut new_root, 0)?;
         }
 
-        return self.insert_non_full(root.page_id, key, rid);
+        self.insert_non_full(self.root, key, rid)
     }
 
     fn insert_non_full(&mut self, page_id: u32, key: String, rid: RecordId) -> std::io::Result<()> {
-        let mut node = Node::load(&mut self.storage, page_id)?;
+        let mut node = Node::load(self.storage, page_id)?;
         if node.is_leaf {
             let pos = node.keys.binary_search(&key).unwrap_or_else(|e| e);
             node.keys.insert(pos, key);
+            node.key_overflow.insert(pos, None);
             node.rids.insert(pos, rid);
-            node.persist(&mut self.storage)?;
+            node.persist(self.storage)?;
             return Ok(());
         }
 
@@ -193,22 +255,21 @@ impl BTree {
             Err(idx) => idx,
         };
 
-        let mut child = Node::load(&mut self.storage, node.children[next_index])?;
+        let child = Node::load(self.storage, node.children[next_index])?;
         if child.keys.len() == MAX_KEYS {
             self.split_child(&mut node, next_index)?;
-            if key > node.keys[next_index] {
+            if key >= node.keys[next_index] {
                 next_index += 1;
             }
         }
 
         let next_child = node.children[next_index];
-        return self.insert_non_full(next_child, key, rid)
-
+        self.insert_non_full(next_child, key, rid)
     }
 
     fn split_child(&mut self, parent: &mut Node, index: usize) -> std::io::Result<()> {
         //get left
-        let mut left = Node::load(&mut self.storage, parent.children[index])?;
+        let mut left = Node::load(self.storage, parent.children[index])?;
         // create a new right node
         let right_id = self.storage.allocate_page(PageType::Index)?;
         let mut right = if left.is_leaf {
@@ -219,57 +280,135 @@ impl BTree {
         // move half the keys to right
         // no children if leaf
         if right.is_leaf {
-            let mid = (left.keys.len() +1) /2;
+            let mid = left.keys.len().div_ceil(2);
             right.keys = left.keys.split_off(mid);
+            right.key_overflow = left.key_overflow.split_off(mid);
+            right.rids = left.rids.split_off(mid);
             right.next_leaf = left.next_leaf;
             left.next_leaf = right.page_id;
 
-            parent.keys.insert(index, right.keys[0].clone()); // first key of right goes into parent
+            // first key of right goes into parent, the parent gets its own copy of any overflow chain
+            parent.keys.insert(index, right.keys[0].clone());
+            parent.key_overflow.insert(index, None);
             parent.children.insert(index + 1, right.page_id);
         } else {
-            let mid = (left.keys.len()+1) /2;
+            let mid = left.keys.len().div_ceil(2);
             right.keys = left.keys.split_off(mid);
+            right.key_overflow = left.key_overflow.split_off(mid);
 
             right.children = left.children.split_off(mid + 1); // split off leaves the remainder in left
             let mid_key = right.keys.remove(0); // remove mid key from right to go to parent
+            let mid_overflow = right.key_overflow.remove(0);
             parent.keys.insert(index, mid_key);
+            parent.key_overflow.insert(index, mid_overflow);
             parent.children.insert(index + 1, right.page_id);
         }
 
         // persist all
-        left.persist(&mut self.storage)?;
-        right.persist(&mut self.storage)?;
-        parent.persist(&mut self.storage)?;
-        
+        left.persist(self.storage)?;
+        right.persist(self.storage)?;
+        parent.persist(self.storage)?;
+
         Ok(())
     }
 
-    pub fn get(&mut self, key: &String) -> std::io::Result<Option<RecordId>> {
+    // the leftmost leaf key could be in and the position of the first key >= key in it.
+    // duplicates can straddle a split so this goes down the leftmost path the key could be on
+    fn seek(&mut self, key: &String) -> std::io::Result<(Node, usize)> {
         let mut pid = self.root;
-        loop {
-            let node = Node::load(&mut self.storage, pid)?;
-            let pos = node.keys.binary_search(key);
-
+        let node = loop {
+            let node = Node::load(self.storage, pid)?;
             if node.is_leaf {
-                match pos {
-                    Ok(idx) => return Ok(Some(node.rids[idx])),
-                    Err(_) => return Ok(None),
+                break node;
+            }
+            // example:
+            // keys: [10, 20, 30]
+            // children: [c0, c1, c2, c3]
+            // key = 25 -> 2 -> go to c2
+            // c2 are elements >20 and <30
+            pid = node.children[node.keys.partition_point(|k| k < key)];
+        };
+        let pos = node.keys.partition_point(|k| k < key);
+        Ok((node, pos))
+    }
+
+    // every record stored under key, walking along the leaves from where the key would start
+    pub fn get_all(&mut self, key: &String) -> std::io::Result<Vec<RecordId>> {
+        let (mut node, mut pos) = self.seek(key)?;
+        let mut rids = Vec::new();
+        loop {
+            while pos < node.keys.len() {
+                if &node.keys[pos] != key {
+                    return Ok(rids);
                 }
-            } else {
-                pid = match pos {
-                    Ok(idx) => node.children[idx + 1],
-                    // err(idx) means idx is the first position where key could be inserted
-                    // example:
-                    // keys: [10, 20, 30]
-                    // children: [c0, c1, c2, c3]
-                    // key = 25 -> err(2) -> go to c2
-                    // c2 are elements >20 and <30
-                    Err(idx) => node.children[idx],
+                rids.push(node.rids[pos]);
+                pos += 1;
+            }
+            if node.next_leaf == 0 {
+                return Ok(rids);
+            }
+            node = Node::load(self.storage, node.next_leaf)?;
+            pos = 0;
+        }
+    }
+
+    // every entry with a key between low and high in key order, walking the leaves from the first one
+    pub fn range(&mut self, low: Bound<&String>, high: Bound<&String>) -> std::io::Result<Vec<(String, RecordId)>> {
+        let start = match low {
+            Bound::Included(key) | Bound::Excluded(key) => key.clone(),
+            Bound::Unbounded => String::new(),
+        };
+        let (mut node, mut pos) = self.seek(&start)?;
+        let mut entries = Vec::new();
+        loop {
+            while pos < node.keys.len() {
+                let key = &node.keys[pos];
+                let past_end = match high {
+                    Bound::Included(high) => key > high,
+                    Bound::Excluded(high) => key >= high,
+                    Bound::Unbounded => false,
                 };
+                if past_end {
+                    return Ok(entries);
+                }
+                if !matches!(low, Bound::Excluded(low) if key == low) {
+                    entries.push((key.clone(), node.rids[pos]));
+                }
+                pos += 1;
+            }
+            if node.next_leaf == 0 {
+                return Ok(entries);
             }
+            node = Node::load(self.storage, node.next_leaf)?;
+            pos = 0;
         }
     }
 
+    // remove the entry pointing key at rid. leaves are allowed to go under half full (or empty)
+    // rather than being merged, the separators above them still route correctly
+    pub fn delete(&mut self, key: &String, rid: RecordId) -> std::io::Result<bool> {
+        let (mut node, mut pos) = self.seek(key)?;
+        loop {
+            while pos < node.keys.len() {
+                if &node.keys[pos] != key {
+                    return Ok(false);
+                }
+                if node.rids[pos] == rid {
+                    node.keys.remove(pos);
+                    node.rids.remove(pos);
+                    if let Some(overflow) = node.key_overflow.remove(pos) {
+                        Overflow::free(self.storage, &overflow)?;
+                    }
+                    node.persist(self.storage)?;
+                    return Ok(true);
+                }
+                pos += 1;
+            }
+            if node.next_leaf == 0 {
+                return Ok(false);
+            }
+            node = Node::load(self.storage, node.next_leaf)?;
+            pos = 0;
+        }
+    }
 }
-
-
@@ -0,0 +1,506 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use once_cell::sync::Lazy;
+
+use super::codec::{self, Reader};
+use super::page::PAGE_SIZE;
+use super::storage::default_db_path;
+
+// the write ahead log. every page the storage engine changes in a statement is appended here whole,
+// once however many times the statement wrote it, before it goes into the database file, and so is
+// every time VACUUM cuts the file shorter. a statement that wrote anything ends with a commit record
+// holding the time it finished, and the segment is synced to disk there before the statement writes
+// to the database file. records are numbered by a log sequence number (LSN) that only ever goes up,
+// so a copy of the database file taken at one LSN and the records after it can be brought forward to
+// the end of any later statement, which is what BACKUP and recover do. whole pages make replaying a
+// record the same however many times its done and whatever state the page was copied in.
+// the log is split into segment files in tony_wal next to the database, each named after its first
+// LSN in hex, and a new one is started after a commit once the current one is over SEGMENT_SIZE.
+// thats a checkpoint: the storage engine syncs the database file, so everything logged so far is
+// safe in it, and segments older than that are deleted. segments from the start LSN of the latest
+// backup on are kept (its written to the keep_from file when the backup starts), recovering it needs
+// them. archiving them is copying the files somewhere else before theyre deleted
+
+pub const WAL_DIR: &str = "tony_wal";
+const SEGMENT_SIZE: u64 = 16 * 1024 * 1024;
+const SEGMENT_EXTENSION: &str = "wal";
+const KEEP_FILE: &str = "keep_from"; // the LSN after which the latest backup needs the log, as text
+
+// a record is its kind, its u64 LSN and then
+const PAGE: u8 = 1; // u32 page number and the page
+const TRUNCATE: u8 = 2; // u32 count of pages the file was cut to
+const COMMIT: u8 = 3; // u64 milliseconds since the unix epoch
+const RESET: u8 = 4; // u64 milliseconds, the database file was deleted and the next one starts empty
+
+#[derive(Debug, Clone)]
+pub enum Record {
+    Page { lsn: u64, page_num: u32, data: Vec<u8> },
+    Truncate { lsn: u64, page_count: u32 },
+    Commit { lsn: u64, time: u64 },
+    Reset { lsn: u64, time: u64 },
+}
+
+impl Record {
+    pub fn lsn(&self) -> u64 {
+        match self {
+            Record::Page { lsn, .. } | Record::Truncate { lsn, .. } | Record::Commit { lsn, .. } | Record::Reset { lsn, .. } => *lsn,
+        }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        match self {
+            Record::Page { lsn, page_num, data } => {
+                codec::put_u8(&mut buf, PAGE);
+                codec::put_u64(&mut buf, *lsn);
+                codec::put_u32(&mut buf, *page_num);
+                buf.extend_from_slice(data);
+            }
+            Record::Truncate { lsn, page_count } => {
+                codec::put_u8(&mut buf, TRUNCATE);
+                codec::put_u64(&mut buf, *lsn);
+                codec::put_u32(&mut buf, *page_count);
+            }
+            Record::Commit { lsn, time } | Record::Reset { lsn, time } => {
+                codec::put_u8(&mut buf, if matches!(self, Record::Commit { .. }) { COMMIT } else { RESET });
+                codec::put_u64(&mut buf, *lsn);
+                codec::put_u64(&mut buf, *time);
+            }
+        }
+        buf
+    }
+
+    fn read(reader: &mut Reader) -> io::Result<Self> {
+        let kind = reader.u8()?;
+        let lsn = reader.u64()?;
+        match kind {
+            PAGE => Ok(Record::Page { lsn, page_num: reader.u32()?, data: reader.bytes(PAGE_SIZE)?.to_vec() }),
+            TRUNCATE => Ok(Record::Truncate { lsn, page_count: reader.u32()? }),
+            COMMIT => Ok(Record::Commit { lsn, time: reader.u64()? }),
+            RESET => Ok(Record::Reset { lsn, time: reader.u64()? }),
+            other => Err(io::Error::new(io::ErrorKind::InvalidData, format!("unknown log record kind {}", other))),
+        }
+    }
+}
+
+pub(super) struct Wal {
+    dir: PathBuf,
+    segment: Option<(File, u64)>, // the segment being appended to and how long it is, None until the next record
+    pub(super) segment_size: u64, // SEGMENT_SIZE, tests make it smaller
+    next_lsn: u64,
+    pending: bool, // records were written since the last commit
+}
+
+// one log for the whole server. statements already take turns through the executors lock, this only
+// keeps the log itself in one piece
+static WAL: Lazy<Mutex<Option<Wal>>> = Lazy::new(|| Mutex::new(None));
+
+impl Wal {
+    // carries on from the last record of the newest segment, cutting off a record that was only
+    // partly written when the server stopped
+    pub(super) fn open(dir: PathBuf) -> io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+        let mut wal = Wal { dir, segment: None, segment_size: SEGMENT_SIZE, next_lsn: 1, pending: false };
+        if let Some((first_lsn, path)) = segments(&wal.dir)?.pop() {
+            let (records, valid_len) = read_records(&fs::read(&path)?);
+            wal.next_lsn = records.last().map_or(first_lsn, |record| record.lsn() + 1);
+            wal.pending = records.last().is_some_and(|record| matches!(record, Record::Page { .. } | Record::Truncate { .. }));
+            let file = OpenOptions::new().append(true).open(&path)?;
+            file.set_len(valid_len as u64)?;
+            wal.segment = Some((file, valid_len as u64));
+        }
+        Ok(wal)
+    }
+
+    fn append(&mut self, record: Record) -> io::Result<()> {
+        let (file, len) = match &mut self.segment {
+            Some(segment) => segment,
+            None => {
+                let path = self.dir.join(format!("{:016x}.{}", self.next_lsn, SEGMENT_EXTENSION));
+                let file = OpenOptions::new().create(true).append(true).open(path)?;
+                self.segment.insert((file, 0))
+            }
+        };
+        let bytes = record.to_bytes();
+        file.write_all(&bytes)?;
+        file.flush()?;
+        *len += bytes.len() as u64;
+        self.next_lsn += 1;
+        Ok(())
+    }
+
+    pub(super) fn log_page(&mut self, page_num: u32, data: &[u8; PAGE_SIZE]) -> io::Result<()> {
+        self.pending = true;
+        self.append(Record::Page { lsn: self.next_lsn, page_num, data: data.to_vec() })
+    }
+
+    pub(super) fn log_truncate(&mut self, page_count: u32) -> io::Result<()> {
+        self.pending = true;
+        self.append(Record::Truncate { lsn: self.next_lsn, page_count })
+    }
+
+    // the LSN of the commit record when the segment filled up and its time for a checkpoint
+    pub(super) fn commit(&mut self, time: u64) -> io::Result<Option<u64>> {
+        if !self.pending {
+            return Ok(None);
+        }
+        self.append(Record::Commit { lsn: self.next_lsn, time })?;
+        self.sync()?;
+        Ok(self.end_statement().then(|| self.current_lsn()))
+    }
+
+    pub(super) fn reset(&mut self, time: u64) -> io::Result<()> {
+        self.append(Record::Reset { lsn: self.next_lsn, time })?;
+        self.sync()?;
+        self.end_statement();
+        Ok(())
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        match &self.segment {
+            Some((file, _)) => file.sync_data(),
+            None => Ok(()),
+        }
+    }
+
+    // everything up to lsn is synced into the database file. deletes the segments all of whose records
+    // are at or before it and before where the latest backup needs the log from. the newest segment is
+    // always kept, its where the LSN carries on from after a restart. returns how many were deleted
+    pub(super) fn checkpoint(&mut self, lsn: u64) -> io::Result<usize> {
+        let keep_from = self.kept_from()?.map_or(lsn, |kept| kept.min(lsn));
+        let segments = segments(&self.dir)?;
+        let mut deleted = 0;
+        for pair in segments.windows(2) {
+            // a segment ends where the next one starts
+            if pair[1].0 > keep_from + 1 {
+                break;
+            }
+            fs::remove_file(&pair[0].1)?;
+            deleted += 1;
+        }
+        Ok(deleted)
+    }
+
+    // a backup starting at lsn needs the log after it. the first one holds the log from there on, a
+    // later one only once its done, until then the one before it is still the latest that worked
+    pub(super) fn hold(&self, lsn: u64) -> io::Result<()> {
+        match self.kept_from()? {
+            Some(_) => Ok(()),
+            None => self.keep_from(lsn),
+        }
+    }
+
+    pub(super) fn keep_from(&self, lsn: u64) -> io::Result<()> {
+        fs::write(self.dir.join(KEEP_FILE), lsn.to_string())
+    }
+
+    fn kept_from(&self) -> io::Result<Option<u64>> {
+        match fs::read_to_string(self.dir.join(KEEP_FILE)) {
+            Ok(text) => text.trim().parse().map(Some)
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, format!("{} holds '{}' instead of an LSN", KEEP_FILE, text.trim()))),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub(super) fn current_lsn(&self) -> u64 {
+        self.next_lsn - 1
+    }
+
+    // the next record goes into a new segment if this one is full, true then
+    fn end_statement(&mut self) -> bool {
+        self.pending = false;
+        let full = self.segment.as_ref().is_some_and(|(_, len)| *len >= self.segment_size);
+        if full {
+            self.segment = None;
+        }
+        full
+    }
+}
+
+fn with_wal<T>(f: impl FnOnce(&mut Wal) -> io::Result<T>) -> io::Result<T> {
+    let mut wal = WAL.lock().map_err(|_| io::Error::other("write ahead log lock poisoned"))?;
+    if wal.is_none() {
+        *wal = Some(Wal::open(dir()?)?);
+    }
+    f(wal.as_mut().expect("opened above"))
+}
+
+// the log of the database file next to the executable
+pub fn dir() -> io::Result<PathBuf> {
+    let db_path = default_db_path()?;
+    Ok(db_path.parent().map_or_else(|| PathBuf::from(WAL_DIR), |dir| dir.join(WAL_DIR)))
+}
+
+pub fn log_page(page_num: u32, data: &[u8; PAGE_SIZE]) -> io::Result<()> {
+    with_wal(|wal| wal.log_page(page_num, data))
+}
+
+pub fn log_truncate(page_count: u32) -> io::Result<()> {
+    with_wal(|wal| wal.log_truncate(page_count))
+}
+
+// ends the statement that wrote the records since the last commit, does nothing if there are none.
+// gives the LSN to pass to checkpoint once the database file is synced when a segment filled up
+pub fn commit() -> io::Result<Option<u64>> {
+    with_wal(|wal| wal.commit(now_millis()))
+}
+
+pub fn checkpoint(lsn: u64) -> io::Result<usize> {
+    with_wal(|wal| wal.checkpoint(lsn))
+}
+
+// BACKUP calls hold with its start LSN before copying and keep_from with it once the copy is done
+pub fn hold(lsn: u64) -> io::Result<()> {
+    with_wal(|wal| wal.hold(lsn))
+}
+
+pub fn keep_from(lsn: u64) -> io::Result<()> {
+    with_wal(|wal| wal.keep_from(lsn))
+}
+
+// the database file is being deleted. the log is kept, its the way back to what was in it, but
+// recovering cant carry on past this point into the empty database that comes next
+pub fn reset() -> io::Result<()> {
+    with_wal(|wal| wal.reset(now_millis()))
+}
+
+// the LSN of the last record written, 0 before the first
+pub fn current_lsn() -> io::Result<u64> {
+    with_wal(|wal| Ok(wal.current_lsn()))
+}
+
+pub fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+// the segments in dir with their first LSN, oldest first. other files are left alone
+pub fn segments(dir: &Path) -> io::Result<Vec<(u64, PathBuf)>> {
+    let mut segments = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|extension| extension == SEGMENT_EXTENSION)
+            && let Some(first_lsn) = path.file_stem().and_then(|stem| u64::from_str_radix(&stem.to_string_lossy(), 16).ok())
+        {
+            segments.push((first_lsn, path));
+        }
+    }
+    segments.sort();
+    Ok(segments)
+}
+
+pub fn read_segment(path: &Path) -> io::Result<Vec<Record>> {
+    Ok(read_records(&fs::read(path)?).0)
+}
+
+// the records in a segment and how many bytes they take up. a record that cant be read ends it,
+// thats what a crash while one was being appended leaves behind
+fn read_records(bytes: &[u8]) -> (Vec<Record>, usize) {
+    let mut reader = Reader::new(bytes);
+    let mut records = Vec::new();
+    let mut valid_len = 0;
+    while valid_len < bytes.len() {
+        match Record::read(&mut reader) {
+            Ok(record) => {
+                records.push(record);
+                valid_len = reader.position();
+            }
+            Err(_) => break,
+        }
+    }
+    (records, valid_len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a directory of its own for each test, deleted again when the test is done
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("tony_db_wal_{}_{}", std::process::id(), name));
+            let _ = fs::remove_dir_all(&dir);
+            TempDir(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn page(fill: u8) -> [u8; PAGE_SIZE] {
+        [fill; PAGE_SIZE]
+    }
+
+    fn all_records(dir: &Path) -> Vec<Record> {
+        segments(dir).unwrap().iter().flat_map(|(_, path)| read_segment(path).unwrap()).collect()
+    }
+
+    fn lsns(records: &[Record]) -> Vec<u64> {
+        records.iter().map(Record::lsn).collect()
+    }
+
+    #[test]
+    fn records_read_back_as_they_were_appended() {
+        let dir = TempDir::new("append");
+        let mut wal = Wal::open(dir.0.clone()).unwrap();
+        assert_eq!(wal.current_lsn(), 0);
+        wal.log_page(3, &page(7)).unwrap();
+        wal.log_truncate(2).unwrap();
+        wal.commit(1000).unwrap();
+        wal.reset(2000).unwrap();
+        assert_eq!(wal.current_lsn(), 4);
+
+        let records = all_records(&dir.0);
+        assert_eq!(lsns(&records), [1, 2, 3, 4]);
+        assert!(matches!(&records[0], Record::Page { page_num: 3, data, .. } if data.as_slice() == page(7)));
+        assert!(matches!(records[1], Record::Truncate { page_count: 2, .. }));
+        assert!(matches!(records[2], Record::Commit { time: 1000, .. }));
+        assert!(matches!(records[3], Record::Reset { time: 2000, .. }));
+    }
+
+    #[test]
+    fn commit_without_writes_logs_nothing() {
+        let dir = TempDir::new("empty_commit");
+        let mut wal = Wal::open(dir.0.clone()).unwrap();
+        wal.commit(1000).unwrap();
+        assert_eq!(wal.current_lsn(), 0);
+        assert!(segments(&dir.0).unwrap().is_empty());
+    }
+
+    #[test]
+    fn reopening_carries_on_from_the_last_record() {
+        let dir = TempDir::new("reopen");
+        let mut wal = Wal::open(dir.0.clone()).unwrap();
+        wal.log_page(1, &page(1)).unwrap();
+        wal.commit(1000).unwrap();
+        drop(wal);
+
+        let mut wal = Wal::open(dir.0.clone()).unwrap();
+        assert_eq!(wal.current_lsn(), 2);
+        wal.log_page(1, &page(2)).unwrap();
+        wal.commit(2000).unwrap();
+        assert_eq!(lsns(&all_records(&dir.0)), [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn a_torn_record_at_the_end_is_cut_off() {
+        let dir = TempDir::new("torn");
+        let mut wal = Wal::open(dir.0.clone()).unwrap();
+        wal.log_page(1, &page(1)).unwrap();
+        wal.commit(1000).unwrap();
+        wal.log_page(2, &page(2)).unwrap();
+        drop(wal);
+        let (_, path) = segments(&dir.0).unwrap().pop().unwrap();
+        let len = fs::metadata(&path).unwrap().len();
+        fs::OpenOptions::new().write(true).open(&path).unwrap().set_len(len - 100).unwrap();
+        assert_eq!(lsns(&read_segment(&path).unwrap()), [1, 2]);
+
+        // the statement that was being logged never committed, the next one takes its LSN
+        let mut wal = Wal::open(dir.0.clone()).unwrap();
+        assert_eq!(wal.current_lsn(), 2);
+        wal.log_page(2, &page(3)).unwrap();
+        wal.commit(2000).unwrap();
+        let records = all_records(&dir.0);
+        assert_eq!(lsns(&records), [1, 2, 3, 4]);
+        assert!(matches!(&records[2], Record::Page { page_num: 2, data, .. } if data.as_slice() == page(3)));
+    }
+
+    #[test]
+    fn a_full_segment_is_followed_by_a_new_one_at_the_next_statement() {
+        let dir = TempDir::new("segments");
+        let mut wal = Wal::open(dir.0.clone()).unwrap();
+        wal.segment_size = PAGE_SIZE as u64;
+        for i in 0..3 {
+            wal.log_page(1, &page(i)).unwrap();
+            wal.log_page(2, &page(i)).unwrap(); // both in the same segment, it only rolls over at a commit
+            wal.commit(1000).unwrap();
+        }
+        let firsts: Vec<u64> = segments(&dir.0).unwrap().iter().map(|(first, _)| *first).collect();
+        assert_eq!(firsts, [1, 4, 7]);
+        assert_eq!(lsns(&all_records(&dir.0)), (1..=9).collect::<Vec<_>>());
+
+        // reopening appends to the newest segment
+        let mut wal = Wal::open(dir.0.clone()).unwrap();
+        wal.log_page(1, &page(9)).unwrap();
+        assert_eq!(segments(&dir.0).unwrap().len(), 3);
+        assert_eq!(wal.current_lsn(), 10);
+    }
+
+    // statements of a page and a commit each, with the segment size a page every one of them fills a segment
+    fn fill_segments(wal: &mut Wal, statements: u8) -> Vec<Option<u64>> {
+        (0..statements).map(|i| {
+            wal.log_page(1, &page(i)).unwrap();
+            wal.commit(1000).unwrap()
+        }).collect()
+    }
+
+    fn firsts(dir: &Path) -> Vec<u64> {
+        segments(dir).unwrap().iter().map(|(first, _)| *first).collect()
+    }
+
+    #[test]
+    fn a_checkpoint_deletes_the_segments_before_it() {
+        let dir = TempDir::new("checkpoint");
+        let mut wal = Wal::open(dir.0.clone()).unwrap();
+        wal.segment_size = PAGE_SIZE as u64;
+        let due = fill_segments(&mut wal, 3);
+        assert_eq!(due, [Some(2), Some(4), Some(6)]);
+        assert_eq!(firsts(&dir.0), [1, 3, 5]);
+
+        assert_eq!(wal.checkpoint(4).unwrap(), 2);
+        assert_eq!(firsts(&dir.0), [5]);
+        // the newest segment stays even when its all before the checkpoint
+        assert_eq!(wal.checkpoint(6).unwrap(), 0);
+        drop(wal);
+        let wal = Wal::open(dir.0.clone()).unwrap();
+        assert_eq!(wal.current_lsn(), 6);
+    }
+
+    #[test]
+    fn a_checkpoint_keeps_the_segments_the_latest_backup_needs() {
+        let dir = TempDir::new("checkpoint_backup");
+        let mut wal = Wal::open(dir.0.clone()).unwrap();
+        wal.segment_size = PAGE_SIZE as u64;
+        fill_segments(&mut wal, 2);
+        wal.hold(2).unwrap();
+        fill_segments(&mut wal, 3);
+        assert_eq!(firsts(&dir.0), [1, 3, 5, 7, 9]);
+
+        // the backup started at LSN 2 needs 3 on
+        assert_eq!(wal.checkpoint(10).unwrap(), 1);
+        assert_eq!(firsts(&dir.0), [3, 5, 7, 9]);
+        // a second backup doesnt let go of the first until its done
+        wal.hold(6).unwrap();
+        assert_eq!(wal.checkpoint(10).unwrap(), 0);
+        wal.keep_from(6).unwrap();
+        assert_eq!(wal.checkpoint(10).unwrap(), 2);
+        assert_eq!(firsts(&dir.0), [7, 9]);
+    }
+
+    #[test]
+    fn a_segment_that_isnt_full_asks_for_no_checkpoint() {
+        let dir = TempDir::new("checkpoint_none");
+        let mut wal = Wal::open(dir.0.clone()).unwrap();
+        assert_eq!(fill_segments(&mut wal, 2), [None, None]);
+        assert_eq!(wal.checkpoint(4).unwrap(), 0);
+        assert_eq!(lsns(&all_records(&dir.0)), [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn files_that_arent_segments_are_left_alone() {
+        let dir = TempDir::new("other_files");
+        fs::create_dir_all(&dir.0).unwrap();
+        fs::write(dir.0.join("notes.txt"), "x").unwrap();
+        fs::write(dir.0.join("zz.wal"), "x").unwrap();
+        assert!(segments(&dir.0).unwrap().is_empty());
+    }
+}
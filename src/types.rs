@@ -0,0 +1,252 @@
+use crate::error::Error;
+use std::cmp::Ordering;
+use std::fmt;
+
+const SIGN_BIT: u64 = 1 << 63;
+
+// column types. what a column holds is checked and converted to its type when a row is written
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DataType {
+    Integer = 0,
+    Real = 1,
+    Text = 2,
+    Boolean = 3,
+}
+
+impl DataType {
+    // the type a column gets from its declared type name in CREATE
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_uppercase().as_str() {
+            "INT" | "INTEGER" | "BIGINT" | "SMALLINT" => Some(DataType::Integer),
+            "REAL" | "FLOAT" | "DOUBLE" | "DECIMAL" | "NUMERIC" => Some(DataType::Real),
+            "TEXT" | "VARCHAR" | "CHAR" | "STRING" => Some(DataType::Text),
+            "BOOL" | "BOOLEAN" => Some(DataType::Boolean),
+            _ => None,
+        }
+    }
+
+    pub fn from_code(code: u8) -> Option<Self> {
+        match code {
+            0 => Some(DataType::Integer),
+            1 => Some(DataType::Real),
+            2 => Some(DataType::Text),
+            3 => Some(DataType::Boolean),
+            _ => None,
+        }
+    }
+
+    // the value an index key was made from by Value::to_index_key
+    pub fn from_index_key(&self, key: &str) -> Option<Value> {
+        match self {
+            DataType::Integer | DataType::Real if key.len() != 16 => None,
+            DataType::Integer => u64::from_str_radix(key, 16).ok().map(|bits| Value::Integer((bits ^ SIGN_BIT) as i64)),
+            DataType::Real => u64::from_str_radix(key, 16).ok()
+                .map(|bits| Value::Real(f64::from_bits(if bits & SIGN_BIT != 0 { bits ^ SIGN_BIT } else { !bits }))),
+            DataType::Text | DataType::Boolean => self.parse(key),
+        }
+    }
+
+    // the value stored text of this type stands for, None if it isnt valid for the type
+    pub fn parse(&self, text: &str) -> Option<Value> {
+        match self {
+            DataType::Integer => text.trim().parse::<i64>().ok().map(Value::Integer),
+            DataType::Real => text.trim().parse::<f64>().ok().map(Value::Real),
+            DataType::Text => Some(Value::Text(text.to_string())),
+            DataType::Boolean => parse_bool(text).map(Value::Boolean),
+        }
+    }
+}
+
+impl fmt::Display for DataType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            DataType::Integer => "INTEGER",
+            DataType::Real => "REAL",
+            DataType::Text => "TEXT",
+            DataType::Boolean => "BOOLEAN",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+// what happens to the rows referencing a parent row through a foreign key when that row is deleted or
+// its key changes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReferentialAction {
+    NoAction = 0, // like RESTRICT, but a deferred constraint only checks once the statement is done
+    Restrict = 1,
+    Cascade = 2,
+    SetNull = 3,
+    SetDefault = 4,
+}
+
+impl ReferentialAction {
+    pub fn from_code(code: u8) -> Option<Self> {
+        match code {
+            0 => Some(ReferentialAction::NoAction),
+            1 => Some(ReferentialAction::Restrict),
+            2 => Some(ReferentialAction::Cascade),
+            3 => Some(ReferentialAction::SetNull),
+            4 => Some(ReferentialAction::SetDefault),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ReferentialAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ReferentialAction::NoAction => "NO ACTION",
+            ReferentialAction::Restrict => "RESTRICT",
+            ReferentialAction::Cascade => "CASCADE",
+            ReferentialAction::SetNull => "SET NULL",
+            ReferentialAction::SetDefault => "SET DEFAULT",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+// a single value in a row or produced by an expression
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Integer(i64),
+    Real(f64),
+    Text(String),
+    Boolean(bool),
+}
+
+impl Value {
+    pub fn is_null(&self) -> bool {
+        matches!(self, Value::Null)
+    }
+
+    // convert to the type of a column. quoted literals like '42' become numbers here, anything
+    // that doesnt convert cleanly is an error
+    pub fn coerce(self, data_type: DataType) -> Result<Value, Error> {
+        let converted = match (&self, data_type) {
+            (Value::Null, _) => Some(Value::Null),
+            (Value::Integer(_), DataType::Integer)
+            | (Value::Real(_), DataType::Real)
+            | (Value::Text(_), DataType::Text)
+            | (Value::Boolean(_), DataType::Boolean) => Some(self.clone()),
+            (Value::Integer(i), DataType::Real) => Some(Value::Real(*i as f64)),
+            (Value::Real(r), DataType::Integer) if r.fract() == 0.0 && r.abs() < i64::MAX as f64 => Some(Value::Integer(*r as i64)),
+            (Value::Text(t), _) => data_type.parse(t),
+            (_, DataType::Text) => Some(Value::Text(self.to_string())),
+            _ => None,
+        };
+        converted.ok_or_else(|| Error::Type(format!("'{}' is not a valid {}", self, data_type)))
+    }
+
+    // how the value is written into a row. NULL isnt text, rows store it separately
+    pub fn to_stored(&self) -> Option<String> {
+        match self {
+            Value::Null => None,
+            other => Some(other.to_string()),
+        }
+    }
+
+    // how the value is written as an index key, which compare as strings. numbers are fixed width hex
+    // of their bits made to sort the way the numbers do, so a range of values is a range of keys: the
+    // sign bit of an integer flipped, and for a real the sign bit flipped when its positive and every
+    // bit when its negative. 0 and -0 are one key as they are equal
+    pub fn to_index_key(&self) -> Option<String> {
+        match self {
+            Value::Null => None,
+            Value::Integer(i) => Some(format!("{:016x}", (*i as u64) ^ SIGN_BIT)),
+            Value::Real(r) => {
+                let bits = if *r == 0.0 { 0 } else { r.to_bits() };
+                Some(format!("{:016x}", if bits & SIGN_BIT == 0 { bits | SIGN_BIT } else { !bits }))
+            }
+            other => Some(other.to_string()),
+        }
+    }
+
+    // SQL ordering, None when either side is NULL. text compared against a number or boolean is
+    // read as one if it can be so that '10' > 9 holds, otherwise both sides compare as text
+    pub fn compare(&self, other: &Value) -> Option<Ordering> {
+        match (self, other) {
+            (Value::Null, _) | (_, Value::Null) => None,
+            (Value::Integer(l), Value::Integer(r)) => Some(l.cmp(r)),
+            (Value::Integer(l), Value::Real(r)) => (*l as f64).partial_cmp(r),
+            (Value::Real(l), Value::Integer(r)) => l.partial_cmp(&(*r as f64)),
+            (Value::Real(l), Value::Real(r)) => l.partial_cmp(r),
+            (Value::Text(l), Value::Text(r)) => Some(l.cmp(r)),
+            (Value::Boolean(l), Value::Boolean(r)) => Some(l.cmp(r)),
+            (Value::Text(t), typed) => match Self::read_text_as(t, typed) {
+                Some(converted) => converted.compare(typed),
+                None => Some(t.as_str().cmp(typed.to_string().as_str())),
+            },
+            (typed, Value::Text(_)) => other.compare(typed).map(Ordering::reverse),
+            (l, r) => Some(l.to_string().cmp(&r.to_string())),
+        }
+    }
+
+    fn read_text_as(text: &str, like: &Value) -> Option<Value> {
+        match like {
+            Value::Integer(_) | Value::Real(_) => DataType::Real.parse(text),
+            Value::Boolean(_) => DataType::Boolean.parse(text),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Null => write!(f, "NULL"),
+            Value::Integer(i) => write!(f, "{}", i),
+            Value::Real(r) => write!(f, "{}", r),
+            Value::Text(t) => write!(f, "{}", t),
+            Value::Boolean(b) => write!(f, "{}", b),
+        }
+    }
+}
+
+fn parse_bool(value: &str) -> Option<bool> {
+    match value.trim().to_lowercase().as_str() {
+        "true" | "t" | "1" => Some(true),
+        "false" | "f" | "0" => Some(false),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sorts_like_the_values(data_type: DataType, values: &[Value]) {
+        let keys: Vec<String> = values.iter().map(|v| v.to_index_key().unwrap()).collect();
+        for (pair, keys) in values.windows(2).zip(keys.windows(2)) {
+            assert_eq!(keys[0].cmp(&keys[1]), pair[0].compare(&pair[1]).unwrap(), "{} and {}", pair[0], pair[1]);
+        }
+        for (value, key) in values.iter().zip(&keys) {
+            assert_eq!(data_type.from_index_key(key).unwrap().compare(value), Some(Ordering::Equal), "{}", value);
+        }
+    }
+
+    #[test]
+    fn integer_keys_sort_like_the_integers() {
+        let values = [i64::MIN, -1_000_000, -256, -1, 0, 1, 9, 10, 255, 256, 1_000_000, i64::MAX];
+        sorts_like_the_values(DataType::Integer, &values.map(Value::Integer));
+    }
+
+    #[test]
+    fn real_keys_sort_like_the_reals() {
+        let values = [f64::NEG_INFINITY, f64::MIN, -1e10, -2.5, -1.0, -f64::MIN_POSITIVE, 0.0, f64::MIN_POSITIVE, 0.5, 1.0, 2.5, 10.0, 1e10, f64::MAX, f64::INFINITY];
+        sorts_like_the_values(DataType::Real, &values.map(Value::Real));
+    }
+
+    #[test]
+    fn zero_and_negative_zero_are_one_key() {
+        assert_eq!(Value::Real(-0.0).to_index_key(), Value::Real(0.0).to_index_key());
+    }
+
+    #[test]
+    fn keys_that_arent_numbers_dont_read_back() {
+        assert!(DataType::Integer.from_index_key("12").is_none());
+        assert!(DataType::Real.from_index_key("not a real key!!").is_none());
+        assert!(Value::Null.to_index_key().is_none());
+    }
+}